@@ -0,0 +1,3 @@
+-- Add migration script here
+CREATE INDEX IF NOT EXISTS users_chat_id_idx ON users (chat_id);
+CREATE INDEX IF NOT EXISTS runs_user_id_run_datetime_idx ON runs (user_id, run_datetime);
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE runs
+ADD COLUMN duration_secs INTEGER;
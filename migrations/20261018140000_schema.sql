@@ -0,0 +1,6 @@
+-- Add migration script here
+ALTER TABLE runs
+ADD COLUMN sport VARCHAR(16),
+ADD COLUMN avg_heart_rate INTEGER,
+ADD COLUMN max_heart_rate INTEGER,
+ADD COLUMN elevation_gain REAL;
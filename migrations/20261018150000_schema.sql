@@ -0,0 +1,24 @@
+-- Add migration script here
+ALTER TABLE runs
+ADD COLUMN external_id VARCHAR;
+CREATE UNIQUE INDEX IF NOT EXISTS runs_user_id_external_id_idx ON runs (user_id, external_id);
+CREATE TABLE IF NOT EXISTS provider_links (
+    id serial PRIMARY KEY,
+    user_id integer NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    provider varchar(16) NOT NULL,
+    account_id varchar NOT NULL,
+    access_token varchar NOT NULL,
+    refresh_token varchar NOT NULL,
+    expires_at timestamp NOT NULL,
+    UNIQUE (user_id, provider)
+);
+CREATE INDEX IF NOT EXISTS provider_links_account_idx ON provider_links (provider, account_id);
+
+CREATE TABLE IF NOT EXISTS link_requests (
+    state varchar PRIMARY KEY DEFAULT gen_random_uuid()::text,
+    provider varchar(16) NOT NULL,
+    telegram_userid varchar NOT NULL,
+    chat_id varchar NOT NULL,
+    user_name varchar NOT NULL,
+    requested timestamp NOT NULL DEFAULT now()
+);
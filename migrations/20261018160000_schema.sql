@@ -0,0 +1,5 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS chat_settings (
+    chat_id varchar PRIMARY KEY,
+    rank_emojis varchar(255)
+);
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS recap_schedules (
+    chat_id varchar NOT NULL,
+    period varchar(8) NOT NULL,
+    local_time time NOT NULL,
+    timezone varchar(64) NOT NULL,
+    last_sent timestamp NOT NULL DEFAULT now(),
+    PRIMARY KEY (chat_id, period)
+);
@@ -0,0 +1,14 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS reminder_schedules (
+    chat_id varchar PRIMARY KEY,
+    inactive_days integer NOT NULL,
+    local_time time NOT NULL,
+    timezone varchar(64) NOT NULL,
+    last_sent timestamp NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS reminder_opt_outs (
+    chat_id varchar NOT NULL,
+    telegram_userid varchar NOT NULL,
+    PRIMARY KEY (chat_id, telegram_userid)
+);
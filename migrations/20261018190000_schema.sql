@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS chat_goals (
+    chat_id varchar PRIMARY KEY,
+    distance real NOT NULL,
+    period varchar(8) NOT NULL,
+    starts timestamp NOT NULL,
+    ends timestamp NOT NULL,
+    reached timestamp
+);
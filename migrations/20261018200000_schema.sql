@@ -0,0 +1,21 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS personal_goals (
+    chat_id varchar NOT NULL,
+    telegram_userid varchar NOT NULL,
+    period varchar(8) NOT NULL,
+    target real NOT NULL,
+    unit varchar(8) NOT NULL,
+    since timestamp NOT NULL DEFAULT now(),
+    PRIMARY KEY (chat_id, telegram_userid)
+);
+
+CREATE TABLE IF NOT EXISTS personal_goal_achievements (
+    chat_id varchar NOT NULL,
+    telegram_userid varchar NOT NULL,
+    period varchar(8) NOT NULL,
+    period_start timestamp NOT NULL,
+    target real NOT NULL,
+    unit varchar(8) NOT NULL,
+    reached timestamp NOT NULL DEFAULT now(),
+    PRIMARY KEY (chat_id, telegram_userid, period, period_start)
+);
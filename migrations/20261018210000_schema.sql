@@ -0,0 +1,22 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS challenges (
+    id serial PRIMARY KEY,
+    chat_id varchar NOT NULL,
+    challenger_userid varchar NOT NULL,
+    challenger_name varchar NOT NULL,
+    opponent_name varchar NOT NULL,
+    opponent_userid varchar,
+    distance real NOT NULL,
+    days integer NOT NULL,
+    created timestamp NOT NULL DEFAULT now(),
+    starts timestamp,
+    ends timestamp
+);
+
+CREATE TABLE IF NOT EXISTS challenge_results (
+    challenge_id integer PRIMARY KEY REFERENCES challenges(id) ON DELETE CASCADE,
+    winner_userid varchar,
+    challenger_distance real NOT NULL,
+    opponent_distance real NOT NULL,
+    settled timestamp NOT NULL DEFAULT now()
+);
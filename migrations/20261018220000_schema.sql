@@ -0,0 +1,20 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS teams (
+    id serial PRIMARY KEY,
+    chat_id varchar NOT NULL,
+    name varchar(32) NOT NULL,
+    created timestamp NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS teams_chat_id_name ON teams (chat_id, LOWER(name));
+
+CREATE TABLE IF NOT EXISTS team_members (
+    chat_id varchar NOT NULL,
+    telegram_userid varchar NOT NULL,
+    user_name varchar NOT NULL,
+    team_id integer NOT NULL REFERENCES teams(id) ON DELETE CASCADE,
+    joined timestamp NOT NULL DEFAULT now(),
+    PRIMARY KEY (chat_id, telegram_userid)
+);
+
+ALTER TABLE chat_settings ADD COLUMN IF NOT EXISTS teams_per_member boolean NOT NULL DEFAULT false;
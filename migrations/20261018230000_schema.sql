@@ -0,0 +1,23 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS profiles (
+    telegram_userid varchar(20) PRIMARY KEY,
+    user_name varchar NOT NULL,
+    created timestamp NOT NULL DEFAULT now()
+);
+
+INSERT INTO profiles (telegram_userid, user_name)
+SELECT DISTINCT ON (telegram_userid) telegram_userid, user_name
+FROM users
+ORDER BY telegram_userid, id DESC
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE users ADD CONSTRAINT users_telegram_userid_fkey
+    FOREIGN KEY (telegram_userid) REFERENCES profiles (telegram_userid);
+
+ALTER TABLE runs ADD COLUMN IF NOT EXISTS shared_from integer;
+
+CREATE TABLE IF NOT EXISTS shared_chats (
+    telegram_userid varchar(20) NOT NULL REFERENCES profiles (telegram_userid),
+    chat_id varchar NOT NULL,
+    PRIMARY KEY (telegram_userid, chat_id)
+);
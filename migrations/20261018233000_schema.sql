@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS chat_journeys (
+    chat_id varchar PRIMARY KEY,
+    route varchar NOT NULL,
+    starts timestamp NOT NULL,
+    reached integer NOT NULL DEFAULT 0
+);
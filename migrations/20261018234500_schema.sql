@@ -0,0 +1,9 @@
+-- Add migration script here
+ALTER TABLE runs ADD COLUMN IF NOT EXISTS race boolean NOT NULL DEFAULT false;
+
+CREATE TABLE IF NOT EXISTS run_splits (
+    run_id integer NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+    distance real NOT NULL,
+    elapsed_secs integer NOT NULL,
+    PRIMARY KEY (run_id, distance)
+);
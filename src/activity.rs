@@ -0,0 +1,560 @@
+//! Activity files recorded by watches and phones.
+//!
+//! GPX and TCX tracks, as well as binary FIT files (see `fit`), are
+//! reduced to the few numbers a run is stored with. Distances are worked
+//! out with the haversine formula between consecutive trackpoints, unless
+//! the file records distances itself.
+use crate::{
+    fit::parse_fit,
+    models::{RunDetails, Split, Sport},
+};
+use chrono::{DateTime, NaiveDateTime};
+use roxmltree::{Document, Node};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// Mean radius of the Earth in metres.
+const EARTH_RADIUS: f64 = 6_371_000.;
+
+/// Speed in m/s below which someone is considered to be standing still.
+const MOVING_SPEED: f64 = 0.5;
+
+/// A single point of a recorded track.
+#[derive(Clone, Copy, Default)]
+pub struct TrackPoint {
+    /// Latitude and longitude in degrees.
+    pub position: Option<(f64, f64)>,
+    /// When the point was recorded.
+    pub time: Option<NaiveDateTime>,
+    /// Elevation in metres.
+    pub elevation: Option<f64>,
+    /// Heart rate in beats per minute.
+    pub heart_rate: Option<f64>,
+    /// Distance covered so far in metres, as recorded by the device.
+    pub distance: Option<f64>,
+}
+
+/// Summary of a recorded activity.
+#[derive(Debug, PartialEq)]
+pub struct Activity {
+    /// Kind of activity, if the file says.
+    pub sport: Option<Sport>,
+    /// Distance covered in km.
+    pub distance: f32,
+    /// When the activity started.
+    pub start_time: Option<NaiveDateTime>,
+    /// Time from start to finish in seconds.
+    pub elapsed_secs: Option<i32>,
+    /// Time spent moving in seconds, excluding pauses.
+    pub moving_secs: Option<i32>,
+    /// Average heart rate in beats per minute.
+    pub avg_heart_rate: Option<i32>,
+    /// Maximum heart rate in beats per minute.
+    pub max_heart_rate: Option<i32>,
+    /// Total ascent in metres.
+    pub elevation_gain: Option<f32>,
+    /// Whether the activity was a race effort.
+    pub race: bool,
+    /// Time taken to reach the end of every km, then the end of the
+    /// activity, if the track has times.
+    pub splits: Vec<Split>,
+}
+
+impl Activity {
+    /// Works out an activity from the segments of a recorded track.
+    ///
+    /// Distances are only summed within segments, since recording was
+    /// stopped in between them. Splits are timed from the first point,
+    /// working out when every km was reached between the points either
+    /// side of it.
+    pub fn from_track(
+        sport: Option<Sport>,
+        segments: &[Vec<TrackPoint>],
+    ) -> Result<Activity, String> {
+        if segments.iter().all(|segment| segment.len() < 2) {
+            return Err("The file has no track to work out a distance from.".into());
+        }
+
+        let origin = segments.iter().flatten().find_map(|point| point.time);
+        let mut distance: f64 = 0.;
+        let mut moving_secs = 0;
+        let mut elevation_gain = None;
+        let mut splits = vec![];
+        for segment in segments {
+            for pair in segment.windows(2) {
+                let step = match (pair[0].distance, pair[1].distance) {
+                    (Some(from), Some(to)) => (to - from).max(0.),
+                    _ => match (pair[0].position, pair[1].position) {
+                        (Some(from), Some(to)) => haversine(from, to),
+                        _ => 0.,
+                    },
+                };
+                if let (Some(origin), Some(start), Some(end)) = (origin, pair[0].time, pair[1].time)
+                {
+                    let secs_at =
+                        |time: NaiveDateTime| (time - origin).num_milliseconds() as f64 / 1000.;
+                    let mut km = (distance / 1000.).floor() + 1.;
+                    while step > 0. && km * 1000. <= distance + step {
+                        let share = (km * 1000. - distance) / step;
+                        let secs = secs_at(start) + share * (secs_at(end) - secs_at(start));
+                        splits.push(Split {
+                            distance: km as f32,
+                            elapsed_secs: secs.round() as i32,
+                        });
+                        km += 1.;
+                    }
+                }
+                distance += step;
+                if let (Some(start), Some(end)) = (pair[0].time, pair[1].time) {
+                    let secs = (end - start).num_seconds();
+                    if secs > 0 && step / secs as f64 >= MOVING_SPEED {
+                        moving_secs += secs;
+                    }
+                }
+                if let (Some(from), Some(to)) = (pair[0].elevation, pair[1].elevation) {
+                    *elevation_gain.get_or_insert(0.) += (to - from).max(0.);
+                }
+            }
+        }
+
+        let points = || segments.iter().flatten();
+        let times: Vec<NaiveDateTime> = points().filter_map(|point| point.time).collect();
+        let start_time = times.iter().min().copied();
+        let elapsed_secs = start_time
+            .zip(times.iter().max())
+            .map(|(start, end)| (*end - start).num_seconds() as i32);
+        let heart_rates: Vec<f64> = points().filter_map(|point| point.heart_rate).collect();
+        if let Some(elapsed_secs) = elapsed_secs {
+            let total = round_km(distance);
+            if splits.last().map_or(0., |split: &Split| split.distance) < total {
+                splits.push(Split {
+                    distance: total,
+                    elapsed_secs,
+                });
+            }
+        }
+
+        Ok(Activity {
+            sport,
+            distance: round_km(distance),
+            start_time,
+            elapsed_secs,
+            moving_secs: elapsed_secs.map(|_| moving_secs as i32),
+            avg_heart_rate: (!heart_rates.is_empty()).then(|| {
+                (heart_rates.iter().sum::<f64>() / heart_rates.len() as f64).round() as i32
+            }),
+            max_heart_rate: heart_rates
+                .iter()
+                .copied()
+                .reduce(f64::max)
+                .map(|max| max.round() as i32),
+            elevation_gain: elevation_gain.map(|gain: f64| gain.round() as f32),
+            race: false,
+            splits,
+        })
+    }
+
+    /// Details the run is stored with.
+    pub fn details(&self) -> RunDetails {
+        RunDetails {
+            run_datetime: self.start_time,
+            duration_secs: self.elapsed_secs,
+            sport: self.sport,
+            avg_heart_rate: self.avg_heart_rate,
+            max_heart_rate: self.max_heart_rate,
+            elevation_gain: self.elevation_gain,
+            external_id: None,
+            race: self.race,
+            splits: self.splits.clone(),
+        }
+    }
+}
+
+/// Converts metres to km, rounded to 10m.
+pub fn round_km(metres: f64) -> f32 {
+    (metres / 10.).round() as f32 / 100.
+}
+
+/// Whether a document looks like an activity file, going by its name.
+pub fn is_activity_file(file_name: &str) -> bool {
+    let file_name = file_name.to_lowercase();
+    [".gpx", ".tcx", ".fit"]
+        .iter()
+        .any(|extension| file_name.ends_with(extension))
+}
+
+/// Id of an activity file, so that a file sent again, or to a chat the
+/// run was shared to, is logged only once.
+pub fn file_id(file: &[u8]) -> String {
+    format!("file:{:x}", Sha256::digest(file))
+}
+
+/// Parses an activity file in the format given by its extension.
+pub fn parse_activity(file_name: &str, file: &[u8]) -> Result<Activity, String> {
+    let file_name = file_name.to_lowercase();
+    if file_name.ends_with(".fit") {
+        parse_fit(file)
+    } else if file_name.ends_with(".tcx") {
+        parse_tcx(file)
+    } else {
+        parse_gpx(file)
+    }
+}
+
+/// Parses `file` as XML, naming `format` in the error if it is not.
+fn parse_xml<'a>(file: &'a [u8], format: &str) -> Result<Document<'a>, String> {
+    std::str::from_utf8(file)
+        .ok()
+        .and_then(|text| Document::parse(text).ok())
+        .ok_or(format!("The file is not valid {}.", format))
+}
+
+/// Text of the first child of `node` named `name`.
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|child| child.has_tag_name(name))
+        .and_then(|child| child.text())
+        .map(str::trim)
+}
+
+/// Parses an RFC 3339 timestamp, as used by GPX and TCX, into UTC.
+fn parse_time(time: &str) -> Option<NaiveDateTime> {
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|time| time.naive_utc())
+}
+
+/// Parses a GPX file into an `Activity`.
+///
+/// Heart rates are read from Garmin's `TrackPointExtension`, which most
+/// devices write.
+pub fn parse_gpx(file: &[u8]) -> Result<Activity, String> {
+    let document = parse_xml(file, "GPX")?;
+    let sport = document
+        .descendants()
+        .find(|node| node.has_tag_name("trk"))
+        .and_then(|track| child_text(track, "type"))
+        .map(Sport::from_activity_name);
+
+    let segments: Vec<Vec<TrackPoint>> = document
+        .descendants()
+        .filter(|node| node.has_tag_name("trkseg"))
+        .map(|segment| {
+            segment
+                .children()
+                .filter(|node| node.has_tag_name("trkpt"))
+                .map(|point| TrackPoint {
+                    position: point
+                        .attribute("lat")
+                        .and_then(|lat| lat.parse().ok())
+                        .zip(point.attribute("lon").and_then(|lon| lon.parse().ok())),
+                    time: child_text(point, "time").and_then(parse_time),
+                    elevation: child_text(point, "ele").and_then(|ele| ele.parse().ok()),
+                    heart_rate: point
+                        .descendants()
+                        .find(|node| node.has_tag_name("hr"))
+                        .and_then(|hr| hr.text())
+                        .and_then(|hr| hr.trim().parse().ok()),
+                    distance: None,
+                })
+                .collect()
+        })
+        .collect();
+
+    Activity::from_track(sport, &segments)
+}
+
+/// Parses a TCX file into an `Activity`.
+///
+/// Only the first activity in the file is read. Distances and moving times
+/// recorded in laps take precedence over those worked out from the track.
+pub fn parse_tcx(file: &[u8]) -> Result<Activity, String> {
+    let document = parse_xml(file, "TCX")?;
+    let activity = document
+        .descendants()
+        .find(|node| node.has_tag_name("Activity"))
+        .ok_or("The file has no activity in it.")?;
+    let sport = activity.attribute("Sport").map(Sport::from_activity_name);
+
+    let segments: Vec<Vec<TrackPoint>> = activity
+        .descendants()
+        .filter(|node| node.has_tag_name("Track"))
+        .map(|track| {
+            track
+                .children()
+                .filter(|node| node.has_tag_name("Trackpoint"))
+                .map(|point| {
+                    let position = point.children().find(|node| node.has_tag_name("Position"));
+                    let degrees = |name| {
+                        position
+                            .and_then(|position| child_text(position, name))
+                            .and_then(|degrees| degrees.parse().ok())
+                    };
+                    TrackPoint {
+                        position: degrees("LatitudeDegrees").zip(degrees("LongitudeDegrees")),
+                        time: child_text(point, "Time").and_then(parse_time),
+                        elevation: child_text(point, "AltitudeMeters")
+                            .and_then(|ele| ele.parse().ok()),
+                        heart_rate: point
+                            .children()
+                            .find(|node| node.has_tag_name("HeartRateBpm"))
+                            .and_then(|hr| child_text(hr, "Value"))
+                            .and_then(|hr| hr.parse().ok()),
+                        distance: child_text(point, "DistanceMeters")
+                            .and_then(|distance| distance.parse().ok()),
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    let mut parsed = Activity::from_track(sport, &segments)?;
+
+    let laps: Vec<Node> = activity
+        .children()
+        .filter(|node| node.has_tag_name("Lap"))
+        .collect();
+    let lap_total = |name| {
+        laps.iter()
+            .map(|lap| child_text(*lap, name).and_then(|value| value.parse::<f64>().ok()))
+            .sum::<Option<f64>>()
+    };
+    if let Some(distance) = lap_total("DistanceMeters").filter(|_| !laps.is_empty()) {
+        parsed.distance = round_km(distance);
+    }
+    if let Some(moving_secs) = lap_total("TotalTimeSeconds").filter(|_| !laps.is_empty()) {
+        parsed.moving_secs = Some(moving_secs.round() as i32);
+    }
+
+    Ok(parsed)
+}
+
+/// Great-circle distance between two positions in metres.
+fn haversine(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (from.0.to_radians(), to.0.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (to.1 - from.1).to_radians();
+
+    let a = (d_lat / 2.).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.).sin().powi(2);
+    2. * EARTH_RADIUS * a.sqrt().asin()
+}
+
+/// Formats seconds as `h:mm:ss`, or `m:ss` under an hour.
+pub fn format_duration(secs: i32) -> String {
+    let (hours, minutes, seconds) = (secs / 3600, secs % 3600 / 60, secs % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+/// Reply sent once an activity has been logged for `user_name`.
+pub fn summarise_activity(user_name: &str, activity: &Activity) -> String {
+    let verb = activity.sport.unwrap_or(Sport::Run).verb();
+    let mut summary = format!("{} {} {}km", user_name, verb, activity.distance);
+    if let Some(elapsed_secs) = activity.elapsed_secs {
+        let _ = write!(summary, " in {}", format_duration(elapsed_secs));
+    }
+    if let Some(moving_secs) = activity.moving_secs {
+        let _ = write!(summary, " ({} moving)", format_duration(moving_secs));
+    }
+    if let Some(start_time) = activity.start_time {
+        let _ = write!(summary, " from {}", start_time.format("%Y-%m-%d %H:%M"));
+    }
+    if let Some(avg_heart_rate) = activity.avg_heart_rate {
+        let _ = write!(summary, ", avg HR {}", avg_heart_rate);
+        if let Some(max_heart_rate) = activity.max_heart_rate {
+            let _ = write!(summary, " (max {})", max_heart_rate);
+        }
+    }
+    if let Some(elevation_gain) = activity.elevation_gain {
+        let _ = write!(summary, ", {}m climbed", elevation_gain);
+    }
+    summary.push_str(", added to database.");
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    /// A track heading north along a meridian, with a minute's pause at
+    /// the second point and a gap between segments.
+    const GPX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="test" xmlns="http://www.topografix.com/GPX/1/1"
+  xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v1">
+  <trk>
+    <name>Morning Run</name>
+    <type>running</type>
+    <trkseg>
+      <trkpt lat="1.3000" lon="103.8000"><ele>10</ele><time>2023-10-14T07:12:00Z</time>
+        <extensions><gpxtpx:TrackPointExtension><gpxtpx:hr>140</gpxtpx:hr></gpxtpx:TrackPointExtension></extensions>
+      </trkpt>
+      <trkpt lat="1.3090" lon="103.8000"><ele>25</ele><time>2023-10-14T07:17:00Z</time>
+        <extensions><gpxtpx:TrackPointExtension><gpxtpx:hr>160</gpxtpx:hr></gpxtpx:TrackPointExtension></extensions>
+      </trkpt>
+      <trkpt lat="1.3090" lon="103.8000"><ele>20</ele><time>2023-10-14T07:18:00Z</time></trkpt>
+    </trkseg>
+    <trkseg>
+      <trkpt lat="1.3200" lon="103.8000"><time>2023-10-14T07:20:00Z</time></trkpt>
+      <trkpt lat="1.3290" lon="103.8000"><time>2023-10-14T07:25:00Z</time></trkpt>
+    </trkseg>
+  </trk>
+</gpx>
+"#;
+
+    /// A ride with a single lap, whose recorded distance and timer time
+    /// differ from the track's.
+    const TCX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<TrainingCenterDatabase xmlns="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2">
+  <Activities>
+    <Activity Sport="Biking">
+      <Id>2023-10-14T07:12:00Z</Id>
+      <Lap StartTime="2023-10-14T07:12:00Z">
+        <TotalTimeSeconds>3500</TotalTimeSeconds>
+        <DistanceMeters>20150.0</DistanceMeters>
+        <Track>
+          <Trackpoint>
+            <Time>2023-10-14T07:12:00Z</Time>
+            <Position><LatitudeDegrees>1.3</LatitudeDegrees><LongitudeDegrees>103.8</LongitudeDegrees></Position>
+            <AltitudeMeters>5.0</AltitudeMeters>
+            <DistanceMeters>0.0</DistanceMeters>
+            <HeartRateBpm><Value>120</Value></HeartRateBpm>
+          </Trackpoint>
+          <Trackpoint>
+            <Time>2023-10-14T08:12:00Z</Time>
+            <Position><LatitudeDegrees>1.4</LatitudeDegrees><LongitudeDegrees>103.8</LongitudeDegrees></Position>
+            <AltitudeMeters>47.4</AltitudeMeters>
+            <DistanceMeters>20000.0</DistanceMeters>
+            <HeartRateBpm><Value>141</Value></HeartRateBpm>
+          </Trackpoint>
+        </Track>
+      </Lap>
+    </Activity>
+  </Activities>
+</TrainingCenterDatabase>
+"#;
+
+    #[test]
+    fn haversine_distance() {
+        // A degree of latitude is a 360th of the Earth's circumference.
+        let degree = haversine((0., 0.), (1., 0.));
+        assert!((degree - 111_194.9).abs() < 0.1);
+        // Singapore to Kuala Lumpur.
+        let distance = haversine((1.3521, 103.8198), (3.1390, 101.6869));
+        assert!((distance / 1000. - 309.).abs() < 1.);
+    }
+
+    #[test]
+    fn parse_gpx_track() {
+        let activity = parse_activity("Morning_Run.GPX", GPX.as_bytes()).unwrap();
+        assert_eq!(
+            activity,
+            Activity {
+                sport: Some(Sport::Run),
+                distance: 2.,
+                start_time: NaiveDate::from_ymd_opt(2023, 10, 14)
+                    .unwrap()
+                    .and_hms_opt(7, 12, 0),
+                elapsed_secs: Some(13 * 60),
+                moving_secs: Some(10 * 60),
+                avg_heart_rate: Some(150),
+                max_heart_rate: Some(160),
+                elevation_gain: Some(15.),
+                race: false,
+                splits: vec![
+                    Split {
+                        distance: 1.,
+                        elapsed_secs: 300,
+                    },
+                    Split {
+                        distance: 2.,
+                        elapsed_secs: 780,
+                    },
+                ],
+            }
+        );
+        assert_eq!(
+            summarise_activity("reuben", &activity),
+            "reuben ran 2km in 13:00 (10:00 moving) from 2023-10-14 07:12, avg HR 150 (max 160), 15m climbed, added to database."
+        );
+    }
+
+    #[test]
+    fn parse_gpx_track_without_times() {
+        let gpx = r#"<gpx><trk><trkseg>
+            <trkpt lat="0" lon="0"/><trkpt lat="0.01" lon="0"/>
+        </trkseg></trk></gpx>"#;
+        let activity = parse_gpx(gpx.as_bytes()).unwrap();
+        assert_eq!(activity.distance, 1.11);
+        assert_eq!(activity.start_time, None);
+        assert_eq!(activity.moving_secs, None);
+        assert_eq!(
+            summarise_activity("reuben", &activity),
+            "reuben ran 1.11km, added to database."
+        );
+    }
+
+    #[test]
+    fn parse_tcx_activity() {
+        let activity = parse_activity("ride.tcx", TCX.as_bytes()).unwrap();
+        assert_eq!(
+            activity,
+            Activity {
+                sport: Some(Sport::Ride),
+                distance: 20.15,
+                start_time: NaiveDate::from_ymd_opt(2023, 10, 14)
+                    .unwrap()
+                    .and_hms_opt(7, 12, 0),
+                elapsed_secs: Some(3600),
+                moving_secs: Some(3500),
+                avg_heart_rate: Some(131),
+                max_heart_rate: Some(141),
+                elevation_gain: Some(42.),
+                race: false,
+                splits: (1..=20)
+                    .map(|km| Split {
+                        distance: km as f32,
+                        elapsed_secs: km * 180,
+                    })
+                    .collect(),
+            }
+        );
+        assert_eq!(
+            summarise_activity("reuben", &activity),
+            "reuben rode 20.15km in 1:00:00 (58:20 moving) from 2023-10-14 07:12, avg HR 131 (max 141), 42m climbed, added to database."
+        );
+    }
+
+    #[test]
+    fn reject_invalid_files() {
+        assert_eq!(
+            parse_gpx(b"not xml").unwrap_err(),
+            "The file is not valid GPX."
+        );
+        assert_eq!(
+            parse_gpx(b"<gpx><trk><trkseg/></trk></gpx>").unwrap_err(),
+            "The file has no track to work out a distance from."
+        );
+        assert_eq!(
+            parse_tcx(b"<TrainingCenterDatabase/>").unwrap_err(),
+            "The file has no activity in it."
+        );
+    }
+
+    #[test]
+    fn activity_file_names() {
+        assert!(is_activity_file("run.gpx"));
+        assert!(is_activity_file("Ride.TCX"));
+        assert!(is_activity_file("12345.fit"));
+        assert!(!is_activity_file("runs.csv"));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(59), "0:59");
+        assert_eq!(format_duration(28 * 60 + 31), "28:31");
+        assert_eq!(format_duration(3600 + 5), "1:00:05");
+    }
+}
@@ -3,12 +3,56 @@
 //! The shuttle_runtime for the BotService is defined here, which
 //! binds itself to the `SocketAddr` provided by shuttle.
 use crate::{
+    activity::{file_id, is_activity_file, parse_activity, summarise_activity},
+    cache::TallyCache,
+    challenge::{answer_challenge, answer_challenge_button, settle_challenges},
+    chart::{parse_chart_args, render_chart, ChartStyle},
     database::*,
-    message::{display_tally, list_runs, list_users},
+    export::{export_runs, parse_export_args, ExportFormat},
+    followup::{after_run_added, AddedRun},
+    goal::{answer_goal, answer_me, celebrate_goal},
+    import::{parse_import, preview_import, PendingImport, PendingImports, IMPORT_PROMPT},
+    journey::{announce_landmarks, answer_journey},
+    message::{display_tally, list_runs, list_users, split_message, Page},
+    models::{
+        parse_add_args, parse_edit_args, parse_list_args, Period, RankEmojis, RunDetails,
+        RunFilter, TallyGrouping, Timing,
+    },
+    profile::answer_profile,
+    provider::{answer_link, answer_unlink, Providers},
+    recap::{answer_recap, send_due_recaps},
+    record::{answer_pbs, congratulate_on_records, current_records},
+    reminder::{answer_remind, send_due_reminders},
+    team::answer_team,
+    webhook::{self, WebhookState},
 };
+use chrono_tz::Tz;
+use shuttle_runtime::Context;
 use sqlx::PgPool;
-use teloxide::{prelude::*, utils::command::BotCommands};
-use tracing::error;
+use std::{
+    fmt::{self, Debug},
+    future::Future,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use teloxide::{
+    dispatching::{DefaultKey, UpdateHandler},
+    error_handlers::LoggingErrorHandler,
+    net::Download,
+    prelude::*,
+    types::{Document, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, Me, ParseMode},
+    update_listeners::{self, UpdateListener},
+    utils::command::BotCommands,
+    RequestError,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    time::{Instant, MissedTickBehavior},
+};
+use tracing::{error, info, warn};
 
 /// Encapsulate the BotService.
 pub struct BotService {
@@ -16,16 +60,35 @@ pub struct BotService {
     pub bot: Bot,
     /// Database connection.
     pub postgres: PgPool,
+    /// Providers that can push activities to the bot.
+    pub providers: Providers,
+    /// Tallies shared between commands and pushed activities.
+    pub tally_cache: Arc<TallyCache>,
 }
 
+/// How long in-flight updates are given to finish once shutdown starts.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How long closing the database pool is given, once in-flight work has
+/// finished or run out of time.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often recaps, reminders and challenges are checked for being due.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Largest file accepted for imports, in bytes.
+const MAX_IMPORT_SIZE: u32 = 1024 * 1024;
+
+/// Largest activity file accepted, in bytes. Bots cannot download files
+/// larger than 20MB anyway.
+const MAX_ACTIVITY_SIZE: u32 = 20 * 1024 * 1024;
+
 /// Required implementation of the `shuttle_runtime::Service` trait for `BotService`.
 #[shuttle_runtime::async_trait]
 impl shuttle_runtime::Service for BotService {
-    async fn bind(self, _addr: std::net::SocketAddr) -> Result<(), shuttle_runtime::Error> {
+    async fn bind(self, addr: std::net::SocketAddr) -> Result<(), shuttle_runtime::Error> {
         // Start your service and bind to the socket address
-        self.start()
-            .await
-            .expect("An error occured while using the bot!");
+        self.start(addr).await?;
 
         Ok(())
     }
@@ -33,20 +96,169 @@ impl shuttle_runtime::Service for BotService {
 
 /// impl block for `BotService`.
 impl BotService {
-    /// Clones `bot` and `db_connection` before passing these over to `Command`, also
-    /// defined within teloxide. It parses incoming commands, matches them and
-    /// hands them over to the `answer` methold.
-    async fn start(&self) -> Result<(), shuttle_runtime::CustomError> {
-        let bot = self.bot.clone();
-        let db_connection = self.postgres.clone();
-
-        Command::repl(bot, move |bot, msg, cmd| {
-            answer(bot, msg, cmd, db_connection.clone())
-        })
-        .await;
+    /// Long polls Telegram for updates and dispatches them, while serving
+    /// provider webhooks on `addr`, until the process receives SIGTERM or
+    /// ctrl-c. Then closes the database pool, within `CLOSE_TIMEOUT`.
+    async fn start(&self, addr: SocketAddr) -> Result<(), shuttle_runtime::CustomError> {
+        let shutdown = shutdown_signal().context("Unable to listen for shutdown signals")?;
+        let (stop, stopped) = watch::channel(());
+        tokio::spawn(async move {
+            shutdown.await;
+            let _ = stop.send(());
+        });
+        let wait_for_stop = |mut stopped: watch::Receiver<()>| async move {
+            let _ = stopped.changed().await;
+        };
+
+        let webhooks = axum::Server::try_bind(&addr)
+            .context("Unable to bind the webhook server")?
+            .serve(
+                webhook::router(WebhookState {
+                    bot: self.bot.clone(),
+                    postgres: self.postgres.clone(),
+                    tally_cache: self.tally_cache.clone(),
+                    providers: self.providers.clone(),
+                })
+                .into_make_service(),
+            )
+            .with_graceful_shutdown(wait_for_stop(stopped.clone()));
+        let webhooks = tokio::spawn(webhooks);
+        let scheduled = tokio::spawn(post_scheduled(
+            self.bot.clone(),
+            self.postgres.clone(),
+            stopped.clone(),
+        ));
+        let listener = update_listeners::polling_default(self.bot.clone()).await;
+        let deadline = self.serve(listener, wait_for_stop(stopped)).await;
+
+        if tokio::time::timeout_at(deadline, scheduled).await.is_err() {
+            warn!("Scheduled messages being sent did not finish in time.");
+        }
+
+        // In-flight webhooks share the deadline of in-flight updates.
+        match tokio::time::timeout_at(deadline, webhooks).await {
+            Ok(Ok(Err(err))) => error!("Webhook server failed: {:?}", err),
+            Ok(Err(err)) => error!("Webhook server panicked: {:?}", err),
+            Err(_) => warn!("In-flight webhooks did not finish in time."),
+            Ok(Ok(Ok(()))) => {}
+        }
+
+        // Closing gets a budget of its own, as draining may have used up the
+        // whole deadline.
+        if tokio::time::timeout(CLOSE_TIMEOUT, self.postgres.close())
+            .await
+            .is_err()
+        {
+            warn!("Timed out closing database connections.");
+        } else {
+            info!("Closed database connections.");
+        }
 
         Ok(())
     }
+
+    /// Dispatches updates from `listener` until `shutdown` resolves.
+    ///
+    /// Once `shutdown` resolves, no further updates are taken in and handlers
+    /// that are already running, along with their database transactions, get
+    /// `SHUTDOWN_TIMEOUT` to finish. Returns the deadline handed out to them.
+    pub async fn serve<L>(&self, listener: L, shutdown: impl Future<Output = ()>) -> Instant
+    where
+        L: UpdateListener,
+        L::Err: Debug,
+    {
+        let mut dispatcher = self.dispatcher();
+        let shutdown_token = dispatcher.shutdown_token();
+        let dispatching = dispatcher.dispatch_with_listener(
+            listener,
+            LoggingErrorHandler::with_custom_text("An error from the update listener"),
+        );
+        tokio::pin!(dispatching);
+
+        tokio::select! {
+            _ = &mut dispatching => return Instant::now(),
+            _ = shutdown => {}
+        }
+
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        // Shutting down only fails if dispatching never started, in which
+        // case nothing is in flight.
+        if shutdown_token.shutdown().is_ok() {
+            info!("Shutting down, waiting for in-flight updates.");
+            if tokio::time::timeout_at(deadline, dispatching)
+                .await
+                .is_err()
+            {
+                warn!(
+                    "In-flight updates did not finish within {:?}.",
+                    SHUTDOWN_TIMEOUT
+                );
+            }
+        }
+
+        deadline
+    }
+
+    /// Builds the `Dispatcher` driving the bot.
+    ///
+    /// Clones `bot`, `db_connection`, `providers` and `tally_cache` into the
+    /// dispatcher, along with a fresh `PendingImports`. The dispatcher
+    /// parses incoming commands, matches them and hands them over to the
+    /// `answer` method.
+    fn dispatcher(&self) -> Dispatcher<Bot, RequestError, DefaultKey> {
+        let pending_imports = Arc::new(PendingImports::default());
+        Dispatcher::builder(self.bot.clone(), schema())
+            .dependencies(dptree::deps![
+                self.postgres.clone(),
+                self.tally_cache.clone(),
+                self.providers.clone(),
+                pending_imports
+            ])
+            // Other update types are of no interest to us.
+            .default_handler(|_update| async {})
+            .build()
+    }
+}
+
+/// Sends due recaps and reminders, and settles challenges whose time is up,
+/// every `SCHEDULE_INTERVAL` until `stopped` changes. Messages being sent when it changes are finished first.
+async fn post_scheduled(bot: Bot, connection: PgPool, mut stopped: watch::Receiver<()>) {
+    let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stopped.changed() => return,
+        }
+        let now = chrono::Utc::now().naive_utc();
+        send_due_recaps(&bot, &connection, now).await;
+        send_due_reminders(&bot, &connection, now).await;
+        settle_challenges(&bot, None, &connection, now).await;
+    }
+}
+
+/// Resolves once the process receives SIGTERM or ctrl-c.
+fn shutdown_signal() -> std::io::Result<impl Future<Output = ()>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => info!("Received SIGTERM."),
+            _ = tokio::signal::ctrl_c() => info!("Received ctrl-c."),
+        }
+    })
+}
+
+/// Handler tree routing updates to their handlers.
+fn schema() -> UpdateHandler<RequestError> {
+    let messages = Update::filter_message()
+        .branch(dptree::entry().filter_command::<Command>().endpoint(answer))
+        .branch(dptree::filter(is_import_reply).endpoint(receive_import))
+        .branch(dptree::filter(is_activity_upload).endpoint(receive_activity));
+
+    dptree::entry()
+        .branch(messages)
+        .branch(Update::filter_callback_query().endpoint(answer_callback))
 }
 
 /// Enumeration of commands accepted by the bot.
@@ -65,25 +277,31 @@ enum Command {
     #[command(description = "Show users registered on telerun within the chat. Usage: /show")]
     /// Matched to `/show` -> displays users within chat.
     Show,
-    /// Matched to `/add <distance>` -> creates users in db if not present,
-    /// then adds run data to runs table.
+    /// Matched to `/add <distance> [time] [race] [splits ...]` -> creates
+    /// users in db if not present, then adds run data to runs table.
     #[command(
-        description = "Add run data to database. Usage: /add <distance> (in km). Example: /add 5"
+        description = "Add run data to database, optionally with its time, whether it was a race and the time each km took, for personal records. Usage: /add <distance> (in km) [time] [race] [splits <time per km>...]. Example: /add 5 24:30",
+        parse_with = parse_add_args
     )]
     Add {
         /// Distance run in km
         distance: f32,
+        /// Time, race mark and splits of the run, if given.
+        timing: Timing,
     },
-    /// Matched to `/edit <run_id> <distance>` -> edits stored run data.
+    /// Matched to `/edit <run_id> <distance> [time] [race] [splits ...]` ->
+    /// edits stored run data.
     #[command(
-        description = "Edit data for a run. Usage: /edit <run_id> <distance>. Example: /edit 3 6",
-        parse_with = "split"
+        description = "Edit data for a run. Its time and splits are kept unless given, and it is only a race if race is given. Usage: /edit <run_id> <distance> [time] [race] [splits <time per km>...]. Example: /edit 3 6",
+        parse_with = parse_edit_args
     )]
     Edit {
         /// Id of run as stored in runs table.
         run_id: i32,
         /// Corrected distance run in km.
         distance: f32,
+        /// Corrected time, race mark and splits of the run.
+        timing: Timing,
     },
     /// Matched to `/delete <run_id>` -> removes a certain run from database.
     #[command(
@@ -93,31 +311,353 @@ enum Command {
         /// Id of run to remove from table.
         run_id: i32,
     },
-    /// Matched to `/tally` -> sends score board as message through Telegram.
-    #[command(description = "Tallies current medals and distances. Usage: /tally")]
-    Tally,
-    /// Matched to `/list <limit>` -> displays runs registered by the group chat, subject to a limit.
+    /// Matched to `/pbs` -> displays the user's personal records.
     #[command(
-        description = "Lists recent runs. Number of runs to display must be specified. Usage: /list <num_runs_to_show>. Example: /list 5"
+        description = "Show your best times over 1k, 5k, 10k, half and full marathon, from runs added with their time. Usage: /pbs"
+    )]
+    Pbs,
+    /// Matched to `/tally [period]` -> sends score board as message through Telegram.
+    #[command(
+        description = "Tallies current medals and distances, optionally for this week, month or year. Usage: /tally [week|month|year]. Example: /tally week"
+    )]
+    Tally {
+        /// Period to tally runs over, all time if not given.
+        period: Period,
+    },
+    /// Matched to `/goal [set <distance> <period>]` -> shows or sets the chat's goal.
+    #[command(
+        description = "Show progress towards the distance the chat aims to run together, or set a goal for this week, month or year. Usage: /goal [set <distance> <week|month|year>]. Example: /goal set 500 month"
+    )]
+    Goal {
+        /// `set` with the goal, or nothing to show progress.
+        args: String,
+    },
+    /// Matched to `/journey [routes|start <route>|stop]` -> shows or sets
+    /// off on the chat's journey along a route.
+    #[command(
+        description = "Show how far along a route, such as Singapore to Kuala Lumpur, the chat has run together, with the next landmark. Landmarks are announced as you pass them. Usage: /journey [routes|start <route>|stop]. Example: /journey start singapore-kuala-lumpur"
+    )]
+    Journey {
+        /// `routes`, `start` with a route, `stop`, or nothing to show
+        /// progress.
+        args: String,
+    },
+    /// Matched to `/me [goal <target> <period>|goal off]` -> shows or sets the user's own goal.
+    #[command(
+        description = "Show your progress towards your own goal, or aim for a distance or a number of runs every week or month. Usage: /me [goal <distance>km|<count> runs <week|month>], or /me goal off. Example: /me goal 4 runs week"
+    )]
+    Me {
+        /// `goal` with the goal or `off`, or nothing to show progress.
+        args: String,
+    },
+    /// Matched to `/challenge [@user <distance> <duration>]` -> challenges a
+    /// user to a race, or shows the chat's challenges.
+    #[command(
+        description = "Challenge someone to be the first to run a distance within a number of days or weeks, counting from when they accept. Without arguments, shows how challenges under way stand and how the latest ones ended. Usage: /challenge [@user <distance>km <days>d|<weeks>w]. Example: /challenge @bob 50km 2w"
+    )]
+    Challenge {
+        /// Who to challenge, to what and for how long, or nothing to show
+        /// the chat's challenges.
+        args: String,
+    },
+    /// Matched to `/team [action]` -> manages teams and shows the team tally.
+    #[command(
+        description = "List the chat's teams, create or join one, or leave yours. The team tally adds up members' distances, optionally for this week, month or year. /team normalise on divides them by the number of members. Usage: /team [list|create <name>|join <name>|leave|tally [week|month|year]|normalise on|off]. Example: /team join Engineering"
+    )]
+    Team {
+        /// What to do with teams, or nothing to list them.
+        args: String,
+    },
+    /// Matched to `/profile [share on|off]` -> shows the user's runs across
+    /// chats, or shares runs between chats.
+    #[command(
+        description = "In a private chat with me, show everything you ran across your chats. In a group chat, share on also posts runs you add there to your other chats that share runs. Usage: /profile [share on|off]. Example: /profile share on"
+    )]
+    Profile {
+        /// `share on` or `share off`, or nothing to show the profile.
+        args: String,
+    },
+    /// Matched to `/emojis [emojis|off|default]` -> sets the emojis marking places in the tally.
+    #[command(
+        description = "Show or change the emojis marking places in the tally: one for each of the first places, then one for everyone else and one for last place, using - for none. Usage: /emojis [emojis|off|default]. Example: /emojis 🥇 🥈 🥉 🏃 -"
+    )]
+    Emojis {
+        /// New emojis, `off` or `default`, or nothing to show the current ones.
+        emojis: String,
+    },
+    /// Matched to `/recap [week|month [time] [time zone]|off]` -> posts recaps on a schedule.
+    #[command(
+        description = "Post a recap of the week every Sunday, or of the month on its last day, at a time of day in your time zone, 20:00 UTC if not given. Usage: /recap [week|month] [HH:MM] [time zone], or /recap off [week|month]. Example: /recap week 18:30 Asia/Singapore"
+    )]
+    Recap {
+        /// When to post recaps, `off`, or nothing to show the chat's recaps.
+        args: String,
+    },
+    /// Matched to `/remind [days [time] [time zone]|on|off|stop]` -> reminds inactive users to run.
+    #[command(
+        description = "Remind members who haven't run for a number of days, once a day at a time in your time zone, 19:00 UTC if not given. Use off to stop being reminded yourself, on to be reminded again and stop to turn reminders off for the chat. Usage: /remind [days] [HH:MM] [time zone], or /remind on|off|stop. Example: /remind 3 07:00 Europe/London"
+    )]
+    Remind {
+        /// When to remind users, `on`, `off`, `stop`, or nothing to show
+        /// the chat's reminders.
+        args: String,
+    },
+    /// Matched to `/list [filters]` -> displays runs registered by the group chat, a page at a time.
+    #[command(
+        description = "Lists recent runs with their ids, 10 at a time unless a number is given. Runs can be narrowed down to yours, someone else's, a period or a sport. Usage: /list [me|@user] [num_runs_to_show] [week|month|year] [run|ride|walk|hike|swim]. Example: /list me week",
+        parse_with = parse_list_args
     )]
     List {
-        /// Limit to query from db.
+        /// Which runs to show.
+        filter: RunFilter,
+        /// Number of runs to show per page.
+        limit: u32,
+    },
+    /// Matched to `/export [csv|json] [period]` -> sends every run in the chat as a document.
+    #[command(
+        description = "Export every run in the chat as a CSV or JSON file, optionally for this week, month or year. Usage: /export [csv|json] [week|month|year]. Example: /export json month",
+        parse_with = parse_export_args
+    )]
+    Export {
+        /// File format of the export, CSV if not given.
+        format: ExportFormat,
+        /// Period to export runs from, all time if not given.
+        period: Period,
+    },
+    /// Matched to `/chart [period] [lines|bars]` -> sends a chart of distance ran over time.
+    #[command(
+        description = "Chart distance ran over time, as a line per user or as weekly bars, optionally for this week, month or year. Usage: /chart [week|month|year] [lines|bars]. Example: /chart year bars",
+        parse_with = parse_chart_args
+    )]
+    Chart {
+        /// How to chart the runs, lines if not given.
+        style: ChartStyle,
+        /// Period to chart runs from, all time if not given.
+        period: Period,
+    },
+    /// Matched to `/import` -> asks for a CSV file of runs to import.
+    #[command(
+        description = "Import runs from a CSV file with user, distance and date columns. Usage: /import, then reply with the file."
+    )]
+    Import,
+    /// Matched to `/link <provider>` -> sends a link to log in with the
+    /// provider, after which activities it pushes are logged in this chat.
+    #[command(
+        description = "Link your account with an activity provider by logging in with it, so that new activities are added automatically. Usage: /link <provider>. Example: /link strava"
+    )]
+    Link {
+        /// Name of the provider, e.g. `strava`.
+        provider: String,
+    },
+    /// Matched to `/unlink <provider>` -> stops logging pushed activities.
+    #[command(
+        description = "Stop adding activities from a provider in this chat. Usage: /unlink <provider>. Example: /unlink strava"
+    )]
+    Unlink {
+        /// Name of the provider, e.g. `strava`.
+        provider: String,
+    },
+}
+
+/// Actions behind inline keyboard buttons, carried as callback data.
+#[derive(Debug, PartialEq)]
+pub enum Callback {
+    /// Confirms the pending import with the given id.
+    ConfirmImport(u64),
+    /// Cancels the pending import with the given id.
+    CancelImport(u64),
+    /// Accepts the challenge with the given id.
+    AcceptChallenge(i32),
+    /// Declines or withdraws the challenge with the given id.
+    DeclineChallenge(i32),
+    /// Shows a page of `/tally`.
+    TallyPage {
+        /// Period the tally is over.
+        period: Period,
+        /// Index of the page, counting from 0.
+        page: usize,
+    },
+    /// Shows a page of `/team tally`.
+    TeamTallyPage {
+        /// Period the tally is over.
+        period: Period,
+        /// Index of the page, counting from 0.
+        page: usize,
+    },
+    /// Shows the page of `/show` with the given index.
+    UsersPage(usize),
+    /// Shows the page of `/list` starting after `offset` runs.
+    ListPage {
+        /// Number of runs before the page.
+        offset: u32,
+        /// Number of runs per page.
         limit: u32,
+        /// Which runs are listed.
+        filter: RunFilter,
     },
 }
 
+impl fmt::Display for Callback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Callback::ConfirmImport(id) => write!(f, "import:confirm:{}", id),
+            Callback::CancelImport(id) => write!(f, "import:cancel:{}", id),
+            Callback::AcceptChallenge(id) => write!(f, "challenge:accept:{}", id),
+            Callback::DeclineChallenge(id) => write!(f, "challenge:decline:{}", id),
+            Callback::TallyPage { period, page } => write!(f, "tally:{}:{}", period, page),
+            Callback::TeamTallyPage { period, page } => write!(f, "teams:{}:{}", period, page),
+            Callback::UsersPage(page) => write!(f, "show:{}", page),
+            Callback::ListPage {
+                offset,
+                limit,
+                filter,
+            } => write!(f, "list:{}:{} {}", offset, limit, filter),
+        }
+    }
+}
+
+impl FromStr for Callback {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Filters may contain colons themselves, e.g. `id:11`.
+        let parts: Vec<&str> = s.splitn(3, ':').collect();
+        match parts[..] {
+            ["import", "confirm", id] => Ok(Callback::ConfirmImport(id.parse().map_err(|_| ())?)),
+            ["import", "cancel", id] => Ok(Callback::CancelImport(id.parse().map_err(|_| ())?)),
+            ["challenge", "accept", id] => {
+                Ok(Callback::AcceptChallenge(id.parse().map_err(|_| ())?))
+            }
+            ["challenge", "decline", id] => {
+                Ok(Callback::DeclineChallenge(id.parse().map_err(|_| ())?))
+            }
+            ["tally", period, page] => Ok(Callback::TallyPage {
+                period: period.parse().map_err(|_| ())?,
+                page: page.parse().map_err(|_| ())?,
+            }),
+            ["teams", period, page] => Ok(Callback::TeamTallyPage {
+                period: period.parse().map_err(|_| ())?,
+                page: page.parse().map_err(|_| ())?,
+            }),
+            ["show", page] => Ok(Callback::UsersPage(page.parse().map_err(|_| ())?)),
+            ["list", offset, arguments] => {
+                let (filter, limit) = parse_list_args(arguments.into()).map_err(|_| ())?;
+                Ok(Callback::ListPage {
+                    offset: offset.parse().map_err(|_| ())?,
+                    limit,
+                    filter,
+                })
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+/// Longest callback data Telegram accepts, in bytes.
+const MAX_CALLBACK_DATA: usize = 64;
+
+/// Renders a page of `/list`, along with buttons to the pages around it.
+///
+/// Runs are listed newest first, so the next page holds older runs.
+async fn list_page(
+    chat_id: ChatId,
+    filter: &RunFilter,
+    limit: u32,
+    offset: u32,
+    db_connection: &PgPool,
+) -> Result<(String, Option<InlineKeyboardMarkup>), sqlx::Error> {
+    // One run more than is shown tells whether there is a next page.
+    let runs = get_runs(
+        chat_id,
+        filter,
+        i64::from(limit) + 1,
+        offset.into(),
+        db_connection,
+    )
+    .await?;
+    let fetched = runs.as_ref().map_or(0, Vec::len);
+    let runs = runs.map(|mut runs| {
+        runs.truncate(limit as usize);
+        runs
+    });
+    let page = list_runs(runs, chrono::Utc::now().naive_utc());
+    // Pages may hold fewer runs than the limit if the runs would not fit
+    // in a single message, so the next page starts after those shown.
+    let shown = page.rows as u32;
+    let list_page = |offset| Callback::ListPage {
+        offset,
+        limit,
+        filter: filter.clone(),
+    };
+    let keyboard = page_buttons(
+        (offset > 0).then(|| list_page(offset.saturating_sub(limit))),
+        (fetched > page.rows).then(|| list_page(offset + shown)),
+    );
+
+    Ok((page.text, keyboard))
+}
+
+/// Emojis marking places in the chat's tally, the default ones if they
+/// can't be read.
+pub async fn chat_rank_emojis(chat_id: ChatId, db_connection: &PgPool) -> RankEmojis {
+    get_rank_emojis(chat_id, db_connection)
+        .await
+        .map_err(|err| error!("Unable to retrieve rank emojis: {:?}", err))
+        .unwrap_or_default()
+}
+
+/// Buttons to the previous and next pages of a message, if there are any.
+fn page_buttons(
+    previous: Option<Callback>,
+    next: Option<Callback>,
+) -> Option<InlineKeyboardMarkup> {
+    let buttons: Vec<InlineKeyboardButton> = [("« Previous", previous), ("Next »", next)]
+        .into_iter()
+        .filter_map(|(text, callback)| {
+            let data = callback?.to_string();
+            (data.len() <= MAX_CALLBACK_DATA).then(|| InlineKeyboardButton::callback(text, data))
+        })
+        .collect();
+    (!buttons.is_empty()).then(|| InlineKeyboardMarkup::new([buttons]))
+}
+
+/// Buttons to the pages around `page`, each made into a callback by
+/// `callback`.
+pub fn page_numbers(
+    page: &Page,
+    callback: impl Fn(usize) -> Callback,
+) -> Option<InlineKeyboardMarkup> {
+    page_buttons(
+        page.index.checked_sub(1).map(&callback),
+        (page.index + 1 < page.count).then(|| callback(page.index + 1)),
+    )
+}
+
 /// Function used for handling various commands matched.
-async fn answer(bot: Bot, msg: Message, cmd: Command, db_connection: PgPool) -> ResponseResult<()> {
+async fn answer(
+    bot: Bot,
+    msg: Message,
+    cmd: Command,
+    db_connection: PgPool,
+    tally_cache: Arc<TallyCache>,
+    providers: Providers,
+) -> ResponseResult<()> {
     match cmd {
         Command::Help => {
-            bot.send_message(msg.chat.id, Command::descriptions().to_string())
-                .await?;
+            for part in split_message(&Command::descriptions().to_string()) {
+                bot.send_message(msg.chat.id, part).await?;
+            }
         }
         Command::Show => {
             let users = get_users_in_chat(msg.chat.id, &db_connection).await;
             if let Ok(users) = users {
-                let show_message = list_users(users);
-                bot.send_message(msg.chat.id, show_message)
+                let show_message = list_users(users, 0);
+                let mut request = bot
+                    .send_message(msg.chat.id, &show_message.text)
+                    .parse_mode(ParseMode::Html);
+                request.reply_markup =
+                    page_numbers(&show_message, Callback::UsersPage).map(Into::into);
+                request
                     .await
                     .map_err(|error| error!("Unable to send Show message: {:?}", error))
                     .ok();
@@ -125,27 +665,41 @@ async fn answer(bot: Bot, msg: Message, cmd: Command, db_connection: PgPool) ->
                 error!("Unable to retrieve items required for Show.");
             }
         }
-        Command::Add { distance } => {
+        Command::Add { distance, timing } => {
             let telegram_user = msg.from();
             if let Some(user) = telegram_user {
                 let user_name = &user.username;
                 if let Some(user_name) = user_name {
-                    let add_result = add_run_wrapper(
+                    let records = current_records(msg.chat.id, user.id, &db_connection).await;
+                    let add_result = add_shared_run(
                         distance,
+                        RunDetails::from(timing),
                         user_name.as_str(),
                         user.id,
                         msg.chat.id,
                         &db_connection,
                     )
                     .await;
-                    if add_result.is_ok() {
-                        bot.send_message(
-                            msg.chat.id,
-                            format!("{} ran {}km added to database.", user_name, distance),
-                        )
-                        .await
-                        .map_err(|error| error!("Unable to send Add message: {:?}", error))
-                        .ok();
+                    if let Ok(Some(shared_to)) = add_result {
+                        let mut reply =
+                            format!("{} ran {}km added to database.", user_name, distance);
+                        if !shared_to.is_empty() {
+                            reply = format!(
+                                "{}\nAlso posted to {} other chat{}.",
+                                reply,
+                                shared_to.len(),
+                                if shared_to.len() == 1 { "" } else { "s" }
+                            );
+                        }
+                        let run = AddedRun {
+                            chat_id: msg.chat.id,
+                            telegram_userid: user.id,
+                            user_name,
+                            distance,
+                            shared_to,
+                            records_before: records,
+                        };
+                        after_run_added(&bot, run, reply, None, &tally_cache, &db_connection).await;
                     } else {
                         error!("Unable to Add run information.");
                     }
@@ -156,21 +710,52 @@ async fn answer(bot: Bot, msg: Message, cmd: Command, db_connection: PgPool) ->
                 error!("Unable to retrieve user from message.");
             }
         }
-        Command::Edit { run_id, distance } => {
+        Command::Edit {
+            run_id,
+            distance,
+            timing,
+        } => {
             let telegram_user = msg.from();
             if let Some(user) = telegram_user {
-                let update_outcome = update_run(run_id, user.id, distance, &db_connection).await;
-                if update_outcome.is_ok() {
+                // Only runs the user added in this chat can be edited, so
+                // any records set are theirs.
+                let records = current_records(msg.chat.id, user.id, &db_connection).await;
+                let update_outcome = update_run(
+                    run_id,
+                    user.id,
+                    msg.chat.id,
+                    distance,
+                    &timing,
+                    &db_connection,
+                )
+                .await;
+                if let Ok(None) = update_outcome {
                     bot.send_message(
                         msg.chat.id,
-                        format!(
-                            "Run {} successfully updated with distance {}km.",
-                            run_id, distance
-                        ),
+                        format!("No run {} of yours in this chat.", run_id),
                     )
                     .await
                     .map_err(|error| error!("Unable to send update message: {:?}", error))
                     .ok();
+                } else if let Ok(Some(shared_to)) = update_outcome {
+                    tally_cache.invalidate(msg.chat.id);
+                    for chat_id in shared_to {
+                        tally_cache.invalidate(chat_id);
+                    }
+                    let mut reply = format!(
+                        "Run {} successfully updated with distance {}km.",
+                        run_id, distance
+                    );
+                    if let Some(records) =
+                        congratulate_on_records(records, msg.chat.id, user.id, &db_connection).await
+                    {
+                        reply = format!("{}\n{}", reply, records);
+                    }
+                    bot.send_message(msg.chat.id, reply)
+                        .await
+                        .map_err(|error| error!("Unable to send update message: {:?}", error))
+                        .ok();
+                    celebrate_goal(&bot, msg.chat.id, &db_connection).await;
                 } else {
                     error!("Unable to update database entry for run_id: {}", run_id);
                 }
@@ -181,8 +766,20 @@ async fn answer(bot: Bot, msg: Message, cmd: Command, db_connection: PgPool) ->
         Command::Delete { run_id } => {
             let telegram_user = msg.from();
             if let Some(user) = telegram_user {
-                let delete_outcome = delete_run(run_id, user.id, &db_connection).await;
-                if delete_outcome.is_ok() {
+                let delete_outcome = delete_run(run_id, user.id, msg.chat.id, &db_connection).await;
+                if let Ok(None) = delete_outcome {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("No run {} of yours in this chat.", run_id),
+                    )
+                    .await
+                    .map_err(|error| error!("Unable to send delete message: {:?}", error))
+                    .ok();
+                } else if let Ok(Some(shared_to)) = delete_outcome {
+                    tally_cache.invalidate(msg.chat.id);
+                    for chat_id in shared_to {
+                        tally_cache.invalidate(chat_id);
+                    }
                     bot.send_message(msg.chat.id, format!("Run {} successfully deleted!", run_id))
                         .await
                         .map_err(|error| error!("Unable to send delete message: {:?}", error))
@@ -192,11 +789,22 @@ async fn answer(bot: Bot, msg: Message, cmd: Command, db_connection: PgPool) ->
                 }
             }
         }
-        Command::Tally => {
-            let tally = get_tally(msg.chat.id, &db_connection).await;
+        Command::Pbs => answer_pbs(&bot, &msg, &db_connection).await,
+        Command::Tally { period } => {
+            let tally = tally_cache
+                .get_tally(msg.chat.id, period, TallyGrouping::Users, &db_connection)
+                .await;
             if let Ok(tally) = tally {
-                let tally_message = display_tally(tally);
-                bot.send_message(msg.chat.id, tally_message)
+                let caller = msg.from().map(|user| user.id);
+                let emojis = chat_rank_emojis(msg.chat.id, &db_connection).await;
+                let tally_message = display_tally(tally, &emojis, 0, caller, TallyGrouping::Users);
+                let mut request = bot
+                    .send_message(msg.chat.id, &tally_message.text)
+                    .parse_mode(ParseMode::Html);
+                request.reply_markup =
+                    page_numbers(&tally_message, |page| Callback::TallyPage { period, page })
+                        .map(Into::into);
+                request
                     .await
                     .map_err(|err| error!("Unable to send Tally message: {:?}", err))
                     .ok();
@@ -204,11 +812,65 @@ async fn answer(bot: Bot, msg: Message, cmd: Command, db_connection: PgPool) ->
                 error!("Unable to retrieve tally from database.");
             }
         }
-        Command::List { limit } => {
-            let runs = get_runs(msg.chat.id, limit.into(), &db_connection).await;
-            if let Ok(runs) = runs {
-                let run_message = list_runs(runs);
-                bot.send_message(msg.chat.id, run_message)
+        Command::Goal { args } => answer_goal(&bot, msg.chat.id, &args, &db_connection).await,
+        Command::Journey { args } => answer_journey(&bot, msg.chat.id, &args, &db_connection).await,
+        Command::Me { args } => answer_me(&bot, &msg, &args, &db_connection).await,
+        Command::Challenge { args } => answer_challenge(&bot, &msg, &args, &db_connection).await,
+        Command::Team { args } => {
+            answer_team(&bot, &msg, &args, &tally_cache, &db_connection).await
+        }
+        Command::Profile { args } => answer_profile(&bot, &msg, &args, &db_connection).await,
+        Command::Emojis { emojis } => {
+            let reply = match emojis.trim() {
+                "" => Ok(format!(
+                    "Places in the tally are marked with: {}",
+                    chat_rank_emojis(msg.chat.id, &db_connection).await
+                )),
+                "default" => set_rank_emojis(msg.chat.id, None, &db_connection)
+                    .await
+                    .map(|_| {
+                        format!(
+                            "Places in the tally are back to being marked with: {}",
+                            RankEmojis::default()
+                        )
+                    }),
+                emojis => match emojis.parse::<RankEmojis>() {
+                    Ok(emojis) => set_rank_emojis(msg.chat.id, Some(&emojis), &db_connection)
+                        .await
+                        .map(|_| {
+                            if emojis == RankEmojis::off() {
+                                "Places in the tally are no longer marked with emojis.".into()
+                            } else {
+                                format!("Places in the tally are now marked with: {}", emojis)
+                            }
+                        }),
+                    Err(err) => Ok(err.to_string()),
+                },
+            };
+            match reply {
+                Ok(reply) => {
+                    bot.send_message(msg.chat.id, reply)
+                        .await
+                        .map_err(|err| error!("Unable to send Emojis message: {:?}", err))
+                        .ok();
+                }
+                Err(err) => error!("Unable to update rank emojis: {:?}", err),
+            }
+        }
+        Command::Recap { args } => answer_recap(&bot, msg.chat.id, &args, &db_connection).await,
+        Command::Remind { args } => answer_remind(&bot, &msg, &args, &db_connection).await,
+        Command::List { filter, limit } => {
+            let filter = match msg.from() {
+                Some(user) => filter.resolve(user.id),
+                None => filter,
+            };
+            let page = list_page(msg.chat.id, &filter, limit, 0, &db_connection).await;
+            if let Ok((run_message, keyboard)) = page {
+                let mut request = bot
+                    .send_message(msg.chat.id, run_message)
+                    .parse_mode(ParseMode::Html);
+                request.reply_markup = keyboard.map(Into::into);
+                request
                     .await
                     .map_err(|err| error!("Unable to send List message: {:?}", err))
                     .ok();
@@ -216,6 +878,1412 @@ async fn answer(bot: Bot, msg: Message, cmd: Command, db_connection: PgPool) ->
                 error!("Unable to retrieve runs from database.");
             }
         }
+        Command::Export { format, period } => {
+            let since = period.start(chrono::Utc::now().naive_utc());
+            let runs = get_chat_runs(msg.chat.id, since, &db_connection).await;
+            match runs {
+                Ok(runs) if runs.is_empty() => {
+                    bot.send_message(msg.chat.id, "No runs in database.")
+                        .await
+                        .map_err(|err| error!("Unable to send Export message: {:?}", err))
+                        .ok();
+                }
+                Ok(runs) => match export_runs(&runs, format) {
+                    Ok(document) => {
+                        let document = InputFile::memory(document)
+                            .file_name(format!("runs.{}", format.extension()));
+                        bot.send_document(msg.chat.id, document)
+                            .await
+                            .map_err(|err| error!("Unable to send Export document: {:?}", err))
+                            .ok();
+                    }
+                    Err(err) => error!("Unable to export runs: {:?}", err),
+                },
+                Err(_) => error!("Unable to retrieve runs from database."),
+            }
+        }
+        Command::Chart { style, period } => {
+            let now = chrono::Utc::now().naive_utc();
+            let since = period.start(now);
+            let days = get_daily_distances(msg.chat.id, since, None, Tz::UTC, &db_connection).await;
+            match days {
+                Ok(days) if days.is_empty() => {
+                    bot.send_message(msg.chat.id, "No runs in database.")
+                        .await
+                        .map_err(|err| error!("Unable to send Chart message: {:?}", err))
+                        .ok();
+                }
+                Ok(days) => {
+                    match render_chart(&days, style, since.map(|since| since.date()), now.date()) {
+                        Ok(chart) => {
+                            bot.send_photo(
+                                msg.chat.id,
+                                InputFile::memory(chart).file_name("chart.png"),
+                            )
+                            .await
+                            .map_err(|err| error!("Unable to send Chart photo: {:?}", err))
+                            .ok();
+                        }
+                        Err(err) => error!("Unable to render chart: {:?}", err),
+                    }
+                }
+                Err(_) => error!("Unable to retrieve runs from database."),
+            }
+        }
+        Command::Import => {
+            bot.send_message(msg.chat.id, IMPORT_PROMPT)
+                .reply_to_message_id(msg.id)
+                .await
+                .map_err(|err| error!("Unable to send Import message: {:?}", err))
+                .ok();
+        }
+        Command::Link { provider } => {
+            answer_link(&bot, &msg, &provider, &providers, &db_connection).await
+        }
+        Command::Unlink { provider } => {
+            answer_unlink(&bot, &msg, &provider, &providers, &db_connection).await
+        }
+    }
+    Ok(())
+}
+
+/// Whether `msg` is a file sent in reply to `/import` or its prompt.
+fn is_import_reply(msg: Message, me: Me) -> bool {
+    let Some(replied) = msg.reply_to_message() else {
+        return false;
+    };
+    let replied_text = replied.text().unwrap_or_default();
+    let replied_to_prompt =
+        replied.from().is_some_and(|from| from.id == me.id) && replied_text == IMPORT_PROMPT;
+
+    msg.document().is_some() && (replied_to_prompt || replied_text.starts_with("/import"))
+}
+
+/// Validates an uploaded CSV file and asks the uploader to confirm the import.
+async fn receive_import(
+    bot: Bot,
+    msg: Message,
+    db_connection: PgPool,
+    pending_imports: Arc<PendingImports>,
+) -> ResponseResult<()> {
+    let (Some(document), Some(user)) = (msg.document(), msg.from()) else {
+        return Ok(());
+    };
+    if document.file.size > MAX_IMPORT_SIZE {
+        bot.send_message(msg.chat.id, "File is too large to import.")
+            .reply_to_message_id(msg.id)
+            .await?;
+        return Ok(());
+    }
+
+    let Some(contents) = download_document(&bot, document).await? else {
+        return Ok(());
+    };
+    let Ok(users) = get_users_in_chat(msg.chat.id, &db_connection).await else {
+        error!("Unable to retrieve users required for Import.");
+        return Ok(());
+    };
+
+    let import = match parse_import(&contents, &users.unwrap_or_default()) {
+        Ok(import) => import,
+        Err(reason) => {
+            bot.send_message(msg.chat.id, reason)
+                .reply_to_message_id(msg.id)
+                .await?;
+            return Ok(());
+        }
+    };
+    let preview = preview_import(&import);
+    let reply = bot
+        .send_message(msg.chat.id, preview)
+        .reply_to_message_id(msg.id);
+    let reply = if import.runs.is_empty() {
+        reply
+    } else {
+        let id = pending_imports.insert(
+            PendingImport {
+                chat_id: msg.chat.id,
+                uploader: user.id,
+                runs: import.runs,
+            },
+            std::time::Instant::now(),
+        );
+        reply.reply_markup(InlineKeyboardMarkup::new([[
+            InlineKeyboardButton::callback("Import", Callback::ConfirmImport(id).to_string()),
+            InlineKeyboardButton::callback("Cancel", Callback::CancelImport(id).to_string()),
+        ]]))
+    };
+    reply
+        .await
+        .map_err(|err| error!("Unable to send Import preview: {:?}", err))
+        .ok();
+
+    Ok(())
+}
+
+/// Downloads the contents of a document sent to the bot.
+///
+/// Returns `None` if the download fails part way, which is only logged.
+async fn download_document(bot: &Bot, document: &Document) -> ResponseResult<Option<Vec<u8>>> {
+    let file = bot.get_file(&document.file.id).await?;
+    let mut contents = vec![];
+    match bot.download_file(&file.path, &mut contents).await {
+        Ok(()) => Ok(Some(contents)),
+        Err(err) => {
+            error!("Unable to download document: {:?}", err);
+            Ok(None)
+        }
+    }
+}
+
+/// Whether `msg` is an activity file recorded by a watch or phone.
+fn is_activity_upload(msg: Message) -> bool {
+    msg.document()
+        .and_then(|document| document.file_name.as_deref())
+        .is_some_and(is_activity_file)
+}
+
+/// Logs a run from an activity file, the same way as `/add` does.
+async fn receive_activity(
+    bot: Bot,
+    msg: Message,
+    db_connection: PgPool,
+    tally_cache: Arc<TallyCache>,
+) -> ResponseResult<()> {
+    let Some(document) = msg.document() else {
+        return Ok(());
+    };
+    let Some(user) = msg.from() else {
+        error!("Unable to retrieve user from message.");
+        return Ok(());
+    };
+    let Some(user_name) = &user.username else {
+        error!("Unable to retrieve username information from Telegram.");
+        return Ok(());
+    };
+    if document.file.size > MAX_ACTIVITY_SIZE {
+        bot.send_message(msg.chat.id, "File is too large to read.")
+            .reply_to_message_id(msg.id)
+            .await?;
+        return Ok(());
+    }
+    let Some(contents) = download_document(&bot, document).await? else {
+        return Ok(());
+    };
+
+    let file_name = document.file_name.as_deref().unwrap_or_default();
+    let activity = match parse_activity(file_name, &contents) {
+        Ok(activity) => activity,
+        Err(reason) => {
+            bot.send_message(msg.chat.id, reason)
+                .reply_to_message_id(msg.id)
+                .await?;
+            return Ok(());
+        }
+    };
+    let details = RunDetails {
+        external_id: Some(file_id(&contents)),
+        ..activity.details()
+    };
+    let records = current_records(msg.chat.id, user.id, &db_connection).await;
+    let add_result = add_shared_run(
+        activity.distance,
+        details,
+        user_name,
+        user.id,
+        msg.chat.id,
+        &db_connection,
+    )
+    .await;
+    if let Ok(None) = add_result {
+        bot.send_message(msg.chat.id, "This activity is already logged.")
+            .reply_to_message_id(msg.id)
+            .await
+            .map_err(|error| error!("Unable to send activity summary: {:?}", error))
+            .ok();
+    } else if let Ok(Some(shared_to)) = add_result {
+        let run = AddedRun {
+            chat_id: msg.chat.id,
+            telegram_userid: user.id,
+            user_name,
+            distance: activity.distance,
+            shared_to,
+            records_before: records,
+        };
+        let summary = summarise_activity(user_name, &activity);
+        after_run_added(
+            &bot,
+            run,
+            summary,
+            Some(msg.id),
+            &tally_cache,
+            &db_connection,
+        )
+        .await;
+    } else {
+        error!("Unable to Add run information from activity file.");
+    }
+
+    Ok(())
+}
+
+/// Function used for handling presses of inline keyboard buttons.
+async fn answer_callback(
+    bot: Bot,
+    query: CallbackQuery,
+    db_connection: PgPool,
+    tally_cache: Arc<TallyCache>,
+    pending_imports: Arc<PendingImports>,
+) -> ResponseResult<()> {
+    let (Some(callback), Some(message)) = (
+        query.data.as_deref().and_then(|data| data.parse().ok()),
+        query.message.as_ref(),
+    ) else {
+        bot.answer_callback_query(&query.id).await?;
+        return Ok(());
+    };
+
+    match callback {
+        Callback::ConfirmImport(id) | Callback::CancelImport(id) => {
+            let Some(import) = pending_imports
+                .take(id, query.from.id, std::time::Instant::now())
+                .filter(|import| import.chat_id == message.chat.id)
+            else {
+                bot.answer_callback_query(&query.id)
+                    .text("Only the person who sent the file can do this, once, within an hour of sending it.")
+                    .await?;
+                return Ok(());
+            };
+            bot.answer_callback_query(&query.id).await?;
+
+            let outcome = if callback == Callback::CancelImport(id) {
+                "Import cancelled.".to_string()
+            } else {
+                match add_runs(&import.runs, import.chat_id, &db_connection).await {
+                    Ok(added) => {
+                        tally_cache.invalidate(import.chat_id);
+                        format!("Imported {} runs.", added)
+                    }
+                    Err(err) => {
+                        error!("Unable to import runs: {:?}", err);
+                        "Import failed, no runs were added.".to_string()
+                    }
+                }
+            };
+            bot.edit_message_text(message.chat.id, message.id, outcome)
+                .await
+                .map_err(|err| error!("Unable to send Import outcome: {:?}", err))
+                .ok();
+            celebrate_goal(&bot, import.chat_id, &db_connection).await;
+            announce_landmarks(&bot, import.chat_id, &db_connection).await;
+        }
+        Callback::AcceptChallenge(id) | Callback::DeclineChallenge(id) => {
+            let accepting = callback == Callback::AcceptChallenge(id);
+            answer_challenge_button(&bot, &query, message, id, accepting, &db_connection).await?;
+        }
+        Callback::TallyPage { period, page } | Callback::TeamTallyPage { period, page } => {
+            bot.answer_callback_query(&query.id).await?;
+            let grouping = match callback {
+                Callback::TeamTallyPage { .. } => TallyGrouping::Teams {
+                    per_member: get_teams_per_member(message.chat.id, &db_connection)
+                        .await
+                        .map_err(|err| error!("Unable to retrieve team settings: {:?}", err))
+                        .unwrap_or_default(),
+                },
+                _ => TallyGrouping::Users,
+            };
+            match tally_cache
+                .get_tally(message.chat.id, period, grouping, &db_connection)
+                .await
+            {
+                Ok(tally) => {
+                    let emojis = chat_rank_emojis(message.chat.id, &db_connection).await;
+                    let tally_message =
+                        display_tally(tally, &emojis, page, Some(query.from.id), grouping);
+                    let mut request = bot
+                        .edit_message_text(message.chat.id, message.id, &tally_message.text)
+                        .parse_mode(ParseMode::Html);
+                    request.reply_markup = page_numbers(&tally_message, |page| match grouping {
+                        TallyGrouping::Users => Callback::TallyPage { period, page },
+                        TallyGrouping::Teams { .. } => Callback::TeamTallyPage { period, page },
+                    });
+                    request
+                        .await
+                        .map_err(|err| error!("Unable to send Tally page: {:?}", err))
+                        .ok();
+                }
+                Err(_) => error!("Unable to retrieve tally from database."),
+            }
+        }
+        Callback::UsersPage(page) => {
+            bot.answer_callback_query(&query.id).await?;
+            match get_users_in_chat(message.chat.id, &db_connection).await {
+                Ok(users) => {
+                    let show_message = list_users(users, page);
+                    let mut request = bot
+                        .edit_message_text(message.chat.id, message.id, &show_message.text)
+                        .parse_mode(ParseMode::Html);
+                    request.reply_markup = page_numbers(&show_message, Callback::UsersPage);
+                    request
+                        .await
+                        .map_err(|err| error!("Unable to send Show page: {:?}", err))
+                        .ok();
+                }
+                Err(_) => error!("Unable to retrieve items required for Show."),
+            }
+        }
+        Callback::ListPage {
+            offset,
+            limit,
+            filter,
+        } => {
+            bot.answer_callback_query(&query.id).await?;
+            match list_page(message.chat.id, &filter, limit, offset, &db_connection).await {
+                Ok((run_message, keyboard)) => {
+                    let mut request = bot
+                        .edit_message_text(message.chat.id, message.id, run_message)
+                        .parse_mode(ParseMode::Html);
+                    request.reply_markup = keyboard;
+                    request
+                        .await
+                        .map_err(|err| error!("Unable to send List page: {:?}", err))
+                        .ok();
+                }
+                Err(_) => error!("Unable to retrieve runs from database."),
+            }
+        }
     }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{buttons, sent_files, sent_messages, Harness, TestUser};
+    use serde_json::{json, Value};
+
+    const CHAT: i64 = -100;
+    const REUBEN: TestUser = TestUser {
+        id: 11,
+        username: "reuben",
+    };
+    const MILTON: TestUser = TestUser {
+        id: 22,
+        username: "milton",
+    };
+
+    #[sqlx::test]
+    async fn add_replies_with_distance(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/add 5");
+
+        let replies = harness.run(1).await;
+        assert_eq!(replies, vec!["reuben ran 5km added to database."]);
+    }
+
+    #[sqlx::test]
+    async fn add_then_tally(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/add 5");
+        harness.send(CHAT, &MILTON, "/add 7.5");
+        harness.send(CHAT, &REUBEN, "/add 1");
+        harness.send(CHAT, &REUBEN, "/tally");
+
+        let replies = harness.run(4).await;
+        assert_eq!(
+            replies[3],
+            r#"<b>Leaderboard</b>
+🥇 <code>1.  7.5km  1🏅</code> <b><a href="tg://user?id=22">milton</a></b>
+🥈 <code>2.    6km  2🏅</code> <a href="tg://user?id=11">reuben</a>
+"#
+        );
+        let tally = harness.calls().pop().unwrap();
+        assert_eq!(tally.body["parse_mode"], "HTML");
+    }
+
+    #[sqlx::test]
+    async fn show_lists_users_in_chat(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/add 5");
+        harness.send(CHAT - 1, &MILTON, "/add 5");
+        harness.send(CHAT, &REUBEN, "/show");
+
+        let replies = harness.run(3).await;
+        let show = replies
+            .iter()
+            .find(|reply| reply.starts_with("<pre>\n#   UserID"))
+            .unwrap();
+        assert!(show.contains("reuben"));
+        assert!(!show.contains("milton"));
+    }
+
+    #[sqlx::test]
+    async fn edit_and_delete_runs(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/add 5");
+        harness.send(CHAT, &REUBEN, "/edit 1 6");
+        harness.send(CHAT, &REUBEN, "/tally");
+        harness.send(CHAT, &REUBEN, "/delete 1");
+        harness.send(CHAT, &REUBEN, "/tally");
+
+        let replies = harness.run(5).await;
+        assert_eq!(
+            replies[1..],
+            [
+                "Run 1 successfully updated with distance 6km.",
+                r#"<b>Leaderboard</b>
+🥇 <code>1.  6km  1🏅</code> <b><a href="tg://user?id=11">reuben</a></b>
+"#,
+                "Run 1 successfully deleted!",
+                "<b>Leaderboard</b>\n",
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn runs_of_others_are_left_alone(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/add 5");
+        harness.send(CHAT, &MILTON, "/edit 1 6");
+        harness.send(CHAT, &MILTON, "/delete 1");
+        harness.send(CHAT, &REUBEN, "/delete 2");
+        harness.send(CHAT, &REUBEN, "/tally");
+
+        let replies = harness.run(5).await;
+        assert_eq!(
+            replies[1..],
+            [
+                "No run 1 of yours in this chat.",
+                "No run 1 of yours in this chat.",
+                "No run 2 of yours in this chat.",
+                r#"<b>Leaderboard</b>
+🥇 <code>1.  5km  1🏅</code> <b><a href="tg://user?id=11">reuben</a></b>
+"#,
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn records_are_only_set_on_own_runs(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/add 5 25:00");
+        harness.send(CHAT, &MILTON, "/add 5 30:00");
+        harness.send(CHAT, &MILTON, "/edit 1 5 20:00");
+        harness.send(CHAT, &REUBEN, "/edit 1 5 24:00");
+
+        let replies = harness.run(4).await;
+        assert_eq!(replies[2], "No run 1 of yours in this chat.");
+        assert_eq!(
+            replies[3],
+            "Run 1 successfully updated with distance 5km.\n🏅 New 5k PR: 24:00, 1:00 faster than before!"
+        );
+    }
+
+    #[sqlx::test]
+    async fn tally_for_period_reflects_new_runs(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/add 5");
+        harness.send(CHAT, &REUBEN, "/tally week");
+        harness.send(CHAT, &REUBEN, "/add 2");
+        harness.send(CHAT, &REUBEN, "/tally week");
+
+        let replies = harness.run(4).await;
+        assert_eq!(
+            [&replies[1], &replies[3]],
+            [
+                r#"<b>Leaderboard</b>
+🥇 <code>1.  5km  1🏅</code> <b><a href="tg://user?id=11">reuben</a></b>
+"#,
+                r#"<b>Leaderboard</b>
+🥇 <code>1.  7km  2🏅</code> <b><a href="tg://user?id=11">reuben</a></b>
+"#,
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn export_sends_document(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/add 5");
+        harness.send(CHAT, &MILTON, "/add 2.5");
+        harness.send(CHAT, &REUBEN, "/export json week");
+
+        harness
+            .run_until(|calls| !sent_files(calls, "sendDocument").is_empty())
+            .await;
+        let documents = sent_files(&harness.calls(), "sendDocument");
+        assert_eq!(documents[0].field, "document");
+        assert_eq!(documents[0].file_name, "runs.json");
+        let runs: serde_json::Value = serde_json::from_slice(&documents[0].bytes).unwrap();
+        assert_eq!(runs[0]["user_name"], "reuben");
+        assert_eq!(runs[1]["user_name"], "milton");
+        assert_eq!(runs[1]["distance"], 2.5);
+    }
+
+    #[sqlx::test]
+    async fn chart_sends_photo(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/chart");
+        harness.send(CHAT, &REUBEN, "/add 5");
+        harness.send(CHAT, &MILTON, "/add 2.5");
+        harness.send(CHAT, &REUBEN, "/chart week bars");
+
+        harness
+            .run_until(|calls| !sent_files(calls, "sendPhoto").is_empty())
+            .await;
+        let calls = harness.calls();
+        assert_eq!(sent_messages(&calls)[0], "No runs in database.");
+        let photos = sent_files(&calls, "sendPhoto");
+        assert_eq!(photos[0].field, "photo");
+        assert_eq!(photos[0].file_name, "chart.png");
+        assert!(photos[0].bytes.starts_with(b"\x89PNG"));
+    }
+
+    #[sqlx::test]
+    async fn list_pages_through_filtered_runs(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        for distance in [1, 2, 3] {
+            harness.send(CHAT, &REUBEN, &format!("/add {}", distance));
+        }
+        harness.send(CHAT, &MILTON, "/add 4");
+        harness.send(CHAT, &MILTON, "/list me");
+        harness.send(CHAT, &MILTON, "/list 2 @Reuben");
+        harness.press(CHAT, &MILTON, "list:2:2 @Reuben");
+
+        harness
+            .run_until(|calls| calls.iter().any(|call| call.method == "editMessageText"))
+            .await;
+        let calls = harness.calls();
+        let replies = sent_messages(&calls);
+        assert_eq!(replies[4].lines().count(), 2);
+        assert!(replies[4].contains("milton · 4.0km"));
+        assert!(buttons(&calls[4]).is_empty());
+
+        assert!(replies[5].contains("<code>#3</code> reuben · 3.0km"));
+        assert!(replies[5].contains("<code>#2</code> reuben · 2.0km"));
+        assert_eq!(buttons(&calls[5]), ["list:2:2 @Reuben"]);
+
+        let page = calls.last().unwrap();
+        assert!(page.body["text"]
+            .as_str()
+            .unwrap()
+            .contains("<code>#1</code> reuben · 1.0km"));
+        assert_eq!(buttons(page), ["list:0:2 @Reuben"]);
+    }
+
+    #[sqlx::test]
+    async fn tally_pages_show_callers_rank(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        let users: Vec<TestUser> = (1..=30)
+            .map(|id| TestUser {
+                id,
+                username: Box::leak(format!("runner{}", id).into_boxed_str()),
+            })
+            .collect();
+        for user in &users {
+            harness.send(CHAT, user, &format!("/add {}", 100 - user.id));
+        }
+        harness.send(CHAT, &users[27], "/tally");
+        harness.press(CHAT, &users[0], "tally:all:1");
+
+        harness
+            .run_until(|calls| calls.iter().any(|call| call.method == "editMessageText"))
+            .await;
+        let calls = harness.calls();
+        let tally = calls
+            .iter()
+            .find(|call| {
+                call.body["text"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("<b>Leaderboard")
+            })
+            .unwrap();
+        let text = tally.body["text"].as_str().unwrap();
+        assert!(text.contains("runner25<"));
+        assert!(!text.contains("runner26<"));
+        assert!(text.contains("…\n🏃 <code>28.  72km  1🏅</code>"));
+        assert!(text.ends_with("<i>Page 1 of 2</i>\n"));
+        assert_eq!(buttons(tally), ["tally:all:1"]);
+
+        let page = calls.last().unwrap();
+        let text = page.body["text"].as_str().unwrap();
+        assert!(text.contains("🤡 <code>30.  70km  1🏅</code>"));
+        assert!(text.contains("…\n🥇 <code> 1.  99km  1🏅</code>"));
+        assert_eq!(buttons(page), ["tally:all:0"]);
+    }
+
+    #[sqlx::test]
+    async fn chats_choose_their_rank_emojis(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/add 5");
+        harness.send(CHAT, &MILTON, "/add 1");
+        harness.send(CHAT, &REUBEN, "/emojis 🏆 🐢 -");
+        harness.send(CHAT, &REUBEN, "/tally");
+        harness.send(CHAT, &REUBEN, "/emojis");
+        harness.send(CHAT, &REUBEN, "/emojis 🏆");
+        harness.send(CHAT, &REUBEN, "/emojis off");
+        harness.send(CHAT, &REUBEN, "/tally");
+        harness.send(CHAT, &REUBEN, "/emojis default");
+
+        let replies = harness.run(8).await;
+        assert_eq!(
+            replies[2],
+            "Places in the tally are now marked with: 🏆 🐢 -"
+        );
+        assert!(replies[3].contains("\n🏆 <code>1.  5km  1🏅</code>"));
+        assert!(replies[3].contains("\n<code>2.  1km  1🏅</code>"));
+        assert_eq!(replies[4], "Places in the tally are marked with: 🏆 🐢 -");
+        assert!(replies[5].starts_with("Expected up to 10 emojis"));
+        assert_eq!(
+            replies[6],
+            "Places in the tally are no longer marked with emojis."
+        );
+        assert!(replies[7].contains("\n<code>1.  5km  1🏅</code>"));
+        assert_eq!(
+            replies[8],
+            "Places in the tally are back to being marked with: 🥇 🥈 🥉 🏃 🤡"
+        );
+    }
+
+    #[sqlx::test]
+    async fn chats_schedule_recaps(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/recap");
+        harness.send(CHAT, &REUBEN, "/recap week 18:30 Asia/Singapore");
+        harness.send(CHAT, &REUBEN, "/recap month");
+        harness.send(CHAT, &REUBEN, "/recap year");
+        harness.send(CHAT, &REUBEN, "/recap week Mars/Olympus");
+        harness.send(CHAT, &REUBEN, "/recap");
+        harness.send(CHAT, &REUBEN, "/recap off month");
+        harness.send(CHAT, &REUBEN, "/recap off");
+        harness.send(CHAT, &REUBEN, "/recap off");
+
+        let replies = harness.run(8).await;
+        assert_eq!(
+            replies,
+            vec![
+                "No recaps are posted in this chat.",
+                "Weekly recaps are posted on Sundays at 18:30 (Asia/Singapore).",
+                "Monthly recaps are posted on the last day of the month at 20:00 (UTC).",
+                "Unknown recap \"year\", expected week, month or off.",
+                "Unknown time or time zone \"Mars/Olympus\", expected e.g. 18:30 Asia/Singapore.",
+                "Weekly recaps are posted on Sundays at 18:30 (Asia/Singapore).\nMonthly recaps are posted on the last day of the month at 20:00 (UTC).",
+                "Monthly recaps are no longer posted.",
+                "Recaps are no longer posted.",
+                "No recaps are posted in this chat.",
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn chats_turn_on_reminders(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/remind");
+        harness.send(CHAT, &REUBEN, "/remind 3 07:00 Europe/London");
+        harness.send(CHAT, &MILTON, "/remind off");
+        harness.send(CHAT, &MILTON, "/remind on");
+        harness.send(CHAT, &MILTON, "/remind never");
+        harness.send(CHAT, &REUBEN, "/remind 1");
+        harness.send(CHAT, &REUBEN, "/remind stop");
+        harness.send(CHAT, &REUBEN, "/remind");
+
+        let replies = harness.run(8).await;
+        assert_eq!(
+            replies,
+            vec![
+                "Reminders are off in this chat.",
+                "Members who haven't run in 3 days are reminded at 07:00 (Europe/London).",
+                "You will no longer be reminded to run.",
+                "You will be reminded to run again.",
+                "Unknown reminder \"never\", expected a number of days from 1 to 365, on, off or stop.",
+                "Members who haven't run in 1 day are reminded at 19:00 (UTC).",
+                "Reminders are off in this chat.",
+                "Reminders are off in this chat.",
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn chats_reach_goals(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/goal");
+        harness.send(CHAT, &REUBEN, "/goal set 10 month");
+        harness.send(CHAT, &REUBEN, "/goal set 10 fortnight");
+        harness.send(CHAT, &REUBEN, "/add 6");
+        harness.send(CHAT, &MILTON, "/add 5");
+        harness.send(CHAT, &MILTON, "/add 1");
+        harness.send(CHAT, &REUBEN, "/goal");
+
+        let replies = harness.run(8).await;
+        let month = chrono::Utc::now().format("%B %Y");
+        assert_eq!(
+            replies[0],
+            "No goal set. Usage: /goal set &lt;distance&gt; &lt;week|month|year&gt;"
+        );
+        assert!(replies[1].starts_with(&format!(
+            "<b>Goal: 10.0km in {}</b>\n<code>░░░░░░░░░░</code> 0%\n0.0km run, 10.0km to go by ",
+            month
+        )));
+        assert!(replies[1].ends_with("Nobody has run towards it yet."));
+        assert_eq!(
+            replies[2],
+            "Expected /goal set <distance> <week|month|year>, e.g. /goal set 500 month."
+        );
+        // The goal is only celebrated once.
+        assert_eq!(
+            replies[5],
+            format!(
+                "🎉 Goal reached! Together you've run 11.0km of the 10.0km you set out to run in {}.",
+                month
+            )
+        );
+        assert_eq!(replies[6], "milton ran 1km added to database.");
+        assert!(replies[7].contains("<code>██████████</code> 120%\n12.0km run.\n🎉 Reached on "));
+    }
+
+    #[sqlx::test]
+    async fn chats_run_journeys(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/add 50");
+        harness.send(CHAT, &REUBEN, "/journey");
+        harness.send(CHAT, &REUBEN, "/journey start mars");
+        harness.send(CHAT, &REUBEN, "/journey start london-brighton");
+        harness.send(CHAT, &REUBEN, "/add 20");
+        harness.send(CHAT, &MILTON, "/add 2");
+        harness.send(CHAT, &MILTON, "/add 70");
+        harness.send(CHAT, &REUBEN, "/journey");
+        harness.send(CHAT, &REUBEN, "/journey stop");
+
+        let replies = harness.run(11).await;
+        assert_eq!(
+            replies[1],
+            "No journey under way. See /journey routes to pick one."
+        );
+        assert_eq!(replies[2], "No route called mars. See /journey routes.");
+        // Runs from before the chat set off don't count.
+        assert!(replies[3].starts_with("<b>🗺️ London to Brighton</b>\n<i>Since "));
+        assert!(replies[3].ends_with(
+            "<code>░░░░░░░░░░</code> 0%\nSetting off from London.\nNext up: Croydon, 16.0km away."
+        ));
+        assert_eq!(
+            replies[5],
+            "📍 You've reached Croydon, 16.0km from London! Next up: Coulsdon, 7.0km on."
+        );
+        // Landmarks passed together are announced once, by the furthest.
+        assert_eq!(replies[6], "milton ran 2km added to database.");
+        assert_eq!(replies[7], "milton ran 70km added to database.");
+        assert_eq!(
+            replies[8],
+            "🏁 Journey complete! Together you've run all 87.0km from London to Brighton."
+        );
+        assert!(replies[9].ends_with("100%\n🏁 Arrived in Brighton, 92.0km run!"));
+        assert_eq!(replies[10], "Journey stopped.");
+    }
+
+    #[sqlx::test]
+    async fn users_set_personal_records(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/pbs");
+        harness.send(CHAT, &REUBEN, "/add 5 25:00");
+        harness.send(CHAT, &REUBEN, "/add 5.3 24:00");
+        harness.send(CHAT, &REUBEN, "/add 5 24:30");
+        harness.send(CHAT, &REUBEN, "/add 10.4 48:00 race");
+        harness.send(CHAT, &REUBEN, "/add 2 splits 3:50 4:10");
+        harness.send(CHAT, &REUBEN, "/edit 2 5.3 23:00 race");
+        harness.send(CHAT, &REUBEN, "/pbs");
+
+        let replies = harness.run(8).await;
+        assert!(replies[0].ends_with("e.g. /add 5 24:30, or send an activity file.\n"));
+        assert_eq!(
+            replies[1],
+            "reuben ran 5km added to database.\n🏅 First 5k on record: 25:00!"
+        );
+        // Longer runs don't count unless raced or split.
+        assert_eq!(replies[2], "reuben ran 5.3km added to database.");
+        assert_eq!(
+            replies[3],
+            "reuben ran 5km added to database.\n🏅 New 5k PR: 24:30, 0:30 faster than before!"
+        );
+        assert_eq!(
+            replies[4],
+            "reuben ran 10.4km added to database.\n🏅 First 10k on record: 48:00!"
+        );
+        assert_eq!(
+            replies[5],
+            "reuben ran 2km added to database.\n🏅 First 1k on record: 3:50!"
+        );
+        assert_eq!(
+            replies[6],
+            "Run 2 successfully updated with distance 5.3km.\n🏅 New 5k PR: 23:00, 1:30 faster than before!"
+        );
+        let today = chrono::Utc::now().format("%a %-d %b %Y");
+        assert_eq!(
+            replies[7],
+            format!(
+                "<b>Personal records of reuben</b>
+<code>1k    3:50</code> · #5 · {today}
+<code>5k   23:00</code> · #2 · {today}
+<code>10k  48:00</code> · #4 · {today}
+"
+            )
+        );
+    }
+
+    #[sqlx::test]
+    async fn users_reach_personal_goals(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/me");
+        harness.send(CHAT, &REUBEN, "/me goal 2 runs week");
+        harness.send(CHAT, &REUBEN, "/add 5");
+        harness.send(CHAT, &MILTON, "/add 5");
+        harness.send(CHAT, &REUBEN, "/add 3");
+        harness.send(CHAT, &REUBEN, "/add 1");
+        harness.send(CHAT, &REUBEN, "/me");
+        harness.send(CHAT, &REUBEN, "/me goal 20 miles week");
+        harness.send(CHAT, &REUBEN, "/me goal off");
+
+        let replies = harness.run(8).await;
+        assert!(replies[0].starts_with("<b>reuben</b>\nNo personal goal set."));
+        assert_eq!(
+            replies[1],
+            "<b>reuben</b>\n🎯 2 runs a week\n<code>░░░░░░░░░░</code> 0%\n0 runs this week, 2 runs to go\n"
+        );
+        assert_eq!(replies[2], "reuben ran 5km added to database.");
+        assert_eq!(
+            replies[4],
+            "reuben ran 3km added to database.\n🎯 That's your weekly goal of 2 runs reached!"
+        );
+        // Goals are only celebrated once a period.
+        assert_eq!(replies[5], "reuben ran 1km added to database.");
+        assert_eq!(
+            replies[6],
+            "<b>reuben</b>\n🎯 2 runs a week\n<code>██████████</code> 150%\n3 runs this week, goal reached!\nReached 1 of 1 week so far (100%).\n"
+        );
+        assert!(replies[7].starts_with("Expected /me goal"));
+        assert!(replies[8].starts_with("<b>reuben</b>\nNo personal goal set."));
+    }
+
+    #[sqlx::test]
+    async fn users_challenge_each_other(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/challenge @reuben 10km 1w");
+        harness.send(CHAT, &REUBEN, "/challenge @Milton 10km 1w");
+        harness.press(CHAT, &REUBEN, "challenge:accept:1");
+        harness.press(CHAT, &MILTON, "challenge:accept:1");
+        harness.send(CHAT, &REUBEN, "/challenge @milton 5km 3d");
+        harness.press(CHAT, &MILTON, "challenge:decline:2");
+        harness.send(CHAT, &REUBEN, "/add 6");
+        harness.send(CHAT, &REUBEN, "/challenge");
+        harness.send(CHAT, &MILTON, "/add 11");
+        harness.send(CHAT, &MILTON, "/challenge");
+        let replies = harness.run(8).await;
+
+        assert_eq!(replies[0], "You can't challenge yourself.");
+        assert_eq!(
+            replies[1],
+            "🥊 reuben challenges @Milton to be the first to run 10.0km within 1 week! @Milton, do you accept?"
+        );
+        let calls = harness.calls();
+        let invitation = calls
+            .iter()
+            .find(|call| call.body["text"] == replies[1].as_str())
+            .unwrap();
+        assert_eq!(
+            buttons(invitation),
+            vec!["challenge:accept:1", "challenge:decline:1"]
+        );
+        let answers: Vec<_> = calls
+            .iter()
+            .filter(|call| call.method == "answerCallbackQuery")
+            .map(|call| call.body["text"].clone())
+            .collect();
+        assert_eq!(
+            answers,
+            vec![
+                json!("Only @Milton can accept this challenge."),
+                Value::Null,
+                Value::Null
+            ]
+        );
+        let edits: Vec<_> = calls
+            .iter()
+            .filter(|call| call.method == "editMessageText")
+            .map(|call| call.body["text"].as_str().unwrap())
+            .collect();
+        assert!(
+            edits[0].starts_with("🥊 Milton accepted reuben's challenge! First to run 10.0km by ")
+        );
+        assert_eq!(edits[1], "milton declined the challenge.");
+        assert!(replies[4].starts_with(
+            "<b>🥊 reuben vs Milton, first to 10.0km</b>
+<code>reuben  ██████░░░░  6.0km</code>
+<code>Milton  ░░░░░░░░░░  0.0km</code>
+Ends "
+        ));
+        // The result is announced as soon as milton gets there, and once.
+        assert_eq!(
+            replies[6],
+            "🏆 Milton won the challenge, reaching 10.0km first! reuben ran 6.0km."
+        );
+        assert_eq!(
+            replies[7],
+            "<b>Results</b>\n🏆 Milton beat reuben, 11.0km to 6.0km"
+        );
+    }
+
+    #[sqlx::test]
+    async fn teams_tally_members_runs(postgres: PgPool) {
+        const TAIGY: TestUser = TestUser {
+            id: 33,
+            username: "taigy",
+        };
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/team");
+        harness.send(CHAT, &REUBEN, "/team create Engineering");
+        harness.send(CHAT, &MILTON, "/team create engineering");
+        harness.send(CHAT, &MILTON, "/team create Sales");
+        harness.send(CHAT, &TAIGY, "/team join ENGINEERING");
+        harness.send(CHAT, &REUBEN, "/add 10");
+        harness.send(CHAT, &MILTON, "/add 6");
+        harness.send(CHAT, &TAIGY, "/add 4");
+        harness.send(CHAT, &REUBEN, "/team list");
+        harness.send(CHAT, &REUBEN, "/team tally");
+        harness.send(CHAT, &REUBEN, "/team normalise on");
+        harness.send(CHAT, &REUBEN, "/team tally");
+        harness.send(CHAT, &TAIGY, "/team leave");
+        let replies = harness.run(13).await;
+
+        assert_eq!(
+            replies[0],
+            "No teams yet. Create one with /team create &lt;name&gt;, then others can /team join it."
+        );
+        assert_eq!(replies[1], "Team Engineering created, and you're in it.");
+        assert_eq!(
+            replies[2],
+            "There's already a team called engineering. Join it with /team join engineering."
+        );
+        assert_eq!(replies[4], "taigy joined Engineering.");
+        assert_eq!(
+            replies[8],
+            "<b>Teams</b>\n<b>Engineering</b> (2): reuben, taigy\n<b>Sales</b> (1): milton"
+        );
+        assert_eq!(
+            replies[9],
+            "<b>Team leaderboard</b>
+🥇 <code>1.  14km  2🏅</code> <b>Engineering</b>
+🥈 <code>2.   6km  1🏅</code> Sales
+"
+        );
+        assert_eq!(
+            replies[11],
+            "<b>Team leaderboard</b>
+<i>Distance per member</i>
+🥇 <code>1.  7km  2🏅</code> <b>Engineering</b>
+🥈 <code>2.  6km  1🏅</code> Sales
+"
+        );
+        assert_eq!(replies[12], "taigy left their team.");
+    }
+
+    #[sqlx::test]
+    async fn users_share_runs_between_chats(postgres: PgPool) {
+        const OTHER_CHAT: i64 = -200;
+        const THIRD_CHAT: i64 = -300;
+        let harness = Harness::new(postgres).await;
+        // Chats are handled concurrently, so each step is run on its own.
+        let mut sent = 0;
+        for (chat_id, text, replies) in [
+            (CHAT, "/profile share on", 1),
+            (OTHER_CHAT, "/add 1", 1),
+            (OTHER_CHAT, "/profile share on", 1),
+            (CHAT, "/add 5", 2),
+            (THIRD_CHAT, "/add 2.5", 1),
+            (REUBEN.id as i64, "/profile", 1),
+            (REUBEN.id as i64, "/profile share on", 1),
+            (OTHER_CHAT, "/tally", 1),
+            (THIRD_CHAT, "/profile", 1),
+        ] {
+            harness.send(chat_id, &REUBEN, text);
+            sent += replies;
+            harness.run(sent).await;
+        }
+        let replies = sent_messages(&harness.calls());
+
+        assert_eq!(
+            replies[0],
+            "Runs you /add here will be posted to your other chats once you send /profile share on in them too.
+Send me /profile in a private chat to see all your runs."
+        );
+        assert!(replies[2]
+            .starts_with("Runs you /add here are also posted to 1 other chat that shares runs."));
+        assert_eq!(
+            replies[3],
+            "reuben ran 5km added to database.\nAlso posted to 1 other chat."
+        );
+        assert_eq!(replies[4], "reuben ran 5km, shared from another chat.");
+        assert_eq!(replies[5], "reuben ran 2.5km added to database.");
+        // The shared run is counted once.
+        let week = "<b>8.5km</b> over 3 runs";
+        assert_eq!(
+            replies[6],
+            format!(
+                "<b>reuben</b>
+Across 3 chats:
+All time: {week}
+This year: {week}
+This month: {week}
+This week: {week}
+Runs you /add are shared between 2 chats, and only counted once here.
+"
+            )
+        );
+        assert_eq!(
+            replies[7],
+            "Send /profile share on in each of the group chats you want to share runs between."
+        );
+        assert!(replies[8].contains("<code>1.  6km  2🏅</code>"));
+        assert!(replies[9].starts_with("Runs you /add here stay here."));
+    }
+
+    #[sqlx::test]
+    async fn shared_runs_follow_edits_and_deletes(postgres: PgPool) {
+        const OTHER_CHAT: i64 = -200;
+        let harness = Harness::new(postgres).await;
+        // Chats are handled concurrently, so each step is run on its own.
+        let mut sent = 0;
+        for (chat_id, text, replies) in [
+            (CHAT, "/profile share on", 1),
+            (OTHER_CHAT, "/profile share on", 1),
+            (CHAT, "/add 5", 2),
+            (OTHER_CHAT, "/tally", 1),
+            (CHAT, "/edit 1 6", 1),
+            (OTHER_CHAT, "/tally", 1),
+            (CHAT, "/delete 1", 1),
+            (OTHER_CHAT, "/tally", 1),
+        ] {
+            harness.send(chat_id, &REUBEN, text);
+            sent += replies;
+            harness.run(sent).await;
+        }
+        let replies = sent_messages(&harness.calls());
+
+        assert!(replies[4].contains("<code>1.  5km  1🏅</code>"));
+        assert!(replies[6].contains("<code>1.  6km  1🏅</code>"));
+        assert_eq!(replies[7], "Run 1 successfully deleted!");
+        assert_eq!(replies[8], "<b>Leaderboard</b>\n");
+    }
+
+    #[sqlx::test]
+    async fn import_after_confirmation(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/add 1");
+        harness.send(CHAT, &MILTON, "/add 1");
+        let file = "user,distance,date
+reuben,5,2023-10-01
+milton,2.5,2023-10-02
+bob,3,2023-10-03
+";
+        harness.send_document(
+            CHAT,
+            &REUBEN,
+            Some(IMPORT_PROMPT),
+            "runs.csv",
+            file.as_bytes(),
+        );
+        // Pending imports are numbered from 0, and only the uploader can
+        // confirm them.
+        harness.press(CHAT, &MILTON, "import:confirm:0");
+        harness.press(CHAT, &REUBEN, "import:confirm:0");
+        harness.send(CHAT, &REUBEN, "/tally");
+        let replies = harness.run(4).await;
+
+        let calls = harness.calls();
+        let preview = &calls[2];
+        assert_eq!(
+            preview.body["text"],
+            "Ready to import 2 runs totalling 7.5km by milton, reuben from 2023-10-01 to 2023-10-02.
+1 rows will be skipped:
+Line 4: unknown user \"bob\", they need to /add a run first
+"
+        );
+        assert_eq!(
+            buttons(preview),
+            vec!["import:confirm:0", "import:cancel:0"]
+        );
+        let answers: Vec<_> = calls
+            .iter()
+            .filter(|call| call.method == "answerCallbackQuery")
+            .map(|call| call.body["text"].clone())
+            .collect();
+        assert_eq!(
+            answers,
+            vec![
+                json!("Only the person who sent the file can do this, once, within an hour of sending it."),
+                Value::Null
+            ]
+        );
+        let edit = calls
+            .iter()
+            .find(|call| call.method == "editMessageText")
+            .unwrap();
+        assert_eq!(edit.body["text"], "Imported 2 runs.");
+        assert_eq!(
+            replies[3],
+            r#"<b>Leaderboard</b>
+🥇 <code>1.    6km  2🏅</code> <b><a href="tg://user?id=11">reuben</a></b>
+🥈 <code>2.  3.5km  2🏅</code> <a href="tg://user?id=22">milton</a>
+"#
+        );
+    }
+
+    #[sqlx::test]
+    async fn import_of_a_large_group_history(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/add 1");
+        let mut file = String::from("user,distance,date\nreuben,5,2023-10-01\n");
+        for member in 0..300 {
+            file.push_str(&format!("member{},5,2023-10-01\n", member));
+        }
+        harness.send_document(
+            CHAT,
+            &REUBEN,
+            Some(IMPORT_PROMPT),
+            "runs.csv",
+            file.as_bytes(),
+        );
+        harness.press(CHAT, &REUBEN, "import:confirm:0");
+        harness
+            .run_until(|calls| calls.iter().any(|call| call.method == "editMessageText"))
+            .await;
+
+        let calls = harness.calls();
+        let preview = calls[1].body["text"].as_str().unwrap();
+        assert!(preview.starts_with("Ready to import 1 runs totalling 5km by reuben"));
+        assert!(preview.ends_with("…and 280 more.\n"));
+        let edit = calls
+            .iter()
+            .find(|call| call.method == "editMessageText")
+            .unwrap();
+        assert_eq!(edit.body["text"], "Imported 1 runs.");
+    }
+
+    #[sqlx::test]
+    async fn gpx_upload_logs_run(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        let gpx = r#"<gpx version="1.1" creator="test"><trk><trkseg>
+            <trkpt lat="1.3000" lon="103.8000"><time>2023-10-14T07:12:00Z</time></trkpt>
+            <trkpt lat="1.3450" lon="103.8000"><time>2023-10-14T07:40:00Z</time></trkpt>
+        </trkseg></trk></gpx>"#;
+        harness.send_document(CHAT, &REUBEN, None, "Morning_Run.GPX", gpx.as_bytes());
+        harness.send_document(CHAT, &REUBEN, None, "broken.gpx", b"<gpx>");
+        harness.send(CHAT, &REUBEN, "/export");
+        harness
+            .run_until(|calls| !sent_files(calls, "sendDocument").is_empty())
+            .await;
+
+        let replies = sent_messages(&harness.calls());
+        assert_eq!(
+            replies,
+            vec![
+                "reuben ran 5km in 28:00 (28:00 moving) from 2023-10-14 07:12, added to database.\n🏅 First 1k on record: 5:35!\n🏅 First 5k on record: 27:59!",
+                "The file is not valid GPX."
+            ]
+        );
+        let export = &sent_files(&harness.calls(), "sendDocument")[0];
+        assert_eq!(
+            String::from_utf8(export.bytes.clone()).unwrap(),
+            "run_id,user_name,distance,duration_secs,run_datetime,sport,avg_heart_rate,max_heart_rate,elevation_gain
+1,reuben,5.0,1680,2023-10-14T07:12:00,,,,
+"
+        );
+    }
+
+    #[sqlx::test]
+    async fn gpx_uploaded_twice_is_logged_once(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        let gpx = r#"<gpx version="1.1" creator="test"><trk><trkseg>
+            <trkpt lat="1.3000" lon="103.8000"><time>2023-10-14T07:12:00Z</time></trkpt>
+            <trkpt lat="1.3450" lon="103.8000"><time>2023-10-14T07:40:00Z</time></trkpt>
+        </trkseg></trk></gpx>"#;
+        harness.send_document(CHAT, &REUBEN, None, "Morning_Run.gpx", gpx.as_bytes());
+        harness.send_document(CHAT, &REUBEN, None, "Morning_Run.gpx", gpx.as_bytes());
+        harness.send(CHAT, &REUBEN, "/tally");
+        let replies = harness.run(3).await;
+
+        assert!(replies[0].starts_with("reuben ran 5km in 28:00"));
+        assert_eq!(replies[1], "This activity is already logged.");
+        assert!(replies[2].contains("<code>1.  5km  1🏅</code>"));
+    }
+
+    #[sqlx::test]
+    async fn gpx_upload_is_shared(postgres: PgPool) {
+        const OTHER_CHAT: i64 = -200;
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/profile share on");
+        harness.send(OTHER_CHAT, &REUBEN, "/profile share on");
+        harness.run(2).await;
+        let gpx = r#"<gpx version="1.1" creator="test"><trk><trkseg>
+            <trkpt lat="1.3000" lon="103.8000"><time>2023-10-14T07:12:00Z</time></trkpt>
+            <trkpt lat="1.3450" lon="103.8000"><time>2023-10-14T07:40:00Z</time></trkpt>
+        </trkseg></trk></gpx>"#;
+        // Chats are handled concurrently, so the tally waits for the upload.
+        harness.send_document(CHAT, &REUBEN, None, "Morning_Run.gpx", gpx.as_bytes());
+        harness.run(4).await;
+        harness.send(OTHER_CHAT, &REUBEN, "/tally");
+        let replies = harness.run(5).await;
+
+        assert!(replies[2].starts_with("reuben ran 5km in 28:00"));
+        assert_eq!(replies[3], "reuben ran 5km, shared from another chat.");
+        assert!(replies[4].contains("<code>1.  5km  1🏅</code>"));
+    }
+
+    #[sqlx::test]
+    async fn uploads_settle_challenges_where_they_are_posted(postgres: PgPool) {
+        const OTHER_CHAT: i64 = -200;
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/profile share on");
+        harness.send(OTHER_CHAT, &REUBEN, "/profile share on");
+        harness.run(2).await;
+        harness.send(CHAT, &REUBEN, "/challenge @milton 10km 1w");
+        harness.run(3).await;
+        harness.send(OTHER_CHAT, &REUBEN, "/challenge @milton 10km 1w");
+        harness.run(4).await;
+        harness.press(CHAT, &MILTON, "challenge:accept:1");
+        harness.press(OTHER_CHAT, &MILTON, "challenge:accept:2");
+        harness
+            .run_until(|calls| {
+                calls
+                    .iter()
+                    .filter(|call| call.method == "editMessageText")
+                    .count()
+                    == 2
+            })
+            .await;
+        // Runs only count towards a challenge from when it was accepted, and
+        // up to now.
+        let start = chrono::Utc::now().naive_utc();
+        let gpx = format!(
+            r#"<gpx version="1.1" creator="test"><trk><trkseg>
+            <trkpt lat="1.3000" lon="103.8000"><time>{}</time></trkpt>
+            <trkpt lat="1.3900" lon="103.8000"><time>{}</time></trkpt>
+        </trkseg></trk></gpx>"#,
+            start.format("%Y-%m-%dT%H:%M:%S%.6fZ"),
+            (start + chrono::Duration::minutes(50)).format("%Y-%m-%dT%H:%M:%S%.6fZ"),
+        );
+        harness.send_document(CHAT, &REUBEN, None, "Evening_Run.gpx", gpx.as_bytes());
+        harness.run(8).await;
+
+        let won: Vec<_> = harness
+            .calls()
+            .iter()
+            .filter(|call| {
+                call.method == "sendMessage"
+                    && call.body["text"]
+                        .as_str()
+                        .is_some_and(|text| text.starts_with("🏆 reuben won the challenge"))
+            })
+            .map(|call| call.body["chat_id"].clone())
+            .collect();
+        assert_eq!(won.len(), 2);
+        assert!(won.contains(&json!(CHAT)) && won.contains(&json!(OTHER_CHAT)));
+    }
+
+    #[test]
+    fn callbacks_round_trip() {
+        let list_page = Callback::ListPage {
+            offset: 10,
+            limit: 5,
+            filter: parse_list_args("week @alice".into())
+                .unwrap()
+                .0
+                .resolve(UserId(11)),
+        };
+        for callback in [
+            Callback::ConfirmImport(3),
+            Callback::CancelImport(0),
+            Callback::AcceptChallenge(7),
+            Callback::DeclineChallenge(7),
+            Callback::TallyPage {
+                period: Period::Month,
+                page: 2,
+            },
+            Callback::TeamTallyPage {
+                period: Period::Month,
+                page: 2,
+            },
+            Callback::UsersPage(1),
+            list_page,
+        ] {
+            assert_eq!(callback.to_string().parse(), Ok(callback));
+        }
+        assert!("import:confirm:x".parse::<Callback>().is_err());
+    }
+
+    #[sqlx::test]
+    async fn shutdown_without_updates(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+
+        harness.run_until(|_calls| true).await;
+        assert!(harness.calls().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn shutdown_finishes_in_flight_updates(postgres: PgPool) {
+        let harness = Harness::new(postgres.clone()).await;
+        harness.send(CHAT, &REUBEN, "/add 5");
+
+        // Holds `/add` up on a lock of the runs table, which is only let go
+        // of once shutdown has started.
+        let mut lock = postgres.begin().await.unwrap();
+        sqlx::query("LOCK TABLE runs")
+            .execute(&mut *lock)
+            .await
+            .unwrap();
+        let waiting_for_lock = postgres.clone();
+        let shutdown = async move {
+            loop {
+                let waiting: i64 = sqlx::query_scalar(
+                    "SELECT COUNT(*) FROM pg_stat_activity
+                    WHERE datname = current_database() AND wait_event_type = 'Lock'",
+                )
+                .fetch_one(&waiting_for_lock)
+                .await
+                .unwrap();
+                if waiting > 0 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                lock.commit().await.unwrap();
+            });
+        };
+        harness.run_until_shutdown(shutdown).await;
+
+        assert_eq!(
+            sent_messages(&harness.calls()),
+            vec!["reuben ran 5km added to database."]
+        );
+        let runs: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM runs")
+            .fetch_one(&postgres)
+            .await
+            .unwrap();
+        assert_eq!(runs, 1);
+    }
+
+    #[sqlx::test]
+    async fn help_lists_commands(postgres: PgPool) {
+        let harness = Harness::new(postgres).await;
+        harness.send(CHAT, &REUBEN, "/help");
+
+        // The help text has outgrown a single message.
+        let replies = harness.run(2).await;
+        assert_eq!(
+            replies.join("\n"),
+            Command::descriptions().to_string().trim_end()
+        );
+    }
+}
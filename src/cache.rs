@@ -0,0 +1,180 @@
+//! In-process caching of tallies.
+//!
+//! Busy chats ask for `/tally` far more often than they add runs, so
+//! tallies are kept in memory until a run in the chat changes.
+use crate::{
+    database::get_tally,
+    models::{Period, Score, TallyGrouping},
+};
+use sqlx::{types::chrono, PgPool};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use teloxide::types::ChatId;
+use tracing::info;
+
+/// A tally along with the start of the period it was computed for.
+struct CachedTally {
+    /// Start of the period, periods roll over without any runs changing.
+    since: Option<chrono::NaiveDateTime>,
+    /// Tally as returned by `get_tally`.
+    scores: Option<Vec<Score>>,
+}
+
+/// Tallies cached per chat, period and grouping.
+#[derive(Default)]
+pub struct TallyCache {
+    /// Cached tallies.
+    tallies: Mutex<HashMap<(ChatId, Period, TallyGrouping), CachedTally>>,
+    /// Bumped on every invalidation, so that tallies computed concurrently
+    /// with a change are not cached.
+    generation: AtomicU64,
+    /// Number of tallies served from the cache.
+    hits: AtomicU64,
+    /// Number of tallies computed from the database.
+    misses: AtomicU64,
+}
+
+impl TallyCache {
+    /// Returns the tally for `chat_id` over `period`, grouped by `grouping`.
+    ///
+    /// Served from the cache when possible, otherwise falls back to
+    /// `get_tally` and caches the result.
+    pub async fn get_tally(
+        &self,
+        chat_id: ChatId,
+        period: Period,
+        grouping: TallyGrouping,
+        connection: &PgPool,
+    ) -> Result<Option<Vec<Score>>, sqlx::Error> {
+        let since = period.start(chrono::Utc::now().naive_utc());
+        let cached = self
+            .tallies
+            .lock()
+            .unwrap()
+            .get(&(chat_id, period, grouping))
+            .filter(|cached| cached.since == since)
+            .map(|cached| cached.scores.clone());
+        if let Some(scores) = cached {
+            let hits = self.hits.fetch_add(1, Ordering::Relaxed) + 1;
+            info!(
+                "[tally_cache]: hit for chat_id: {}, period: {:?} (hits: {}, misses: {})",
+                chat_id.0,
+                period,
+                hits,
+                self.misses.load(Ordering::Relaxed)
+            );
+            return Ok(scores);
+        }
+
+        let misses = self.misses.fetch_add(1, Ordering::Relaxed) + 1;
+        info!(
+            "[tally_cache]: miss for chat_id: {}, period: {:?} (hits: {}, misses: {})",
+            chat_id.0,
+            period,
+            self.hits.load(Ordering::Relaxed),
+            misses
+        );
+        let generation = self.generation.load(Ordering::Acquire);
+        let scores = get_tally(chat_id, since, None, grouping, connection).await?;
+
+        let mut tallies = self.tallies.lock().unwrap();
+        if self.generation.load(Ordering::Acquire) == generation {
+            tallies.insert(
+                (chat_id, period, grouping),
+                CachedTally {
+                    since,
+                    scores: scores.clone(),
+                },
+            );
+        }
+
+        Ok(scores)
+    }
+
+    /// Drops every cached tally of `chat_id`.
+    ///
+    /// Must be called whenever runs in the chat are added, updated or
+    /// deleted, and whenever its teams change.
+    pub fn invalidate(&self, chat_id: ChatId) {
+        let mut tallies = self.tallies.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        tallies.retain(|(cached_chat_id, _, _), _| *cached_chat_id != chat_id);
+    }
+
+    /// Number of cache hits and misses so far.
+    #[cfg(test)]
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database::add_shared_run, models::RunDetails};
+    use teloxide::types::UserId;
+
+    const CHAT: ChatId = ChatId(-100);
+
+    #[sqlx::test]
+    async fn tallies_are_cached_until_invalidated(connection: PgPool) {
+        let cache = TallyCache::default();
+        add_shared_run(
+            5.,
+            RunDetails::default(),
+            "reuben",
+            UserId(11),
+            CHAT,
+            &connection,
+        )
+        .await
+        .unwrap();
+
+        cache
+            .get_tally(CHAT, Period::All, TallyGrouping::Users, &connection)
+            .await
+            .unwrap();
+        cache
+            .get_tally(CHAT, Period::All, TallyGrouping::Users, &connection)
+            .await
+            .unwrap();
+        cache
+            .get_tally(CHAT, Period::Week, TallyGrouping::Users, &connection)
+            .await
+            .unwrap();
+        assert_eq!(cache.stats(), (1, 2));
+
+        add_shared_run(
+            5.,
+            RunDetails::default(),
+            "reuben",
+            UserId(11),
+            CHAT,
+            &connection,
+        )
+        .await
+        .unwrap();
+        cache.invalidate(ChatId(-200));
+        let stale = cache
+            .get_tally(CHAT, Period::All, TallyGrouping::Users, &connection)
+            .await
+            .unwrap();
+        assert_eq!(stale.unwrap()[0].medals, 1);
+
+        cache.invalidate(CHAT);
+        let fresh = cache
+            .get_tally(CHAT, Period::All, TallyGrouping::Users, &connection)
+            .await
+            .unwrap();
+        assert_eq!(fresh.unwrap()[0].medals, 2);
+        assert_eq!(cache.stats(), (2, 3));
+    }
+}
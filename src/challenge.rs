@@ -0,0 +1,541 @@
+//! Head-to-head challenges between two users, with `/challenge`.
+//!
+//! A user challenges another to be the first to run a distance within a
+//! number of days. The challenge starts once the user challenged accepts,
+//! and every run either of them adds to the chat from then on counts. The
+//! first to reach the distance wins. If neither does in time, whoever ran
+//! further wins, and equal distances are a draw. Every challenge's result
+//! is recorded and announced once.
+use crate::{
+    bot::Callback,
+    database::{
+        accept_challenge, create_challenge, delete_challenge, get_active_challenges, get_challenge,
+        get_challenge_results, get_challenge_runs, record_challenge_result,
+    },
+    message::{
+        display_challenge, display_challenge_accepted, display_challenge_result,
+        display_challenges, split_message,
+    },
+    models::{Challenge, Outcome, Standings},
+};
+use chrono::NaiveDateTime;
+use sqlx::PgPool;
+use std::{error::Error, fmt, str::FromStr};
+use teloxide::{
+    prelude::*,
+    types::{InlineKeyboardButton, InlineKeyboardMarkup, ParseMode},
+};
+use tracing::{error, info};
+
+/// Most days a challenge can last.
+const MAX_CHALLENGE_DAYS: u32 = 365;
+
+/// Most results of past challenges shown by `/challenge`.
+const CHALLENGE_RESULTS: i64 = 5;
+
+/// What `/challenge` was asked to do.
+#[derive(Debug, PartialEq)]
+pub enum ChallengeAction {
+    /// Show the chat's challenges.
+    Standings,
+    /// Challenge `opponent` to be the first to run `distance` within `days`.
+    Create {
+        /// Username of the user challenged, without the `@`.
+        opponent: String,
+        /// Distance to run first, in km.
+        distance: f32,
+        /// Number of days the challenge lasts once accepted.
+        days: u32,
+    },
+}
+
+/// Error returned when the arguments of `/challenge` cannot be read.
+#[derive(Debug, PartialEq)]
+pub struct ParseChallengeError;
+
+impl fmt::Display for ParseChallengeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Expected /challenge @user <distance>km <days>d|<weeks>w, e.g. /challenge @bob 50km 2w, for up to {} days.",
+            MAX_CHALLENGE_DAYS
+        )
+    }
+}
+
+impl Error for ParseChallengeError {}
+
+/// Parses the arguments of `/challenge [@user <distance> <duration>]`.
+///
+/// Distances are in km, with or without the unit, and durations are a
+/// number of days or weeks, e.g. `10d` or `2w`.
+impl FromStr for ChallengeAction {
+    type Err = ParseChallengeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let arguments: Vec<&str> = s.split_whitespace().collect();
+        let [opponent, distance, duration] = arguments[..] else {
+            return match arguments[..] {
+                [] => Ok(ChallengeAction::Standings),
+                _ => Err(ParseChallengeError),
+            };
+        };
+        let opponent = opponent
+            .strip_prefix('@')
+            .filter(|opponent| !opponent.is_empty())
+            .ok_or(ParseChallengeError)?;
+        let distance: f32 = distance
+            .to_lowercase()
+            .trim_end_matches("km")
+            .parse()
+            .map_err(|_| ParseChallengeError)?;
+
+        let duration = duration.to_lowercase();
+        let (count, days_per_unit) = if let Some(weeks) = duration.strip_suffix('w') {
+            (weeks, 7)
+        } else {
+            (duration.strip_suffix('d').ok_or(ParseChallengeError)?, 1)
+        };
+        let days = count
+            .parse::<u32>()
+            .ok()
+            .and_then(|count| count.checked_mul(days_per_unit))
+            .filter(|days| (1..=MAX_CHALLENGE_DAYS).contains(days))
+            .ok_or(ParseChallengeError)?;
+
+        if !distance.is_finite() || distance <= 0. {
+            return Err(ParseChallengeError);
+        }
+        Ok(ChallengeAction::Create {
+            opponent: opponent.to_string(),
+            distance,
+            days,
+        })
+    }
+}
+
+/// Handles `/challenge`, challenging another user or showing the chat's
+/// challenges.
+pub async fn answer_challenge(bot: &Bot, msg: &Message, args: &str, connection: &PgPool) {
+    let Some(user) = msg.from() else {
+        error!("Unable to retrieve user from message.");
+        return;
+    };
+    let now = chrono::Utc::now().naive_utc();
+    match args.parse::<ChallengeAction>() {
+        Ok(ChallengeAction::Create {
+            opponent,
+            distance,
+            days,
+        }) => {
+            let Some(user_name) = &user.username else {
+                error!("Unable to retrieve username information from Telegram.");
+                return;
+            };
+            if opponent.eq_ignore_ascii_case(user_name) {
+                bot.send_message(msg.chat.id, "You can't challenge yourself.")
+                    .await
+                    .map_err(|err| error!("Unable to send Challenge message: {:?}", err))
+                    .ok();
+                return;
+            }
+            let created = create_challenge(
+                msg.chat.id,
+                user.id,
+                user_name,
+                &opponent,
+                distance,
+                days,
+                connection,
+            )
+            .await;
+            let id = match created {
+                Ok(id) => id,
+                Err(err) => {
+                    error!("Unable to create challenge: {:?}", err);
+                    return;
+                }
+            };
+            let challenge = Challenge {
+                id,
+                chat_id: msg.chat.id,
+                challenger_id: user.id,
+                challenger_name: user_name.clone(),
+                opponent_name: opponent,
+                opponent_id: None,
+                distance,
+                days,
+                starts: None,
+                ends: None,
+            };
+            let buttons = InlineKeyboardMarkup::new([[
+                InlineKeyboardButton::callback("Accept", Callback::AcceptChallenge(id).to_string()),
+                InlineKeyboardButton::callback(
+                    "Decline",
+                    Callback::DeclineChallenge(id).to_string(),
+                ),
+            ]]);
+            bot.send_message(msg.chat.id, display_challenge(&challenge))
+                .reply_markup(buttons)
+                .await
+                .map_err(|err| error!("Unable to send Challenge message: {:?}", err))
+                .ok();
+        }
+        Ok(ChallengeAction::Standings) => {
+            // Challenges whose time is up are ended before showing
+            // them, rather than at the next tick of the schedule.
+            settle_challenges(bot, Some(msg.chat.id), connection, now).await;
+            let active = match get_active_challenges(Some(msg.chat.id), connection).await {
+                Ok(active) => active,
+                Err(err) => {
+                    error!("Unable to retrieve challenges: {:?}", err);
+                    return;
+                }
+            };
+            let mut standings_by_challenge = Vec::with_capacity(active.len());
+            for challenge in active {
+                match get_challenge_runs(&challenge, connection).await {
+                    Ok(runs) => {
+                        let standings = standings(&challenge, &runs, now);
+                        standings_by_challenge.push((challenge, standings));
+                    }
+                    Err(err) => {
+                        error!("Unable to retrieve runs of challenge: {:?}", err);
+                        return;
+                    }
+                }
+            }
+            match get_challenge_results(msg.chat.id, CHALLENGE_RESULTS, connection).await {
+                Ok(results) => {
+                    let reply = display_challenges(&standings_by_challenge, &results);
+                    for part in split_message(&reply) {
+                        bot.send_message(msg.chat.id, part)
+                            .parse_mode(ParseMode::Html)
+                            .await
+                            .map_err(|err| error!("Unable to send Challenge message: {:?}", err))
+                            .ok();
+                    }
+                }
+                Err(err) => error!("Unable to retrieve challenge results: {:?}", err),
+            }
+        }
+        Err(err) => {
+            bot.send_message(msg.chat.id, err.to_string())
+                .await
+                .map_err(|err| error!("Unable to send Challenge message: {:?}", err))
+                .ok();
+        }
+    }
+}
+
+/// Handles presses of the buttons under a challenge, accepting it or, if
+/// `accepting` is false, declining or withdrawing it.
+pub async fn answer_challenge_button(
+    bot: &Bot,
+    query: &CallbackQuery,
+    message: &Message,
+    id: i32,
+    accepting: bool,
+    connection: &PgPool,
+) -> ResponseResult<()> {
+    let challenge = match get_challenge(id, connection).await {
+        Ok(Some(challenge))
+            if challenge.chat_id == message.chat.id && challenge.starts.is_none() =>
+        {
+            challenge
+        }
+        Ok(_) => {
+            bot.answer_callback_query(&query.id)
+                .text("This challenge is no longer open.")
+                .await?;
+            return Ok(());
+        }
+        Err(err) => {
+            error!("Unable to retrieve challenge: {:?}", err);
+            bot.answer_callback_query(&query.id).await?;
+            return Ok(());
+        }
+    };
+    let is_opponent = query
+        .from
+        .username
+        .as_deref()
+        .is_some_and(|user_name| user_name.eq_ignore_ascii_case(&challenge.opponent_name));
+    let is_challenger = query.from.id == challenge.challenger_id;
+    if !is_opponent && (accepting || !is_challenger) {
+        bot.answer_callback_query(&query.id)
+            .text(format!(
+                "Only @{} can {} this challenge.",
+                challenge.opponent_name,
+                if accepting { "accept" } else { "decline" }
+            ))
+            .await?;
+        return Ok(());
+    }
+    bot.answer_callback_query(&query.id).await?;
+
+    let outcome = if accepting {
+        let starts = chrono::Utc::now().naive_utc();
+        let ends = starts + chrono::Duration::days(challenge.days.into());
+        match accept_challenge(id, query.from.id, starts, ends, connection).await {
+            Ok(true) => display_challenge_accepted(&Challenge {
+                opponent_id: Some(query.from.id),
+                starts: Some(starts),
+                ends: Some(ends),
+                ..challenge
+            }),
+            Ok(false) => return Ok(()),
+            Err(err) => {
+                error!("Unable to accept challenge: {:?}", err);
+                return Ok(());
+            }
+        }
+    } else {
+        match delete_challenge(id, connection).await {
+            Ok(true) if is_opponent => {
+                format!("{} declined the challenge.", challenge.opponent_name)
+            }
+            Ok(true) => format!("{} withdrew the challenge.", challenge.challenger_name),
+            Ok(false) => return Ok(()),
+            Err(err) => {
+                error!("Unable to delete challenge: {:?}", err);
+                return Ok(());
+            }
+        }
+    };
+    bot.edit_message_text(message.chat.id, message.id, outcome)
+        .await
+        .map_err(|err| error!("Unable to send Challenge outcome: {:?}", err))
+        .ok();
+    Ok(())
+}
+
+/// Adds up a challenge's `runs`, given in the order they were run, as of
+/// `now`.
+///
+/// The challenge is decided once either side reaches its distance, or
+/// once it ends. Distances are compared to the hundredth of a km, so that
+/// sums of runs that look equal are equal.
+pub fn standings(
+    challenge: &Challenge,
+    runs: &[(UserId, NaiveDateTime, f32)],
+    now: NaiveDateTime,
+) -> Standings {
+    let round = |distance: f32| (distance * 100.).round() / 100.;
+    let mut standings = Standings {
+        challenger: 0.,
+        opponent: 0.,
+        outcome: None,
+    };
+    let in_window = |run_datetime: &NaiveDateTime| {
+        challenge
+            .starts
+            .is_some_and(|starts| starts <= *run_datetime)
+            && challenge.ends.is_some_and(|ends| *run_datetime < ends)
+            && *run_datetime <= now
+    };
+
+    for (telegram_userid, _, distance) in runs.iter().filter(|run| in_window(&run.1)) {
+        if *telegram_userid == challenge.challenger_id {
+            standings.challenger = round(standings.challenger + distance);
+            if standings.outcome.is_none() && standings.challenger >= challenge.distance {
+                standings.outcome = Some(Outcome::Challenger);
+            }
+        } else if Some(*telegram_userid) == challenge.opponent_id {
+            standings.opponent = round(standings.opponent + distance);
+            if standings.outcome.is_none() && standings.opponent >= challenge.distance {
+                standings.outcome = Some(Outcome::Opponent);
+            }
+        }
+    }
+
+    let over = challenge.ends.is_some_and(|ends| now >= ends);
+    if standings.outcome.is_none() && over {
+        standings.outcome = Some(
+            match standings.challenger.partial_cmp(&standings.opponent) {
+                Some(std::cmp::Ordering::Greater) => Outcome::Challenger,
+                Some(std::cmp::Ordering::Less) => Outcome::Opponent,
+                _ => Outcome::Draw,
+            },
+        );
+    }
+    standings
+}
+
+/// Records and announces the result of every challenge decided by `now`,
+/// in a chat, or in every chat if `None`.
+///
+/// Called on schedule, to end challenges whose time is up, and after
+/// a run is added, to end challenges as soon as one side gets there.
+pub async fn settle_challenges(
+    bot: &Bot,
+    chat_id: Option<ChatId>,
+    connection: &PgPool,
+    now: NaiveDateTime,
+) {
+    let challenges = match get_active_challenges(chat_id, connection).await {
+        Ok(challenges) => challenges,
+        Err(err) => {
+            error!("Unable to retrieve challenges: {:?}", err);
+            return;
+        }
+    };
+
+    for challenge in challenges {
+        let runs = match get_challenge_runs(&challenge, connection).await {
+            Ok(runs) => runs,
+            Err(err) => {
+                error!("Unable to retrieve runs of challenge: {:?}", err);
+                continue;
+            }
+        };
+        let standings = standings(&challenge, &runs, now);
+        if standings.outcome.is_none() {
+            continue;
+        }
+
+        match record_challenge_result(&challenge, &standings, connection).await {
+            Ok(true) => {
+                info!(
+                    "[settle_challenges]: chat_id: {}, challenge: {}",
+                    challenge.chat_id, challenge.id
+                );
+                bot.send_message(
+                    challenge.chat_id,
+                    display_challenge_result(&challenge, &standings),
+                )
+                .await
+                .map_err(|err| error!("Unable to send Challenge message: {:?}", err))
+                .ok();
+            }
+            Ok(false) => {}
+            Err(err) => error!("Unable to record challenge result: {:?}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    /// Noon on a day of October 2023.
+    fn day(day: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2023, 10, day)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    }
+
+    /// A challenge between users 1 and 2 to run 20km from the 1st up to
+    /// the 8th.
+    fn challenge() -> Challenge {
+        Challenge {
+            id: 1,
+            chat_id: ChatId(-100),
+            challenger_id: UserId(1),
+            challenger_name: "reuben".into(),
+            opponent_name: "milton".into(),
+            opponent_id: Some(UserId(2)),
+            distance: 20.,
+            days: 7,
+            starts: Some(day(1)),
+            ends: Some(day(8)),
+        }
+    }
+
+    #[test]
+    fn parse_challenge_actions() {
+        assert_eq!("".parse(), Ok(ChallengeAction::Standings));
+        for (input, distance, days) in [
+            ("@bob 50km 2w", 50., 14),
+            ("@bob 21.1 10D", 21.1, 10),
+            ("@bob 5KM 1d", 5., 1),
+        ] {
+            assert_eq!(
+                input.parse(),
+                Ok(ChallengeAction::Create {
+                    opponent: "bob".into(),
+                    distance,
+                    days,
+                })
+            );
+        }
+        for invalid in [
+            "@bob",
+            "bob 50km 2w",
+            "@ 50km 2w",
+            "@bob -5km 2w",
+            "@bob 50km 2m",
+            "@bob 50km 0d",
+            "@bob 50km 53w",
+        ] {
+            assert_eq!(invalid.parse::<ChallengeAction>(), Err(ParseChallengeError));
+        }
+    }
+
+    #[test]
+    fn first_to_the_distance_wins() {
+        let runs = [
+            (UserId(1), day(2), 8.),
+            (UserId(2), day(3), 12.),
+            (UserId(3), day(3), 30.),
+            (UserId(2), day(4), 8.),
+            (UserId(1), day(5), 12.),
+            (UserId(2), day(6), 5.),
+        ];
+        let standings_on = |now| standings(&challenge(), &runs, now);
+        assert_eq!(
+            standings_on(day(3)),
+            Standings {
+                challenger: 8.,
+                opponent: 12.,
+                outcome: None,
+            }
+        );
+        assert_eq!(
+            standings_on(day(4)),
+            Standings {
+                challenger: 8.,
+                opponent: 20.,
+                outcome: Some(Outcome::Opponent),
+            }
+        );
+        // Later runs add up, but the winner stays the first to get there.
+        assert_eq!(
+            standings_on(day(6)),
+            Standings {
+                challenger: 20.,
+                opponent: 25.,
+                outcome: Some(Outcome::Opponent),
+            }
+        );
+
+        // Runs before the challenge started or after it ended don't count.
+        let runs = [
+            (UserId(1), day(1) - chrono::Duration::hours(1), 30.),
+            (UserId(1), day(2), 0.1),
+            (UserId(2), day(2), 0.2),
+            (UserId(1), day(3), 0.2),
+            (UserId(2), day(8), 30.),
+        ];
+        let standings_on = |now| standings(&challenge(), &runs, now);
+        assert_eq!(standings_on(day(7)).outcome, None);
+        assert_eq!(
+            standings_on(day(8)),
+            Standings {
+                challenger: 0.3,
+                opponent: 0.2,
+                outcome: Some(Outcome::Challenger),
+            }
+        );
+        assert_eq!(
+            standings(&challenge(), &runs[1..3], day(8)).outcome,
+            Some(Outcome::Opponent)
+        );
+        assert_eq!(
+            standings(&challenge(), &[], day(9)).outcome,
+            Some(Outcome::Draw)
+        );
+    }
+}
@@ -0,0 +1,509 @@
+//! Progress charts rendered as PNG images.
+//!
+//! Charts are drawn in-process with
+//! [embedded-graphics](https://docs.rs/embedded-graphics) onto a plain RGB
+//! buffer, which is then encoded with [png](https://docs.rs/png). Text uses
+//! embedded-graphics' built-in bitmap font, so no font files are needed.
+use crate::models::{DailyDistance, Period};
+use chrono::{Datelike, Duration, NaiveDate};
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::Rgb888,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use std::{collections::HashMap, convert::Infallible, error::Error, fmt, str::FromStr};
+use teloxide::utils::command::ParseError;
+
+/// Width of rendered charts in pixels.
+const WIDTH: u32 = 800;
+/// Height of rendered charts in pixels.
+const HEIGHT: u32 = 480;
+/// Left edge of the plotting area, leaving room for distance labels.
+const PLOT_LEFT: i32 = 60;
+/// Right edge of the plotting area, leaving room for the legend.
+const PLOT_RIGHT: i32 = WIDTH as i32 - 150;
+/// Top edge of the plotting area, leaving room for the title.
+const PLOT_TOP: i32 = 40;
+/// Bottom edge of the plotting area, leaving room for date labels.
+const PLOT_BOTTOM: i32 = HEIGHT as i32 - 40;
+/// Longest user name shown in the legend, in characters.
+const MAX_LEGEND_NAME: usize = 18;
+
+/// Colour of the background.
+const BACKGROUND: Rgb888 = Rgb888::WHITE;
+/// Colour of the axes, labels and title.
+const FOREGROUND: Rgb888 = Rgb888::new(0x33, 0x33, 0x33);
+/// Colour of the horizontal grid lines.
+const GRID: Rgb888 = Rgb888::new(0xe0, 0xe0, 0xe0);
+/// Colours given to users, in order of distance ran.
+const PALETTE: [Rgb888; 10] = [
+    Rgb888::new(0x1f, 0x77, 0xb4),
+    Rgb888::new(0xff, 0x7f, 0x0e),
+    Rgb888::new(0x2c, 0xa0, 0x2c),
+    Rgb888::new(0xd6, 0x27, 0x28),
+    Rgb888::new(0x94, 0x67, 0xbd),
+    Rgb888::new(0x8c, 0x56, 0x4b),
+    Rgb888::new(0xe3, 0x77, 0xc2),
+    Rgb888::new(0x7f, 0x7f, 0x7f),
+    Rgb888::new(0xbc, 0xbd, 0x22),
+    Rgb888::new(0x17, 0xbe, 0xcf),
+];
+
+/// Ways of charting distance over time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChartStyle {
+    /// A line per user, climbing with every run.
+    #[default]
+    Cumulative,
+    /// A bar per week, stacked by user.
+    Weekly,
+}
+
+/// Error returned when a chart style is unknown.
+#[derive(Debug)]
+pub struct ParseStyleError(String);
+
+impl fmt::Display for ParseStyleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown chart \"{}\", expected lines or bars.", self.0)
+    }
+}
+
+impl Error for ParseStyleError {}
+
+impl FromStr for ChartStyle {
+    type Err = ParseStyleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "lines" | "cumulative" => Ok(ChartStyle::Cumulative),
+            "bars" | "weekly" => Ok(ChartStyle::Weekly),
+            _ => Err(ParseStyleError(s.trim().into())),
+        }
+    }
+}
+
+/// Parses the arguments of `/chart [period] [lines|bars]`.
+///
+/// Both arguments are optional and may come in either order.
+pub fn parse_chart_args(input: String) -> Result<(ChartStyle, Period), ParseError> {
+    let mut style = ChartStyle::default();
+    let mut period = Period::default();
+    for argument in input.split_whitespace() {
+        if let Ok(parsed) = argument.parse() {
+            style = parsed;
+        } else {
+            period = argument
+                .parse()
+                .map_err(|error| ParseError::IncorrectFormat(Box::new(error)))?;
+        }
+    }
+
+    Ok((style, period))
+}
+
+/// A 24-bit RGB image that embedded-graphics can draw on.
+struct Canvas {
+    /// Pixels row by row, three bytes each.
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    /// Creates a chart-sized canvas filled with the background colour.
+    fn new() -> Self {
+        let pixels =
+            [BACKGROUND.r(), BACKGROUND.g(), BACKGROUND.b()].repeat((WIDTH * HEIGHT) as usize);
+        Canvas { pixels }
+    }
+
+    /// Draws anything embedded-graphics can draw.
+    fn draw(&mut self, drawable: &impl Drawable<Color = Rgb888>) {
+        let Ok(_) = drawable.draw(self);
+    }
+
+    /// Writes text with its vertical middle at `position`.
+    fn text(&mut self, text: &str, position: Point, alignment: Alignment) {
+        let style = TextStyleBuilder::new()
+            .alignment(alignment)
+            .baseline(Baseline::Middle)
+            .build();
+        let font = MonoTextStyle::new(&FONT_6X10, FOREGROUND);
+        self.draw(&Text::with_text_style(text, position, font, style));
+    }
+
+    /// Encodes the canvas as a PNG file.
+    fn encode(self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut png = vec![];
+        let mut encoder = png::Encoder::new(&mut png, WIDTH, HEIGHT);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        Ok(png)
+    }
+}
+
+impl OriginDimensions for Canvas {
+    fn size(&self) -> Size {
+        Size::new(WIDTH, HEIGHT)
+    }
+}
+
+impl DrawTarget for Canvas {
+    type Color = Rgb888;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (u32::try_from(point.x), u32::try_from(point.y)) else {
+                continue;
+            };
+            if x < WIDTH && y < HEIGHT {
+                let index = ((y * WIDTH + x) * 3) as usize;
+                self.pixels[index..index + 3].copy_from_slice(&[color.r(), color.g(), color.b()]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Users in `days`, from the most to the least distance ran.
+fn users_by_distance(days: &[DailyDistance]) -> Vec<&str> {
+    let mut totals: HashMap<&str, f32> = HashMap::new();
+    for day in days {
+        *totals.entry(day.user_name.as_str()).or_default() += day.distance;
+    }
+    let mut users: Vec<_> = totals.into_iter().collect();
+    users.sort_by(|(a_name, a), (b_name, b)| b.total_cmp(a).then(a_name.cmp(b_name)));
+    users.into_iter().map(|(user_name, _)| user_name).collect()
+}
+
+/// Distance `user` had ran in total by the end of each day they ran on.
+fn cumulative(days: &[DailyDistance], user: &str) -> Vec<(NaiveDate, f32)> {
+    days.iter()
+        .filter(|day| day.user_name == user)
+        .scan(0., |total, day| {
+            *total += day.distance;
+            Some((day.day, *total))
+        })
+        .collect()
+}
+
+/// Monday of the week `day` is in.
+fn week_start(day: NaiveDate) -> NaiveDate {
+    day - Duration::days(day.weekday().num_days_from_monday().into())
+}
+
+/// Distance each of `users` ran in every week from `start` to `end`.
+///
+/// Weeks are keyed by their Monday, and include weeks without runs.
+fn weekly(
+    days: &[DailyDistance],
+    users: &[&str],
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Vec<(NaiveDate, Vec<f32>)> {
+    let mut weeks = vec![];
+    let mut monday = week_start(start);
+    while monday <= end {
+        weeks.push((monday, vec![0.; users.len()]));
+        monday += Duration::days(7);
+    }
+    for day in days {
+        let week = (week_start(day.day) - week_start(start)).num_days() / 7;
+        let user = users.iter().position(|user| *user == day.user_name);
+        if let (Some((_, distances)), Some(user)) = (weeks.get_mut(week as usize), user) {
+            distances[user] += day.distance;
+        }
+    }
+    weeks
+}
+
+/// Spacing of the distance axis' ticks, a round number giving at most
+/// five ticks up to `max`.
+fn tick_step(max: f32) -> f32 {
+    if max <= 0. {
+        return 1.;
+    }
+    let rough = max / 5.;
+    let magnitude = 10f32.powf(rough.log10().floor());
+    [1., 2., 5., 10.]
+        .into_iter()
+        .map(|multiple| multiple * magnitude)
+        .find(|step| *step >= rough)
+        .unwrap_or(10. * magnitude)
+}
+
+/// The plotting area, mapping distances onto pixels.
+struct Plot {
+    /// Distance at the top of the plotting area.
+    max: f32,
+}
+
+impl Plot {
+    /// Draws the title, the distance axis and its grid lines.
+    fn new(canvas: &mut Canvas, title: &str, max: f32) -> Self {
+        let step = tick_step(max);
+        let ticks = (max / step).ceil().max(1.) as u32;
+        let plot = Plot {
+            max: step * ticks as f32,
+        };
+
+        canvas.text(
+            title,
+            Point::new(WIDTH as i32 / 2, PLOT_TOP / 2),
+            Alignment::Center,
+        );
+        for tick in 0..=ticks {
+            let distance = step * tick as f32;
+            let y = plot.y(distance);
+            let colour = if tick == 0 { FOREGROUND } else { GRID };
+            canvas.draw(
+                &Line::new(Point::new(PLOT_LEFT, y), Point::new(PLOT_RIGHT, y))
+                    .into_styled(PrimitiveStyle::with_stroke(colour, 1)),
+            );
+            let label = format!("{}km", (distance * 100.).round() / 100.);
+            canvas.text(&label, Point::new(PLOT_LEFT - 6, y), Alignment::Right);
+        }
+        canvas.draw(
+            &Line::new(
+                Point::new(PLOT_LEFT, PLOT_TOP),
+                Point::new(PLOT_LEFT, PLOT_BOTTOM),
+            )
+            .into_styled(PrimitiveStyle::with_stroke(FOREGROUND, 1)),
+        );
+
+        plot
+    }
+
+    /// Vertical pixel position of `distance`.
+    fn y(&self, distance: f32) -> i32 {
+        let height = (PLOT_BOTTOM - PLOT_TOP) as f32;
+        PLOT_BOTTOM - (distance / self.max * height).round() as i32
+    }
+}
+
+/// Draws the legend, matching users to their colours.
+fn draw_legend(canvas: &mut Canvas, users: &[&str]) {
+    for (index, user) in users.iter().enumerate() {
+        let y = PLOT_TOP + 6 + 14 * index as i32;
+        if y > PLOT_BOTTOM {
+            break;
+        }
+        canvas.draw(
+            &Rectangle::new(Point::new(PLOT_RIGHT + 15, y - 4), Size::new(8, 8))
+                .into_styled(PrimitiveStyle::with_fill(PALETTE[index % PALETTE.len()])),
+        );
+        let name: String = user.chars().take(MAX_LEGEND_NAME).collect();
+        canvas.text(&name, Point::new(PLOT_RIGHT + 28, y), Alignment::Left);
+    }
+}
+
+/// Renders a chart of `days` from `start` to `end` as a PNG file.
+///
+/// Charts start on the first day with runs if no `start` is given, and
+/// always run until the last day with runs.
+pub fn render_chart(
+    days: &[DailyDistance],
+    style: ChartStyle,
+    start: Option<NaiveDate>,
+    end: NaiveDate,
+) -> Result<Vec<u8>, png::EncodingError> {
+    let first = days.iter().map(|day| day.day).min().unwrap_or(end);
+    let last = days.iter().map(|day| day.day).max().unwrap_or(end);
+    let start = start.unwrap_or(first).min(first);
+    let end = end.max(last);
+    let users = users_by_distance(days);
+    let colour = |user: usize| PALETTE[user % PALETTE.len()];
+    let date_label = |date: NaiveDate| date.format("%d %b").to_string();
+    let label_y = PLOT_BOTTOM + 12;
+
+    let mut canvas = Canvas::new();
+    match style {
+        ChartStyle::Cumulative => {
+            let lines: Vec<_> = users.iter().map(|user| cumulative(days, user)).collect();
+            let max = lines
+                .iter()
+                .filter_map(|line| line.last())
+                .map(|(_, total)| *total)
+                .fold(0., f32::max);
+            let plot = Plot::new(&mut canvas, "Distance ran (km)", max);
+            let span = (end - start).num_days().max(1);
+            let x = |date: NaiveDate| {
+                PLOT_LEFT
+                    + ((date - start).num_days() * (PLOT_RIGHT - PLOT_LEFT) as i64 / span) as i32
+            };
+
+            for (user, line) in lines.iter().enumerate() {
+                let mut from = Point::new(x(start), plot.y(0.));
+                let last = line.last().map(|(_, total)| *total).unwrap_or_default();
+                let points = line
+                    .iter()
+                    .map(|(date, total)| Point::new(x(*date), plot.y(*total)));
+                // Step up on the day of each run, rather than climbing
+                // steadily in between.
+                for to in points.chain([Point::new(x(end), plot.y(last))]) {
+                    let corner = Point::new(to.x, from.y);
+                    let stroke = PrimitiveStyle::with_stroke(colour(user), 2);
+                    canvas.draw(&Line::new(from, corner).into_styled(stroke));
+                    canvas.draw(&Line::new(corner, to).into_styled(stroke));
+                    from = to;
+                }
+            }
+            canvas.text(
+                &date_label(start),
+                Point::new(PLOT_LEFT, label_y),
+                Alignment::Left,
+            );
+            canvas.text(
+                &date_label(end),
+                Point::new(PLOT_RIGHT, label_y),
+                Alignment::Right,
+            );
+        }
+        ChartStyle::Weekly => {
+            let weeks = weekly(days, &users, start, end);
+            let max = weeks
+                .iter()
+                .map(|(_, distances)| distances.iter().sum())
+                .fold(0., f32::max);
+            let plot = Plot::new(&mut canvas, "Distance ran per week (km)", max);
+            let slot = (PLOT_RIGHT - PLOT_LEFT) / weeks.len().max(1) as i32;
+            let bar = (slot * 7 / 10).max(1);
+            // Label as many weeks as fit without overlapping.
+            let label_every = (60 / slot.max(1) + 1) as usize;
+
+            for (week, (monday, distances)) in weeks.iter().enumerate() {
+                let left = PLOT_LEFT + slot * week as i32 + (slot - bar) / 2;
+                let mut total = 0.;
+                for (user, distance) in distances.iter().enumerate() {
+                    let bottom = plot.y(total);
+                    total += distance;
+                    let top = plot.y(total);
+                    if bottom > top {
+                        canvas.draw(
+                            &Rectangle::new(
+                                Point::new(left, top),
+                                Size::new(bar as u32, (bottom - top) as u32),
+                            )
+                            .into_styled(PrimitiveStyle::with_fill(colour(user))),
+                        );
+                    }
+                }
+                if week % label_every == 0 {
+                    let centre = Point::new(left + bar / 2, label_y);
+                    canvas.text(&date_label(*monday), centre, Alignment::Center);
+                }
+            }
+        }
+    }
+    draw_legend(&mut canvas, &users);
+
+    canvas.encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Day of the month in October 2023, the 9th being a Monday.
+    fn october(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, 10, day).unwrap()
+    }
+
+    fn days() -> Vec<DailyDistance> {
+        [
+            ("milton", 2, 3.),
+            ("reuben", 3, 5.),
+            ("milton", 10, 4.),
+            ("reuben", 12, 5.),
+        ]
+        .into_iter()
+        .map(|(user_name, day, distance)| DailyDistance {
+            user_name: user_name.into(),
+            day: october(day),
+            distance,
+        })
+        .collect()
+    }
+
+    /// Decodes a PNG file into its size and RGB pixels.
+    fn decode(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        let mut reader = png::Decoder::new(png).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        (info.width, info.height, pixels)
+    }
+
+    /// Whether `colour` appears anywhere in `pixels`.
+    fn contains(pixels: &[u8], colour: Rgb888) -> bool {
+        pixels
+            .chunks(3)
+            .any(|pixel| pixel == [colour.r(), colour.g(), colour.b()])
+    }
+
+    #[test]
+    fn parse_args() {
+        assert_eq!(
+            parse_chart_args("".into()).unwrap(),
+            (ChartStyle::Cumulative, Period::All)
+        );
+        assert_eq!(
+            parse_chart_args("bars month".into()).unwrap(),
+            (ChartStyle::Weekly, Period::Month)
+        );
+        assert!(parse_chart_args("pie".into()).is_err());
+    }
+
+    #[test]
+    fn users_are_ordered_by_distance() {
+        assert_eq!(users_by_distance(&days()), vec!["reuben", "milton"]);
+    }
+
+    #[test]
+    fn cumulative_distances() {
+        assert_eq!(
+            cumulative(&days(), "milton"),
+            vec![(october(2), 3.), (october(10), 7.)]
+        );
+    }
+
+    #[test]
+    fn weekly_distances() {
+        let weeks = weekly(&days(), &["reuben", "milton"], october(3), october(18));
+        assert_eq!(
+            weeks,
+            vec![
+                (october(2), vec![5., 3.]),
+                (october(9), vec![5., 4.]),
+                (october(16), vec![0., 0.]),
+            ]
+        );
+    }
+
+    #[test]
+    fn tick_steps() {
+        assert_eq!(tick_step(0.), 1.);
+        assert_eq!(tick_step(7.), 2.);
+        assert_eq!(tick_step(42.), 10.);
+        assert_eq!(tick_step(230.), 50.);
+    }
+
+    #[test]
+    fn render_both_styles() {
+        for style in [ChartStyle::Cumulative, ChartStyle::Weekly] {
+            let png = render_chart(&days(), style, None, october(18)).unwrap();
+            let (width, height, pixels) = decode(&png);
+            assert_eq!((width, height), (WIDTH, HEIGHT));
+            assert!(contains(&pixels, PALETTE[0]));
+            assert!(contains(&pixels, PALETTE[1]));
+            assert!(!contains(&pixels, PALETTE[2]));
+        }
+    }
+}
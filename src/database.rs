@@ -3,72 +3,87 @@
 //! [sqlx](https://docs.rs/sqlx/latest/sqlx/) is used to interact with the
 //! Postgresql database. Macros are used to check queries against the
 //! database at compile time.
-use crate::models::{Run, Score, User};
-use sqlx::PgPool;
+use crate::models::{
+    AccessTokens, Challenge, DailyDistance, Goal, Journey, LinkRequest, NewRun, Outcome, Period,
+    PersonalGoal, Progress, RankEmojis, RecapSchedule, ReminderSchedule, Run, RunDetails,
+    RunFilter, RunOwner, Score, Split, Standings, TallyGrouping, Team, TimedRun, Timing, User,
+};
+use chrono_tz::Tz;
+use sqlx::{types::chrono, PgConnection, PgPool};
+use std::collections::HashMap;
 use teloxide::types::{ChatId, UserId};
 use tracing::{error, info};
 
 /// Convenience type to wrap a generic `Ok` and `sqlx::Error`.
 type DBResult<T> = Result<T, sqlx::Error>;
 
-/// Creates a user in users table.
+/// Creates the profiles of users if needed, keeping the latest name
+/// they went by.
+///
+/// A profile is a user's identity across chats, keyed by their Telegram id.
+/// Users appearing more than once get the last of their names given.
+async fn upsert_profiles(
+    telegram_userids: &[String],
+    user_names: &[&str],
+    connection: &mut PgConnection,
+) -> DBResult<()> {
+    sqlx::query!(
+        "INSERT INTO profiles (telegram_userid, user_name)
+        SELECT DISTINCT ON (telegram_userid) telegram_userid, user_name
+        FROM UNNEST($1::varchar[], $2::varchar[])
+            WITH ORDINALITY AS new_profiles(telegram_userid, user_name, position)
+        ORDER BY telegram_userid, position DESC
+        ON CONFLICT (telegram_userid) DO UPDATE SET user_name = EXCLUDED.user_name",
+        telegram_userids,
+        user_names as &[&str],
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Creates a user in users table if needed and returns its id.
 ///
 /// Users are tied to the `chat_id` that the message came from
-/// and the `user_name` input. This combination must be unique.
-pub async fn create_user(
+/// and the `user_name` input. This combination must be unique, so
+/// concurrent first-time callers all resolve to the same row. Their
+/// profile, shared by every chat they are in, is created along with them.
+async fn upsert_user(
     user_name: &str,
     telegram_userid: UserId,
     chat_id: ChatId,
-    connection: &PgPool,
-) -> DBResult<()> {
+    connection: &mut PgConnection,
+) -> DBResult<i32> {
     info!(
-        "[create_user]: user_name: {}, telegram_userid: {}, chat_id: {}",
+        "[upsert_user]: user_name: {}, telegram_userid: {}, chat_id: {}",
         user_name,
         telegram_userid.to_string(),
         chat_id.0,
     );
-    let create_result = sqlx::query!(
-        "INSERT INTO users (telegram_userid, chat_id, user_name) 
+    upsert_profiles(&[telegram_userid.to_string()], &[user_name], connection).await?;
+    // `DO NOTHING` would not return the id of an existing user, so the
+    // conflicting row is updated to itself instead.
+    let user = sqlx::query!(
+        "INSERT INTO users (telegram_userid, chat_id, user_name)
         VALUES ($1, $2, $3)
-        ON CONFLICT (telegram_userid, chat_id, user_name) DO NOTHING",
+        ON CONFLICT (telegram_userid, chat_id, user_name)
+        DO UPDATE SET user_name = EXCLUDED.user_name
+        RETURNING id",
         telegram_userid.to_string(),
         chat_id.to_string(),
         user_name
     )
-    .execute(connection)
+    .fetch_one(connection)
     .await;
-    // Return only needed if we want to return earlier than the last expression
-    match create_result {
-        Ok(_) => Ok(()),
+
+    match user {
+        Ok(user) => Ok(user.id),
         Err(error) => {
             error!("Unable to create user: {:?}", error);
             Err(error)
         }
-    } // Note: if ; is placed here, then this won't return!
-}
-
-/// Retrieves a user.
-///
-/// Fetches user information based on `(user_name, telegram_userid, chat_id)`.
-async fn get_user(
-    user_name: &str,
-    telegram_userid: UserId,
-    chat_id: ChatId,
-    connection: &PgPool,
-) -> DBResult<Option<User>> {
-    let user: Option<User> = sqlx::query_as!(
-        User,
-        "SELECT id, telegram_userid, chat_id, user_name
-    FROM users
-    WHERE user_name = $1 AND telegram_userid = $2 AND chat_id = $3",
-        user_name,
-        telegram_userid.to_string(),
-        chat_id.to_string()
-    )
-    .fetch_optional(connection)
-    .await?;
-
-    Ok(user)
+    }
 }
 
 /// Fetchers users in a chat.
@@ -106,59 +121,210 @@ pub async fn get_users_in_chat(
 ///
 /// # Arguments
 /// * `distance` - Distance run in km
+/// * `details` - When the run happened and how long it took, if known.
 /// * `user_name` - Name user wishes to tie the run to.
 /// * `telegram_userid` - Unique user id from Telegram. Can be retrieved
-///                       from `Message`.
+///   from `Message`.
 /// * `chat_id` - Unique ID identifying the chat, this comes from Telegram.
 ///
 /// # Remarks
 ///
-/// Due to design flaws, we first check whether that user has
-/// added a run before. If not, we need to first create that user.
-/// Afterwards, we run `get_user` again to retrieve its `user_id`.
-/// Following which, we then actually add the run to the database.
-pub async fn add_run_wrapper(
+/// The user is created if this is their first run in the chat. If they
+/// share runs from this chat, a copy of the run is posted to their other
+/// chats that share runs too. Everything is written in a single
+/// transaction, so a failed insert never leaves a user behind without
+/// their run.
+///
+/// Copies remember the run they were shared from, so that the user's
+/// profile counts them once, and keep its `external_id`, so that an
+/// activity logged in several of the chats lands once in each.
+///
+/// Returns the other chats the run was posted to, or `None` if the run was
+/// not added as it has the same `external_id` as a run the user already
+/// has.
+pub async fn add_shared_run(
     distance: f32,
+    details: RunDetails,
     user_name: &str,
     telegram_userid: UserId,
     chat_id: ChatId,
     connection: &PgPool,
-) -> DBResult<()> {
-    let user = get_user(user_name, telegram_userid, chat_id, connection).await?;
+) -> DBResult<Option<Vec<ChatId>>> {
+    let mut transaction = connection.begin().await?;
+    let user_id = upsert_user(user_name, telegram_userid, chat_id, &mut transaction).await?;
+    let Some(run_id) = add_run(distance, details, user_id, &mut transaction).await? else {
+        transaction.commit().await?;
+        return Ok(None);
+    };
 
-    if let Some(user) = user {
-        add_run(distance, user.id, connection).await?;
-    } else {
-        let create_result = create_user(user_name, telegram_userid, chat_id, connection).await;
-        match create_result {
-            Ok(_) => {
-                let user = get_user(user_name, telegram_userid, chat_id, connection).await?;
-                if let Some(user) = user {
-                    add_run(distance, user.id, connection).await?;
-                } else {
-                    error!("Unable to add run to database.");
-                }
-            }
-            Err(err) => {
-                error!("Unable to create user.");
-                return Err(err);
-            }
-        }
+    let shared_chat_ids: Vec<ChatId> = sqlx::query_scalar!(
+        "SELECT chat_id FROM shared_chats
+        WHERE telegram_userid = $1 AND chat_id <> $2
+            AND EXISTS (SELECT 1 FROM shared_chats WHERE telegram_userid = $1 AND chat_id = $2)
+        ORDER BY chat_id",
+        telegram_userid.to_string(),
+        chat_id.to_string(),
+    )
+    .fetch_all(&mut *transaction)
+    .await?
+    .into_iter()
+    .filter_map(|chat_id| chat_id.parse().ok().map(ChatId))
+    .collect();
+    let mut chat_ids = vec![];
+    for shared_chat_id in shared_chat_ids {
+        let user_id =
+            upsert_user(user_name, telegram_userid, shared_chat_id, &mut transaction).await?;
+        let Some(copy_id) = sqlx::query_scalar!(
+            "INSERT INTO runs (distance, user_id, run_datetime, duration_secs, sport,
+                avg_heart_rate, max_heart_rate, elevation_gain, external_id, race, shared_from)
+            SELECT distance, $2, run_datetime, duration_secs, sport,
+                avg_heart_rate, max_heart_rate, elevation_gain, external_id, race, id
+            FROM runs WHERE id = $1
+            ON CONFLICT (user_id, external_id) DO NOTHING
+            RETURNING id",
+            run_id,
+            user_id,
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            continue;
+        };
+        chat_ids.push(shared_chat_id);
+        sqlx::query!(
+            "INSERT INTO run_splits (run_id, distance, elapsed_secs)
+            SELECT $2, distance, elapsed_secs FROM run_splits WHERE run_id = $1",
+            run_id,
+            copy_id,
+        )
+        .execute(&mut *transaction)
+        .await?;
     }
 
-    Ok(())
+    transaction.commit().await?;
+    Ok(Some(chat_ids))
+}
+
+/// Adds many runs to a chat at once, e.g. for imports.
+///
+/// Users are created as needed. Everything is written in a single
+/// transaction, so either all runs are added or none are. Runs without a
+/// `run_datetime` are timestamped with the current time.
+///
+/// Returns the number of runs added.
+pub async fn add_runs(runs: &[NewRun], chat_id: ChatId, connection: &PgPool) -> DBResult<u64> {
+    info!("[add_runs]: {} runs, chat_id: {}", runs.len(), chat_id.0);
+    let mut users: Vec<(String, &str)> = runs
+        .iter()
+        .map(|run| (run.telegram_userid.to_string(), run.user_name.as_str()))
+        .collect();
+    // Profiles take the name of each user's last run, so are upserted in
+    // the order runs were given.
+    let (profile_userids, profile_names): (Vec<String>, Vec<&str>) = users.iter().cloned().unzip();
+    // Postgres refuses to upsert the same row twice in one statement.
+    users.sort_unstable();
+    users.dedup();
+    let (telegram_userids, user_names): (Vec<String>, Vec<&str>) = users.into_iter().unzip();
+    let chat_ids = vec![chat_id.to_string(); telegram_userids.len()];
+
+    let mut transaction = connection.begin().await?;
+    upsert_profiles(&profile_userids, &profile_names, &mut transaction).await?;
+    let user_ids: HashMap<(String, String), i32> = sqlx::query!(
+        "INSERT INTO users (telegram_userid, chat_id, user_name)
+        SELECT * FROM UNNEST($1::varchar[], $2::varchar[], $3::varchar[])
+        ON CONFLICT (telegram_userid, chat_id, user_name)
+        DO UPDATE SET user_name = EXCLUDED.user_name
+        RETURNING id, telegram_userid, user_name",
+        &telegram_userids[..],
+        &chat_ids[..],
+        &user_names as &[&str],
+    )
+    .fetch_all(&mut *transaction)
+    .await?
+    .into_iter()
+    .map(|user| ((user.telegram_userid, user.user_name), user.id))
+    .collect();
+
+    let mut distances = Vec::with_capacity(runs.len());
+    let mut run_user_ids = Vec::with_capacity(runs.len());
+    let mut run_datetimes = Vec::with_capacity(runs.len());
+    for run in runs {
+        distances.push(run.distance);
+        run_user_ids.push(user_ids[&(run.telegram_userid.to_string(), run.user_name.clone())]);
+        run_datetimes.push(run.run_datetime);
+    }
+
+    let added = sqlx::query!(
+        "INSERT INTO runs (distance, user_id, run_datetime)
+        SELECT distance, user_id, COALESCE(run_datetime, now())
+        FROM UNNEST($1::real[], $2::int[], $3::timestamp[])
+            AS new_runs(distance, user_id, run_datetime)",
+        &distances[..],
+        &run_user_ids[..],
+        &run_datetimes as &[Option<chrono::NaiveDateTime>],
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+
+    transaction.commit().await?;
+    Ok(added)
 }
 
 /// Adds run data.
 ///
-/// Performs the actual database update for adding run data.
-async fn add_run(distance: f32, user_id: i32, connection: &PgPool) -> DBResult<()> {
-    sqlx::query!(
-        "INSERT INTO runs (distance, user_id)
-    VALUES ($1, $2)
+/// Performs the actual database update for adding run data. Returns the id
+/// of the run, unless it was not added because the user already has a run
+/// with the same `external_id`.
+async fn add_run(
+    distance: f32,
+    details: RunDetails,
+    user_id: i32,
+    connection: &mut PgConnection,
+) -> DBResult<Option<i32>> {
+    let added = sqlx::query_scalar!(
+        "INSERT INTO runs (distance, user_id, run_datetime, duration_secs,
+        sport, avg_heart_rate, max_heart_rate, elevation_gain, external_id, race)
+    VALUES ($1, $2, COALESCE($3::timestamp, now()), $4, $5, $6, $7, $8, $9, $10)
+    ON CONFLICT (user_id, external_id) DO NOTHING
+    RETURNING id
     ",
         distance,
         user_id,
+        details.run_datetime,
+        details.duration_secs,
+        details.sport.map(|sport| sport.as_str()),
+        details.avg_heart_rate,
+        details.max_heart_rate,
+        details.elevation_gain,
+        details.external_id,
+        details.race,
+    )
+    .fetch_optional(&mut *connection)
+    .await?;
+    if let Some(run_id) = added {
+        add_splits(run_id, &details.splits, connection).await?;
+    }
+
+    Ok(added)
+}
+
+/// Stores the splits of a run.
+async fn add_splits(run_id: i32, splits: &[Split], connection: &mut PgConnection) -> DBResult<()> {
+    if splits.is_empty() {
+        return Ok(());
+    }
+    let (distances, elapsed_secs): (Vec<f32>, Vec<i32>) = splits
+        .iter()
+        .map(|split| (split.distance, split.elapsed_secs))
+        .unzip();
+    sqlx::query!(
+        "INSERT INTO run_splits (run_id, distance, elapsed_secs)
+        SELECT $1, * FROM UNNEST($2::real[], $3::integer[])
+        ON CONFLICT (run_id, distance) DO NOTHING",
+        run_id,
+        &distances,
+        &elapsed_secs,
     )
     .execute(connection)
     .await?;
@@ -166,151 +332,2347 @@ async fn add_run(distance: f32, user_id: i32, connection: &PgPool) -> DBResult<(
     Ok(())
 }
 
-/// Fetches runs fromt the chat.
+/// Fetches runs from the chat along with the user who added each of them.
+///
+/// Runs are filtered by `filter` and ordered from newest to oldest, and
+/// `limit` runs are returned after skipping the first `offset`, for paging.
+/// `RunOwner::Me` needs to be resolved beforehand, it matches no one.
 ///
-/// `limit` must be specified or the `answer` cannot match the enum.
+/// The latest runs of each user in the chat are read off the
+/// `(user_id, run_datetime)` index before being merged, so that only
+/// `limit + offset` runs per user are ever looked at.
 pub async fn get_runs(
     chat_id: ChatId,
+    filter: &RunFilter,
     limit: i64,
+    offset: i64,
     connection: &PgPool,
-) -> DBResult<Option<Vec<Run>>> {
-    let users_in_chat = get_users_in_chat(chat_id, connection).await?;
-    if let Some(users) = users_in_chat {
-        let user_ids: Vec<i32> = users.iter().map(|user| user.id).collect();
-        let runs: Vec<_> = sqlx::query!(
-            "SELECT *
+) -> DBResult<Option<Vec<(Run, User)>>> {
+    let (telegram_userid, user_name) = match &filter.owner {
+        Some(RunOwner::Me) => (Some(String::new()), None),
+        Some(RunOwner::Id(id)) => (Some(id.to_string()), None),
+        Some(RunOwner::Name(name)) => (None, Some(name.as_str())),
+        None => (None, None),
+    };
+    let since = filter.period.start(chrono::Utc::now().naive_utc());
+    let runs: Vec<(Run, User)> = sqlx::query!(
+        r#"SELECT runs.id AS "id!", distance AS "distance!", run_datetime, user_id AS "user_id!",
+            duration_secs, sport, avg_heart_rate, max_heart_rate, elevation_gain,
+            telegram_userid, chat_id, user_name
+        FROM users
+        CROSS JOIN LATERAL (
+            SELECT id, distance, run_datetime, user_id, duration_secs,
+                sport, avg_heart_rate, max_heart_rate, elevation_gain
             FROM runs
-            WHERE user_id = ANY($1)
-            ORDER BY run_datetime DESC
-            LIMIT $2",
-            &user_ids[..],
-            limit
+            WHERE runs.user_id = users.id
+                AND ($4::timestamp IS NULL OR run_datetime >= $4)
+                AND ($5::varchar IS NULL OR COALESCE(sport, 'run') = $5)
+            ORDER BY run_datetime DESC, id DESC
+            LIMIT $6::bigint + $7::bigint
+        ) runs
+        WHERE users.chat_id = $1
+            AND ($2::varchar IS NULL OR telegram_userid = $2)
+            AND ($3::varchar IS NULL OR LOWER(user_name) = LOWER($3))
+        ORDER BY run_datetime DESC, runs.id DESC
+        LIMIT $6 OFFSET $7"#,
+        chat_id.to_string(),
+        telegram_userid,
+        user_name,
+        since,
+        filter.sport.map(|sport| sport.as_str()),
+        limit,
+        offset,
+    )
+    .fetch_all(connection)
+    .await?
+    .into_iter()
+    .map(|row| {
+        (
+            Run {
+                id: row.id,
+                distance: row.distance,
+                run_datetime: row.run_datetime,
+                user_id: row.user_id,
+                duration_secs: row.duration_secs,
+                sport: row.sport,
+                avg_heart_rate: row.avg_heart_rate,
+                max_heart_rate: row.max_heart_rate,
+                elevation_gain: row.elevation_gain,
+            },
+            User {
+                id: row.user_id,
+                telegram_userid: row.telegram_userid,
+                chat_id: row.chat_id,
+                user_name: row.user_name,
+            },
         )
-        .fetch_all(connection)
-        .await?
-        .iter()
-        .map(|row| Run {
-            id: row.id,
-            distance: row.distance,
-            run_datetime: row.run_datetime,
-            user_id: row.user_id,
-        })
-        .collect();
+    })
+    .collect();
 
-        if !runs.is_empty() {
-            Ok(Some(runs))
-        } else {
-            Ok(None)
-        }
+    if !runs.is_empty() {
+        Ok(Some(runs))
     } else {
         Ok(None)
     }
 }
 
-/// Updates a certain run by id.
+/// Fetches every run in the chat along with the user who added it.
+///
+/// Only runs from `since` onwards are returned, if given. Runs are
+/// ordered from oldest to newest.
+pub async fn get_chat_runs(
+    chat_id: ChatId,
+    since: Option<chrono::NaiveDateTime>,
+    connection: &PgPool,
+) -> DBResult<Vec<(Run, User)>> {
+    let runs = sqlx::query!(
+        "SELECT runs.id, distance, run_datetime, user_id, duration_secs,
+            sport, avg_heart_rate, max_heart_rate, elevation_gain,
+            telegram_userid, chat_id, user_name
+        FROM runs
+        JOIN users ON users.id = runs.user_id
+        WHERE users.chat_id = $1 AND ($2::timestamp IS NULL OR run_datetime >= $2)
+        ORDER BY run_datetime, runs.id",
+        chat_id.to_string(),
+        since,
+    )
+    .fetch_all(connection)
+    .await?
+    .into_iter()
+    .map(|row| {
+        (
+            Run {
+                id: row.id,
+                distance: row.distance,
+                run_datetime: row.run_datetime,
+                user_id: row.user_id,
+                duration_secs: row.duration_secs,
+                sport: row.sport,
+                avg_heart_rate: row.avg_heart_rate,
+                max_heart_rate: row.max_heart_rate,
+                elevation_gain: row.elevation_gain,
+            },
+            User {
+                id: row.user_id,
+                telegram_userid: row.telegram_userid,
+                chat_id: row.chat_id,
+                user_name: row.user_name,
+            },
+        )
+    })
+    .collect();
+
+    Ok(runs)
+}
+
+/// Updates a certain run by id, along with the copies of it shared to
+/// other chats.
+///
+/// Only runs added by `telegram_userid` within `chat_id` can be updated.
+/// The run keeps its time and splits unless `timing` gives new ones, and
+/// is a race effort only if `timing` says so. Returns the other chats
+/// whose copies were updated, or `None` if there was no such run to
+/// update.
 pub async fn update_run(
     run_id: i32,
     telegram_userid: UserId,
+    chat_id: ChatId,
     distance: f32,
+    timing: &Timing,
     connection: &PgPool,
-) -> DBResult<()> {
-    let valid_run = sqlx::query!(
-        "SELECT r.id
-        FROM runs r
-        JOIN users u on u.id = r.user_id
-        WHERE u.telegram_userid = $1",
+) -> DBResult<Option<Vec<ChatId>>> {
+    let mut transaction = connection.begin().await?;
+    let updated = sqlx::query!(
+        "UPDATE runs
+        SET distance = $1, duration_secs = COALESCE($3, duration_secs), race = $4
+        WHERE id = $2 AND user_id IN (
+            SELECT id FROM users WHERE telegram_userid = $5 AND chat_id = $6
+        )",
+        distance,
+        run_id,
+        timing.duration_secs,
+        timing.race,
         telegram_userid.to_string(),
+        chat_id.to_string(),
     )
-    .fetch_optional(connection)
-    .await;
-    match valid_run {
-        Ok(Some(_)) => {
-            info!("Matched run_id: {} to user_id: {}", run_id, telegram_userid);
-            sqlx::query!(
-                "UPDATE runs
-                SET distance = $1
-                WHERE id = $2",
-                distance,
-                run_id,
-            )
-            .execute(connection)
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected()
+        > 0;
+    if !updated {
+        error!("No runs matched for the source user");
+        return Ok(None);
+    }
+
+    info!("Matched run_id: {} to user_id: {}", run_id, telegram_userid);
+    let copies = sqlx::query!(
+        "UPDATE runs
+        SET distance = $1, duration_secs = COALESCE($3, duration_secs), race = $4
+        FROM users
+        WHERE users.id = runs.user_id AND runs.shared_from = $2
+        RETURNING runs.id, users.chat_id",
+        distance,
+        run_id,
+        timing.duration_secs,
+        timing.race,
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    if !timing.splits.is_empty() {
+        let run_ids: Vec<i32> = std::iter::once(run_id)
+            .chain(copies.iter().map(|copy| copy.id))
+            .collect();
+        sqlx::query!("DELETE FROM run_splits WHERE run_id = ANY($1)", &run_ids)
+            .execute(&mut *transaction)
             .await?;
-            Ok(())
-        }
-        Ok(None) => {
-            error!("No runs matched for the source user");
-            Ok(())
-        }
-        Err(error) => {
-            error!("Unable to retrieve run data: {:?}", error);
-            Err(error)
+        for run_id in run_ids {
+            add_splits(run_id, &timing.splits, &mut transaction).await?;
         }
     }
+    transaction.commit().await?;
+    Ok(Some(
+        copies
+            .into_iter()
+            .filter_map(|copy| copy.chat_id.parse().ok().map(ChatId))
+            .collect(),
+    ))
 }
 
-/// Deletes a run by id.
-pub async fn delete_run(run_id: i32, telegram_userid: UserId, connection: &PgPool) -> DBResult<()> {
-    let valid_run = sqlx::query!(
-        "SELECT r.id
-        FROM runs r
-        JOIN users u on u.id = r.user_id
-        WHERE u.telegram_userid = $1",
+/// Deletes a run by id, along with the copies of it shared to other
+/// chats.
+///
+/// Only runs added by `telegram_userid` within `chat_id` can be deleted.
+/// Returns the other chats whose copies were deleted, or `None` if there
+/// was no such run to delete.
+pub async fn delete_run(
+    run_id: i32,
+    telegram_userid: UserId,
+    chat_id: ChatId,
+    connection: &PgPool,
+) -> DBResult<Option<Vec<ChatId>>> {
+    let mut transaction = connection.begin().await?;
+    let deleted = sqlx::query!(
+        "DELETE FROM runs
+        WHERE id = $1 AND user_id IN (
+            SELECT id FROM users WHERE telegram_userid = $2 AND chat_id = $3
+        )",
+        run_id,
         telegram_userid.to_string(),
+        chat_id.to_string(),
     )
-    .fetch_optional(connection)
-    .await;
-    match valid_run {
-        Ok(Some(_)) => {
-            info!("Matched run_id: {} to user_id: {}", run_id, telegram_userid);
-            sqlx::query!(
-                "DELETE FROM runs
-                WHERE id = $1",
-                run_id,
-            )
-            .execute(connection)
-            .await?;
-            Ok(())
-        }
-        Ok(None) => {
-            error!("No runs matched for the source user");
-            Ok(())
-        }
-        Err(error) => {
-            error!("Unable to retrieve run data: {:?}", error);
-            Err(error)
-        }
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected()
+        > 0;
+    if !deleted {
+        error!("No runs matched for the source user");
+        return Ok(None);
     }
+
+    info!("Matched run_id: {} to user_id: {}", run_id, telegram_userid);
+    let chat_ids = sqlx::query_scalar!(
+        "DELETE FROM runs
+        USING users
+        WHERE users.id = runs.user_id AND runs.shared_from = $1
+        RETURNING users.chat_id",
+        run_id,
+    )
+    .fetch_all(&mut *transaction)
+    .await?
+    .into_iter()
+    .filter_map(|chat_id| chat_id.parse().ok().map(ChatId))
+    .collect();
+    transaction.commit().await?;
+    Ok(Some(chat_ids))
 }
 
 /// Aggregates runs into a tally (`Vec<Score>`)
-pub async fn get_tally(chat_id: ChatId, connection: &PgPool) -> DBResult<Option<Vec<Score>>> {
-    let users = get_users_in_chat(chat_id, connection).await?;
-
-    if let Some(users) = users {
-        let user_ids: Vec<i32> = users.iter().map(|user| user.id).collect();
-        let tally = sqlx::query!(
-            "SELECT user_name, COUNT(*), SUM(distance) as total_ran
+///
+/// Only runs from `since` onwards and before `until` are counted, if given.
+/// Users are ranked
+/// competition-style, so users who ran the same distance, to the nearest
+/// 10m, share a rank and the ranks after them are skipped (1, 1, 3).
+///
+/// Grouped by teams, every team with members gets a place, named after the
+/// team and with the team's id in place of a Telegram id. Its members' runs
+/// in the chat add up, under any of their names, and their distance is
+/// divided by the number of members if `per_member` is set.
+///
+/// `None` if the chat has no users, or no teams with members when grouped
+/// by teams.
+pub async fn get_tally(
+    chat_id: ChatId,
+    since: Option<chrono::NaiveDateTime>,
+    until: Option<chrono::NaiveDateTime>,
+    grouping: TallyGrouping,
+    connection: &PgPool,
+) -> DBResult<Option<Vec<Score>>> {
+    let scores: Vec<Score> = match grouping {
+        TallyGrouping::Users => sqlx::query!(
+            r#"SELECT user_name, telegram_userid, COUNT(*) AS "count!", SUM(distance) AS "total_ran!",
+                RANK() OVER (ORDER BY ROUND(SUM(distance)::numeric, 2) DESC) AS "rank!"
             FROM runs
             JOIN users ON users.id = runs.user_id
-            WHERE user_id = ANY($1)
-            GROUP BY user_name
-            ORDER BY total_ran DESC",
-            &user_ids[..],
+            WHERE users.chat_id = $1 AND ($2::timestamp IS NULL OR run_datetime >= $2)
+                AND ($3::timestamp IS NULL OR run_datetime < $3)
+            GROUP BY user_name, telegram_userid
+            ORDER BY ROUND(SUM(distance)::numeric, 2) DESC, user_name"#,
+            chat_id.to_string(),
+            since,
+            until,
         )
         .fetch_all(connection)
-        .await?;
-
-        let scores: Vec<Score> = tally
-            .iter()
-            .map(|tally| Score {
-                user_name: tally.user_name.clone(),
-                medals: tally.count.unwrap() as u32,
-                distance: tally.total_ran.unwrap(),
-            })
-            .collect();
-
+        .await?
+        .into_iter()
+        .map(|tally| Score {
+            rank: tally.rank as u32,
+            user_name: tally.user_name,
+            telegram_userid: tally.telegram_userid,
+            medals: tally.count as u32,
+            distance: tally.total_ran,
+        })
+        .collect(),
+        TallyGrouping::Teams { per_member } => sqlx::query!(
+            r#"WITH totals AS (
+                SELECT team_id, COUNT(DISTINCT team_members.telegram_userid) AS members,
+                    COUNT(runs.id) AS runs, COALESCE(SUM(distance), 0) AS distance
+                FROM team_members
+                LEFT JOIN users ON users.chat_id = team_members.chat_id
+                    AND users.telegram_userid = team_members.telegram_userid
+                LEFT JOIN runs ON runs.user_id = users.id
+                    AND ($2::timestamp IS NULL OR run_datetime >= $2)
+                    AND ($3::timestamp IS NULL OR run_datetime < $3)
+                WHERE team_members.chat_id = $1
+                GROUP BY team_id
+            ), scores AS (
+                SELECT teams.id, teams.name, runs, ROUND((CASE WHEN $4
+                    THEN distance / members ELSE distance END)::numeric, 2) AS distance
+                FROM teams
+                JOIN totals ON totals.team_id = teams.id
+            )
+            SELECT id AS "id!", name AS "name!", runs AS "runs!", distance::real AS "distance!",
+                RANK() OVER (ORDER BY distance DESC) AS "rank!"
+            FROM scores
+            ORDER BY distance DESC, LOWER(name)"#,
+            chat_id.to_string(),
+            since,
+            until,
+            per_member,
+        )
+        .fetch_all(connection)
+        .await?
+        .into_iter()
+        .map(|tally| Score {
+            rank: tally.rank as u32,
+            user_name: tally.name,
+            telegram_userid: tally.id.to_string(),
+            medals: tally.runs as u32,
+            distance: tally.distance,
+        })
+        .collect(),
+    };
+    if !scores.is_empty() {
         return Ok(Some(scores));
     }
 
-    Ok(None)
+    // A chat whose users have no runs to count still gets an empty tally of
+    // users, as it did when its users were fetched first.
+    let has_users = match grouping {
+        TallyGrouping::Users => {
+            sqlx::query_scalar!(
+                r#"SELECT EXISTS (SELECT 1 FROM users WHERE chat_id = $1) AS "exists!""#,
+                chat_id.to_string(),
+            )
+            .fetch_one(connection)
+            .await?
+        }
+        TallyGrouping::Teams { .. } => false,
+    };
+    Ok(has_users.then_some(scores))
+}
+
+/// Sums up the distance each user in the chat ran per day in `timezone`.
+///
+/// Only runs from `since` onwards and before `until` are counted, if given.
+/// Days are ordered from oldest to newest, and days without runs are left
+/// out.
+pub async fn get_daily_distances(
+    chat_id: ChatId,
+    since: Option<chrono::NaiveDateTime>,
+    until: Option<chrono::NaiveDateTime>,
+    timezone: Tz,
+    connection: &PgPool,
+) -> DBResult<Vec<DailyDistance>> {
+    let days = sqlx::query!(
+        r#"SELECT user_name, (run_datetime AT TIME ZONE 'UTC' AT TIME ZONE $4)::date AS "day!",
+            SUM(distance) AS "distance!"
+        FROM runs
+        JOIN users ON users.id = runs.user_id
+        WHERE users.chat_id = $1 AND ($2::timestamp IS NULL OR run_datetime >= $2)
+            AND ($3::timestamp IS NULL OR run_datetime < $3)
+        GROUP BY user_name, 2
+        ORDER BY 2, user_name"#,
+        chat_id.to_string(),
+        since,
+        until,
+        timezone.name(),
+    )
+    .fetch_all(connection)
+    .await?
+    .into_iter()
+    .map(|row| DailyDistance {
+        user_name: row.user_name,
+        day: row.day,
+        distance: row.distance,
+    })
+    .collect();
+
+    Ok(days)
+}
+
+/// Fetches the emojis a chat marks places in the tally with.
+pub async fn get_rank_emojis(chat_id: ChatId, connection: &PgPool) -> DBResult<RankEmojis> {
+    let emojis = sqlx::query_scalar!(
+        "SELECT rank_emojis FROM chat_settings WHERE chat_id = $1",
+        chat_id.to_string(),
+    )
+    .fetch_optional(connection)
+    .await?
+    .flatten();
+
+    Ok(emojis
+        .and_then(|emojis| emojis.parse().ok())
+        .unwrap_or_default())
+}
+
+/// Sets the emojis a chat marks places in the tally with, or goes back to
+/// the default ones if `emojis` is `None`.
+pub async fn set_rank_emojis(
+    chat_id: ChatId,
+    emojis: Option<&RankEmojis>,
+    connection: &PgPool,
+) -> DBResult<()> {
+    sqlx::query!(
+        "INSERT INTO chat_settings (chat_id, rank_emojis) VALUES ($1, $2)
+        ON CONFLICT (chat_id) DO UPDATE SET rank_emojis = EXCLUDED.rank_emojis",
+        chat_id.to_string(),
+        emojis.map(|emojis| emojis.to_string()),
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Fetches whether a chat divides team distances by their number of
+/// members.
+pub async fn get_teams_per_member(chat_id: ChatId, connection: &PgPool) -> DBResult<bool> {
+    let per_member = sqlx::query_scalar!(
+        "SELECT teams_per_member FROM chat_settings WHERE chat_id = $1",
+        chat_id.to_string(),
+    )
+    .fetch_optional(connection)
+    .await?;
+
+    Ok(per_member.unwrap_or_default())
+}
+
+/// Sets whether a chat divides team distances by their number of members.
+pub async fn set_teams_per_member(
+    chat_id: ChatId,
+    per_member: bool,
+    connection: &PgPool,
+) -> DBResult<()> {
+    sqlx::query!(
+        "INSERT INTO chat_settings (chat_id, teams_per_member) VALUES ($1, $2)
+        ON CONFLICT (chat_id) DO UPDATE SET teams_per_member = EXCLUDED.teams_per_member",
+        chat_id.to_string(),
+        per_member,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Creates a team in a chat. Returns whether it was created, which it is
+/// not if the chat has a team by that name already, in any case.
+pub async fn create_team(chat_id: ChatId, name: &str, connection: &PgPool) -> DBResult<bool> {
+    let created = sqlx::query!(
+        "INSERT INTO teams (chat_id, name) VALUES ($1, $2)
+        ON CONFLICT (chat_id, LOWER(name)) DO NOTHING",
+        chat_id.to_string(),
+        name,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(created.rows_affected() > 0)
+}
+
+/// Puts a user in the team called `name`, in any case, taking them out of
+/// the team they were in.
+///
+/// Returns the name of the team as it was created, or `None` if the chat
+/// has no such team.
+pub async fn join_team(
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    user_name: &str,
+    name: &str,
+    connection: &PgPool,
+) -> DBResult<Option<String>> {
+    let team = sqlx::query_scalar!(
+        "INSERT INTO team_members (chat_id, telegram_userid, user_name, team_id)
+        SELECT chat_id, $2, $3, id FROM teams WHERE chat_id = $1 AND LOWER(name) = LOWER($4)
+        ON CONFLICT (chat_id, telegram_userid) DO UPDATE SET team_id = EXCLUDED.team_id,
+            user_name = EXCLUDED.user_name, joined = now()
+        RETURNING (SELECT name FROM teams WHERE id = team_id)",
+        chat_id.to_string(),
+        telegram_userid.to_string(),
+        user_name,
+        name,
+    )
+    .fetch_optional(connection)
+    .await?
+    .flatten();
+
+    Ok(team)
+}
+
+/// Takes a user out of their team in a chat. Returns whether they were in
+/// one.
+pub async fn leave_team(
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    connection: &PgPool,
+) -> DBResult<bool> {
+    let left = sqlx::query!(
+        "DELETE FROM team_members WHERE chat_id = $1 AND telegram_userid = $2",
+        chat_id.to_string(),
+        telegram_userid.to_string(),
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(left.rows_affected() > 0)
+}
+
+/// Fetches the teams in a chat along with their members, in alphabetical
+/// order.
+pub async fn get_teams(chat_id: ChatId, connection: &PgPool) -> DBResult<Vec<Team>> {
+    let teams = sqlx::query!(
+        r#"SELECT name, ARRAY_REMOVE(ARRAY_AGG(user_name ORDER BY LOWER(user_name)), NULL)
+            AS "members!"
+        FROM teams
+        LEFT JOIN team_members ON team_members.team_id = teams.id
+        WHERE teams.chat_id = $1
+        GROUP BY teams.id
+        ORDER BY LOWER(name)"#,
+        chat_id.to_string(),
+    )
+    .fetch_all(connection)
+    .await?
+    .into_iter()
+    .map(|team| Team {
+        name: team.name,
+        members: team.members,
+    })
+    .collect();
+
+    Ok(teams)
+}
+
+/// Schedules a chat's weekly or monthly recap, or moves it to another time.
+///
+/// A new schedule counts as sent now, so that a recap that is already due
+/// is not posted straight away.
+pub async fn schedule_recap(
+    chat_id: ChatId,
+    period: Period,
+    local_time: chrono::NaiveTime,
+    timezone: Tz,
+    connection: &PgPool,
+) -> DBResult<()> {
+    sqlx::query!(
+        "INSERT INTO recap_schedules (chat_id, period, local_time, timezone)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (chat_id, period)
+        DO UPDATE SET local_time = EXCLUDED.local_time, timezone = EXCLUDED.timezone",
+        chat_id.to_string(),
+        period.to_string(),
+        local_time,
+        timezone.name(),
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Stops a chat's recap for `period`, or all of its recaps if `None`.
+/// Returns the number of recaps stopped.
+pub async fn unschedule_recap(
+    chat_id: ChatId,
+    period: Option<Period>,
+    connection: &PgPool,
+) -> DBResult<u64> {
+    let stopped = sqlx::query!(
+        "DELETE FROM recap_schedules WHERE chat_id = $1 AND ($2::varchar IS NULL OR period = $2)",
+        chat_id.to_string(),
+        period.map(|period| period.to_string()),
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(stopped.rows_affected())
+}
+
+/// Fetches the recaps scheduled in a chat, or in every chat if `None`.
+///
+/// Schedules that can no longer be read, e.g. because their time zone was
+/// dropped from the time zone database, are skipped.
+pub async fn get_recap_schedules(
+    chat_id: Option<ChatId>,
+    connection: &PgPool,
+) -> DBResult<Vec<RecapSchedule>> {
+    let schedules = sqlx::query!(
+        "SELECT chat_id, period, local_time, timezone, last_sent FROM recap_schedules
+        WHERE $1::varchar IS NULL OR chat_id = $1
+        ORDER BY chat_id, period DESC",
+        chat_id.map(|chat_id| chat_id.to_string()),
+    )
+    .fetch_all(connection)
+    .await?
+    .into_iter()
+    .filter_map(|row| {
+        let schedule = (
+            row.chat_id.parse(),
+            row.period.parse(),
+            row.timezone.parse(),
+        );
+        match schedule {
+            (Ok(chat_id), Ok(period), Ok(timezone)) => Some(RecapSchedule {
+                chat_id: ChatId(chat_id),
+                period,
+                local_time: row.local_time,
+                timezone,
+                last_sent: row.last_sent,
+            }),
+            _ => {
+                error!(
+                    "Unable to read recap schedule: chat_id: {}, period: {}, timezone: {}",
+                    row.chat_id, row.period, row.timezone
+                );
+                None
+            }
+        }
+    })
+    .collect();
+
+    Ok(schedules)
+}
+
+/// Records that a chat's recap for `period` was posted at `sent_at`.
+pub async fn mark_recap_sent(
+    chat_id: ChatId,
+    period: Period,
+    sent_at: chrono::NaiveDateTime,
+    connection: &PgPool,
+) -> DBResult<()> {
+    sqlx::query!(
+        "UPDATE recap_schedules SET last_sent = $3 WHERE chat_id = $1 AND period = $2",
+        chat_id.to_string(),
+        period.to_string(),
+        sent_at,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Sends a chat's users a daily reminder once they have not run for
+/// `inactive_days`, or changes when it is sent.
+///
+/// Like recaps, a new schedule counts as sent now.
+pub async fn schedule_reminder(
+    chat_id: ChatId,
+    inactive_days: u32,
+    local_time: chrono::NaiveTime,
+    timezone: Tz,
+    connection: &PgPool,
+) -> DBResult<()> {
+    sqlx::query!(
+        "INSERT INTO reminder_schedules (chat_id, inactive_days, local_time, timezone)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (chat_id) DO UPDATE SET inactive_days = EXCLUDED.inactive_days,
+            local_time = EXCLUDED.local_time, timezone = EXCLUDED.timezone",
+        chat_id.to_string(),
+        inactive_days as i32,
+        local_time,
+        timezone.name(),
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Stops reminders in a chat. Returns whether they were being sent.
+pub async fn unschedule_reminder(chat_id: ChatId, connection: &PgPool) -> DBResult<bool> {
+    let stopped = sqlx::query!(
+        "DELETE FROM reminder_schedules WHERE chat_id = $1",
+        chat_id.to_string(),
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(stopped.rows_affected() > 0)
+}
+
+/// Fetches the reminder scheduled in a chat, or in every chat if `None`.
+///
+/// Schedules that can no longer be read are skipped.
+pub async fn get_reminder_schedules(
+    chat_id: Option<ChatId>,
+    connection: &PgPool,
+) -> DBResult<Vec<ReminderSchedule>> {
+    let schedules = sqlx::query!(
+        "SELECT chat_id, inactive_days, local_time, timezone, last_sent FROM reminder_schedules
+        WHERE $1::varchar IS NULL OR chat_id = $1
+        ORDER BY chat_id",
+        chat_id.map(|chat_id| chat_id.to_string()),
+    )
+    .fetch_all(connection)
+    .await?
+    .into_iter()
+    .filter_map(|row| match (row.chat_id.parse(), row.timezone.parse()) {
+        (Ok(chat_id), Ok(timezone)) => Some(ReminderSchedule {
+            chat_id: ChatId(chat_id),
+            inactive_days: row.inactive_days as u32,
+            local_time: row.local_time,
+            timezone,
+            last_sent: row.last_sent,
+        }),
+        _ => {
+            error!(
+                "Unable to read reminder schedule: chat_id: {}, timezone: {}",
+                row.chat_id, row.timezone
+            );
+            None
+        }
+    })
+    .collect();
+
+    Ok(schedules)
+}
+
+/// Records that a chat's reminders were sent at `sent_at`.
+pub async fn mark_reminder_sent(
+    chat_id: ChatId,
+    sent_at: chrono::NaiveDateTime,
+    connection: &PgPool,
+) -> DBResult<()> {
+    sqlx::query!(
+        "UPDATE reminder_schedules SET last_sent = $2 WHERE chat_id = $1",
+        chat_id.to_string(),
+        sent_at,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Opts a user out of reminders in a chat, or back in.
+pub async fn set_reminder_opt_out(
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    opted_out: bool,
+    connection: &PgPool,
+) -> DBResult<()> {
+    if opted_out {
+        sqlx::query!(
+            "INSERT INTO reminder_opt_outs (chat_id, telegram_userid) VALUES ($1, $2)
+            ON CONFLICT DO NOTHING",
+            chat_id.to_string(),
+            telegram_userid.to_string(),
+        )
+        .execute(connection)
+        .await?;
+    } else {
+        sqlx::query!(
+            "DELETE FROM reminder_opt_outs WHERE chat_id = $1 AND telegram_userid = $2",
+            chat_id.to_string(),
+            telegram_userid.to_string(),
+        )
+        .execute(connection)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Fetches users in the chat whose latest run was from `from` up to
+/// `until`, and who have not opted out of reminders.
+///
+/// Users are told apart by their Telegram id, so a user who ran under
+/// several names is only returned once, under the name of their latest run.
+pub async fn get_inactive_users(
+    chat_id: ChatId,
+    from: chrono::NaiveDateTime,
+    until: chrono::NaiveDateTime,
+    connection: &PgPool,
+) -> DBResult<Vec<User>> {
+    sqlx::query_as!(
+        User,
+        r#"SELECT id AS "id!", telegram_userid AS "telegram_userid!", chat_id AS "chat_id!",
+            user_name AS "user_name!"
+        FROM (
+            SELECT DISTINCT ON (telegram_userid) users.id, telegram_userid, users.chat_id,
+                user_name, run_datetime
+            FROM users
+            JOIN runs ON runs.user_id = users.id
+            WHERE users.chat_id = $1 AND run_datetime IS NOT NULL
+            ORDER BY telegram_userid, run_datetime DESC
+        ) latest
+        WHERE run_datetime >= $2 AND run_datetime < $3 AND NOT EXISTS (
+            SELECT 1 FROM reminder_opt_outs
+            WHERE reminder_opt_outs.chat_id = latest.chat_id
+                AND reminder_opt_outs.telegram_userid = latest.telegram_userid
+        )
+        ORDER BY user_name"#,
+        chat_id.to_string(),
+        from,
+        until,
+    )
+    .fetch_all(connection)
+    .await
+}
+
+/// Sets the distance a chat aims to run together from `starts` up to
+/// `ends`, replacing any goal it had.
+pub async fn set_goal(chat_id: ChatId, goal: &Goal, connection: &PgPool) -> DBResult<()> {
+    sqlx::query!(
+        "INSERT INTO chat_goals (chat_id, distance, period, starts, ends, reached)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (chat_id) DO UPDATE SET distance = EXCLUDED.distance,
+            period = EXCLUDED.period, starts = EXCLUDED.starts, ends = EXCLUDED.ends,
+            reached = EXCLUDED.reached",
+        chat_id.to_string(),
+        goal.distance,
+        goal.period.to_string(),
+        goal.starts,
+        goal.ends,
+        goal.reached,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Fetches a chat's goal, if it has set one.
+pub async fn get_goal(chat_id: ChatId, connection: &PgPool) -> DBResult<Option<Goal>> {
+    let goal = sqlx::query!(
+        "SELECT distance, period, starts, ends, reached FROM chat_goals WHERE chat_id = $1",
+        chat_id.to_string(),
+    )
+    .fetch_optional(connection)
+    .await?
+    .and_then(|row| {
+        Some(Goal {
+            distance: row.distance,
+            period: row.period.parse().ok()?,
+            starts: row.starts,
+            ends: row.ends,
+            reached: row.reached,
+        })
+    });
+
+    Ok(goal)
+}
+
+/// Records that a chat reached its goal at `reached`. Returns whether it
+/// had not been reached before, so that it is only celebrated once.
+pub async fn mark_goal_reached(
+    chat_id: ChatId,
+    reached: chrono::NaiveDateTime,
+    connection: &PgPool,
+) -> DBResult<bool> {
+    let marked = sqlx::query!(
+        "UPDATE chat_goals SET reached = $2 WHERE chat_id = $1 AND reached IS NULL",
+        chat_id.to_string(),
+        reached,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(marked.rows_affected() > 0)
+}
+
+/// Sums up the distance the chat ran from `from` up to `until`.
+pub async fn get_total_distance(
+    chat_id: ChatId,
+    from: chrono::NaiveDateTime,
+    until: chrono::NaiveDateTime,
+    connection: &PgPool,
+) -> DBResult<f32> {
+    let total = sqlx::query_scalar!(
+        r#"SELECT COALESCE(SUM(distance), 0) AS "total!"
+        FROM runs
+        JOIN users ON users.id = runs.user_id
+        WHERE users.chat_id = $1 AND run_datetime >= $2 AND run_datetime < $3"#,
+        chat_id.to_string(),
+        from,
+        until,
+    )
+    .fetch_one(connection)
+    .await?;
+
+    Ok(total)
+}
+
+/// Fetches the runs a user added to a chat with a time, oldest first,
+/// along with their splits. Only runs, rather than other sports, are
+/// included.
+pub async fn get_timed_runs(
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    connection: &PgPool,
+) -> DBResult<Vec<TimedRun>> {
+    let runs = sqlx::query!(
+        r#"SELECT r.id, r.run_datetime AS "run_datetime!", r.distance,
+            r.duration_secs AS "duration_secs!", r.race,
+            COALESCE(ARRAY_AGG(s.distance ORDER BY s.distance)
+                FILTER (WHERE s.run_id IS NOT NULL), '{}') AS "split_distances!",
+            COALESCE(ARRAY_AGG(s.elapsed_secs ORDER BY s.distance)
+                FILTER (WHERE s.run_id IS NOT NULL), '{}') AS "split_secs!"
+        FROM runs r
+        JOIN users u ON u.id = r.user_id
+        LEFT JOIN run_splits s ON s.run_id = r.id
+        WHERE u.chat_id = $1 AND u.telegram_userid = $2 AND r.duration_secs > 0
+            AND COALESCE(r.sport, 'run') = 'run'
+        GROUP BY r.id
+        ORDER BY r.run_datetime, r.id"#,
+        chat_id.to_string(),
+        telegram_userid.to_string(),
+    )
+    .fetch_all(connection)
+    .await?
+    .into_iter()
+    .map(|row| TimedRun {
+        id: row.id,
+        run_datetime: row.run_datetime,
+        distance: row.distance,
+        duration_secs: row.duration_secs,
+        race: row.race,
+        splits: row
+            .split_distances
+            .into_iter()
+            .zip(row.split_secs)
+            .map(|(distance, elapsed_secs)| Split {
+                distance,
+                elapsed_secs,
+            })
+            .collect(),
+    })
+    .collect();
+
+    Ok(runs)
+}
+
+/// Sets a chat off along a route, replacing any journey it was on.
+pub async fn start_journey(
+    chat_id: ChatId,
+    journey: &Journey,
+    connection: &PgPool,
+) -> DBResult<()> {
+    sqlx::query!(
+        "INSERT INTO chat_journeys (chat_id, route, starts, reached)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (chat_id) DO UPDATE SET route = EXCLUDED.route,
+            starts = EXCLUDED.starts, reached = EXCLUDED.reached",
+        chat_id.to_string(),
+        journey.route,
+        journey.starts,
+        journey.reached as i32,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Fetches the journey a chat is on, if any.
+pub async fn get_journey(chat_id: ChatId, connection: &PgPool) -> DBResult<Option<Journey>> {
+    let journey = sqlx::query!(
+        "SELECT route, starts, reached FROM chat_journeys WHERE chat_id = $1",
+        chat_id.to_string(),
+    )
+    .fetch_optional(connection)
+    .await?
+    .map(|row| Journey {
+        route: row.route,
+        starts: row.starts,
+        reached: row.reached as u32,
+    });
+
+    Ok(journey)
+}
+
+/// Records that a chat's journey reached the waypoint at index `reached`.
+/// Returns whether it was further than any waypoint reached before, so
+/// that every landmark is only announced once.
+pub async fn mark_landmark_reached(
+    chat_id: ChatId,
+    reached: u32,
+    connection: &PgPool,
+) -> DBResult<bool> {
+    let marked = sqlx::query!(
+        "UPDATE chat_journeys SET reached = $2 WHERE chat_id = $1 AND reached < $2",
+        chat_id.to_string(),
+        reached as i32,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(marked.rows_affected() > 0)
+}
+
+/// Ends the journey a chat is on. Returns whether it was on one.
+pub async fn stop_journey(chat_id: ChatId, connection: &PgPool) -> DBResult<bool> {
+    let stopped = sqlx::query!(
+        "DELETE FROM chat_journeys WHERE chat_id = $1",
+        chat_id.to_string(),
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(stopped.rows_affected() > 0)
+}
+
+/// Sets what a user aims to run every week or month in a chat, replacing
+/// any goal they had.
+///
+/// Goals changed to another amount keep counting from when the user first
+/// set a goal over the same period, so that their attainment rate carries
+/// on. Switching between weeks and months starts over.
+pub async fn set_personal_goal(
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    goal: &PersonalGoal,
+    connection: &PgPool,
+) -> DBResult<()> {
+    sqlx::query!(
+        "INSERT INTO personal_goals (chat_id, telegram_userid, period, target, unit, since)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (chat_id, telegram_userid) DO UPDATE SET
+            since = CASE WHEN personal_goals.period = EXCLUDED.period
+                THEN personal_goals.since ELSE EXCLUDED.since END,
+            period = EXCLUDED.period, target = EXCLUDED.target, unit = EXCLUDED.unit",
+        chat_id.to_string(),
+        telegram_userid.to_string(),
+        goal.period.to_string(),
+        goal.target,
+        goal.unit.to_string(),
+        goal.since,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Removes a user's goal in a chat. Returns whether they had one.
+pub async fn delete_personal_goal(
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    connection: &PgPool,
+) -> DBResult<bool> {
+    let deleted = sqlx::query!(
+        "DELETE FROM personal_goals WHERE chat_id = $1 AND telegram_userid = $2",
+        chat_id.to_string(),
+        telegram_userid.to_string(),
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(deleted.rows_affected() > 0)
+}
+
+/// Fetches a user's goal in a chat, if they have set one.
+pub async fn get_personal_goal(
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    connection: &PgPool,
+) -> DBResult<Option<PersonalGoal>> {
+    let goal = sqlx::query!(
+        "SELECT period, target, unit, since FROM personal_goals
+        WHERE chat_id = $1 AND telegram_userid = $2",
+        chat_id.to_string(),
+        telegram_userid.to_string(),
+    )
+    .fetch_optional(connection)
+    .await?
+    .and_then(|row| {
+        Some(PersonalGoal {
+            period: row.period.parse().ok()?,
+            target: row.target,
+            unit: row.unit.parse().ok()?,
+            since: row.since,
+        })
+    });
+
+    Ok(goal)
+}
+
+/// Sums up what a user ran in the chat from `from` up to `until`, under
+/// any of their names.
+pub async fn get_progress(
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    from: chrono::NaiveDateTime,
+    until: chrono::NaiveDateTime,
+    connection: &PgPool,
+) -> DBResult<Progress> {
+    let progress = sqlx::query!(
+        r#"SELECT COALESCE(SUM(distance), 0) AS "distance!", COUNT(*) AS "runs!"
+        FROM runs
+        JOIN users ON users.id = runs.user_id
+        WHERE users.chat_id = $1 AND users.telegram_userid = $2
+            AND run_datetime >= $3 AND run_datetime < $4"#,
+        chat_id.to_string(),
+        telegram_userid.to_string(),
+        from,
+        until,
+    )
+    .fetch_one(connection)
+    .await?;
+
+    Ok(Progress {
+        distance: progress.distance,
+        runs: progress.runs as u32,
+    })
+}
+
+/// Records that a user reached their goal for the period starting at
+/// `period_start`. Returns whether it had not been recorded before, so that
+/// each goal is only celebrated once.
+pub async fn record_goal_achievement(
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    goal: &PersonalGoal,
+    period_start: chrono::NaiveDateTime,
+    connection: &PgPool,
+) -> DBResult<bool> {
+    let recorded = sqlx::query!(
+        "INSERT INTO personal_goal_achievements
+            (chat_id, telegram_userid, period, period_start, target, unit)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT DO NOTHING",
+        chat_id.to_string(),
+        telegram_userid.to_string(),
+        goal.period.to_string(),
+        period_start,
+        goal.target,
+        goal.unit.to_string(),
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(recorded.rows_affected() > 0)
+}
+
+/// Counts the periods from `since` onwards in which a user reached their
+/// goals over `period`.
+pub async fn count_goal_achievements(
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    period: Period,
+    since: chrono::NaiveDateTime,
+    connection: &PgPool,
+) -> DBResult<u32> {
+    let count = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM personal_goal_achievements
+        WHERE chat_id = $1 AND telegram_userid = $2 AND period = $3 AND period_start >= $4"#,
+        chat_id.to_string(),
+        telegram_userid.to_string(),
+        period.to_string(),
+        since,
+    )
+    .fetch_one(connection)
+    .await?;
+
+    Ok(count as u32)
+}
+
+/// A row of the `challenges` table, before its ids are parsed.
+struct ChallengeRow {
+    /// Challenge id
+    id: i32,
+    /// Id of telegram chat
+    chat_id: String,
+    /// Telegram id of the user who made the challenge
+    challenger_userid: String,
+    /// Username of the user who made the challenge
+    challenger_name: String,
+    /// Username of the user challenged
+    opponent_name: String,
+    /// Telegram id of the user challenged, once they accept
+    opponent_userid: Option<String>,
+    /// Distance to run first, in km
+    distance: f32,
+    /// Number of days the challenge lasts once accepted
+    days: i32,
+    /// When the challenge was accepted
+    starts: Option<chrono::NaiveDateTime>,
+    /// When the challenge ends
+    ends: Option<chrono::NaiveDateTime>,
+}
+
+impl ChallengeRow {
+    /// Parses the ids of the row, logging rows that can no longer be read.
+    fn into_challenge(self) -> Option<Challenge> {
+        let ids = (
+            self.chat_id.parse(),
+            self.challenger_userid.parse(),
+            self.opponent_userid.as_deref().map(str::parse).transpose(),
+        );
+        let (Ok(chat_id), Ok(challenger_id), Ok(opponent_id)) = ids else {
+            error!("Unable to read challenge: id: {}", self.id);
+            return None;
+        };
+        Some(Challenge {
+            id: self.id,
+            chat_id: ChatId(chat_id),
+            challenger_id: UserId(challenger_id),
+            challenger_name: self.challenger_name,
+            opponent_name: self.opponent_name,
+            opponent_id: opponent_id.map(UserId),
+            distance: self.distance,
+            days: self.days as u32,
+            starts: self.starts,
+            ends: self.ends,
+        })
+    }
+}
+
+/// Creates a challenge to run `distance` within `days` of it being
+/// accepted, and returns its id.
+pub async fn create_challenge(
+    chat_id: ChatId,
+    challenger_id: UserId,
+    challenger_name: &str,
+    opponent_name: &str,
+    distance: f32,
+    days: u32,
+    connection: &PgPool,
+) -> DBResult<i32> {
+    let id = sqlx::query_scalar!(
+        "INSERT INTO challenges (chat_id, challenger_userid, challenger_name, opponent_name,
+            distance, days)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id",
+        chat_id.to_string(),
+        challenger_id.to_string(),
+        challenger_name,
+        opponent_name,
+        distance,
+        days as i32,
+    )
+    .fetch_one(connection)
+    .await?;
+
+    Ok(id)
+}
+
+/// Fetches a challenge by its id.
+pub async fn get_challenge(id: i32, connection: &PgPool) -> DBResult<Option<Challenge>> {
+    let challenge = sqlx::query_as!(
+        ChallengeRow,
+        "SELECT id, chat_id, challenger_userid, challenger_name, opponent_name,
+            opponent_userid, distance, days, starts, ends
+        FROM challenges WHERE id = $1",
+        id,
+    )
+    .fetch_optional(connection)
+    .await?
+    .and_then(ChallengeRow::into_challenge);
+
+    Ok(challenge)
+}
+
+/// Starts a challenge that is waiting to be accepted, from `starts` up to
+/// `ends`. Returns whether it was still waiting, so that it is only
+/// accepted once.
+pub async fn accept_challenge(
+    id: i32,
+    opponent_id: UserId,
+    starts: chrono::NaiveDateTime,
+    ends: chrono::NaiveDateTime,
+    connection: &PgPool,
+) -> DBResult<bool> {
+    let accepted = sqlx::query!(
+        "UPDATE challenges SET opponent_userid = $2, starts = $3, ends = $4
+        WHERE id = $1 AND starts IS NULL",
+        id,
+        opponent_id.to_string(),
+        starts,
+        ends,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(accepted.rows_affected() > 0)
+}
+
+/// Removes a challenge that is waiting to be accepted. Returns whether it
+/// was still waiting.
+pub async fn delete_challenge(id: i32, connection: &PgPool) -> DBResult<bool> {
+    let deleted = sqlx::query!(
+        "DELETE FROM challenges WHERE id = $1 AND starts IS NULL",
+        id,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(deleted.rows_affected() > 0)
+}
+
+/// Fetches challenges that were accepted and have no result yet, in a chat,
+/// or in every chat if `None`.
+pub async fn get_active_challenges(
+    chat_id: Option<ChatId>,
+    connection: &PgPool,
+) -> DBResult<Vec<Challenge>> {
+    let challenges = sqlx::query_as!(
+        ChallengeRow,
+        "SELECT id, chat_id, challenger_userid, challenger_name, opponent_name,
+            opponent_userid, distance, days, starts, ends
+        FROM challenges
+        WHERE ($1::varchar IS NULL OR chat_id = $1) AND starts IS NOT NULL
+            AND NOT EXISTS (SELECT 1 FROM challenge_results WHERE challenge_id = id)
+        ORDER BY ends, id",
+        chat_id.map(|chat_id| chat_id.to_string()),
+    )
+    .fetch_all(connection)
+    .await?
+    .into_iter()
+    .filter_map(ChallengeRow::into_challenge)
+    .collect();
+
+    Ok(challenges)
+}
+
+/// Fetches the runs that count towards a challenge, in the order they were
+/// run, as `(telegram_userid, run_datetime, distance)`.
+pub async fn get_challenge_runs(
+    challenge: &Challenge,
+    connection: &PgPool,
+) -> DBResult<Vec<(UserId, chrono::NaiveDateTime, f32)>> {
+    let (Some(opponent_id), Some(starts), Some(ends)) =
+        (challenge.opponent_id, challenge.starts, challenge.ends)
+    else {
+        return Ok(vec![]);
+    };
+    let runs = sqlx::query!(
+        r#"SELECT telegram_userid, run_datetime AS "run_datetime!", distance
+        FROM runs
+        JOIN users ON users.id = runs.user_id
+        WHERE users.chat_id = $1 AND telegram_userid IN ($2, $3)
+            AND run_datetime >= $4 AND run_datetime < $5
+        ORDER BY run_datetime, runs.id"#,
+        challenge.chat_id.to_string(),
+        challenge.challenger_id.to_string(),
+        opponent_id.to_string(),
+        starts,
+        ends,
+    )
+    .fetch_all(connection)
+    .await?
+    .into_iter()
+    .filter_map(|run| {
+        let telegram_userid = run.telegram_userid.parse().ok()?;
+        Some((UserId(telegram_userid), run.run_datetime, run.distance))
+    })
+    .collect();
+
+    Ok(runs)
+}
+
+/// Records how a challenge ended. Returns whether it had not been recorded
+/// before, so that each result is only announced once.
+pub async fn record_challenge_result(
+    challenge: &Challenge,
+    standings: &Standings,
+    connection: &PgPool,
+) -> DBResult<bool> {
+    let winner = match standings.outcome {
+        Some(Outcome::Challenger) => Some(challenge.challenger_id),
+        Some(Outcome::Opponent) => challenge.opponent_id,
+        Some(Outcome::Draw) | None => None,
+    };
+    let recorded = sqlx::query!(
+        "INSERT INTO challenge_results
+            (challenge_id, winner_userid, challenger_distance, opponent_distance)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT DO NOTHING",
+        challenge.id,
+        winner.map(|winner| winner.to_string()),
+        standings.challenger,
+        standings.opponent,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(recorded.rows_affected() > 0)
+}
+
+/// Fetches the latest `limit` challenges that ended in a chat, along with
+/// how they ended.
+pub async fn get_challenge_results(
+    chat_id: ChatId,
+    limit: i64,
+    connection: &PgPool,
+) -> DBResult<Vec<(Challenge, Standings)>> {
+    let results = sqlx::query!(
+        "SELECT id, chat_id, challenger_userid, challenger_name, opponent_name,
+            opponent_userid, distance, days, starts, ends,
+            winner_userid, challenger_distance, opponent_distance
+        FROM challenges
+        JOIN challenge_results ON challenge_id = id
+        WHERE chat_id = $1
+        ORDER BY settled DESC, id DESC
+        LIMIT $2",
+        chat_id.to_string(),
+        limit,
+    )
+    .fetch_all(connection)
+    .await?
+    .into_iter()
+    .filter_map(|row| {
+        let outcome = match &row.winner_userid {
+            None => Outcome::Draw,
+            Some(winner) if *winner == row.challenger_userid => Outcome::Challenger,
+            Some(_) => Outcome::Opponent,
+        };
+        let standings = Standings {
+            challenger: row.challenger_distance,
+            opponent: row.opponent_distance,
+            outcome: Some(outcome),
+        };
+        let challenge = ChallengeRow {
+            id: row.id,
+            chat_id: row.chat_id,
+            challenger_userid: row.challenger_userid,
+            challenger_name: row.challenger_name,
+            opponent_name: row.opponent_name,
+            opponent_userid: row.opponent_userid,
+            distance: row.distance,
+            days: row.days,
+            starts: row.starts,
+            ends: row.ends,
+        }
+        .into_challenge()?;
+        Some((challenge, standings))
+    })
+    .collect();
+
+    Ok(results)
+}
+
+/// Shares runs a user adds in a chat with their other chats that share
+/// runs, or stops doing so.
+///
+/// The user's profile is created if needed, as they may not have run yet.
+pub async fn set_chat_shared(
+    telegram_userid: UserId,
+    user_name: &str,
+    chat_id: ChatId,
+    shared: bool,
+    connection: &PgPool,
+) -> DBResult<()> {
+    if shared {
+        let mut transaction = connection.begin().await?;
+        upsert_profiles(
+            &[telegram_userid.to_string()],
+            &[user_name],
+            &mut transaction,
+        )
+        .await?;
+        sqlx::query!(
+            "INSERT INTO shared_chats (telegram_userid, chat_id) VALUES ($1, $2)
+            ON CONFLICT DO NOTHING",
+            telegram_userid.to_string(),
+            chat_id.to_string(),
+        )
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+    } else {
+        sqlx::query!(
+            "DELETE FROM shared_chats WHERE telegram_userid = $1 AND chat_id = $2",
+            telegram_userid.to_string(),
+            chat_id.to_string(),
+        )
+        .execute(connection)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Fetches the chats a user shares runs between.
+pub async fn get_shared_chats(
+    telegram_userid: UserId,
+    connection: &PgPool,
+) -> DBResult<Vec<ChatId>> {
+    let chat_ids = sqlx::query_scalar!(
+        "SELECT chat_id FROM shared_chats WHERE telegram_userid = $1 ORDER BY chat_id",
+        telegram_userid.to_string(),
+    )
+    .fetch_all(connection)
+    .await?
+    .into_iter()
+    .filter_map(|chat_id| chat_id.parse().ok().map(ChatId))
+    .collect();
+
+    Ok(chat_ids)
+}
+
+/// Counts the chats a user has added runs to.
+pub async fn count_user_chats(telegram_userid: UserId, connection: &PgPool) -> DBResult<u32> {
+    let count = sqlx::query_scalar!(
+        r#"SELECT COUNT(DISTINCT chat_id) AS "count!" FROM users
+        WHERE telegram_userid = $1 AND EXISTS (SELECT 1 FROM runs WHERE user_id = users.id)"#,
+        telegram_userid.to_string(),
+    )
+    .fetch_one(connection)
+    .await?;
+
+    Ok(count as u32)
+}
+
+/// Sums up what a user ran across all of their chats from `since` onwards,
+/// or all time if `None`.
+///
+/// Runs shared to several chats, and activities pushed by a provider to
+/// several linked chats, are only counted once.
+pub async fn get_profile_progress(
+    telegram_userid: UserId,
+    since: Option<chrono::NaiveDateTime>,
+    connection: &PgPool,
+) -> DBResult<Progress> {
+    let progress = sqlx::query!(
+        r#"SELECT COALESCE(SUM(distance), 0) AS "distance!", COUNT(*) AS "runs!"
+        FROM (
+            SELECT DISTINCT ON (COALESCE(external_id, COALESCE(shared_from, runs.id)::varchar))
+                distance, run_datetime
+            FROM runs
+            JOIN users ON users.id = runs.user_id
+            WHERE users.telegram_userid = $1
+            ORDER BY COALESCE(external_id, COALESCE(shared_from, runs.id)::varchar), runs.id
+        ) AS distinct_runs
+        WHERE $2::timestamp IS NULL OR run_datetime >= $2"#,
+        telegram_userid.to_string(),
+        since,
+    )
+    .fetch_one(connection)
+    .await?;
+
+    Ok(Progress {
+        distance: progress.distance,
+        runs: progress.runs as u32,
+    })
+}
+
+/// Starts linking a user in a chat to their account with a provider.
+///
+/// Returns the state handed to the provider, which `take_link_request`
+/// is given back once the user has logged in with the provider. Requests
+/// expire after an hour, and expired ones are cleared out along the way.
+pub async fn request_link(
+    provider: &str,
+    user_name: &str,
+    telegram_userid: UserId,
+    chat_id: ChatId,
+    connection: &PgPool,
+) -> DBResult<String> {
+    sqlx::query!("DELETE FROM link_requests WHERE requested < now() - interval '1 hour'")
+        .execute(connection)
+        .await?;
+    let request = sqlx::query!(
+        "INSERT INTO link_requests (provider, telegram_userid, chat_id, user_name)
+        VALUES ($1, $2, $3, $4)
+        RETURNING state",
+        provider,
+        telegram_userid.to_string(),
+        chat_id.to_string(),
+        user_name
+    )
+    .fetch_one(connection)
+    .await?;
+
+    Ok(request.state)
+}
+
+/// Removes and returns the request to link an account with a provider
+/// that `state` was handed out for, if it was made within the hour.
+pub async fn take_link_request(
+    provider: &str,
+    state: &str,
+    connection: &PgPool,
+) -> DBResult<Option<LinkRequest>> {
+    let request = sqlx::query!(
+        "DELETE FROM link_requests
+        WHERE state = $1 AND provider = $2 AND requested >= now() - interval '1 hour'
+        RETURNING telegram_userid, chat_id, user_name",
+        state,
+        provider
+    )
+    .fetch_optional(connection)
+    .await?;
+
+    Ok(request.and_then(|request| {
+        Some(LinkRequest {
+            telegram_userid: UserId(request.telegram_userid.parse().ok()?),
+            chat_id: ChatId(request.chat_id.parse().ok()?),
+            user_name: request.user_name,
+        })
+    }))
+}
+
+/// Links a user in a chat to their account with an activity provider,
+/// along with the tokens to read its activities.
+///
+/// Each user can link one account per provider in every chat, linking
+/// again replaces the account.
+pub async fn link_account(
+    provider: &str,
+    account_id: &str,
+    tokens: &AccessTokens,
+    user_name: &str,
+    telegram_userid: UserId,
+    chat_id: ChatId,
+    connection: &PgPool,
+) -> DBResult<()> {
+    let mut transaction = connection.begin().await?;
+    let user_id = upsert_user(user_name, telegram_userid, chat_id, &mut transaction).await?;
+    sqlx::query!(
+        "INSERT INTO provider_links
+            (user_id, provider, account_id, access_token, refresh_token, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (user_id, provider)
+        DO UPDATE SET account_id = EXCLUDED.account_id,
+            access_token = EXCLUDED.access_token,
+            refresh_token = EXCLUDED.refresh_token,
+            expires_at = EXCLUDED.expires_at",
+        user_id,
+        provider,
+        account_id,
+        tokens.access_token,
+        tokens.refresh_token,
+        tokens.expires_at
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await
+}
+
+/// Fetches the latest tokens of an account linked with a provider, if it
+/// is linked in any chat.
+pub async fn get_access_tokens(
+    provider: &str,
+    account_id: &str,
+    connection: &PgPool,
+) -> DBResult<Option<AccessTokens>> {
+    sqlx::query_as!(
+        AccessTokens,
+        "SELECT access_token, refresh_token, expires_at
+        FROM provider_links
+        WHERE provider = $1 AND account_id = $2
+        ORDER BY expires_at DESC
+        LIMIT 1",
+        provider,
+        account_id
+    )
+    .fetch_optional(connection)
+    .await
+}
+
+/// Stores refreshed tokens of an account linked with a provider, in every
+/// chat it is linked in.
+pub async fn update_access_tokens(
+    provider: &str,
+    account_id: &str,
+    tokens: &AccessTokens,
+    connection: &PgPool,
+) -> DBResult<()> {
+    sqlx::query!(
+        "UPDATE provider_links
+        SET access_token = $3, refresh_token = $4, expires_at = $5
+        WHERE provider = $1 AND account_id = $2",
+        provider,
+        account_id,
+        tokens.access_token,
+        tokens.refresh_token,
+        tokens.expires_at
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+/// Removes the links of a user in a chat to a provider.
+///
+/// Returns whether there was anything to remove.
+pub async fn unlink_account(
+    provider: &str,
+    telegram_userid: UserId,
+    chat_id: ChatId,
+    connection: &PgPool,
+) -> DBResult<bool> {
+    let removed = sqlx::query!(
+        "DELETE FROM provider_links
+        USING users
+        WHERE users.id = provider_links.user_id
+            AND provider = $1 AND users.telegram_userid = $2 AND users.chat_id = $3",
+        provider,
+        telegram_userid.to_string(),
+        chat_id.to_string()
+    )
+    .execute(connection)
+    .await?
+    .rows_affected();
+
+    Ok(removed > 0)
+}
+
+/// Fetches every user, across chats, linked to an account with a provider.
+pub async fn get_linked_users(
+    provider: &str,
+    account_id: &str,
+    connection: &PgPool,
+) -> DBResult<Vec<User>> {
+    sqlx::query_as!(
+        User,
+        "SELECT users.id, telegram_userid, chat_id, user_name
+        FROM provider_links
+        JOIN users ON users.id = provider_links.user_id
+        WHERE provider = $1 AND account_id = $2
+        ORDER BY users.id",
+        provider,
+        account_id
+    )
+    .fetch_all(connection)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Sport;
+
+    const CHAT: ChatId = ChatId(-100);
+
+    /// Tokens of a linked account, with `access_token`.
+    fn tokens(access_token: &str) -> AccessTokens {
+        AccessTokens {
+            access_token: access_token.into(),
+            refresh_token: "refresh".into(),
+            expires_at: chrono::NaiveDate::from_ymd_opt(2023, 10, 14)
+                .unwrap()
+                .and_hms_opt(7, 12, 0)
+                .unwrap(),
+        }
+    }
+
+    #[sqlx::test]
+    async fn link_requests_are_taken_once(connection: PgPool) {
+        let state = request_link("strava", "reuben", UserId(11), CHAT, &connection)
+            .await
+            .unwrap();
+        let other = request_link("strava", "milton", UserId(22), CHAT, &connection)
+            .await
+            .unwrap();
+        assert_ne!(state, other);
+
+        assert_eq!(
+            take_link_request("garmin", &state, &connection)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            take_link_request("strava", &state, &connection)
+                .await
+                .unwrap(),
+            Some(LinkRequest {
+                telegram_userid: UserId(11),
+                chat_id: CHAT,
+                user_name: "reuben".into(),
+            })
+        );
+        assert_eq!(
+            take_link_request("strava", &state, &connection)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[sqlx::test]
+    async fn refreshed_tokens_are_used_in_every_chat(connection: PgPool) {
+        for chat_id in [CHAT, ChatId(-200)] {
+            link_account(
+                "strava",
+                "99",
+                &tokens("token"),
+                "reuben",
+                UserId(11),
+                chat_id,
+                &connection,
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(
+            get_access_tokens("strava", "99", &connection)
+                .await
+                .unwrap(),
+            Some(tokens("token"))
+        );
+
+        update_access_tokens("strava", "99", &tokens("fresh"), &connection)
+            .await
+            .unwrap();
+        let stale = sqlx::query!(
+            r#"SELECT COUNT(*) AS "count!" FROM provider_links WHERE access_token = 'token'"#
+        )
+        .fetch_one(&connection)
+        .await
+        .unwrap();
+        assert_eq!(stale.count, 0);
+        assert_eq!(
+            get_access_tokens("strava", "77", &connection)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[sqlx::test]
+    async fn external_activities_are_added_once(connection: PgPool) {
+        let details = || RunDetails {
+            external_id: Some("strava:1".into()),
+            ..Default::default()
+        };
+        for chat_id in [CHAT, ChatId(-200)] {
+            link_account(
+                "strava",
+                "99",
+                &tokens("token"),
+                "reuben",
+                UserId(11),
+                chat_id,
+                &connection,
+            )
+            .await
+            .unwrap();
+        }
+        let linked = get_linked_users("strava", "99", &connection).await.unwrap();
+        assert_eq!(linked.len(), 2);
+
+        let add = || add_shared_run(5., details(), "reuben", UserId(11), CHAT, &connection);
+        assert!(add().await.unwrap().is_some());
+        assert!(add().await.unwrap().is_none());
+        let added_elsewhere = add_shared_run(
+            5.,
+            details(),
+            "reuben",
+            UserId(11),
+            ChatId(-200),
+            &connection,
+        )
+        .await
+        .unwrap();
+        assert!(added_elsewhere.is_some());
+        // The activity is in both chats, but is one run of the user's.
+        let progress = get_profile_progress(UserId(11), None, &connection)
+            .await
+            .unwrap();
+        assert_eq!(progress.runs, 1);
+        assert_eq!(progress.distance, 5.);
+
+        assert!(unlink_account("strava", UserId(11), CHAT, &connection)
+            .await
+            .unwrap());
+        assert!(!unlink_account("strava", UserId(11), CHAT, &connection)
+            .await
+            .unwrap());
+        let linked = get_linked_users("strava", "99", &connection).await.unwrap();
+        assert_eq!(linked[0].chat_id, "-200");
+    }
+
+    #[sqlx::test]
+    async fn concurrent_first_runs_create_one_user(connection: PgPool) {
+        let adds: Vec<_> = (0..10)
+            .map(|_| {
+                let connection = connection.clone();
+                tokio::spawn(async move {
+                    add_shared_run(
+                        5.,
+                        RunDetails::default(),
+                        "reuben",
+                        UserId(11),
+                        CHAT,
+                        &connection,
+                    )
+                    .await
+                })
+            })
+            .collect();
+        for add in adds {
+            add.await.unwrap().unwrap();
+        }
+
+        let users = get_users_in_chat(CHAT, &connection).await.unwrap().unwrap();
+        assert_eq!(users.len(), 1);
+        let tally = get_tally(CHAT, None, None, TallyGrouping::Users, &connection)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tally[0].medals, 10);
+    }
+
+    #[sqlx::test]
+    async fn add_runs_in_bulk(connection: PgPool) {
+        add_shared_run(
+            1.,
+            RunDetails::default(),
+            "reuben",
+            UserId(11),
+            CHAT,
+            &connection,
+        )
+        .await
+        .unwrap();
+        let run_datetime = chrono::NaiveDate::from_ymd_opt(2023, 10, 1)
+            .unwrap()
+            .and_hms_opt(7, 0, 0);
+        let runs: Vec<NewRun> = [("reuben", 11, 5.), ("milton", 22, 3.), ("reuben", 11, 2.)]
+            .into_iter()
+            .map(|(user_name, telegram_userid, distance)| NewRun {
+                user_name: user_name.into(),
+                telegram_userid: UserId(telegram_userid),
+                distance,
+                run_datetime,
+            })
+            .collect();
+
+        let added = add_runs(&runs, CHAT, &connection).await.unwrap();
+        assert_eq!(added, 3);
+
+        let users = get_users_in_chat(CHAT, &connection).await.unwrap().unwrap();
+        assert_eq!(users.len(), 2);
+        let tally = get_tally(CHAT, None, None, TallyGrouping::Users, &connection)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tally[0].user_name, "reuben");
+        assert_eq!(tally[0].medals, 3);
+        assert_eq!(tally[0].distance, 8.);
+        let runs = get_runs(CHAT, &RunFilter::default(), 10, 0, &connection)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            runs.iter()
+                .filter(|(run, _)| run.run_datetime == run_datetime)
+                .count(),
+            3
+        );
+    }
+
+    #[sqlx::test]
+    async fn profiles_keep_the_last_name_given(connection: PgPool) {
+        let runs: Vec<NewRun> = ["rw", "reuben", "reuben_w"]
+            .into_iter()
+            .map(|user_name| NewRun {
+                user_name: user_name.into(),
+                telegram_userid: UserId(11),
+                distance: 5.,
+                run_datetime: None,
+            })
+            .collect();
+
+        add_runs(&runs, CHAT, &connection).await.unwrap();
+
+        let user_name: String =
+            sqlx::query_scalar("SELECT user_name FROM profiles WHERE telegram_userid = '11'")
+                .fetch_one(&connection)
+                .await
+                .unwrap();
+        assert_eq!(user_name, "reuben_w");
+    }
+
+    /// Distances of the runs `get_runs` returns.
+    async fn listed(filter: RunFilter, limit: i64, offset: i64, connection: &PgPool) -> Vec<f32> {
+        get_runs(CHAT, &filter, limit, offset, connection)
+            .await
+            .unwrap()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(run, _)| run.distance)
+            .collect()
+    }
+
+    #[sqlx::test]
+    async fn filter_and_page_runs(connection: PgPool) {
+        for (distance, sport, user_name, telegram_userid) in [
+            (5., None, "reuben", 11),
+            (20., Some(Sport::Ride), "reuben", 11),
+            (3., None, "Milton", 22),
+        ] {
+            let details = RunDetails {
+                sport,
+                ..Default::default()
+            };
+            add_shared_run(
+                distance,
+                details,
+                user_name,
+                UserId(telegram_userid),
+                CHAT,
+                &connection,
+            )
+            .await
+            .unwrap();
+        }
+        let owner = |owner| RunFilter {
+            owner: Some(owner),
+            ..Default::default()
+        };
+        let sport = |sport| RunFilter {
+            sport: Some(sport),
+            period: Period::Week,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            listed(RunFilter::default(), 10, 0, &connection).await,
+            [3., 20., 5.]
+        );
+        assert_eq!(listed(RunFilter::default(), 1, 1, &connection).await, [20.]);
+        assert_eq!(
+            listed(owner(RunOwner::Name("milton".into())), 10, 0, &connection).await,
+            [3.]
+        );
+        assert_eq!(
+            listed(owner(RunOwner::Id(UserId(11))), 10, 0, &connection).await,
+            [20., 5.]
+        );
+        assert!(listed(owner(RunOwner::Me), 10, 0, &connection)
+            .await
+            .is_empty());
+        assert_eq!(
+            listed(sport(Sport::Run), 10, 0, &connection).await,
+            [3., 5.]
+        );
+        assert_eq!(listed(sport(Sport::Ride), 10, 0, &connection).await, [20.]);
+    }
+
+    #[sqlx::test]
+    async fn runs_are_only_changed_by_their_owner(connection: PgPool) {
+        add_shared_run(
+            5.,
+            RunDetails::default(),
+            "reuben",
+            UserId(11),
+            CHAT,
+            &connection,
+        )
+        .await
+        .unwrap();
+        add_shared_run(
+            3.,
+            RunDetails::default(),
+            "milton",
+            UserId(22),
+            CHAT,
+            &connection,
+        )
+        .await
+        .unwrap();
+        let reuben_run = 1;
+
+        assert!(update_run(
+            reuben_run,
+            UserId(22),
+            CHAT,
+            10.,
+            &Timing::default(),
+            &connection,
+        )
+        .await
+        .unwrap()
+        .is_none());
+        assert!(update_run(
+            reuben_run,
+            UserId(11),
+            ChatId(-200),
+            10.,
+            &Timing::default(),
+            &connection,
+        )
+        .await
+        .unwrap()
+        .is_none());
+        assert!(delete_run(reuben_run, UserId(22), CHAT, &connection)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(delete_run(42, UserId(11), CHAT, &connection)
+            .await
+            .unwrap()
+            .is_none());
+
+        let tally = get_tally(CHAT, None, None, TallyGrouping::Users, &connection)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tally[0].user_name, "reuben");
+        assert_eq!(tally[0].distance, 5.);
+    }
+
+    #[sqlx::test]
+    async fn tally_since(connection: PgPool) {
+        let run_datetime = |day| {
+            chrono::NaiveDate::from_ymd_opt(2023, 10, day)
+                .unwrap()
+                .and_hms_opt(7, 0, 0)
+        };
+        let runs: Vec<NewRun> = [(1, 5.), (9, 3.), (10, 2.)]
+            .into_iter()
+            .map(|(day, distance)| NewRun {
+                user_name: "reuben".into(),
+                telegram_userid: UserId(11),
+                distance,
+                run_datetime: run_datetime(day),
+            })
+            .collect();
+        add_runs(&runs, CHAT, &connection).await.unwrap();
+
+        let tally = get_tally(
+            CHAT,
+            run_datetime(9),
+            None,
+            TallyGrouping::Users,
+            &connection,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(tally[0].medals, 2);
+        assert_eq!(tally[0].distance, 5.);
+
+        let tally = get_tally(
+            CHAT,
+            run_datetime(9),
+            run_datetime(10),
+            TallyGrouping::Users,
+            &connection,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(tally[0].medals, 1);
+        assert_eq!(tally[0].distance, 3.);
+    }
+
+    #[sqlx::test]
+    async fn tied_users_share_a_rank(connection: PgPool) {
+        let runs: Vec<NewRun> = [
+            ("reuben", 11, 5.),
+            ("milton", 22, 2.5),
+            ("milton", 22, 2.5),
+            ("jerrell", 33, 3.),
+            ("taigy", 44, 1.),
+        ]
+        .into_iter()
+        .map(|(user_name, telegram_userid, distance)| NewRun {
+            user_name: user_name.into(),
+            telegram_userid: UserId(telegram_userid),
+            distance,
+            run_datetime: None,
+        })
+        .collect();
+        add_runs(&runs, CHAT, &connection).await.unwrap();
+
+        let tally = get_tally(CHAT, None, None, TallyGrouping::Users, &connection)
+            .await
+            .unwrap()
+            .unwrap();
+        let ranks: Vec<(&str, u32)> = tally
+            .iter()
+            .map(|score| (score.user_name.as_str(), score.rank))
+            .collect();
+        assert_eq!(
+            ranks,
+            [("milton", 1), ("reuben", 1), ("jerrell", 3), ("taigy", 4)]
+        );
+    }
+
+    #[sqlx::test]
+    async fn rank_emojis_per_chat(connection: PgPool) {
+        let other_chat = ChatId(-200);
+        let emojis: RankEmojis = "⭐ - -".parse().unwrap();
+        set_rank_emojis(CHAT, Some(&emojis), &connection)
+            .await
+            .unwrap();
+
+        assert_eq!(get_rank_emojis(CHAT, &connection).await.unwrap(), emojis);
+        assert_eq!(
+            get_rank_emojis(other_chat, &connection).await.unwrap(),
+            RankEmojis::default()
+        );
+        set_rank_emojis(CHAT, None, &connection).await.unwrap();
+        assert_eq!(
+            get_rank_emojis(CHAT, &connection).await.unwrap(),
+            RankEmojis::default()
+        );
+    }
+
+    #[sqlx::test]
+    async fn failed_bulk_add_writes_nothing(connection: PgPool) {
+        let runs = vec![NewRun {
+            user_name: "a_name_that_is_far_too_long_for_the_column".into(),
+            telegram_userid: UserId(11),
+            distance: 5.,
+            run_datetime: None,
+        }];
+
+        assert!(add_runs(&runs, CHAT, &connection).await.is_err());
+        assert!(get_users_in_chat(CHAT, &connection)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
+
+/// Latency benchmarks, run with
+/// `cargo test --release -- --ignored --nocapture bench`.
+#[cfg(test)]
+mod benches {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    const CHAT: ChatId = ChatId(-100);
+    const CHATS: i64 = 20;
+    const USERS: u64 = 50;
+    const RUNS_PER_USER: u64 = 100;
+    const ITERATIONS: u32 = 200;
+
+    /// Seeds `CHATS` chats with `USERS * RUNS_PER_USER` runs each.
+    async fn seed(connection: &PgPool) {
+        for chat_id in (1..=CHATS).map(|chat| ChatId(chat * CHAT.0)) {
+            let runs: Vec<NewRun> = (0..USERS * RUNS_PER_USER)
+                .map(|run| NewRun {
+                    user_name: format!("user{}", run % USERS),
+                    telegram_userid: UserId(run % USERS),
+                    distance: (run % 21) as f32,
+                    run_datetime: chrono::DateTime::from_timestamp(run as i64 * 3600, 0)
+                        .map(|datetime| datetime.naive_utc()),
+                })
+                .collect();
+            add_runs(&runs, chat_id, connection).await.unwrap();
+        }
+        sqlx::query("ANALYZE").execute(connection).await.unwrap();
+    }
+
+    /// Mean latency of `query` over `ITERATIONS` runs.
+    async fn time<F, Fut>(query: F) -> Duration
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = ()>,
+    {
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            query().await;
+        }
+        start.elapsed() / ITERATIONS
+    }
+
+    /// The tally as it was computed before, prefetching the chat's users.
+    async fn prefetched_tally(connection: &PgPool) {
+        let user_ids: Vec<i32> = get_users_in_chat(CHAT, connection)
+            .await
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|user| user.id)
+            .collect();
+        sqlx::query(
+            "SELECT user_name, COUNT(*), SUM(distance) as total_ran
+            FROM runs
+            JOIN users ON users.id = runs.user_id
+            WHERE user_id = ANY($1)
+            GROUP BY user_name
+            ORDER BY total_ran DESC",
+        )
+        .bind(&user_ids[..])
+        .fetch_all(connection)
+        .await
+        .unwrap();
+    }
+
+    /// The run listing as it was computed before, prefetching the chat's users.
+    async fn prefetched_runs(connection: &PgPool) {
+        let user_ids: Vec<i32> = get_users_in_chat(CHAT, connection)
+            .await
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|user| user.id)
+            .collect();
+        sqlx::query(
+            "SELECT *
+            FROM runs
+            WHERE user_id = ANY($1)
+            ORDER BY run_datetime DESC
+            LIMIT $2",
+        )
+        .bind(&user_ids[..])
+        .bind(10_i64)
+        .fetch_all(connection)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test]
+    #[ignore]
+    async fn bench_tally_and_runs(connection: PgPool) {
+        seed(&connection).await;
+
+        let prefetched = time(|| prefetched_tally(&connection)).await;
+        let joined = time(|| async {
+            get_tally(CHAT, None, None, TallyGrouping::Users, &connection)
+                .await
+                .unwrap();
+        })
+        .await;
+        println!("tally: prefetched users {prefetched:?}, joined {joined:?}");
+
+        let prefetched = time(|| prefetched_runs(&connection)).await;
+        let joined = time(|| async {
+            get_runs(CHAT, &RunFilter::default(), 10, 0, &connection)
+                .await
+                .unwrap();
+        })
+        .await;
+        println!("list:  prefetched users {prefetched:?}, joined {joined:?}");
+    }
 }
@@ -0,0 +1,204 @@
+//! Exports of a chat's runs.
+//!
+//! Runs are serialised with [serde](https://serde.rs/) into either CSV or
+//! JSON, so that groups can work with their data in their own spreadsheets.
+use crate::models::{Period, Run, User};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use std::{error::Error, fmt, str::FromStr};
+use teloxide::utils::command::ParseError;
+
+/// File formats runs can be exported as.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Comma separated values, with a header row.
+    #[default]
+    Csv,
+    /// A JSON array of runs.
+    Json,
+}
+
+impl ExportFormat {
+    /// File extension used for exported documents.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+/// Error returned when an export format is not `csv` or `json`.
+#[derive(Debug)]
+pub struct ParseFormatError(String);
+
+impl fmt::Display for ParseFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown format \"{}\", expected csv or json.", self.0)
+    }
+}
+
+impl Error for ParseFormatError {}
+
+impl FromStr for ExportFormat {
+    type Err = ParseFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(ParseFormatError(s.trim().into())),
+        }
+    }
+}
+
+/// Parses the arguments of `/export [csv|json] [period]`.
+///
+/// Both arguments are optional and may come in either order.
+pub fn parse_export_args(input: String) -> Result<(ExportFormat, Period), ParseError> {
+    let mut format = ExportFormat::default();
+    let mut period = Period::default();
+    for argument in input.split_whitespace() {
+        if let Ok(parsed) = argument.parse() {
+            format = parsed;
+        } else {
+            period = argument
+                .parse()
+                .map_err(|error| ParseError::IncorrectFormat(Box::new(error)))?;
+        }
+    }
+
+    Ok((format, period))
+}
+
+/// A single exported run.
+#[derive(Serialize)]
+struct ExportedRun<'a> {
+    /// Run id, as used by `/edit` and `/delete`.
+    run_id: i32,
+    /// Name of the user who ran.
+    user_name: &'a str,
+    /// Distance ran in km.
+    distance: f32,
+    /// Time taken in seconds, if known.
+    duration_secs: Option<i32>,
+    /// When the run was added.
+    run_datetime: Option<NaiveDateTime>,
+    /// Kind of activity, if known.
+    sport: Option<&'a str>,
+    /// Average heart rate in bpm, if recorded.
+    avg_heart_rate: Option<i32>,
+    /// Maximum heart rate in bpm, if recorded.
+    max_heart_rate: Option<i32>,
+    /// Total ascent in metres, if recorded.
+    elevation_gain: Option<f32>,
+}
+
+/// Serialises runs and the users who added them in `format`.
+pub fn export_runs(
+    runs: &[(Run, User)],
+    format: ExportFormat,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let exported = runs.iter().map(|(run, user)| ExportedRun {
+        run_id: run.id,
+        user_name: &user.user_name,
+        distance: run.distance,
+        duration_secs: run.duration_secs,
+        run_datetime: run.run_datetime,
+        sport: run.sport.as_deref(),
+        avg_heart_rate: run.avg_heart_rate,
+        max_heart_rate: run.max_heart_rate,
+        elevation_gain: run.elevation_gain,
+    });
+
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            for run in exported {
+                writer.serialize(run)?;
+            }
+            Ok(writer.into_inner()?)
+        }
+        ExportFormat::Json => Ok(serde_json::to_vec_pretty(&exported.collect::<Vec<_>>())?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn runs() -> Vec<(Run, User)> {
+        let user = |id, user_name: &str| User {
+            id,
+            telegram_userid: id.to_string(),
+            chat_id: "-100".into(),
+            user_name: user_name.into(),
+        };
+        let run = |id, distance, user_id, duration_secs| Run {
+            id,
+            distance,
+            run_datetime: NaiveDate::from_ymd_opt(2023, 10, id as u32)
+                .unwrap()
+                .and_hms_opt(7, 12, 0),
+            user_id,
+            duration_secs,
+            sport: duration_secs.map(|_| "run".into()),
+            avg_heart_rate: duration_secs.map(|_| 150),
+            max_heart_rate: duration_secs.map(|_| 172),
+            elevation_gain: duration_secs.map(|_| 42.5),
+        };
+
+        vec![
+            (run(1, 5., 1, Some(1800)), user(1, "reuben")),
+            (run(2, 2.5, 2, None), user(2, "milton, jr")),
+        ]
+    }
+
+    #[test]
+    fn export_csv() {
+        let csv = export_runs(&runs(), ExportFormat::Csv).unwrap();
+        let ans = "run_id,user_name,distance,duration_secs,run_datetime,sport,avg_heart_rate,max_heart_rate,elevation_gain
+1,reuben,5.0,1800,2023-10-01T07:12:00,run,150,172,42.5
+2,\"milton, jr\",2.5,,2023-10-02T07:12:00,,,,
+";
+        assert_eq!(String::from_utf8(csv).unwrap(), ans);
+    }
+
+    #[test]
+    fn export_json() {
+        let json = export_runs(&runs(), ExportFormat::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            json[1],
+            serde_json::json!({
+                "run_id": 2,
+                "user_name": "milton, jr",
+                "distance": 2.5,
+                "duration_secs": null,
+                "run_datetime": "2023-10-02T07:12:00",
+                "sport": null,
+                "avg_heart_rate": null,
+                "max_heart_rate": null,
+                "elevation_gain": null,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_arguments() {
+        assert_eq!(
+            parse_export_args("".into()).unwrap(),
+            (ExportFormat::Csv, Period::All)
+        );
+        assert_eq!(
+            parse_export_args("json week".into()).unwrap(),
+            (ExportFormat::Json, Period::Week)
+        );
+        assert_eq!(
+            parse_export_args("month".into()).unwrap(),
+            (ExportFormat::Csv, Period::Month)
+        );
+        assert!(parse_export_args("xml".into()).is_err());
+    }
+}
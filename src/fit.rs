@@ -0,0 +1,455 @@
+//! Decoding of FIT activity files.
+//!
+//! FIT is the binary format written by Garmin, Wahoo and most other
+//! devices. A file is a stream of definition messages, which describe the
+//! layout of a local message type, and data messages laid out accordingly.
+//! Only the `session` and `record` messages are read here. The CRC is not
+//! checked, a corrupt file is far more likely to fail to decode.
+use crate::{
+    activity::{round_km, Activity, TrackPoint},
+    models::Sport,
+};
+use chrono::{DateTime, NaiveDateTime};
+use std::collections::HashMap;
+
+/// FIT timestamps count seconds from 1989-12-31T00:00:00Z.
+const FIT_EPOCH: i64 = 631_065_600;
+
+/// Global message number of `session` messages, one per activity.
+const SESSION: u16 = 18;
+/// Global message number of `record` messages, one per trackpoint.
+const RECORD: u16 = 20;
+
+/// Field number of the timestamp, shared by every message.
+const TIMESTAMP: u8 = 253;
+
+/// Converts semicircles, which FIT stores positions in, to degrees.
+const SEMICIRCLES_TO_DEGREES: f64 = 180. / 2_147_483_648.;
+
+/// A field of a definition message.
+struct FieldDefinition {
+    /// Field number within the global message.
+    number: u8,
+    /// Size of the field in bytes.
+    size: usize,
+    /// Whether the base type of the field is signed.
+    signed: bool,
+}
+
+/// Layout of data messages of a local message type.
+struct Definition {
+    /// Global message number, e.g. `SESSION`.
+    global: u16,
+    /// Whether multi-byte fields are big endian.
+    big_endian: bool,
+    /// Fields in the order they appear.
+    fields: Vec<FieldDefinition>,
+    /// Bytes taken up by developer fields, which are skipped.
+    developer_size: usize,
+}
+
+/// A decoded data message, mapping field numbers to valid integer values.
+struct DataMessage {
+    /// Global message number.
+    global: u16,
+    /// Integer fields, without those set to the invalid value.
+    fields: HashMap<u8, i64>,
+}
+
+impl DataMessage {
+    /// Value of a field, scaled as given by the FIT profile.
+    fn scaled(&self, number: u8, scale: f64, offset: f64) -> Option<f64> {
+        self.fields
+            .get(&number)
+            .map(|value| *value as f64 / scale - offset)
+    }
+
+    /// Value of a timestamp field.
+    fn time(&self, number: u8) -> Option<NaiveDateTime> {
+        self.fields
+            .get(&number)
+            .and_then(|value| DateTime::from_timestamp(value + FIT_EPOCH, 0))
+            .map(|time| time.naive_utc())
+    }
+}
+
+/// Reads bytes off a FIT file.
+struct Reader<'a> {
+    /// Bytes of the data records.
+    bytes: &'a [u8],
+    /// Position of the next byte.
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Takes the next `size` bytes.
+    fn take(&mut self, size: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.position..self.position + size)?;
+        self.position += size;
+        Some(bytes)
+    }
+
+    /// Takes the next byte.
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+}
+
+/// Decodes an integer field, returning `None` for the invalid value.
+///
+/// FIT marks missing values with the largest value of the base type.
+fn decode_integer(bytes: &[u8], big_endian: bool, signed: bool) -> Option<i64> {
+    if !matches!(bytes.len(), 1 | 2 | 4) {
+        return None;
+    }
+    let mut value: u64 = 0;
+    for i in 0..bytes.len() {
+        let byte = if big_endian {
+            bytes[i]
+        } else {
+            bytes[bytes.len() - 1 - i]
+        };
+        value = value << 8 | u64::from(byte);
+    }
+    let bits = bytes.len() as u32 * 8;
+    if signed {
+        let invalid = (1 << (bits - 1)) - 1;
+        if value == invalid {
+            return None;
+        }
+        // Sign extend.
+        let shift = 64 - bits;
+        Some(((value << shift) as i64) >> shift)
+    } else {
+        let invalid = (1 << bits) - 1;
+        (value != invalid).then_some(value as i64)
+    }
+}
+
+/// Decodes every data message of a FIT file.
+fn decode_messages(file: &[u8]) -> Option<Vec<DataMessage>> {
+    let header_size = usize::from(*file.first()?);
+    let data_size = u32::from_le_bytes(file.get(4..8)?.try_into().ok()?) as usize;
+    if file.get(8..12)? != b".FIT" || header_size < 12 {
+        return None;
+    }
+    let mut reader = Reader {
+        bytes: file.get(header_size..header_size + data_size)?,
+        position: 0,
+    };
+
+    let mut definitions: HashMap<u8, Definition> = HashMap::new();
+    let mut messages = vec![];
+    let mut last_timestamp: Option<i64> = None;
+    while reader.position < reader.bytes.len() {
+        let header = reader.byte()?;
+        let (local, time_offset) = if header & 0x80 != 0 {
+            // Compressed timestamp header, always a data message.
+            ((header >> 5) & 0x03, Some(i64::from(header & 0x1F)))
+        } else if header & 0x40 != 0 {
+            let local = header & 0x0F;
+            reader.byte()?;
+            let big_endian = reader.byte()? == 1;
+            let global = reader.take(2)?;
+            let global = if big_endian {
+                u16::from_be_bytes([global[0], global[1]])
+            } else {
+                u16::from_le_bytes([global[0], global[1]])
+            };
+            let field_count = reader.byte()?;
+            let mut fields = vec![];
+            for _ in 0..field_count {
+                let field = reader.take(3)?;
+                fields.push(FieldDefinition {
+                    number: field[0],
+                    size: usize::from(field[1]),
+                    signed: matches!(field[2] & 0x1F, 0x01 | 0x03 | 0x05),
+                });
+            }
+            let mut developer_size = 0;
+            if header & 0x20 != 0 {
+                for _ in 0..reader.byte()? {
+                    developer_size += usize::from(reader.take(3)?[1]);
+                }
+            }
+            definitions.insert(
+                local,
+                Definition {
+                    global,
+                    big_endian,
+                    fields,
+                    developer_size,
+                },
+            );
+            continue;
+        } else {
+            (header & 0x0F, None)
+        };
+
+        let definition = definitions.get(&local)?;
+        let mut fields = HashMap::new();
+        for field in &definition.fields {
+            let bytes = reader.take(field.size)?;
+            if let Some(value) = decode_integer(bytes, definition.big_endian, field.signed) {
+                fields.insert(field.number, value);
+            }
+        }
+        reader.take(definition.developer_size)?;
+
+        // Compressed timestamps hold the low 5 bits of the time, relative
+        // to the last full timestamp.
+        if let (Some(offset), Some(last)) = (time_offset, last_timestamp) {
+            let mut timestamp = (last & !0x1F) + offset;
+            if offset < last & 0x1F {
+                timestamp += 0x20;
+            }
+            fields.insert(TIMESTAMP, timestamp);
+        }
+        if let Some(timestamp) = fields.get(&TIMESTAMP) {
+            last_timestamp = Some(*timestamp);
+        }
+        messages.push(DataMessage {
+            global: definition.global,
+            fields,
+        });
+    }
+
+    Some(messages)
+}
+
+/// Maps FIT's `sport` enum onto a `Sport`.
+fn sport(value: i64) -> Option<Sport> {
+    match value {
+        0 => None,
+        1 => Some(Sport::Run),
+        2 => Some(Sport::Ride),
+        5 => Some(Sport::Swim),
+        11 => Some(Sport::Walk),
+        17 => Some(Sport::Hike),
+        _ => Some(Sport::Other),
+    }
+}
+
+/// Parses a FIT file into an `Activity`.
+///
+/// Totals come from the first `session` message where present, falling
+/// back to what can be worked out from the `record` trackpoints.
+pub fn parse_fit(file: &[u8]) -> Result<Activity, String> {
+    let messages = decode_messages(file).ok_or("The file is not valid FIT.")?;
+    let session = messages.iter().find(|message| message.global == SESSION);
+
+    let points: Vec<TrackPoint> = messages
+        .iter()
+        .filter(|message| message.global == RECORD)
+        .map(|record| TrackPoint {
+            position: record
+                .scaled(0, 1. / SEMICIRCLES_TO_DEGREES, 0.)
+                .zip(record.scaled(1, 1. / SEMICIRCLES_TO_DEGREES, 0.)),
+            time: record.time(TIMESTAMP),
+            // Enhanced altitude replaces altitude on newer devices.
+            elevation: record
+                .scaled(78, 5., 500.)
+                .or_else(|| record.scaled(2, 5., 500.)),
+            heart_rate: record.scaled(3, 1., 0.),
+            distance: record.scaled(5, 100., 0.),
+        })
+        .collect();
+    let tracked = Activity::from_track(None, &[points]);
+    let Some(session) = session else {
+        return tracked;
+    };
+
+    let mut activity = tracked.unwrap_or(Activity {
+        sport: None,
+        distance: 0.,
+        start_time: None,
+        elapsed_secs: None,
+        moving_secs: None,
+        avg_heart_rate: None,
+        max_heart_rate: None,
+        elevation_gain: None,
+        race: false,
+        splits: vec![],
+    });
+    activity.sport = session.fields.get(&5).copied().and_then(sport);
+    if let Some(distance) = session.scaled(9, 100., 0.) {
+        activity.distance = round_km(distance);
+    }
+    activity.start_time = session.time(2).or(activity.start_time);
+    let seconds = |number| {
+        session
+            .scaled(number, 1000., 0.)
+            .map(|secs| secs.round() as i32)
+    };
+    activity.elapsed_secs = seconds(7).or(activity.elapsed_secs);
+    activity.moving_secs = seconds(8).or(activity.moving_secs);
+    let integer = |number| session.fields.get(&number).map(|value| *value as i32);
+    activity.avg_heart_rate = integer(16).or(activity.avg_heart_rate);
+    activity.max_heart_rate = integer(17).or(activity.max_heart_rate);
+    activity.elevation_gain = integer(22)
+        .map(|ascent| ascent as f32)
+        .or(activity.elevation_gain);
+
+    if activity.distance <= 0. {
+        return Err("The file has no distance in it.".into());
+    }
+    Ok(activity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Split;
+    use chrono::NaiveDate;
+
+    /// Base type of enum fields.
+    const ENUM: u8 = 0x00;
+    /// Base type of 8 bit unsigned fields.
+    const UINT8: u8 = 0x02;
+    /// Base type of 16 bit unsigned fields.
+    const UINT16: u8 = 0x84;
+    /// Base type of 32 bit signed fields.
+    const SINT32: u8 = 0x85;
+    /// Base type of 32 bit unsigned fields.
+    const UINT32: u8 = 0x86;
+
+    /// Seconds since the FIT epoch of 2023-10-14T07:12:00Z.
+    const START: u32 = 1_697_267_520 - FIT_EPOCH as u32;
+
+    /// A global message number along with its fields, each given as field
+    /// number, base type and little endian value.
+    type TestMessage = (u16, Vec<(u8, u8, Vec<u8>)>);
+
+    /// Builds a little endian FIT file, with each message given its own
+    /// definition as local message type 0.
+    fn fit_file(messages: &[TestMessage]) -> Vec<u8> {
+        let mut records = vec![];
+        for (global, fields) in messages {
+            records.extend([0x40, 0, 0]);
+            records.extend(global.to_le_bytes());
+            records.push(fields.len() as u8);
+            for (number, base_type, bytes) in fields {
+                records.extend([*number, bytes.len() as u8, *base_type]);
+            }
+            records.push(0x00);
+            for (_, _, bytes) in fields {
+                records.extend(bytes);
+            }
+        }
+
+        let mut file = vec![14, 0x20];
+        file.extend(2132u16.to_le_bytes());
+        file.extend((records.len() as u32).to_le_bytes());
+        file.extend(b".FIT");
+        file.extend([0, 0]);
+        file.extend(records);
+        // CRC, which is not checked.
+        file.extend([0, 0]);
+        file
+    }
+
+    /// A record at `secs` after the start, `metres` into the activity.
+    fn record(secs: u32, metres: u32, heart_rate: u8) -> TestMessage {
+        (
+            RECORD,
+            vec![
+                (TIMESTAMP, UINT32, (START + secs).to_le_bytes().to_vec()),
+                (0, SINT32, 15_510_000i32.to_le_bytes().to_vec()),
+                (1, SINT32, 1_238_000_000i32.to_le_bytes().to_vec()),
+                (3, UINT8, vec![heart_rate]),
+                (5, UINT32, (metres * 100).to_le_bytes().to_vec()),
+            ],
+        )
+    }
+
+    #[test]
+    fn parse_session() {
+        let file = fit_file(&[
+            record(0, 0, 140),
+            record(1700, 5000, 0xFF),
+            (
+                SESSION,
+                vec![
+                    (2, UINT32, START.to_le_bytes().to_vec()),
+                    (5, ENUM, vec![1]),
+                    (7, UINT32, 1_700_000u32.to_le_bytes().to_vec()),
+                    (8, UINT32, 1_650_000u32.to_le_bytes().to_vec()),
+                    (9, UINT32, 501_234u32.to_le_bytes().to_vec()),
+                    (16, UINT8, vec![150]),
+                    (17, UINT8, vec![172]),
+                    (22, UINT16, 42u16.to_le_bytes().to_vec()),
+                ],
+            ),
+        ]);
+
+        assert_eq!(
+            parse_fit(&file).unwrap(),
+            Activity {
+                sport: Some(Sport::Run),
+                distance: 5.01,
+                start_time: NaiveDate::from_ymd_opt(2023, 10, 14)
+                    .unwrap()
+                    .and_hms_opt(7, 12, 0),
+                elapsed_secs: Some(1700),
+                moving_secs: Some(1650),
+                avg_heart_rate: Some(150),
+                max_heart_rate: Some(172),
+                elevation_gain: Some(42.),
+                race: false,
+                splits: (1..=5)
+                    .map(|km| Split {
+                        distance: km as f32,
+                        elapsed_secs: km * 340,
+                    })
+                    .collect(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_records_without_session() {
+        let file = fit_file(&[record(0, 0, 140), record(1700, 5000, 160)]);
+        let activity = parse_fit(&file).unwrap();
+
+        assert_eq!(activity.sport, None);
+        assert_eq!(activity.distance, 5.);
+        assert_eq!(activity.elapsed_secs, Some(1700));
+        assert_eq!(activity.avg_heart_rate, Some(150));
+    }
+
+    #[test]
+    fn compressed_timestamps() {
+        let mut file = fit_file(&[record(0, 0, 140)]);
+        // A compressed timestamp record 16 seconds later, reusing the
+        // definition of the first record with the same values.
+        let record_size = 1 + 4 + 4 + 4 + 1 + 4;
+        let mut compressed = file[file.len() - 2 - record_size..file.len() - 2].to_vec();
+        compressed[0] = 0x80 | ((START + 16) & 0x1F) as u8;
+        let data_size = u32::from_le_bytes(file[4..8].try_into().unwrap()) as usize;
+        file.splice(file.len() - 2..file.len() - 2, compressed);
+        file[4..8].copy_from_slice(&((data_size + record_size) as u32).to_le_bytes());
+
+        let messages = decode_messages(&file).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[1].fields[&TIMESTAMP] - messages[0].fields[&TIMESTAMP],
+            16
+        );
+    }
+
+    #[test]
+    fn signed_and_invalid_values() {
+        assert_eq!(decode_integer(&[0xFE, 0xFF], false, true), Some(-2));
+        assert_eq!(decode_integer(&[0xFF, 0x7F], false, true), None);
+        assert_eq!(decode_integer(&[0x01, 0x02], true, false), Some(0x0102));
+        assert_eq!(decode_integer(&[0xFF], false, false), None);
+    }
+
+    #[test]
+    fn reject_invalid_files() {
+        assert_eq!(
+            parse_fit(b"not a fit file").unwrap_err(),
+            "The file is not valid FIT."
+        );
+    }
+}
@@ -0,0 +1,96 @@
+//! What happens once a run is added to a chat.
+//!
+//! Runs come in through `/add`, activity files and provider webhooks, and
+//! all of them are followed up the same way: the reply congratulates the
+//! runner on goals and records, then goals reached, landmarks passed and
+//! challenges won are announced, in the chat and in the chats the run was
+//! shared to.
+use crate::{
+    cache::TallyCache,
+    challenge::settle_challenges,
+    goal::{celebrate_goal, congratulate_on_goal},
+    journey::announce_landmarks,
+    profile::post_shared_run,
+    record::{congratulate_on_records, PersonalBest},
+};
+use sqlx::PgPool;
+use teloxide::{prelude::*, types::MessageId};
+use tracing::error;
+
+/// A run that was just added to a chat.
+pub struct AddedRun<'a> {
+    /// Chat the run was added to.
+    pub chat_id: ChatId,
+    /// Telegram user who ran.
+    pub telegram_userid: UserId,
+    /// Name of the user who ran.
+    pub user_name: &'a str,
+    /// Distance ran, in km.
+    pub distance: f32,
+    /// Other chats the run was copied to.
+    pub shared_to: Vec<ChatId>,
+    /// The user's personal records from before the run was added, from
+    /// `current_records`.
+    pub records_before: Option<Vec<PersonalBest>>,
+}
+
+/// Follows up on a run that was just added.
+///
+/// Sends `summary` to the chat, as a reply to `reply_to` if given, along
+/// with any goal reached or records set by the user. Then announces what
+/// the run achieved for the chat, and posts it to the chats it was shared
+/// to.
+pub async fn after_run_added(
+    bot: &Bot,
+    run: AddedRun<'_>,
+    summary: String,
+    reply_to: Option<MessageId>,
+    tally_cache: &TallyCache,
+    connection: &PgPool,
+) {
+    let AddedRun {
+        chat_id,
+        telegram_userid,
+        user_name,
+        distance,
+        shared_to,
+        records_before,
+    } = run;
+    tally_cache.invalidate(chat_id);
+
+    let mut reply = summary;
+    if let Some(congratulations) = congratulate_on_goal(chat_id, telegram_userid, connection).await
+    {
+        reply = format!("{}\n{}", reply, congratulations);
+    }
+    if let Some(records) =
+        congratulate_on_records(records_before, chat_id, telegram_userid, connection).await
+    {
+        reply = format!("{}\n{}", reply, records);
+    }
+    let mut request = bot.send_message(chat_id, reply);
+    request.reply_to_message_id = reply_to;
+    request
+        .await
+        .map_err(|err| error!("Unable to send run summary: {:?}", err))
+        .ok();
+
+    celebrate_goal(bot, chat_id, connection).await;
+    announce_landmarks(bot, chat_id, connection).await;
+    settle_challenges(
+        bot,
+        Some(chat_id),
+        connection,
+        chrono::Utc::now().naive_utc(),
+    )
+    .await;
+    post_shared_run(
+        bot,
+        &shared_to,
+        user_name,
+        distance,
+        tally_cache,
+        connection,
+    )
+    .await;
+}
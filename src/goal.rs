@@ -0,0 +1,469 @@
+//! Distances chats set out to run together, with `/goal`, and what each
+//! user aims to run, with `/me goal`.
+//!
+//! A chat's goal covers the week, month or year it is set in, and every run
+//! in the chat within that window counts towards it. Once the chat's runs
+//! add up to the goal, the chat is congratulated, once.
+//!
+//! Personal goals repeat every week or month instead, and count either
+//! distance or runs. Every period in which a user reaches their goal is
+//! recorded, which gives their attainment rate.
+use crate::{
+    database::{
+        count_goal_achievements, delete_personal_goal, get_goal, get_personal_goal, get_progress,
+        get_total_distance, mark_goal_reached, record_goal_achievement, set_goal,
+        set_personal_goal,
+    },
+    message::{display_goal, display_goal_reached, display_me},
+    models::{Goal, GoalUnit, Period, PersonalGoal, Progress},
+};
+use chrono::{NaiveDateTime, Utc};
+use sqlx::PgPool;
+use std::{error::Error, fmt, str::FromStr};
+use teloxide::{prelude::*, types::ParseMode};
+use tracing::{error, info};
+
+/// What `/goal` was asked to do.
+#[derive(Debug, PartialEq)]
+pub enum GoalAction {
+    /// Show progress towards the chat's goal.
+    Show,
+    /// Set a goal of running `distance` over the current `period`.
+    Set {
+        /// Distance to run together, in km.
+        distance: f32,
+        /// Period the goal is for.
+        period: Period,
+    },
+}
+
+/// Error returned when the arguments of `/goal` cannot be read.
+#[derive(Debug, PartialEq)]
+pub struct ParseGoalError;
+
+impl fmt::Display for ParseGoalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Expected /goal set <distance> <week|month|year>, e.g. /goal set 500 month."
+        )
+    }
+}
+
+impl Error for ParseGoalError {}
+
+/// Parses the arguments of `/goal [set <distance> <week|month|year>]`.
+///
+/// Distances are in km, with or without the unit, e.g. `500` or `500km`.
+impl FromStr for GoalAction {
+    type Err = ParseGoalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let arguments: Vec<&str> = s.split_whitespace().collect();
+        let [set, distance, period] = arguments[..] else {
+            return match arguments[..] {
+                [] => Ok(GoalAction::Show),
+                _ => Err(ParseGoalError),
+            };
+        };
+        let distance: f32 = distance
+            .trim_end_matches("km")
+            .parse()
+            .map_err(|_| ParseGoalError)?;
+        match (set.to_lowercase().as_str(), period.parse()) {
+            ("set", Ok(period @ (Period::Week | Period::Month | Period::Year)))
+                if distance.is_finite() && distance > 0.0 =>
+            {
+                Ok(GoalAction::Set { distance, period })
+            }
+            _ => Err(ParseGoalError),
+        }
+    }
+}
+
+/// Handles `/goal`, showing the chat's progress towards its goal or
+/// setting a new one.
+pub async fn answer_goal(bot: &Bot, chat_id: ChatId, args: &str, connection: &PgPool) {
+    let now = chrono::Utc::now().naive_utc();
+    let set = match args.parse::<GoalAction>() {
+        Ok(GoalAction::Show) => Ok(()),
+        Ok(GoalAction::Set { distance, period }) => match new_goal(distance, period, now) {
+            Some(goal) => set_goal(chat_id, &goal, connection).await,
+            None => Ok(()),
+        },
+        Err(err) => {
+            bot.send_message(chat_id, err.to_string())
+                .await
+                .map_err(|err| error!("Unable to send Goal message: {:?}", err))
+                .ok();
+            return;
+        }
+    };
+    // Goals can be set that the chat has already run.
+    celebrate_goal(bot, chat_id, connection).await;
+
+    let goal = match set {
+        Ok(()) => get_goal(chat_id, connection).await,
+        Err(err) => Err(err),
+    };
+    let reply = match goal {
+        Ok(Some(goal)) => get_total_distance(chat_id, goal.starts, goal.ends, connection)
+            .await
+            .map(|done| display_goal(&goal, done, now)),
+        Ok(None) => {
+            Ok("No goal set. Usage: /goal set &lt;distance&gt; &lt;week|month|year&gt;".into())
+        }
+        Err(err) => Err(err),
+    };
+    match reply {
+        Ok(reply) => {
+            bot.send_message(chat_id, reply)
+                .parse_mode(ParseMode::Html)
+                .await
+                .map_err(|err| error!("Unable to send Goal message: {:?}", err))
+                .ok();
+        }
+        Err(err) => error!("Unable to update goal: {:?}", err),
+    }
+}
+
+/// A goal of running `distance` over the `period` containing `now`.
+pub fn new_goal(distance: f32, period: Period, now: NaiveDateTime) -> Option<Goal> {
+    Some(Goal {
+        distance,
+        period,
+        starts: period.start(now)?,
+        ends: period.end(now)?,
+        reached: None,
+    })
+}
+
+/// Congratulates the chat if its runs have just added up to its goal.
+///
+/// Called whenever runs are added or changed. Goals that were already
+/// reached, or whose window is over, are left alone.
+pub async fn celebrate_goal(bot: &Bot, chat_id: ChatId, connection: &PgPool) {
+    let now = Utc::now().naive_utc();
+    let goal = match get_goal(chat_id, connection).await {
+        Ok(Some(goal)) if goal.reached.is_none() && now < goal.ends => goal,
+        Ok(_) => return,
+        Err(err) => {
+            error!("Unable to retrieve goal: {:?}", err);
+            return;
+        }
+    };
+    let done = match get_total_distance(chat_id, goal.starts, goal.ends, connection).await {
+        Ok(done) if done >= goal.distance => done,
+        Ok(_) => return,
+        Err(err) => {
+            error!("Unable to retrieve distance towards goal: {:?}", err);
+            return;
+        }
+    };
+
+    match mark_goal_reached(chat_id, now, connection).await {
+        Ok(true) => {
+            info!("[celebrate_goal]: chat_id: {}", chat_id);
+            bot.send_message(chat_id, display_goal_reached(&goal, done))
+                .await
+                .map_err(|err| error!("Unable to send Goal message: {:?}", err))
+                .ok();
+        }
+        Ok(false) => {}
+        Err(err) => error!("Unable to mark goal as reached: {:?}", err),
+    }
+}
+
+/// What `/me` was asked to do.
+#[derive(Debug, PartialEq)]
+pub enum MeAction {
+    /// Show the user's progress.
+    Show,
+    /// Aim for `target` of `unit` every `period`.
+    SetGoal {
+        /// Distance or number of runs to reach.
+        target: f32,
+        /// Whether `target` is a distance or a number of runs.
+        unit: GoalUnit,
+        /// Period the goal repeats over, either `Week` or `Month`.
+        period: Period,
+    },
+    /// Stop aiming for a goal.
+    ClearGoal,
+}
+
+/// Error returned when the arguments of `/me` cannot be read.
+#[derive(Debug, PartialEq)]
+pub struct ParseMeError;
+
+impl fmt::Display for ParseMeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Expected /me goal <distance>km|<count> runs <week|month>, e.g. /me goal 20km week, or /me goal off."
+        )
+    }
+}
+
+impl Error for ParseMeError {}
+
+/// Parses the arguments of `/me [goal <target> <week|month>|goal off]`.
+///
+/// Targets are a distance in km, with or without the unit, e.g. `20km` or
+/// `20`, or a whole number of runs, e.g. `4 runs` or `4runs`.
+impl FromStr for MeAction {
+    type Err = ParseMeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let arguments: Vec<String> = s.split_whitespace().map(str::to_lowercase).collect();
+        let (target, period) = match &arguments[..] {
+            [] => return Ok(MeAction::Show),
+            [goal, off] if goal == "goal" && off == "off" => return Ok(MeAction::ClearGoal),
+            [goal, target @ .., period] if goal == "goal" && !target.is_empty() => {
+                (target.concat(), period)
+            }
+            _ => return Err(ParseMeError),
+        };
+
+        let split = target
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(target.len());
+        let (amount, unit) = target.split_at(split);
+        let unit = match unit {
+            "" => GoalUnit::Km,
+            unit => unit.parse().map_err(|_| ParseMeError)?,
+        };
+        let target: f32 = amount.parse().map_err(|_| ParseMeError)?;
+        let whole = unit == GoalUnit::Km || target.fract() == 0.;
+        match period.parse() {
+            Ok(period @ (Period::Week | Period::Month))
+                if target.is_finite() && target > 0. && whole =>
+            {
+                Ok(MeAction::SetGoal {
+                    target,
+                    unit,
+                    period,
+                })
+            }
+            _ => Err(ParseMeError),
+        }
+    }
+}
+
+/// Handles `/me`, showing the user's progress towards their own goal or
+/// changing it.
+pub async fn answer_me(bot: &Bot, msg: &Message, args: &str, connection: &PgPool) {
+    let Some(user) = msg.from() else {
+        error!("Unable to retrieve user from message.");
+        return;
+    };
+    let now = chrono::Utc::now().naive_utc();
+    let set = match args.parse::<MeAction>() {
+        Ok(MeAction::Show) => Ok(()),
+        Ok(MeAction::SetGoal {
+            target,
+            unit,
+            period,
+        }) => {
+            let goal = PersonalGoal {
+                period,
+                target,
+                unit,
+                since: now,
+            };
+            set_personal_goal(msg.chat.id, user.id, &goal, connection).await
+        }
+        Ok(MeAction::ClearGoal) => delete_personal_goal(msg.chat.id, user.id, connection)
+            .await
+            .map(|_| ()),
+        Err(err) => {
+            bot.send_message(msg.chat.id, err.to_string())
+                .await
+                .map_err(|err| error!("Unable to send Me message: {:?}", err))
+                .ok();
+            return;
+        }
+    };
+    let status = match set {
+        Ok(()) => personal_goal_status(msg.chat.id, user.id, now, connection).await,
+        Err(err) => Err(err),
+    };
+    match status {
+        Ok(status) => {
+            let user_name = user.username.as_deref().unwrap_or(&user.first_name);
+            bot.send_message(msg.chat.id, display_me(user_name, status.as_ref()))
+                .parse_mode(ParseMode::Html)
+                .await
+                .map_err(|err| error!("Unable to send Me message: {:?}", err))
+                .ok();
+        }
+        Err(err) => error!("Unable to update personal goal: {:?}", err),
+    }
+}
+
+/// Number of whole periods from the one containing `since` up to, but not
+/// including, the one containing `now`.
+fn periods_between(period: Period, since: NaiveDateTime, now: NaiveDateTime) -> u32 {
+    let (Some(mut start), Some(current)) = (period.start(since), period.start(now)) else {
+        return 0;
+    };
+    let mut periods = 0;
+    while start < current {
+        let Some(next) = period.end(start) else {
+            break;
+        };
+        start = next;
+        periods += 1;
+    }
+    periods
+}
+
+/// A user's goal along with how far they are this period, and how often
+/// they reached it before, as `(reached, periods)`.
+pub struct GoalStatus {
+    /// The user's goal.
+    pub goal: PersonalGoal,
+    /// What they ran this period.
+    pub progress: Progress,
+    /// Periods in which they reached their goal, and periods counted, which
+    /// are the ones since they set it that are over or were reached.
+    pub attainment: (u32, u32),
+    /// Whether their goal was reached this period, for the first time.
+    pub just_reached: bool,
+}
+
+/// Looks up a user's goal and how they are doing, at `now`.
+///
+/// Reaching the goal this period is recorded here, so every change to the
+/// user's runs should look up their goal.
+pub async fn personal_goal_status(
+    chat_id: ChatId,
+    user_id: UserId,
+    now: NaiveDateTime,
+    connection: &PgPool,
+) -> Result<Option<GoalStatus>, sqlx::Error> {
+    let Some(goal) = get_personal_goal(chat_id, user_id, connection).await? else {
+        return Ok(None);
+    };
+    let (Some(start), Some(end)) = (goal.period.start(now), goal.period.end(now)) else {
+        return Ok(None);
+    };
+    let progress = get_progress(chat_id, user_id, start, end, connection).await?;
+    let reached = progress.amount(goal.unit) >= goal.target;
+    let just_reached =
+        reached && record_goal_achievement(chat_id, user_id, &goal, start, connection).await?;
+
+    let first = goal.period.start(goal.since).unwrap_or(start);
+    let achieved =
+        count_goal_achievements(chat_id, user_id, goal.period, first, connection).await?;
+    let periods = periods_between(goal.period, goal.since, now) + u32::from(reached);
+
+    Ok(Some(GoalStatus {
+        attainment: (achieved.min(periods), periods),
+        goal,
+        progress,
+        just_reached,
+    }))
+}
+
+/// Congratulates a user if their latest run took them past their goal.
+pub async fn congratulate_on_goal(
+    chat_id: ChatId,
+    user_id: UserId,
+    connection: &PgPool,
+) -> Option<String> {
+    let now = Utc::now().naive_utc();
+    match personal_goal_status(chat_id, user_id, now, connection).await {
+        Ok(Some(status)) if status.just_reached => Some(format!(
+            "🎯 That's your {} goal of {} reached!",
+            match status.goal.period {
+                Period::Month => "monthly",
+                _ => "weekly",
+            },
+            status.goal.unit.format(status.goal.target)
+        )),
+        Ok(_) => None,
+        Err(err) => {
+            error!("Unable to check personal goal: {:?}", err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_me_actions() {
+        assert_eq!("".parse(), Ok(MeAction::Show));
+        assert_eq!("goal off".parse(), Ok(MeAction::ClearGoal));
+        for (input, target, unit, period) in [
+            ("goal 20km week", 20., GoalUnit::Km, Period::Week),
+            ("goal 21.1 month", 21.1, GoalUnit::Km, Period::Month),
+            ("Goal 4 Runs week", 4., GoalUnit::Runs, Period::Week),
+            ("goal 1run month", 1., GoalUnit::Runs, Period::Month),
+        ] {
+            assert_eq!(
+                input.parse(),
+                Ok(MeAction::SetGoal {
+                    target,
+                    unit,
+                    period,
+                })
+            );
+        }
+        for invalid in [
+            "goal",
+            "goal 20km",
+            "goal 20km year",
+            "goal 2.5 runs week",
+            "goal 20 miles week",
+            "stats",
+        ] {
+            assert_eq!(invalid.parse::<MeAction>(), Err(ParseMeError));
+        }
+    }
+
+    #[test]
+    fn periods_since_goals_were_set() {
+        let day = |month, day| {
+            chrono::NaiveDate::from_ymd_opt(2023, month, day)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap()
+        };
+        assert_eq!(periods_between(Period::Week, day(10, 11), day(10, 15)), 0);
+        assert_eq!(periods_between(Period::Week, day(10, 11), day(10, 30)), 3);
+        assert_eq!(periods_between(Period::Month, day(10, 31), day(11, 1)), 1);
+        assert_eq!(periods_between(Period::Month, day(1, 31), day(10, 1)), 9);
+    }
+
+    #[test]
+    fn parse_goal_actions() {
+        assert_eq!("".parse(), Ok(GoalAction::Show));
+        assert_eq!(
+            "set 500 month".parse(),
+            Ok(GoalAction::Set {
+                distance: 500.0,
+                period: Period::Month,
+            })
+        );
+        assert_eq!(
+            "SET 42.2km week".parse(),
+            Ok(GoalAction::Set {
+                distance: 42.2,
+                period: Period::Week,
+            })
+        );
+        for invalid in [
+            "set 500",
+            "set 500 all",
+            "set -5 week",
+            "set far year",
+            "500",
+        ] {
+            assert_eq!(invalid.parse::<GoalAction>(), Err(ParseGoalError));
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! End-to-end test harness.
+//!
+//! teloxide allows the Bot API url to be overridden, so instead of talking
+//! to Telegram we start a fake Bot API server on localhost. The server hands
+//! scripted updates to `BotService` through `getUpdates` and records every
+//! other call made by the bot, which lets tests assert on the exact replies.
+use crate::{bot::BotService, cache::TallyCache, message::MAX_MESSAGE_LENGTH, provider::Providers};
+use axum::{
+    body::Body,
+    extract::{FromRequest, Multipart, Path, State},
+    http::{header::CONTENT_TYPE, Request},
+    routing::{get, post},
+    Json, Router,
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use teloxide::{prelude::*, update_listeners::Polling};
+use tokio::sync::Notify;
+
+/// Token handed to the bot under test. It is never checked.
+const TEST_TOKEN: &str = "1234:TEST";
+
+/// How long to wait for the bot to make the expected calls.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The bot under test, as seen in messages it has sent.
+const BOT: TestUser = TestUser {
+    id: 1,
+    username: "telerun_test_bot",
+};
+
+/// A Telegram user sending scripted messages.
+pub struct TestUser {
+    /// Telegram user id.
+    pub id: u64,
+    /// Telegram username.
+    pub username: &'static str,
+}
+
+/// A call made by the bot to the fake Bot API.
+#[derive(Clone, Debug)]
+pub struct ApiCall {
+    /// Name of the Bot API method, e.g. `sendMessage`.
+    pub method: String,
+    /// JSON payload of the call. Fields of multipart calls are collected
+    /// into an object.
+    pub body: Value,
+    /// Files uploaded with the call.
+    pub files: Vec<ApiFile>,
+}
+
+/// A file uploaded by the bot, e.g. through `sendDocument`.
+#[derive(Clone, Debug)]
+pub struct ApiFile {
+    /// Parameter the file was sent as, e.g. `document`.
+    pub field: String,
+    /// Name the file was uploaded with.
+    pub file_name: String,
+    /// Contents of the file.
+    pub bytes: Vec<u8>,
+}
+
+/// State shared between the fake Bot API and the test.
+#[derive(Default)]
+struct MockState {
+    /// Scripted updates, served through `getUpdates` until confirmed.
+    updates: Mutex<Vec<Value>>,
+    /// Files users have sent, keyed by file id.
+    files: Mutex<HashMap<String, Vec<u8>>>,
+    /// Calls made by the bot, excluding polling.
+    calls: Mutex<Vec<ApiCall>>,
+    /// Woken up whenever a call is recorded.
+    notify: Notify,
+    /// Counter used for update and message ids.
+    next_id: Mutex<i64>,
+    /// Users who have left a chat, as `(chat_id, user_id)`.
+    left: Mutex<HashSet<(i64, u64)>>,
+}
+
+impl MockState {
+    /// Hands out the next update or message id.
+    fn next_id(&self) -> i64 {
+        let mut next_id = self.next_id.lock().unwrap();
+        *next_id += 1;
+        *next_id
+    }
+}
+
+/// Drives a `BotService` against a fake Bot API server.
+pub struct Harness {
+    /// Address the fake Bot API is listening on.
+    addr: SocketAddr,
+    /// State shared with the fake Bot API.
+    state: Arc<MockState>,
+    /// Database connection handed to the bot.
+    postgres: PgPool,
+    /// Activity providers handed to the bot.
+    providers: Providers,
+}
+
+impl Harness {
+    /// Starts the fake Bot API on an ephemeral port.
+    pub async fn new(postgres: PgPool) -> Self {
+        Self::with_providers(postgres, Providers::default()).await
+    }
+
+    /// Starts the fake Bot API, with `providers` set up in the bot.
+    pub async fn with_providers(postgres: PgPool, providers: Providers) -> Self {
+        let state = Arc::new(MockState::default());
+        let app = Router::new()
+            .route("/:token/:method", post(handle_method))
+            .route("/file/:token/*path", get(download_file))
+            .with_state(state.clone());
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        Harness {
+            addr,
+            state,
+            postgres,
+            providers,
+        }
+    }
+
+    /// A bot talking to the fake Bot API.
+    pub fn bot(&self) -> Bot {
+        Bot::new(TEST_TOKEN).set_api_url(format!("http://{}/", self.addr).parse().unwrap())
+    }
+
+    /// Queues a text message from `user` in `chat_id`.
+    pub fn send(&self, chat_id: i64, user: &TestUser, text: &str) {
+        let mut message = message_json(self.state.next_id(), chat_id, user);
+        message["text"] = json!(text);
+        self.push_update(json!({ "message": message }));
+    }
+
+    /// Queues a document from `user` in `chat_id`, optionally sent in
+    /// reply to a message from the bot reading `reply_to`.
+    pub fn send_document(
+        &self,
+        chat_id: i64,
+        user: &TestUser,
+        reply_to: Option<&str>,
+        file_name: &str,
+        contents: &[u8],
+    ) {
+        let file_id = format!("file{}", self.state.next_id());
+        self.state
+            .files
+            .lock()
+            .unwrap()
+            .insert(file_id.clone(), contents.to_vec());
+
+        let mut message = message_json(self.state.next_id(), chat_id, user);
+        message["document"] = json!({
+            "file_id": file_id,
+            "file_unique_id": file_id,
+            "file_name": file_name,
+            "file_size": contents.len(),
+        });
+        if let Some(reply_to) = reply_to {
+            let mut replied = message_json(self.state.next_id(), chat_id, &BOT);
+            replied["text"] = json!(reply_to);
+            message["reply_to_message"] = replied;
+        }
+        self.push_update(json!({ "message": message }));
+    }
+
+    /// Queues `user` pressing an inline keyboard button carrying `data`,
+    /// attached to a message from the bot in `chat_id`.
+    pub fn press(&self, chat_id: i64, user: &TestUser, data: &str) {
+        let id = self.state.next_id();
+        let mut message = message_json(id, chat_id, &BOT);
+        message["text"] = json!("");
+        self.push_update(json!({
+            "callback_query": {
+                "id": id.to_string(),
+                "from": user_json(user),
+                "chat_instance": chat_id.to_string(),
+                "message": message,
+                "data": data,
+            },
+        }));
+    }
+
+    /// Makes the user with `user_id` leave `chat_id`, as seen through
+    /// `getChatMember`.
+    pub fn leave(&self, chat_id: i64, user_id: u64) {
+        self.state.left.lock().unwrap().insert((chat_id, user_id));
+    }
+
+    /// Queues an update, assigning it the next update id.
+    fn push_update(&self, mut update: Value) {
+        update["update_id"] = json!(self.state.next_id());
+        self.state.updates.lock().unwrap().push(update);
+    }
+
+    /// Runs the bot until it has sent `count` messages, then shuts it down.
+    ///
+    /// Returns the text of every message sent, in order.
+    pub async fn run(&self, count: usize) -> Vec<String> {
+        self.run_until(|calls| sent_messages(calls).len() >= count)
+            .await;
+        sent_messages(&self.calls())
+    }
+
+    /// Runs the bot until `done` returns true for the calls recorded so far.
+    pub async fn run_until(&self, done: impl Fn(&[ApiCall]) -> bool) {
+        let waiting = async {
+            let _ = tokio::time::timeout(REPLY_TIMEOUT, async {
+                loop {
+                    let notified = self.state.notify.notified();
+                    if done(&self.calls()) {
+                        break;
+                    }
+                    notified.await;
+                }
+            })
+            .await;
+        };
+        self.run_until_shutdown(waiting).await;
+
+        assert!(
+            done(&self.calls()),
+            "bot did not make the expected calls, got: {:#?}",
+            self.calls()
+        );
+    }
+
+    /// Runs the bot until `shutdown` resolves, then lets it shut down as it
+    /// would on SIGTERM.
+    pub async fn run_until_shutdown(&self, shutdown: impl Future<Output = ()>) {
+        let bot = self.bot();
+        let service = BotService {
+            bot: bot.clone(),
+            postgres: self.postgres.clone(),
+            providers: self.providers.clone(),
+            tally_cache: Arc::new(TallyCache::default()),
+        };
+        // A zero polling timeout keeps shutdown quick.
+        let listener = Polling::builder(bot).timeout(Duration::ZERO).build();
+        service.serve(listener, shutdown).await;
+    }
+
+    /// Calls made by the bot so far.
+    pub fn calls(&self) -> Vec<ApiCall> {
+        self.state.calls.lock().unwrap().clone()
+    }
+}
+
+/// Extracts every file uploaded through `method`, e.g. `sendDocument`.
+pub fn sent_files(calls: &[ApiCall], method: &str) -> Vec<ApiFile> {
+    calls
+        .iter()
+        .filter(|call| call.method == method)
+        .flat_map(|call| call.files.clone())
+        .collect()
+}
+
+/// Extracts the text of every `sendMessage` call.
+pub fn sent_messages(calls: &[ApiCall]) -> Vec<String> {
+    calls
+        .iter()
+        .filter(|call| call.method == "sendMessage")
+        .map(|call| call.body["text"].as_str().unwrap_or_default().to_string())
+        .collect()
+}
+
+/// Extracts the callback data of every inline keyboard button sent with
+/// `call`, row by row.
+pub fn buttons(call: &ApiCall) -> Vec<String> {
+    call.body["reply_markup"]["inline_keyboard"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|row| row.as_array().into_iter().flatten())
+        .filter_map(|button| button["callback_data"].as_str())
+        .map(str::to_string)
+        .collect()
+}
+
+/// User object of `user`.
+fn user_json(user: &TestUser) -> Value {
+    json!({
+        "id": user.id,
+        "is_bot": user.id == BOT.id,
+        "first_name": user.username,
+        "username": user.username,
+    })
+}
+
+/// Message object without any content, sent by `user` in `chat_id`.
+fn message_json(message_id: i64, chat_id: i64, user: &TestUser) -> Value {
+    json!({
+        "message_id": message_id,
+        "date": 1_700_000_000,
+        "chat": chat_json(chat_id),
+        "from": user_json(user),
+    })
+}
+
+/// Chat object used for scripted updates and echoed messages.
+///
+/// Like on Telegram, positive ids are private chats with the user of the
+/// same id, and negative ids are groups.
+fn chat_json(chat_id: i64) -> Value {
+    if chat_id > 0 {
+        json!({ "id": chat_id, "type": "private", "first_name": "Runner" })
+    } else {
+        json!({ "id": chat_id, "type": "group", "title": "Runners" })
+    }
+}
+
+/// Reads the payload of a call, which is either JSON or multipart.
+async fn read_payload(request: Request<Body>) -> (Value, Vec<ApiFile>) {
+    let is_multipart = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("multipart/form-data"));
+    if !is_multipart {
+        let body = hyper::body::to_bytes(request.into_body())
+            .await
+            .unwrap_or_default();
+        return (serde_json::from_slice(&body).unwrap_or_default(), vec![]);
+    }
+
+    let mut multipart = Multipart::from_request(request, &()).await.unwrap();
+    let mut fields = serde_json::Map::new();
+    let mut files = vec![];
+    while let Some(field) = multipart.next_field().await.unwrap() {
+        let name = field.name().unwrap_or_default().to_string();
+        match field.file_name().map(str::to_string) {
+            Some(file_name) => files.push(ApiFile {
+                field: name,
+                file_name,
+                bytes: field.bytes().await.unwrap().to_vec(),
+            }),
+            None => {
+                let text = field.text().await.unwrap();
+                // Non-string fields such as `chat_id` are sent as JSON.
+                let value = serde_json::from_str(&text).unwrap_or(Value::String(text));
+                fields.insert(name, value);
+            }
+        }
+    }
+
+    // teloxide uploads files under a random name and refers to them from
+    // the parameter they belong to, e.g. `"document": "attach://<name>"`.
+    for file in &mut files {
+        let attachment = format!("attach://{}", file.field);
+        if let Some((parameter, _)) = fields.iter().find(|(_, value)| **value == attachment) {
+            file.field = parameter.clone();
+        }
+    }
+
+    (Value::Object(fields), files)
+}
+
+/// Answers a single Bot API call.
+async fn handle_method(
+    State(state): State<Arc<MockState>>,
+    Path((_token, method)): Path<(String, String)>,
+    request: Request<Body>,
+) -> Json<Value> {
+    let (body, files) = read_payload(request).await;
+    // teloxide names methods in PascalCase, while Telegram documents them in
+    // camelCase. Method names are case insensitive to the Bot API.
+    let method = method[..1].to_lowercase() + &method[1..];
+    // Like Telegram, refuse messages that are too long.
+    let text_length = body["text"]
+        .as_str()
+        .map_or(0, |text| text.encode_utf16().count());
+    if text_length > MAX_MESSAGE_LENGTH {
+        return Json(json!({
+            "ok": false,
+            "error_code": 400,
+            "description": "Bad Request: message is too long",
+        }));
+    }
+    let result = match method.as_str() {
+        "getMe" => {
+            let mut me = user_json(&BOT);
+            me["can_join_groups"] = json!(true);
+            me["can_read_all_group_messages"] = json!(false);
+            me["supports_inline_queries"] = json!(false);
+            me
+        }
+        "getWebhookInfo" => json!({
+            "url": "",
+            "has_custom_certificate": false,
+            "pending_update_count": 0,
+        }),
+        "getFile" => {
+            let file_id = body["file_id"].as_str().unwrap_or_default();
+            let file_size = state.files.lock().unwrap().get(file_id).map(Vec::len);
+            json!({
+                "file_id": file_id,
+                "file_unique_id": file_id,
+                "file_size": file_size,
+                "file_path": format!("documents/{}", file_id),
+            })
+        }
+        "getChatMember" => {
+            let chat_id = body["chat_id"].as_i64().unwrap_or_default();
+            let user_id = body["user_id"].as_u64().unwrap_or_default();
+            let left = state.left.lock().unwrap().contains(&(chat_id, user_id));
+            json!({
+                "user": { "id": user_id, "is_bot": false, "first_name": "member" },
+                "status": if left { "left" } else { "member" },
+            })
+        }
+        "getUpdates" => {
+            let offset = body["offset"].as_i64().unwrap_or_default();
+            let updates = {
+                let mut updates = state.updates.lock().unwrap();
+                // Like Telegram, forget updates once they are confirmed through
+                // the offset, so that they are not handed out again on restart.
+                updates.retain(|update| update["update_id"].as_i64().unwrap_or_default() >= offset);
+                updates.clone()
+            };
+            if updates.is_empty() {
+                // Avoid spinning, polling runs with a zero timeout.
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            json!(updates)
+        }
+        _ => {
+            let message_id = body["message_id"]
+                .as_i64()
+                .unwrap_or_else(|| state.next_id());
+            let chat_id = body["chat_id"].as_i64().unwrap_or_default();
+            let mut message = message_json(message_id, chat_id, &BOT);
+            let result = match method.as_str() {
+                "sendMessage" | "editMessageText" => {
+                    message["text"] = body["text"].clone();
+                    message
+                }
+                "sendDocument" => {
+                    message["document"] =
+                        json!({ "file_id": "document", "file_unique_id": "document" });
+                    message
+                }
+                "sendPhoto" => {
+                    message["photo"] = json!([{
+                        "file_id": "photo",
+                        "file_unique_id": "photo",
+                        "width": 800,
+                        "height": 480,
+                    }]);
+                    message
+                }
+                _ => json!(true),
+            };
+            state.calls.lock().unwrap().push(ApiCall {
+                method,
+                body,
+                files,
+            });
+            state.notify.notify_waiters();
+            result
+        }
+    };
+
+    Json(json!({ "ok": true, "result": result }))
+}
+
+/// Serves a file a user has sent.
+async fn download_file(
+    State(state): State<Arc<MockState>>,
+    Path((_token, path)): Path<(String, String)>,
+) -> Vec<u8> {
+    let file_id = path.rsplit('/').next().unwrap_or_default();
+    state
+        .files
+        .lock()
+        .unwrap()
+        .get(file_id)
+        .cloned()
+        .unwrap_or_default()
+}
@@ -0,0 +1,485 @@
+//! Imports of historical runs from CSV files.
+//!
+//! Imports happen in two steps. The uploaded file is first validated row by
+//! row and summarised in a preview. The valid runs are kept in
+//! `PendingImports` until the uploader confirms, at which point they are
+//! bulk-inserted through `add_runs`. Imports left unconfirmed are dropped
+//! after `PENDING_IMPORT_TTL`.
+use crate::models::{NewRun, User};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use teloxide::types::{ChatId, UserId};
+
+/// Message the bot replies to `/import` with. Documents sent in reply to it
+/// are treated as imports.
+pub const IMPORT_PROMPT: &str = "Reply to this message with a CSV file of runs to import. \
+The file needs a header row with the columns user, distance (in km) and date \
+(e.g. 2023-10-14 or 2023-10-14 07:12). Files from /export can be imported as they are.";
+
+/// Largest distance in km accepted for a single run.
+const MAX_DISTANCE: f32 = 1000.;
+
+/// Most rejected rows listed in a preview, which has to fit in a single
+/// message. The rest are only counted.
+const MAX_REJECTED_SHOWN: usize = 20;
+
+/// Most users named in a preview. The rest are only counted.
+const MAX_USERS_SHOWN: usize = 10;
+
+/// Longest value from the file quoted in the reason a row was rejected,
+/// in characters.
+const MAX_QUOTED_LENGTH: usize = 32;
+
+/// How long an import waits to be confirmed before it is dropped.
+const PENDING_IMPORT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Most imports waiting to be confirmed at once. The oldest ones are
+/// dropped to make room for new ones.
+const MAX_PENDING_IMPORTS: usize = 100;
+
+/// Header names accepted for each column, lowercased.
+const USER_COLUMNS: [&str; 2] = ["user", "user_name"];
+/// Header names accepted for the distance column, lowercased.
+const DISTANCE_COLUMNS: [&str; 1] = ["distance"];
+/// Header names accepted for the date column, lowercased.
+const DATE_COLUMNS: [&str; 3] = ["date", "run_datetime", "datetime"];
+
+/// A row of the file that could not be imported.
+#[derive(Debug, PartialEq)]
+pub struct RejectedRow {
+    /// Line number within the file, the header being line 1.
+    pub line: u64,
+    /// Why the row was rejected.
+    pub reason: String,
+}
+
+/// Outcome of validating an uploaded file.
+pub struct ParsedImport {
+    /// Runs that passed validation.
+    pub runs: Vec<NewRun>,
+    /// Rows that did not.
+    pub rejected: Vec<RejectedRow>,
+}
+
+/// Parses and validates a CSV file of runs.
+///
+/// Runs can only be imported for users already registered in the chat,
+/// since a user's Telegram id is needed to tie their runs to them.
+/// Usernames are matched case insensitively, with or without a leading `@`.
+pub fn parse_import(file: &[u8], users: &[User]) -> Result<ParsedImport, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(file);
+    let headers: Vec<String> = reader
+        .headers()
+        .map_err(|error| format!("Unable to read the header row: {}", error))?
+        .iter()
+        .map(str::to_lowercase)
+        .collect();
+    let column = |names: &[&str], description: &str| {
+        headers
+            .iter()
+            .position(|header| names.contains(&header.as_str()))
+            .ok_or(format!("The header row has no {} column.", description))
+    };
+    let user_column = column(&USER_COLUMNS, "user")?;
+    let distance_column = column(&DISTANCE_COLUMNS, "distance")?;
+    let date_column = column(&DATE_COLUMNS, "date")?;
+
+    let users: HashMap<String, &User> = users
+        .iter()
+        .map(|user| (user.user_name.to_lowercase(), user))
+        .collect();
+    let now = Utc::now().naive_utc();
+    let mut runs = vec![];
+    let mut rejected = vec![];
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(error) => {
+                let line = error
+                    .position()
+                    .map(|position| position.line())
+                    .unwrap_or(0);
+                rejected.push(RejectedRow {
+                    line,
+                    reason: "unreadable row".into(),
+                });
+                continue;
+            }
+        };
+        let line = record
+            .position()
+            .map(|position| position.line())
+            .unwrap_or(0);
+        let field = |column| record.get(column).unwrap_or_default();
+
+        let user_name = field(user_column).trim_start_matches('@');
+        let Some(user) = users.get(&user_name.to_lowercase()) else {
+            let reason = if user_name.is_empty() {
+                "missing user".into()
+            } else {
+                format!(
+                    "unknown user \"{}\", they need to /add a run first",
+                    shorten(user_name)
+                )
+            };
+            rejected.push(RejectedRow { line, reason });
+            continue;
+        };
+        let Ok(telegram_userid) = user.telegram_userid.parse() else {
+            rejected.push(RejectedRow {
+                line,
+                reason: format!("user \"{}\" has no valid Telegram id", shorten(user_name)),
+            });
+            continue;
+        };
+
+        let distance = match field(distance_column).parse::<f32>() {
+            Ok(distance) if distance > 0. && distance <= MAX_DISTANCE => distance,
+            _ => {
+                rejected.push(RejectedRow {
+                    line,
+                    reason: format!("invalid distance \"{}\"", shorten(field(distance_column))),
+                });
+                continue;
+            }
+        };
+
+        let run_datetime = match parse_date(field(date_column)) {
+            Some(run_datetime) if run_datetime <= now => run_datetime,
+            Some(_) => {
+                rejected.push(RejectedRow {
+                    line,
+                    reason: format!("date \"{}\" is in the future", shorten(field(date_column))),
+                });
+                continue;
+            }
+            None => {
+                rejected.push(RejectedRow {
+                    line,
+                    reason: format!("invalid date \"{}\"", shorten(field(date_column))),
+                });
+                continue;
+            }
+        };
+
+        runs.push(NewRun {
+            user_name: user.user_name.clone(),
+            telegram_userid: UserId(telegram_userid),
+            distance,
+            run_datetime: Some(run_datetime),
+        });
+    }
+
+    Ok(ParsedImport { runs, rejected })
+}
+
+/// Cuts a value from the file short enough to be quoted in a preview.
+fn shorten(value: &str) -> String {
+    match value.char_indices().nth(MAX_QUOTED_LENGTH) {
+        Some((end, _)) => format!("{}…", &value[..end]),
+        None => value.into(),
+    }
+}
+
+/// Parses dates with or without a time of day.
+fn parse_date(date: &str) -> Option<NaiveDateTime> {
+    const DATETIME_FORMATS: [&str; 4] = [
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M",
+    ];
+    DATETIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+}
+
+/// Summarises a parsed import for the uploader to confirm.
+///
+/// Only the first `MAX_USERS_SHOWN` users and `MAX_REJECTED_SHOWN`
+/// rejected rows are listed, so that the preview fits in a message however
+/// large the file is.
+pub fn preview_import(import: &ParsedImport) -> String {
+    let mut preview = String::new();
+    if import.runs.is_empty() {
+        preview.push_str("No runs can be imported from this file.\n");
+    } else {
+        let mut users: Vec<&str> = import
+            .runs
+            .iter()
+            .map(|run| run.user_name.as_str())
+            .collect();
+        users.sort_unstable();
+        users.dedup();
+        let total: f32 = import.runs.iter().map(|run| run.distance).sum();
+        let dates = import.runs.iter().filter_map(|run| run.run_datetime);
+        let first = dates.clone().min().unwrap_or_default().date();
+        let last = dates.max().unwrap_or_default().date();
+        let mut named = users
+            .iter()
+            .take(MAX_USERS_SHOWN)
+            .copied()
+            .collect::<Vec<_>>()
+            .join(", ");
+        if users.len() > MAX_USERS_SHOWN {
+            let _ = write!(named, " and {} others", users.len() - MAX_USERS_SHOWN);
+        }
+        let _ = writeln!(
+            preview,
+            "Ready to import {} runs totalling {}km by {} from {} to {}.",
+            import.runs.len(),
+            total,
+            named,
+            first,
+            last
+        );
+    }
+
+    if !import.rejected.is_empty() {
+        let _ = writeln!(preview, "{} rows will be skipped:", import.rejected.len());
+        for row in import.rejected.iter().take(MAX_REJECTED_SHOWN) {
+            let _ = writeln!(preview, "Line {}: {}", row.line, row.reason);
+        }
+        if import.rejected.len() > MAX_REJECTED_SHOWN {
+            let _ = writeln!(
+                preview,
+                "…and {} more.",
+                import.rejected.len() - MAX_REJECTED_SHOWN
+            );
+        }
+    }
+
+    preview
+}
+
+/// An import waiting to be confirmed.
+pub struct PendingImport {
+    /// Chat the runs are imported into.
+    pub chat_id: ChatId,
+    /// User who uploaded the file, the only one allowed to confirm it.
+    pub uploader: UserId,
+    /// Runs to be inserted.
+    pub runs: Vec<NewRun>,
+}
+
+/// Imports waiting to be confirmed, keyed by an id handed out on insert.
+///
+/// Imports expire after `PENDING_IMPORT_TTL`, and at most
+/// `MAX_PENDING_IMPORTS` are kept.
+#[derive(Default)]
+pub struct PendingImports {
+    /// Pending imports, along with when they were stored.
+    imports: Mutex<HashMap<u64, (Instant, PendingImport)>>,
+    /// Id of the next import.
+    next_id: AtomicU64,
+}
+
+impl PendingImports {
+    /// Stores an import and returns its id, dropping imports that have
+    /// expired by `now` and, if there are still too many, the oldest ones.
+    pub fn insert(&self, import: PendingImport, now: Instant) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut imports = self.imports.lock().unwrap();
+        imports.retain(|_, (stored, _)| now.duration_since(*stored) < PENDING_IMPORT_TTL);
+        while imports.len() >= MAX_PENDING_IMPORTS {
+            // Ids are handed out in order, so the smallest is the oldest.
+            let Some(oldest) = imports.keys().min().copied() else {
+                break;
+            };
+            imports.remove(&oldest);
+        }
+        imports.insert(id, (now, import));
+        id
+    }
+
+    /// Removes and returns an import, if `user` is allowed to act on it and
+    /// it has not expired by `now`.
+    pub fn take(&self, id: u64, user: UserId, now: Instant) -> Option<PendingImport> {
+        let mut imports = self.imports.lock().unwrap();
+        match imports.get(&id) {
+            Some((stored, import))
+                if import.uploader == user && now.duration_since(*stored) < PENDING_IMPORT_TTL =>
+            {
+                imports.remove(&id).map(|(_, import)| import)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MAX_MESSAGE_LENGTH;
+
+    fn users() -> Vec<User> {
+        vec![
+            User {
+                id: 1,
+                telegram_userid: "11".into(),
+                chat_id: "-100".into(),
+                user_name: "reuben".into(),
+            },
+            User {
+                id: 2,
+                telegram_userid: "22".into(),
+                chat_id: "-100".into(),
+                user_name: "Milton".into(),
+            },
+        ]
+    }
+
+    #[test]
+    fn parse_valid_and_rejected_rows() {
+        let file = "user,distance,date
+reuben,5,2023-10-01
+@milton, 2.5 ,2023-10-02 07:12
+bob,3,2023-10-03
+reuben,-1,2023-10-04
+reuben,abc,2023-10-04
+milton,4,yesterday
+milton,4,2999-01-01
+";
+        let import = parse_import(file.as_bytes(), &users()).unwrap();
+
+        assert_eq!(import.runs.len(), 2);
+        assert_eq!(import.runs[1].user_name, "Milton");
+        assert_eq!(import.runs[1].telegram_userid, UserId(22));
+        assert_eq!(import.runs[1].distance, 2.5);
+        assert_eq!(
+            import.runs[1].run_datetime,
+            NaiveDate::from_ymd_opt(2023, 10, 2)
+                .unwrap()
+                .and_hms_opt(7, 12, 0)
+        );
+        let lines: Vec<u64> = import.rejected.iter().map(|row| row.line).collect();
+        assert_eq!(lines, vec![4, 5, 6, 7, 8]);
+        assert_eq!(
+            import.rejected[0].reason,
+            "unknown user \"bob\", they need to /add a run first"
+        );
+    }
+
+    #[test]
+    fn parse_exported_file() {
+        let file = "run_id,user_name,distance,duration_secs,run_datetime
+1,reuben,5.0,1800,2023-10-01T07:12:00
+";
+        let import = parse_import(file.as_bytes(), &users()).unwrap();
+
+        assert_eq!(import.runs.len(), 1);
+        assert!(import.rejected.is_empty());
+    }
+
+    #[test]
+    fn missing_columns() {
+        let error = parse_import("user,date\n".as_bytes(), &users())
+            .err()
+            .unwrap();
+        assert_eq!(error, "The header row has no distance column.");
+    }
+
+    #[test]
+    fn preview() {
+        let file = "user,distance,date
+reuben,5,2023-10-01
+milton,2.5,2023-10-03
+bob,3,2023-10-03
+";
+        let import = parse_import(file.as_bytes(), &users()).unwrap();
+        let ans = "Ready to import 2 runs totalling 7.5km by Milton, reuben from 2023-10-01 to 2023-10-03.
+1 rows will be skipped:
+Line 4: unknown user \"bob\", they need to /add a run first
+";
+        assert_eq!(preview_import(&import), ans);
+    }
+
+    #[test]
+    fn preview_of_many_rejected_rows_fits_in_a_message() {
+        let mut file = String::from("user,distance,date\nreuben,5,2023-10-01\n");
+        for member in 0..500 {
+            let _ = writeln!(file, "member_with_a_long_name_{},5,2023-10-01", member);
+        }
+        let import = parse_import(file.as_bytes(), &users()).unwrap();
+        assert_eq!(import.rejected.len(), 500);
+
+        let preview = preview_import(&import);
+        assert!(preview.encode_utf16().count() <= MAX_MESSAGE_LENGTH);
+        assert_eq!(preview.lines().count(), 2 + MAX_REJECTED_SHOWN + 1);
+        assert!(preview.ends_with("\n…and 480 more.\n"));
+    }
+
+    #[test]
+    fn long_values_are_shortened() {
+        let file = format!(
+            "user,distance,date\nreuben,{},2023-10-01\n",
+            "9".repeat(5000)
+        );
+        let import = parse_import(file.as_bytes(), &users()).unwrap();
+
+        assert_eq!(
+            import.rejected[0].reason,
+            format!("invalid distance \"{}…\"", "9".repeat(MAX_QUOTED_LENGTH))
+        );
+    }
+
+    fn pending_import() -> PendingImport {
+        PendingImport {
+            chat_id: ChatId(-100),
+            uploader: UserId(11),
+            runs: vec![],
+        }
+    }
+
+    #[test]
+    fn only_uploader_can_take_import() {
+        let imports = PendingImports::default();
+        let now = Instant::now();
+        let id = imports.insert(pending_import(), now);
+
+        assert!(imports.take(id, UserId(22), now).is_none());
+        assert!(imports.take(id, UserId(11), now).is_some());
+        assert!(imports.take(id, UserId(11), now).is_none());
+    }
+
+    #[test]
+    fn pending_imports_expire() {
+        let imports = PendingImports::default();
+        let now = Instant::now();
+        let expired = imports.insert(pending_import(), now);
+        let later = now + PENDING_IMPORT_TTL;
+
+        assert!(imports.take(expired, UserId(11), later).is_none());
+        imports.insert(pending_import(), later);
+        assert_eq!(imports.imports.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn oldest_pending_imports_make_room() {
+        let imports = PendingImports::default();
+        let now = Instant::now();
+        let ids: Vec<u64> = (0..=MAX_PENDING_IMPORTS)
+            .map(|_| imports.insert(pending_import(), now))
+            .collect();
+
+        assert_eq!(imports.imports.lock().unwrap().len(), MAX_PENDING_IMPORTS);
+        assert!(imports.take(ids[0], UserId(11), now).is_none());
+        assert!(imports.take(ids[1], UserId(11), now).is_some());
+    }
+}
@@ -0,0 +1,348 @@
+//! Virtual journeys along bundled routes, with `/journey`.
+//!
+//! A chat sets off along a route, such as Singapore to Kuala Lumpur, and
+//! every km its members run from then on takes it further along. Routes
+//! are lists of waypoints with their distance from the start, bundled from
+//! the `routes` directory. Every landmark is announced once, as the chat
+//! passes it.
+use crate::{
+    database::{
+        get_journey, get_total_distance, mark_landmark_reached, start_journey, stop_journey,
+    },
+    message::{display_journey, display_landmark, display_routes},
+    models::Journey,
+};
+use chrono::{Duration, Utc};
+use sqlx::PgPool;
+use std::{error::Error, fmt, str::FromStr};
+use teloxide::{prelude::*, types::ParseMode};
+use tracing::{error, info};
+
+/// Routes bundled with the bot, by id, as CSV files of waypoints with a
+/// `distance` from the start in km and a `name`.
+const ROUTES: [(&str, &str); 2] = [
+    (
+        "london-brighton",
+        include_str!("../routes/london-brighton.csv"),
+    ),
+    (
+        "singapore-kuala-lumpur",
+        include_str!("../routes/singapore-kuala-lumpur.csv"),
+    ),
+];
+
+/// A named place along a route.
+#[derive(Debug, PartialEq)]
+pub struct Waypoint {
+    /// Name of the place.
+    pub name: String,
+    /// Distance from the start of the route, in km.
+    pub distance: f32,
+}
+
+/// A route a chat can run along, from its first waypoint to its last.
+#[derive(Debug, PartialEq)]
+pub struct Route {
+    /// Id the route is started with, e.g. `singapore-kuala-lumpur`.
+    pub id: &'static str,
+    /// Waypoints in order, the first being the start.
+    pub waypoints: Vec<Waypoint>,
+}
+
+impl Route {
+    /// Name of the route, e.g. `Singapore to Kuala Lumpur`.
+    pub fn name(&self) -> String {
+        format!(
+            "{} to {}",
+            self.waypoints[0].name,
+            self.waypoints[self.waypoints.len() - 1].name
+        )
+    }
+
+    /// Length of the route, in km.
+    pub fn length(&self) -> f32 {
+        self.waypoints[self.waypoints.len() - 1].distance
+    }
+
+    /// Index of the furthest waypoint reached having run `done` km.
+    pub fn reached(&self, done: f32) -> usize {
+        self.waypoints
+            .iter()
+            .rposition(|waypoint| waypoint.distance <= done)
+            .unwrap_or(0)
+    }
+}
+
+/// Reads a bundled route. Bundled routes are checked by tests, so an
+/// invalid one is a bug.
+fn parse_route(id: &'static str, data: &str) -> Route {
+    let waypoints = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(data.as_bytes())
+        .records()
+        .map(|record| {
+            let record = record.expect("bundled routes are valid CSV");
+            Waypoint {
+                name: record[1].to_string(),
+                distance: record[0].parse().expect("bundled distances are numbers"),
+            }
+        })
+        .collect();
+    Route { id, waypoints }
+}
+
+/// The routes bundled with the bot.
+pub fn routes() -> Vec<Route> {
+    ROUTES
+        .iter()
+        .map(|(id, data)| parse_route(id, data))
+        .collect()
+}
+
+/// Finds a bundled route by its id, ignoring case.
+pub fn find_route(id: &str) -> Option<Route> {
+    ROUTES
+        .iter()
+        .find(|(route, _)| route.eq_ignore_ascii_case(id))
+        .map(|(id, data)| parse_route(id, data))
+}
+
+/// What `/journey` was asked to do.
+#[derive(Debug, PartialEq)]
+pub enum JourneyAction {
+    /// Show how far along its route the chat is.
+    Show,
+    /// List the routes the chat can run along.
+    Routes,
+    /// Set off along the route with the given id, starting over.
+    Start(String),
+    /// End the chat's journey.
+    Stop,
+}
+
+/// Error returned when the arguments of `/journey` cannot be read.
+#[derive(Debug, PartialEq)]
+pub struct ParseJourneyError;
+
+impl fmt::Display for ParseJourneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Expected /journey [routes|start <route>|stop], e.g. /journey start singapore-kuala-lumpur."
+        )
+    }
+}
+
+impl Error for ParseJourneyError {}
+
+/// Parses the arguments of `/journey [routes|start <route>|stop]`.
+impl FromStr for JourneyAction {
+    type Err = ParseJourneyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let arguments: Vec<&str> = s.split_whitespace().collect();
+        match &arguments[..] {
+            [] => Ok(JourneyAction::Show),
+            [action] if action.eq_ignore_ascii_case("routes") => Ok(JourneyAction::Routes),
+            [action] if action.eq_ignore_ascii_case("stop") => Ok(JourneyAction::Stop),
+            [action, route] if action.eq_ignore_ascii_case("start") => {
+                Ok(JourneyAction::Start(route.to_lowercase()))
+            }
+            _ => Err(ParseJourneyError),
+        }
+    }
+}
+
+/// Handles `/journey`, showing how far along its route the chat is,
+/// listing routes, or starting or stopping a journey.
+pub async fn answer_journey(bot: &Bot, chat_id: ChatId, args: &str, connection: &PgPool) {
+    let now = chrono::Utc::now().naive_utc();
+    let started = match args.parse::<JourneyAction>() {
+        Ok(JourneyAction::Show) => Ok(()),
+        Ok(JourneyAction::Routes) => {
+            bot.send_message(chat_id, display_routes(&routes()))
+                .parse_mode(ParseMode::Html)
+                .await
+                .map_err(|err| error!("Unable to send Journey message: {:?}", err))
+                .ok();
+            return;
+        }
+        Ok(JourneyAction::Start(route)) => match find_route(&route) {
+            Some(route) => {
+                let journey = Journey {
+                    route: route.id.to_string(),
+                    starts: now,
+                    reached: 0,
+                };
+                start_journey(chat_id, &journey, connection).await
+            }
+            None => {
+                bot.send_message(
+                    chat_id,
+                    format!("No route called {}. See /journey routes.", route),
+                )
+                .await
+                .map_err(|err| error!("Unable to send Journey message: {:?}", err))
+                .ok();
+                return;
+            }
+        },
+        Ok(JourneyAction::Stop) => {
+            let reply = match stop_journey(chat_id, connection).await {
+                Ok(true) => "Journey stopped.",
+                Ok(false) => "No journey under way.",
+                Err(err) => {
+                    error!("Unable to stop journey: {:?}", err);
+                    return;
+                }
+            };
+            bot.send_message(chat_id, reply)
+                .await
+                .map_err(|err| error!("Unable to send Journey message: {:?}", err))
+                .ok();
+            return;
+        }
+        Err(err) => {
+            bot.send_message(chat_id, err.to_string())
+                .await
+                .map_err(|err| error!("Unable to send Journey message: {:?}", err))
+                .ok();
+            return;
+        }
+    };
+
+    let journey = match started {
+        Ok(()) => get_journey(chat_id, connection).await,
+        Err(err) => Err(err),
+    };
+    let reply = match journey {
+        Ok(Some(journey)) => match find_route(&journey.route) {
+            Some(route) => journey_distance(chat_id, &journey, connection)
+                .await
+                .map(|done| display_journey(&route, &journey, done)),
+            None => Ok("This journey's route is no longer available. See /journey routes.".into()),
+        },
+        Ok(None) => Ok("No journey under way. See /journey routes to pick one.".into()),
+        Err(err) => Err(err),
+    };
+    match reply {
+        Ok(reply) => {
+            bot.send_message(chat_id, reply)
+                .parse_mode(ParseMode::Html)
+                .await
+                .map_err(|err| error!("Unable to send Journey message: {:?}", err))
+                .ok();
+        }
+        Err(err) => error!("Unable to update journey: {:?}", err),
+    }
+}
+
+/// Sums up the distance a chat ran since setting off on its journey.
+///
+/// Runs dated up to a day ahead count too, so that runs added by clocks
+/// running slightly fast are not left out.
+pub async fn journey_distance(
+    chat_id: ChatId,
+    journey: &Journey,
+    connection: &PgPool,
+) -> Result<f32, sqlx::Error> {
+    let until = Utc::now().naive_utc() + Duration::days(1);
+    get_total_distance(chat_id, journey.starts, until, connection).await
+}
+
+/// Announces the furthest landmark the chat has passed on its journey, if
+/// it has not been announced yet.
+///
+/// Called whenever runs are added. Landmarks passed together are
+/// announced once, by the furthest of them.
+pub async fn announce_landmarks(bot: &Bot, chat_id: ChatId, connection: &PgPool) {
+    let (journey, route) = match get_journey(chat_id, connection).await {
+        Ok(Some(journey)) => match find_route(&journey.route) {
+            Some(route) => (journey, route),
+            None => return,
+        },
+        Ok(None) => return,
+        Err(err) => {
+            error!("Unable to retrieve journey: {:?}", err);
+            return;
+        }
+    };
+    let reached = match journey_distance(chat_id, &journey, connection).await {
+        Ok(done) => route.reached(done),
+        Err(err) => {
+            error!("Unable to retrieve distance along journey: {:?}", err);
+            return;
+        }
+    };
+    if reached as u32 <= journey.reached {
+        return;
+    }
+
+    match mark_landmark_reached(chat_id, reached as u32, connection).await {
+        Ok(true) => {
+            info!(
+                "[announce_landmarks]: chat_id: {}, route: {}, reached: {}",
+                chat_id, route.id, reached
+            );
+            bot.send_message(chat_id, display_landmark(&route, reached))
+                .await
+                .map_err(|err| error!("Unable to send Journey message: {:?}", err))
+                .ok();
+        }
+        Ok(false) => {}
+        Err(err) => error!("Unable to mark landmark as reached: {:?}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_routes_are_valid() {
+        for route in routes() {
+            assert!(route.waypoints.len() >= 2, "{} is too short", route.id);
+            assert_eq!(route.waypoints[0].distance, 0.);
+            assert!(
+                route
+                    .waypoints
+                    .windows(2)
+                    .all(|pair| pair[0].distance < pair[1].distance),
+                "{} has waypoints out of order",
+                route.id
+            );
+        }
+        let route = find_route("Singapore-Kuala-Lumpur").unwrap();
+        assert_eq!(route.name(), "Singapore to Kuala Lumpur");
+        assert_eq!(route.length(), 350.);
+        assert_eq!(find_route("singapore"), None);
+    }
+
+    #[test]
+    fn waypoints_are_reached_by_distance() {
+        let route = find_route("london-brighton").unwrap();
+        assert_eq!(route.reached(0.), 0);
+        assert_eq!(route.reached(15.9), 0);
+        assert_eq!(route.reached(16.), 1);
+        assert_eq!(route.reached(40.), 3);
+        assert_eq!(route.reached(500.), route.waypoints.len() - 1);
+    }
+
+    #[test]
+    fn parse_journey_actions() {
+        for (input, action) in [
+            ("", JourneyAction::Show),
+            ("Routes", JourneyAction::Routes),
+            ("stop", JourneyAction::Stop),
+            (
+                "start London-Brighton",
+                JourneyAction::Start("london-brighton".into()),
+            ),
+        ] {
+            assert_eq!(input.parse(), Ok(action));
+        }
+        for invalid in ["start", "start london brighton", "go"] {
+            assert_eq!(invalid.parse::<JourneyAction>(), Err(ParseJourneyError));
+        }
+    }
+}
@@ -7,14 +7,35 @@
 //! Shuttle provisions infrastructure from our infrastructure as code
 //! that is used in this codebase.
 
+mod activity;
 mod bot;
+mod cache;
+mod challenge;
+mod chart;
 mod database;
+mod export;
+mod fit;
+mod followup;
+mod goal;
+#[cfg(test)]
+mod harness;
+mod import;
+mod journey;
 mod message;
 mod models;
+mod profile;
+mod provider;
+mod recap;
+mod record;
+mod reminder;
+mod team;
+mod webhook;
 
 use bot::BotService;
+use provider::{Providers, Strava, StravaConfig, STRAVA_API_URL, STRAVA_AUTHORIZE_URL};
 use shuttle_secrets::SecretStore;
 use sqlx::PgPool;
+use std::sync::Arc;
 use teloxide::prelude::*;
 
 /// Entry point to the telegram bot service.
@@ -24,6 +45,7 @@ use teloxide::prelude::*;
 /// as the first step as well.
 ///
 /// Next, we load in our telegram bot's key and as it is a requirement for teloxide.
+/// Strava is set up as an activity provider if `STRAVA_CLIENT_ID` is set.
 ///
 /// Finally, we start our service.
 #[shuttle_runtime::main]
@@ -40,8 +62,36 @@ async fn shuttle_main(
         .get("TELOXIDE_TOKEN")
         .expect("TELOXIDE_TOKEN needs to be set.");
 
+    let mut providers = Providers::default();
+    if let Some(client_id) = secrets.get("STRAVA_CLIENT_ID") {
+        let strava_secret = |key: &str| {
+            secrets
+                .get(key)
+                .unwrap_or_else(|| panic!("{} needs to be set along with STRAVA_CLIENT_ID.", key))
+        };
+        providers = providers.with(Strava::new(StravaConfig {
+            api_url: secrets
+                .get("STRAVA_API_URL")
+                .unwrap_or_else(|| STRAVA_API_URL.into()),
+            authorize_url: secrets
+                .get("STRAVA_AUTHORIZE_URL")
+                .unwrap_or_else(|| STRAVA_AUTHORIZE_URL.into())
+                .parse()
+                .expect("STRAVA_AUTHORIZE_URL needs to be a url."),
+            client_id,
+            client_secret: strava_secret("STRAVA_CLIENT_SECRET"),
+            redirect_url: strava_secret("STRAVA_REDIRECT_URL"),
+            verify_token: strava_secret("STRAVA_VERIFY_TOKEN"),
+            subscription_id: strava_secret("STRAVA_SUBSCRIPTION_ID")
+                .parse()
+                .expect("STRAVA_SUBSCRIPTION_ID needs to be a number."),
+        }));
+    }
+
     Ok(BotService {
         bot: Bot::new(teloxide_key),
         postgres,
+        providers,
+        tally_cache: Arc::new(Default::default()),
     })
 }
@@ -2,81 +2,206 @@
 //!
 //! This module reads in Rust-native objects and renders
 //! them as `String`s using [askama](https://crates.io/crates/askama/0.7.2)
-//! as the templating engine.
+//! as the templating engine. Messages are formatted as
+//! [Telegram HTML](https://core.telegram.org/bots/api#html-style), with
+//! tables in monospace so that their columns line up on any screen.
 
-use crate::models::{Run, Score, User};
+use crate::{
+    activity::format_duration,
+    goal::GoalStatus,
+    journey::Route,
+    models::{
+        Challenge, Goal, Improvement, Journey, Outcome, Period, RankEmojis, Recap, Run, Score,
+        Standings, Streak, TallyGrouping, Team, User,
+    },
+    profile::ProfileStats,
+    record::{NewRecord, PersonalBest},
+};
 use askama::Template;
-use std::fmt;
-use std::ops;
-
-/// NewType implementation so that Display can be implemented for it.
-struct RunDisplay(Run);
-
-/// Deref is implemented so that accessing `Run`'s contents is easier.
-impl ops::Deref for RunDisplay {
-    type Target = Run;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl fmt::Display for RunDisplay {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // No longer need to access via self.0.id, since we deref
-        // a RunDisplay into a Run!
-        write!(
-            f,
-            "{} {} {} {}",
-            self.id,
-            self.distance,
-            self.run_datetime
-                .map(|x| x.to_string())
-                .unwrap_or("NULL".to_string()),
-            self.user_id
-        )
-    }
+use chrono::NaiveDateTime;
+use teloxide::types::UserId;
+
+/// Pads every column to its widest cell, so that rows line up when shown
+/// in monospace.
+///
+/// Columns listed in `right_aligned` are padded on the left, which suits
+/// numbers. Columns are separated by two spaces.
+fn align_columns(rows: &[Vec<String>], right_aligned: &[usize]) -> Vec<String> {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or_default();
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    rows.iter()
+        .map(|row| {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .enumerate()
+                .map(|(column, (cell, &width))| {
+                    if right_aligned.contains(&column) {
+                        format!("{:>width$}", cell)
+                    } else {
+                        format!("{:<width$}", cell)
+                    }
+                })
+                .collect();
+            cells.join("  ").trim_end().to_string()
+        })
+        .collect()
 }
 
-impl fmt::Display for User {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", self.id, self.user_name)
-    }
+/// Longest message Telegram accepts, in UTF-16 code units.
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
+/// Room kept free on every page for its footer, such as the page number.
+const FOOTER_LENGTH: usize = 512;
+/// Most rows on a page of the tally or of the list of users.
+const ROWS_PER_PAGE: usize = 25;
+
+/// A page of a message that may be too long to be sent at once.
+#[derive(Debug, PartialEq)]
+pub struct Page {
+    /// Rendered page.
+    pub text: String,
+    /// Index of the page, counting from 0.
+    pub index: usize,
+    /// Number of pages.
+    pub count: usize,
+    /// Number of rows on the page.
+    pub rows: usize,
 }
 
-impl fmt::Display for Score {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{} {}🏅 {}km",
-            self.user_name, self.medals, self.distance
-        )
+/// Splits `rows` into pages of at most `per_page` rows, each short enough
+/// to fit in a message, footer included, once rendered by `render`.
+///
+/// There is always at least one page, even if it is empty.
+fn paginate<T>(rows: &[T], per_page: usize, render: impl Fn(&[T]) -> String) -> Vec<&[T]> {
+    let fits =
+        |rows: &[T]| render(rows).encode_utf16().count() + FOOTER_LENGTH <= MAX_MESSAGE_LENGTH;
+    let mut pages = vec![];
+    let mut start = 0;
+    while start < rows.len() {
+        let mut end = (start + per_page).min(rows.len());
+        // A single row that is too long is still shown on its own page.
+        while end > start + 1 && !fits(&rows[start..end]) {
+            end -= 1;
+        }
+        pages.push(&rows[start..end]);
+        start = end;
     }
+    if pages.is_empty() {
+        pages.push(rows);
+    }
+    pages
+}
+
+/// Splits plain text that may be too long to be sent at once into
+/// messages, between its lines.
+pub fn split_message(text: &str) -> Vec<String> {
+    let lines: Vec<&str> = text.lines().collect();
+    paginate(&lines, lines.len(), |lines| lines.join("\n"))
+        .into_iter()
+        .map(|lines| lines.join("\n"))
+        .collect()
+}
+
+/// A line of the list of runs.
+struct RunRow<'a> {
+    /// Run the line is for.
+    run: &'a Run,
+    /// User who added the run.
+    user: &'a User,
+    /// Distance ran, e.g. `5.0km`.
+    distance: String,
+    /// When the run happened, e.g. `Tue 14 Oct 07:12 (2 days ago)`.
+    when: String,
 }
 
 /// Struct Run display.
+///
+/// Like every template here, askama escapes the values it is given, so
+/// replies using them need to be sent with `ParseMode::Html`.
 #[derive(Template)]
 #[template(path = "list_runs.j2")]
 struct ListRunTemplate<'a> {
-    /// Reference to `runs` for askama to access.
-    runs: &'a Vec<RunDisplay>,
+    /// Lines of the list, from newest to oldest run.
+    rows: &'a [RunRow<'a>],
+}
+
+/// Formats a distance with at least one decimal place, e.g. `5.0km`.
+fn format_km(distance: f32) -> String {
+    if distance.fract() == 0. {
+        format!("{:.1}km", distance)
+    } else {
+        format!("{}km", distance)
+    }
+}
+
+/// Describes how long ago `then` was, in the largest whole unit.
+fn time_ago(then: NaiveDateTime, now: NaiveDateTime) -> String {
+    let days = (now.date() - then.date()).num_days();
+    let (count, unit) = match days {
+        ..=0 => return "today".into(),
+        1 => return "yesterday".into(),
+        2..=13 => (days, "day"),
+        14..=59 => (days / 7, "week"),
+        60..=729 => (days / 30, "month"),
+        _ => (days / 365, "year"),
+    };
+    format!("{} {}s ago", count, unit)
 }
 
 /// Displays runs fetched from database.
 ///
 /// Function takes in an `Option` and will check if any records have
 /// been retrieved, else it will output that there are no runs
-/// stored in the database.
-pub fn list_runs(runs: Option<Vec<Run>>) -> String {
+/// stored in the database. Each run is shown with its id, since `/edit`
+/// and `/delete` need it, and with how long before `now` it was.
+///
+/// Only the first page of runs is rendered, the rest being left for the
+/// caller to page through. The page's `rows` tells how many runs it shows.
+pub fn list_runs(runs: Option<Vec<(Run, User)>>, now: NaiveDateTime) -> Page {
     if let Some(runs) = runs {
-        let run_displays: Vec<RunDisplay> = runs.into_iter().map(RunDisplay).collect();
-        let run_template = ListRunTemplate {
-            runs: &run_displays,
-        };
+        let rows: Vec<RunRow> = runs
+            .iter()
+            .map(|(run, user)| RunRow {
+                run,
+                user,
+                distance: format_km(run.distance),
+                when: run
+                    .run_datetime
+                    .map(|then| {
+                        format!(
+                            "{} ({})",
+                            then.format("%a %-d %b %H:%M"),
+                            time_ago(then, now)
+                        )
+                    })
+                    .unwrap_or("unknown date".to_string()),
+            })
+            .collect();
+        let render = |rows: &[RunRow]| ListRunTemplate { rows }.render().unwrap();
+        let pages = paginate(&rows, rows.len().max(1), render);
 
-        run_template.render().unwrap().to_string()
+        Page {
+            text: render(pages[0]),
+            index: 0,
+            count: pages.len(),
+            rows: pages[0].len(),
+        }
     } else {
-        "No runs in database.".into()
+        Page {
+            text: "No runs in database.".into(),
+            index: 0,
+            count: 1,
+            rows: 0,
+        }
     }
 }
 
@@ -84,48 +209,852 @@ pub fn list_runs(runs: Option<Vec<Run>>) -> String {
 #[derive(Template)]
 #[template(path = "list_users.j2")]
 struct ListUserTemplate<'a> {
-    /// Reference to `users` for askama to access.
-    users: &'a Vec<User>,
+    /// Header of the table of users, aligned with its rows.
+    header: &'a str,
+    /// Rows of the table of users, already aligned.
+    rows: &'a [String],
+    /// Index of the page, counting from 0.
+    page: usize,
+    /// Number of pages.
+    pages: usize,
 }
 
 /// Displays users fetched from database.
 ///
 /// Function takes in an `Option` and will check if any records have
 /// been retrieved, else it will output that there are no users
-/// stored in the database.
-pub fn list_users(users: Option<Vec<User>>) -> String {
+/// stored in the database. Long lists are split into pages, of which the
+/// one at `page` is rendered.
+pub fn list_users(users: Option<Vec<User>>, page: usize) -> Page {
     if let Some(users) = users {
-        let user_template = ListUserTemplate { users: &users };
+        let header = ["#", "UserID", "UserName"].map(String::from);
+        let cells = users.iter().enumerate().map(|(index, user)| {
+            vec![
+                format!("{}.", index + 1),
+                user.id.to_string(),
+                user.user_name.clone(),
+            ]
+        });
+        let mut rows = align_columns(
+            &std::iter::once(header.to_vec())
+                .chain(cells)
+                .collect::<Vec<_>>(),
+            &[1],
+        );
+        let header = rows.remove(0);
+        let render = |rows: &[String], page, pages| {
+            ListUserTemplate {
+                header: &header,
+                rows,
+                page,
+                pages,
+            }
+            .render()
+            .unwrap()
+        };
+        let pages = paginate(&rows, ROWS_PER_PAGE, |rows| render(rows, 0, 1));
+        let index = page.min(pages.len() - 1);
 
-        user_template.render().unwrap().to_string()
+        Page {
+            text: render(pages[index], index, pages.len()),
+            index,
+            count: pages.len(),
+            rows: pages[index].len(),
+        }
     } else {
-        "No users in database.".into()
+        Page {
+            text: "No users in database.".into(),
+            index: 0,
+            count: 1,
+            rows: 0,
+        }
     }
 }
 
+/// A line of the tally.
+struct TallyRow<'a> {
+    /// Emoji marking the user's place, if the chat uses one.
+    emoji: Option<&'a str>,
+    /// Place, medals and distance, aligned with the other rows.
+    stats: String,
+    /// Whether the user is in first place, and shown in bold.
+    leader: bool,
+    /// Score the row is for.
+    score: &'a Score,
+}
+
+/// Lines of the tally for `scores`, from first to last place.
+fn tally_rows<'a>(scores: &'a [Score], emojis: &'a RankEmojis) -> Vec<TallyRow<'a>> {
+    let last_rank = scores.last().map(|score| score.rank).unwrap_or_default();
+    let cells: Vec<Vec<String>> = scores
+        .iter()
+        .map(|score| {
+            vec![
+                format!("{}.", score.rank),
+                format!("{}km", score.distance),
+                format!("{}🏅", score.medals),
+            ]
+        })
+        .collect();
+    align_columns(&cells, &[0, 1, 2])
+        .into_iter()
+        .zip(scores)
+        .map(|(stats, score)| TallyRow {
+            emoji: emojis.emoji(score.rank, last_rank),
+            stats,
+            leader: score.rank == 1,
+            score,
+        })
+        .collect()
+}
+
 /// Struct Tally display.
 #[derive(Template)]
 #[template(path = "list_tally.j2")]
 struct ListTallyTemplate<'a> {
-    /// Reference to `scores` for askama to access.
-    scores: &'a Vec<Score>,
+    /// Lines of the tally on this page, from first to last place.
+    rows: &'a [TallyRow<'a>],
+    /// Line of the user asking, if they are not on this page.
+    caller: Option<&'a TallyRow<'a>>,
+    /// Index of the page, counting from 0.
+    page: usize,
+    /// Number of pages.
+    pages: usize,
+}
+
+/// Struct Team Tally display.
+#[derive(Template)]
+#[template(path = "team_tally.j2")]
+struct TeamTallyTemplate<'a> {
+    /// Lines of the tally on this page, from first to last place.
+    rows: &'a [TallyRow<'a>],
+    /// Whether distances are divided by the number of members.
+    per_member: bool,
+    /// Index of the page, counting from 0.
+    page: usize,
+    /// Number of pages.
+    pages: usize,
 }
 
 /// Displays score aggregates fetched from database.
 ///
 /// Function takes in an `Option` and will check if any records have
 /// been retrieved, else it will output that there the tally
-/// cannot be generated.
-pub fn display_tally(scores: Option<Vec<Score>>) -> String {
+/// cannot be generated. Users are mentioned by name, leaders are shown
+/// in bold and places are marked with the chat's `emojis`.
+///
+/// Long tallies are split into pages, of which the one at `page` is
+/// rendered. The `caller`'s own line is added below when they are not
+/// on that page. Tallies of teams, as given by `grouping`, name teams
+/// without mentioning anyone, and leave out the `caller`.
+pub fn display_tally(
+    scores: Option<Vec<Score>>,
+    emojis: &RankEmojis,
+    page: usize,
+    caller: Option<UserId>,
+    grouping: TallyGrouping,
+) -> Page {
     if let Some(scores) = scores {
-        let tally_template = ListTallyTemplate { scores: &scores };
+        let rows = tally_rows(&scores, emojis);
+        let render = |rows: &[TallyRow], caller: Option<&TallyRow>, page, pages| {
+            match grouping {
+                TallyGrouping::Users => ListTallyTemplate {
+                    rows,
+                    caller,
+                    page,
+                    pages,
+                }
+                .render(),
+                TallyGrouping::Teams { per_member } => TeamTallyTemplate {
+                    rows,
+                    per_member,
+                    page,
+                    pages,
+                }
+                .render(),
+            }
+            .unwrap()
+        };
+        let pages = paginate(&rows, ROWS_PER_PAGE, |rows| render(rows, None, 0, 1));
+        let index = page.min(pages.len() - 1);
+        let caller = caller.filter(|_| grouping == TallyGrouping::Users);
+        let caller = caller.map(|caller| caller.to_string()).and_then(|caller| {
+            rows.iter()
+                .find(|row| row.score.telegram_userid == caller)
+                .filter(|row| !pages[index].iter().any(|shown| std::ptr::eq(shown, *row)))
+        });
+
+        Page {
+            text: render(pages[index], caller, index, pages.len()),
+            index,
+            count: pages.len(),
+            rows: pages[index].len(),
+        }
+    } else {
+        Page {
+            text: "Cannot generate tally.".into(),
+            index: 0,
+            count: 1,
+            rows: 0,
+        }
+    }
+}
+
+/// Struct Teams display.
+#[derive(Template)]
+#[template(path = "teams.j2")]
+struct TeamsTemplate<'a> {
+    /// Teams in the chat, in alphabetical order.
+    teams: &'a [Team],
+}
+
+/// Displays the teams in a chat along with their members.
+pub fn display_teams(teams: &[Team]) -> String {
+    TeamsTemplate { teams }.render().unwrap()
+}
+
+/// Escapes text given by users, such as names, for replies too short to
+/// warrant a template.
+fn escape_html(text: &str) -> String {
+    askama::filters::escape(askama::Html, text)
+        .unwrap()
+        .to_string()
+}
+
+/// Tells a user the team they asked for was created, with them in it.
+pub fn display_team_created(team: &str) -> String {
+    format!("Team {} created, and you're in it.", escape_html(team))
+}
+
+/// Tells a user there already is a team by the name they asked for.
+pub fn display_team_taken(team: &str) -> String {
+    let team = escape_html(team);
+    format!(
+        "There's already a team called {}. Join it with /team join {}.",
+        team, team
+    )
+}
+
+/// Announces that a user joined a team.
+pub fn display_team_joined(user_name: &str, team: &str) -> String {
+    format!("{} joined {}.", escape_html(user_name), escape_html(team))
+}
+
+/// Tells a user there is no team by the name they asked to join.
+pub fn display_team_missing(team: &str) -> String {
+    let team = escape_html(team);
+    format!(
+        "There's no team called {}. Create it with /team create {}.",
+        team, team
+    )
+}
+
+/// Announces that a user left their team.
+pub fn display_team_left(user_name: &str) -> String {
+    format!("{} left their team.", escape_html(user_name))
+}
 
-        tally_template.render().unwrap().to_string()
+/// Most places of the leaderboard shown in a recap.
+const RECAP_LEADERS: usize = 10;
+
+/// Struct Recap display.
+#[derive(Template)]
+#[template(path = "recap.j2")]
+struct RecapTemplate<'a> {
+    /// Heading, e.g. `Weekly recap`.
+    title: &'a str,
+    /// Distance the whole chat ran, e.g. `42.5km`.
+    distance: String,
+    /// Number of runs in the chat.
+    runs: u32,
+    /// Most improved user, with their previous and current distances.
+    most_improved: Option<(&'a Improvement, String, String)>,
+    /// Longest ongoing streaks.
+    streaks: &'a [Streak],
+    /// Top of the leaderboard.
+    rows: &'a [TallyRow<'a>],
+    /// Number of users left off the leaderboard.
+    others: usize,
+}
+
+/// Displays a weekly or monthly recap.
+///
+/// The recap leads with the distance the chat ran together, followed by
+/// the most improved user and ongoing streaks, if any, and the top of the
+/// leaderboard with places marked with the chat's `emojis`.
+pub fn display_recap(recap: &Recap, emojis: &RankEmojis) -> String {
+    let rows = tally_rows(&recap.scores, emojis);
+    let shown = rows.len().min(RECAP_LEADERS);
+    RecapTemplate {
+        title: match recap.period {
+            Period::Month => "Monthly recap",
+            _ => "Weekly recap",
+        },
+        distance: format_km(recap.scores.iter().map(|score| score.distance).sum()),
+        runs: recap.scores.iter().map(|score| score.medals).sum(),
+        most_improved: recap.most_improved.as_ref().map(|improved| {
+            (
+                improved,
+                format_km(improved.previous),
+                format_km(improved.current),
+            )
+        }),
+        streaks: &recap.streaks,
+        rows: &rows[..shown],
+        others: rows.len() - shown,
+    }
+    .render()
+    .unwrap()
+}
+
+/// Struct Reminder display.
+#[derive(Template)]
+#[template(path = "reminder.j2")]
+struct ReminderTemplate<'a> {
+    /// Users being reminded.
+    users: &'a [User],
+    /// Days since their last run.
+    days: u32,
+}
+
+/// Displays a reminder mentioning `users`, who have not run for `days`.
+///
+/// Reminders mentioning too many users to fit in a message are split into
+/// several, each mentioning some of them.
+pub fn display_reminder(users: &[User], days: u32) -> Vec<String> {
+    let render = |users: &[User]| ReminderTemplate { users, days }.render().unwrap();
+    paginate(users, users.len(), render)
+        .into_iter()
+        .map(render)
+        .collect()
+}
+
+/// Number of cells in the progress bar of `/goal`.
+const PROGRESS_BAR_CELLS: usize = 10;
+
+/// Struct Goal display.
+#[derive(Template)]
+#[template(path = "goal.j2")]
+struct GoalTemplate {
+    /// Distance to run, e.g. `500.0km`.
+    distance: String,
+    /// Window of the goal, e.g. `in October 2025`.
+    window: String,
+    /// Progress bar, filled up to the share of the goal run so far.
+    bar: String,
+    /// Share of the goal run so far, in percent.
+    percent: u32,
+    /// Distance run so far.
+    done: String,
+    /// Distance left to run, unless the goal was reached.
+    left: Option<String>,
+    /// Last day of the goal's window, e.g. `Fri 31 Oct`.
+    deadline: String,
+    /// Whether the chat is on track to reach the goal, free of markup.
+    outlook: String,
+}
+
+/// A bar filled up to `share` of its length, e.g. `████░░░░░░` for 0.4.
+fn progress_bar(share: f32) -> String {
+    let filled = ((share * PROGRESS_BAR_CELLS as f32).round() as usize).min(PROGRESS_BAR_CELLS);
+    "█".repeat(filled) + &"░".repeat(PROGRESS_BAR_CELLS - filled)
+}
+
+/// Describes the window of a goal, e.g. `in the week of Mon 13 Oct`.
+fn goal_window(goal: &Goal) -> String {
+    match goal.period {
+        Period::Week => format!("in the week of {}", goal.starts.format("%a %-d %b")),
+        Period::Month => goal.starts.format("in %B %Y").to_string(),
+        _ => goal.starts.format("in %Y").to_string(),
+    }
+}
+
+/// Displays progress towards a goal, having run `done` by `now`.
+///
+/// Progress is shown as a bar, along with when the chat will reach the goal
+/// if it keeps up its pace.
+pub fn display_goal(goal: &Goal, done: f32, now: NaiveDateTime) -> String {
+    let done = (done * 100.0).round() / 100.0;
+    let share = done / goal.distance;
+    let day = |time: NaiveDateTime| time.format("%a %-d %b").to_string();
+    let deadline = day(goal.ends - chrono::Duration::days(1));
+    let outlook = match (goal.reached, goal.projected_finish(done, now)) {
+        (Some(reached), _) => format!("🎉 Reached on {}!", day(reached)),
+        _ if now >= goal.ends => format!("The goal ended on {}.", deadline),
+        (None, Some(finish)) if finish < goal.ends => {
+            format!("📈 At this pace, you'll get there on {}.", day(finish))
+        }
+        (None, Some(finish)) => {
+            format!("📉 At this pace, you'll only get there on {}.", day(finish))
+        }
+        (None, None) => "Nobody has run towards it yet.".into(),
+    };
+
+    GoalTemplate {
+        distance: format_km(goal.distance),
+        window: goal_window(goal),
+        bar: progress_bar(share),
+        percent: (share * 100.0) as u32,
+        done: format_km(done),
+        left: (done < goal.distance)
+            .then(|| format_km(((goal.distance - done) * 100.0).round() / 100.0)),
+        deadline,
+        outlook,
+    }
+    .render()
+    .unwrap()
+}
+
+/// Congratulates a chat on running `done`, reaching its goal.
+pub fn display_goal_reached(goal: &Goal, done: f32) -> String {
+    let done = (done * 100.0).round() / 100.0;
+    format!(
+        "🎉 Goal reached! Together you've run {} of the {} you set out to run {}.",
+        format_km(done),
+        format_km(goal.distance),
+        goal_window(goal)
+    )
+}
+
+/// A user's progress towards their goal, as shown by `/me`.
+struct PersonalGoalLine {
+    /// What the user aims for, e.g. `20.0km` or `4 runs`.
+    target: String,
+    /// Period the goal repeats over, e.g. `week`.
+    period: Period,
+    /// Progress bar, filled up to the share of the goal reached.
+    bar: String,
+    /// Share of the goal reached, in percent.
+    percent: u32,
+    /// What the user ran this period.
+    done: String,
+    /// What is left to reach the goal, unless it was reached.
+    left: Option<String>,
+    /// Periods the goal was reached in, and periods counted.
+    attainment: (u32, u32),
+}
+
+/// Struct Me display.
+#[derive(Template)]
+#[template(path = "me.j2")]
+struct MeTemplate<'a> {
+    /// Name of the user.
+    user_name: &'a str,
+    /// Progress towards their goal, if they set one.
+    goal: Option<PersonalGoalLine>,
+}
+
+/// Displays a user's progress towards their personal goal, if they have
+/// one, along with how often they reached it.
+pub fn display_me(user_name: &str, status: Option<&GoalStatus>) -> String {
+    let goal = status.map(|status| {
+        let goal = &status.goal;
+        let done = status.progress.amount(goal.unit);
+        let share = done / goal.target;
+        PersonalGoalLine {
+            target: goal.unit.format(goal.target),
+            period: goal.period,
+            bar: progress_bar(share),
+            percent: (share * 100.) as u32,
+            done: goal.unit.format(done),
+            left: (done < goal.target).then(|| {
+                goal.unit
+                    .format(((goal.target - done) * 100.).round() / 100.)
+            }),
+            attainment: status.attainment,
+        }
+    });
+
+    MeTemplate { user_name, goal }.render().unwrap()
+}
+
+/// Describes a number of days, in weeks when it is a whole number of them,
+/// e.g. `2 weeks` or `10 days`.
+fn format_days(days: u32) -> String {
+    let (count, unit) = match days {
+        days if days % 7 == 0 => (days / 7, "week"),
+        days => (days, "day"),
+    };
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+/// Invites a user to a challenge.
+pub fn display_challenge(challenge: &Challenge) -> String {
+    format!(
+        "🥊 {} challenges @{} to be the first to run {} within {}! @{}, do you accept?",
+        challenge.challenger_name,
+        challenge.opponent_name,
+        format_km(challenge.distance),
+        format_days(challenge.days),
+        challenge.opponent_name
+    )
+}
+
+/// Announces that a challenge was accepted and when it ends.
+pub fn display_challenge_accepted(challenge: &Challenge) -> String {
+    let ends = challenge
+        .ends
+        .map(|ends| format!(" by {}", ends.format("%a %-d %b %H:%M")))
+        .unwrap_or_default();
+    format!(
+        "🥊 {} accepted {}'s challenge! First to run {}{} wins. Follow along with /challenge.",
+        challenge.opponent_name,
+        challenge.challenger_name,
+        format_km(challenge.distance),
+        ends
+    )
+}
+
+/// Announces how a challenge ended.
+pub fn display_challenge_result(challenge: &Challenge, standings: &Standings) -> String {
+    let (winner, loser, distance, other) = match standings.outcome {
+        Some(Outcome::Challenger) => (
+            &challenge.challenger_name,
+            &challenge.opponent_name,
+            standings.challenger,
+            standings.opponent,
+        ),
+        Some(Outcome::Opponent) => (
+            &challenge.opponent_name,
+            &challenge.challenger_name,
+            standings.opponent,
+            standings.challenger,
+        ),
+        Some(Outcome::Draw) | None => {
+            return format!(
+                "🤝 Time's up! {} and {} drew the challenge, running {} each.",
+                challenge.challenger_name,
+                challenge.opponent_name,
+                format_km(standings.challenger)
+            )
+        }
+    };
+    if distance >= challenge.distance {
+        format!(
+            "🏆 {} won the challenge, reaching {} first! {} ran {}.",
+            winner,
+            format_km(challenge.distance),
+            loser,
+            format_km(other)
+        )
     } else {
-        "Cannot generate tally.".into()
+        format!(
+            "🏆 Time's up! {} won the challenge, running {} to {}'s {}.",
+            winner,
+            format_km(distance),
+            loser,
+            format_km(other)
+        )
     }
 }
 
+/// A challenge under way, as shown by `/challenge`.
+struct ChallengeLine {
+    /// Who is racing and to what, e.g. `reuben vs bob, first to 50.0km`.
+    title: String,
+    /// When the challenge ends, e.g. `Sat 1 Nov 14:05`.
+    ends: String,
+    /// Each side's name, progress bar and distance, aligned.
+    rows: Vec<String>,
+}
+
+/// Struct Challenges display.
+#[derive(Template)]
+#[template(path = "challenges.j2")]
+struct ChallengesTemplate {
+    /// Challenges under way.
+    challenges: Vec<ChallengeLine>,
+    /// How the latest challenges ended, a line each.
+    results: Vec<String>,
+}
+
+/// Displays the standings of challenges under way, followed by how the
+/// latest challenges ended.
+pub fn display_challenges(
+    active: &[(Challenge, Standings)],
+    results: &[(Challenge, Standings)],
+) -> String {
+    let challenges = active
+        .iter()
+        .map(|(challenge, standings)| {
+            let row = |name: &str, distance: f32| {
+                vec![
+                    name.to_string(),
+                    progress_bar(distance / challenge.distance),
+                    format_km((distance * 100.).round() / 100.),
+                ]
+            };
+            ChallengeLine {
+                title: format!(
+                    "{} vs {}, first to {}",
+                    challenge.challenger_name,
+                    challenge.opponent_name,
+                    format_km(challenge.distance)
+                ),
+                ends: challenge
+                    .ends
+                    .map(|ends| ends.format("%a %-d %b %H:%M").to_string())
+                    .unwrap_or_default(),
+                rows: align_columns(
+                    &[
+                        row(&challenge.challenger_name, standings.challenger),
+                        row(&challenge.opponent_name, standings.opponent),
+                    ],
+                    &[2],
+                ),
+            }
+        })
+        .collect();
+    let results = results
+        .iter()
+        .map(|(challenge, standings)| {
+            let challenger = (&challenge.challenger_name, standings.challenger);
+            let opponent = (&challenge.opponent_name, standings.opponent);
+            let ((winner, distance), (loser, other)) = match standings.outcome {
+                Some(Outcome::Opponent) => (opponent, challenger),
+                Some(Outcome::Challenger) => (challenger, opponent),
+                _ => {
+                    return format!(
+                        "🤝 {} and {} drew, {} each",
+                        challenger.0,
+                        opponent.0,
+                        format_km(challenger.1)
+                    )
+                }
+            };
+            format!(
+                "🏆 {} beat {}, {} to {}",
+                winner,
+                loser,
+                format_km(distance),
+                format_km(other)
+            )
+        })
+        .collect();
+
+    ChallengesTemplate {
+        challenges,
+        results,
+    }
+    .render()
+    .unwrap()
+}
+
+/// What a user ran over a period, as shown by `/profile`.
+struct ProfileLine {
+    /// Period, e.g. `This week`.
+    label: &'static str,
+    /// Distance run, e.g. `42.5km`.
+    distance: String,
+    /// Number of runs.
+    runs: u32,
+}
+
+/// Struct Profile display.
+#[derive(Template)]
+#[template(path = "profile.j2")]
+struct ProfileTemplate<'a> {
+    /// Name of the user.
+    user_name: &'a str,
+    /// Number of chats the user has added runs to.
+    chats: u32,
+    /// Number of chats the user shares runs between.
+    shared_chats: u32,
+    /// What they ran, from all time to this week.
+    lines: Vec<ProfileLine>,
+}
+
+/// Displays what a user ran across all of their chats.
+pub fn display_profile(user_name: &str, stats: &ProfileStats) -> String {
+    let lines = stats
+        .periods
+        .iter()
+        .map(|(period, progress)| ProfileLine {
+            label: match period {
+                Period::Week => "This week",
+                Period::Month => "This month",
+                Period::Year => "This year",
+                _ => "All time",
+            },
+            distance: format_km((progress.distance * 100.).round() / 100.),
+            runs: progress.runs,
+        })
+        .collect();
+
+    ProfileTemplate {
+        user_name,
+        chats: stats.chats,
+        shared_chats: stats.shared_chats,
+        lines,
+    }
+    .render()
+    .unwrap()
+}
+
+/// Struct Journey display.
+#[derive(Template)]
+#[template(path = "journey.j2")]
+struct JourneyTemplate {
+    /// Name of the route, e.g. `Singapore to Kuala Lumpur`.
+    route: String,
+    /// When the chat set off, e.g. `Sat 18 Oct`.
+    starts: String,
+    /// Bar filled up to the share of the route run.
+    bar: String,
+    /// Share of the route run, in percent.
+    percent: u32,
+    /// Where the chat is along the route.
+    position: String,
+    /// The next landmark and how far away it is, unless the chat arrived.
+    next: Option<String>,
+}
+
+/// Displays how far along its route a chat is, having run `done` since
+/// setting off on its journey.
+pub fn display_journey(route: &Route, journey: &Journey, done: f32) -> String {
+    let done = (done * 100.).round() / 100.;
+    let share = (done / route.length()).min(1.);
+    let reached = route.reached(done);
+    let last = &route.waypoints[reached];
+    let position = if reached == route.waypoints.len() - 1 {
+        format!("🏁 Arrived in {}, {} run!", last.name, format_km(done))
+    } else if done <= 0. {
+        format!("Setting off from {}.", last.name)
+    } else {
+        format!(
+            "{} of {} run, past {}.",
+            format_km(done),
+            format_km(route.length()),
+            last.name
+        )
+    };
+    let next = route.waypoints.get(reached + 1).map(|next| {
+        format!(
+            "Next up: {}, {} away.",
+            next.name,
+            format_km(((next.distance - done) * 100.).round() / 100.)
+        )
+    });
+
+    JourneyTemplate {
+        route: route.name(),
+        starts: journey.starts.format("%a %-d %b").to_string(),
+        bar: progress_bar(share),
+        percent: (share * 100.) as u32,
+        position,
+        next,
+    }
+    .render()
+    .unwrap()
+}
+
+/// Lists the routes a chat can set off along.
+pub fn display_routes(routes: &[Route]) -> String {
+    let lines: Vec<String> = routes
+        .iter()
+        .map(|route| {
+            format!(
+                "<code>{}</code>: {}, {}",
+                route.id,
+                route.name(),
+                format_km(route.length())
+            )
+        })
+        .collect();
+    format!(
+        "<b>Routes</b>\n{}\nSet off with /journey start followed by a route.",
+        lines.join("\n")
+    )
+}
+
+/// Announces that a chat reached the waypoint at index `reached` along
+/// its route.
+pub fn display_landmark(route: &Route, reached: usize) -> String {
+    let landmark = &route.waypoints[reached];
+    match route.waypoints.get(reached + 1) {
+        Some(next) => format!(
+            "📍 You've reached {}, {} from {}! Next up: {}, {} on.",
+            landmark.name,
+            format_km(landmark.distance),
+            route.waypoints[0].name,
+            next.name,
+            format_km(((next.distance - landmark.distance) * 100.).round() / 100.)
+        ),
+        None => format!(
+            "🏁 Journey complete! Together you've run all {} from {}.",
+            format_km(route.length()),
+            route.name()
+        ),
+    }
+}
+
+/// A personal record, as shown by `/pbs`.
+struct RecordRow {
+    /// Distance and time, aligned, e.g. `5k  24:30`.
+    record: String,
+    /// Id of the run the record was set in.
+    run_id: i32,
+    /// When the record was set, e.g. `Sat 18 Oct 2025`.
+    when: String,
+}
+
+/// Struct Personal records display.
+#[derive(Template)]
+#[template(path = "pbs.j2")]
+struct PersonalBestsTemplate<'a> {
+    /// Name of the user.
+    user_name: &'a str,
+    /// Their records, from the shortest distance to the longest.
+    rows: Vec<RecordRow>,
+}
+
+/// Displays a user's personal records.
+pub fn display_personal_bests(user_name: &str, bests: &[PersonalBest]) -> String {
+    let cells: Vec<Vec<String>> = bests
+        .iter()
+        .map(|best| {
+            vec![
+                best.distance.label().to_string(),
+                format_duration(best.secs),
+            ]
+        })
+        .collect();
+    let rows = align_columns(&cells, &[1])
+        .into_iter()
+        .zip(bests)
+        .map(|(record, best)| RecordRow {
+            record,
+            run_id: best.run_id,
+            when: best.run_datetime.format("%a %-d %b %Y").to_string(),
+        })
+        .collect();
+
+    PersonalBestsTemplate { user_name, rows }.render().unwrap()
+}
+
+/// Announces personal records just set, one per line.
+pub fn display_new_records(records: &[NewRecord]) -> String {
+    records
+        .iter()
+        .map(|record| {
+            let (label, time) = (
+                record.best.distance.label(),
+                format_duration(record.best.secs),
+            );
+            match record.previous {
+                Some(previous) => format!(
+                    "🏅 New {} PR: {}, {} faster than before!",
+                    label,
+                    time,
+                    format_duration(previous - record.best.secs)
+                ),
+                None => format!("🏅 First {} on record: {}!", label, time),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 #[cfg(test)]
 mod tests {
     use std::vec;
@@ -136,32 +1065,60 @@ mod tests {
 
     #[test]
     fn list_runs_template() {
+        let at = |day, hour, min| {
+            chrono::NaiveDate::from_ymd_opt(2025, 10, day)
+                .unwrap()
+                .and_hms_opt(hour, min, 0)
+        };
+        let run = |id, distance, run_datetime, user_id| Run {
+            id,
+            distance,
+            run_datetime,
+            user_id,
+            duration_secs: None,
+            sport: None,
+            avg_heart_rate: None,
+            max_heart_rate: None,
+            elevation_gain: None,
+        };
+        let user = |id, user_name: &str| User {
+            id,
+            telegram_userid: id.to_string(),
+            chat_id: "chat1".into(),
+            user_name: user_name.into(),
+        };
         let runs = vec![
-            Run {
-                id: 1,
-                distance: 1.,
-                run_datetime: chrono::NaiveDateTime::from_timestamp_opt(61, 0),
-                user_id: 1,
-            },
-            Run {
-                id: 2,
-                distance: 2.,
-                run_datetime: chrono::NaiveDateTime::from_timestamp_opt(82, 0),
-                user_id: 2,
-            },
+            (run(3, 5., at(14, 7, 12), 1), user(1, "reuben")),
+            (run(2, 2.5, at(1, 18, 30), 2), user(2, "milton")),
+            (run(1, 1., None, 2), user(2, "milton")),
         ];
-        let render = list_runs(Some(runs));
-        let ans = "#. RunID Distance RunTime
-1. 1 1 1970-01-01 00:01:01 1
-2. 2 2 1970-01-01 00:01:22 2
+        let render = list_runs(Some(runs), at(16, 9, 0).unwrap()).text;
+        let ans = "<b>Recent runs</b>
+<code>#3</code> reuben · 5.0km · Tue 14 Oct 07:12 (2 days ago)
+<code>#2</code> milton · 2.5km · Wed 1 Oct 18:30 (2 weeks ago)
+<code>#1</code> milton · 1.0km · unknown date
 ";
         assert_eq!(render, ans);
     }
 
+    #[test]
+    fn relative_times() {
+        let now = chrono::DateTime::from_timestamp(1_760_000_000, 0)
+            .unwrap()
+            .naive_utc();
+        let ago = |days| time_ago(now - ::chrono::Duration::days(days), now);
+        assert_eq!(ago(0), "today");
+        assert_eq!(ago(1), "yesterday");
+        assert_eq!(ago(6), "6 days ago");
+        assert_eq!(ago(20), "2 weeks ago");
+        assert_eq!(ago(100), "3 months ago");
+        assert_eq!(ago(800), "2 years ago");
+    }
+
     #[test]
     fn list_empty_runs_template() {
-        let runs: Option<Vec<Run>> = None;
-        let render = list_runs(runs);
+        let runs: Option<Vec<(Run, User)>> = None;
+        let render = list_runs(runs, chrono::Utc::now().naive_utc()).text;
         let ans = "No runs in database.";
         assert_eq!(render, ans);
     }
@@ -182,18 +1139,19 @@ mod tests {
                 user_name: "youyou".into(),
             },
         ];
-        let render = list_users(Some(users));
-        let ans = "#. UserID UserName
-1. 1 meme
-2. 2 youyou
-";
+        let render = list_users(Some(users), 0).text;
+        let ans = "<pre>
+#   UserID  UserName
+1.       1  meme
+2.       2  youyou
+</pre>";
         assert_eq!(render, ans);
     }
 
     #[test]
     fn list_empty_users_template() {
         let users: Option<Vec<User>> = None;
-        let render = list_users(users);
+        let render = list_users(users, 0).text;
         let ans = "No users in database.";
         assert_eq!(render, ans);
     }
@@ -202,39 +1160,427 @@ mod tests {
     fn list_tally_template() {
         let scores = vec![
             Score {
+                rank: 1,
                 user_name: "reuben".into(),
+                telegram_userid: "1".into(),
                 medals: 5,
                 distance: 20.0,
             },
             Score {
+                rank: 2,
                 user_name: "milton".into(),
+                telegram_userid: "2".into(),
                 medals: 2,
                 distance: 10.0,
             },
             Score {
+                rank: 3,
                 user_name: "jerrell".into(),
+                telegram_userid: "3".into(),
                 medals: 1,
                 distance: 1.0,
             },
             Score {
+                rank: 4,
                 user_name: "taigy".into(),
+                telegram_userid: "4".into(),
                 medals: 1,
                 distance: 0.2,
             },
             Score {
+                rank: 5,
                 user_name: "riley".into(),
+                telegram_userid: "5".into(),
                 medals: 2,
                 distance: 0.1,
             },
         ];
-        let render = display_tally(Some(scores));
-        let ans = "#. UserName Medals Distance (km)
-🥇 1. reuben 5🏅 20km
-🥈 2. milton 2🏅 10km
-🥉 3. jerrell 1🏅 1km
-🏃 4. taigy 1🏅 0.2km
-🤡 5. riley 2🏅 0.1km
-";
+        let render = display_tally(
+            Some(scores),
+            &RankEmojis::default(),
+            0,
+            None,
+            TallyGrouping::Users,
+        )
+        .text;
+        let ans = r#"<b>Leaderboard</b>
+🥇 <code>1.   20km  5🏅</code> <b><a href="tg://user?id=1">reuben</a></b>
+🥈 <code>2.   10km  2🏅</code> <a href="tg://user?id=2">milton</a>
+🥉 <code>3.    1km  1🏅</code> <a href="tg://user?id=3">jerrell</a>
+🏃 <code>4.  0.2km  1🏅</code> <a href="tg://user?id=4">taigy</a>
+🤡 <code>5.  0.1km  2🏅</code> <a href="tg://user?id=5">riley</a>
+"#;
         assert_eq!(render, ans);
     }
+
+    #[test]
+    fn hostile_user_names_are_escaped() {
+        let user_name = r#"<b>bob</b> & "friends" <a href='x'>"#;
+        let users = vec![User {
+            id: 1,
+            telegram_userid: "1".into(),
+            chat_id: "chat1".into(),
+            user_name: user_name.into(),
+        }];
+        let scores = vec![Score {
+            rank: 1,
+            user_name: user_name.into(),
+            telegram_userid: r#"1"><script>"#.into(),
+            medals: 1,
+            distance: 1.0,
+        }];
+        let escaped =
+            "&lt;b&gt;bob&lt;/b&gt; &amp; &quot;friends&quot; &lt;a href=&#x27;x&#x27;&gt;";
+
+        let render = list_users(Some(users), 0).text;
+        assert!(render.contains(&format!("1.       1  {}\n", escaped)));
+        let render = display_tally(
+            Some(scores),
+            &RankEmojis::default(),
+            0,
+            None,
+            TallyGrouping::Users,
+        )
+        .text;
+        assert_eq!(
+            render,
+            format!(
+                "<b>Leaderboard</b>\n🥇 <code>1.  1km  1🏅</code> <b><a href=\"tg://user?id=1&quot;&gt;&lt;script&gt;\">{}</a></b>\n",
+                escaped
+            )
+        );
+        assert!(!render.contains("<script>"));
+    }
+
+    #[test]
+    fn hostile_team_names_are_escaped() {
+        let name = r#"<b>bob</b> & "friends" <a href='x'>"#;
+        let escaped =
+            "&lt;b&gt;bob&lt;/b&gt; &amp; &quot;friends&quot; &lt;a href=&#x27;x&#x27;&gt;";
+
+        assert_eq!(
+            display_team_created(name),
+            format!("Team {} created, and you're in it.", escaped)
+        );
+        assert_eq!(
+            display_team_taken(name),
+            format!(
+                "There's already a team called {}. Join it with /team join {}.",
+                escaped, escaped
+            )
+        );
+        assert_eq!(
+            display_team_joined(name, name),
+            format!("{} joined {}.", escaped, escaped)
+        );
+        assert_eq!(
+            display_team_missing(name),
+            format!(
+                "There's no team called {}. Create it with /team create {}.",
+                escaped, escaped
+            )
+        );
+        assert_eq!(
+            display_team_left(name),
+            format!("{} left their team.", escaped)
+        );
+    }
+
+    /// Scores of `count` users, from most to least distance ran.
+    fn many_scores(count: u32) -> Vec<Score> {
+        (1..=count)
+            .map(|id| Score {
+                rank: id,
+                user_name: format!("runner_with_a_long_name_{}", id),
+                telegram_userid: id.to_string(),
+                medals: 1,
+                distance: (count - id + 1) as f32,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn long_tallies_are_paginated() {
+        let scores = many_scores(150);
+        let first = display_tally(
+            Some(scores.clone()),
+            &RankEmojis::default(),
+            0,
+            Some(UserId(120)),
+            TallyGrouping::Users,
+        );
+        assert_eq!(first.count, 6);
+        assert_eq!(first.rows, ROWS_PER_PAGE);
+        assert!(first.text.contains("<b><a href=\"tg://user?id=1\">"));
+        assert!(first.text.ends_with(
+            "…\n🏃 <code>120.   31km  1🏅</code> <a href=\"tg://user?id=120\">runner_with_a_long_name_120</a>\n<i>Page 1 of 6</i>\n"
+        ));
+
+        // The caller is not repeated on their own page, and the leader is
+        // only in bold on the first.
+        let fifth = display_tally(
+            Some(scores.clone()),
+            &RankEmojis::default(),
+            4,
+            Some(UserId(120)),
+            TallyGrouping::Users,
+        );
+        assert_eq!(fifth.text.matches("id=120\"").count(), 1);
+        assert!(!fifth.text.contains("<b><a"));
+
+        let last = display_tally(
+            Some(scores),
+            &RankEmojis::default(),
+            99,
+            None,
+            TallyGrouping::Users,
+        );
+        assert_eq!(last.index, 5);
+        assert!(last.text.contains("🤡 <code>150.    1km  1🏅</code>"));
+        for page in [first, fifth, last] {
+            assert!(page.text.encode_utf16().count() <= MAX_MESSAGE_LENGTH);
+        }
+    }
+
+    #[test]
+    fn long_lists_are_split_to_fit_messages() {
+        let users = |count| -> Vec<User> {
+            many_scores(count)
+                .into_iter()
+                .map(|score| User {
+                    id: score.telegram_userid.parse().unwrap(),
+                    telegram_userid: score.telegram_userid,
+                    chat_id: "chat1".into(),
+                    user_name: score.user_name,
+                })
+                .collect()
+        };
+        let page = list_users(Some(users(60)), 2);
+        assert_eq!((page.index, page.count, page.rows), (2, 3, 10));
+        assert!(page.text.starts_with("<pre>\n#    UserID  UserName\n51."));
+        assert!(page.text.ends_with("</pre>\n<i>Page 3 of 3</i>"));
+
+        let users = users(200);
+        let reminders = display_reminder(&users, 3);
+        assert!(reminders.len() > 1);
+        assert!(reminders
+            .iter()
+            .all(|reminder| reminder.encode_utf16().count() <= MAX_MESSAGE_LENGTH));
+        let mentions: usize = reminders
+            .iter()
+            .map(|reminder| reminder.matches("tg://user").count())
+            .sum();
+        assert_eq!(mentions, users.len());
+
+        let now = chrono::Utc::now().naive_utc();
+        let runs: Vec<(Run, User)> = (1..=50)
+            .map(|id| {
+                (
+                    Run {
+                        id,
+                        distance: 10.25,
+                        run_datetime: Some(now),
+                        user_id: 1,
+                        duration_secs: None,
+                        sport: None,
+                        avg_heart_rate: None,
+                        max_heart_rate: None,
+                        elevation_gain: None,
+                    },
+                    User {
+                        id: 1,
+                        telegram_userid: "1".into(),
+                        chat_id: "chat1".into(),
+                        user_name: "a_name_as_long_as_telegram_allows".into(),
+                    },
+                )
+            })
+            .collect();
+        let page = list_runs(Some(runs), now);
+        assert!(page.rows < 50);
+        assert!(page.text.encode_utf16().count() + FOOTER_LENGTH <= MAX_MESSAGE_LENGTH);
+    }
+
+    #[test]
+    fn tied_users_share_places_and_emojis() {
+        let score = |rank, user_name: &str, distance| Score {
+            rank,
+            user_name: user_name.into(),
+            telegram_userid: "1".into(),
+            medals: 1,
+            distance,
+        };
+        let scores = vec![
+            score(1, "milton", 5.),
+            score(1, "reuben", 5.),
+            score(3, "jerrell", 2.),
+            score(4, "taigy", 1.),
+            score(4, "riley", 1.),
+        ];
+
+        let render = display_tally(
+            Some(scores.clone()),
+            &RankEmojis::default(),
+            0,
+            None,
+            TallyGrouping::Users,
+        )
+        .text;
+        let ans = r#"<b>Leaderboard</b>
+🥇 <code>1.  5km  1🏅</code> <b><a href="tg://user?id=1">milton</a></b>
+🥇 <code>1.  5km  1🏅</code> <b><a href="tg://user?id=1">reuben</a></b>
+🥉 <code>3.  2km  1🏅</code> <a href="tg://user?id=1">jerrell</a>
+🤡 <code>4.  1km  1🏅</code> <a href="tg://user?id=1">taigy</a>
+🤡 <code>4.  1km  1🏅</code> <a href="tg://user?id=1">riley</a>
+"#;
+        assert_eq!(render, ans);
+
+        let render = display_tally(
+            Some(scores),
+            &RankEmojis::off(),
+            0,
+            None,
+            TallyGrouping::Users,
+        )
+        .text;
+        assert!(render.contains("\n<code>3.  2km  1🏅</code> <a"));
+        assert!(!render.contains("🤡"));
+    }
+
+    #[test]
+    fn recap_template() {
+        let scores: Vec<Score> = (1..=12)
+            .map(|rank| Score {
+                rank,
+                user_name: format!("runner{}", rank),
+                telegram_userid: rank.to_string(),
+                medals: 1,
+                distance: 13. - rank as f32,
+            })
+            .collect();
+        let recap = Recap {
+            period: Period::Month,
+            scores,
+            most_improved: Some(Improvement {
+                user_name: "runner<2>".into(),
+                telegram_userid: "2".into(),
+                previous: 2.,
+                current: 11.,
+            }),
+            streaks: vec![Streak {
+                user_name: "runner1".into(),
+                days: 4,
+            }],
+        };
+
+        let render = display_recap(&recap, &RankEmojis::off());
+        assert!(render.starts_with(
+            r#"<b>Monthly recap</b>
+Together you ran <b>78.0km</b> over 12 runs.
+📈 Most improved: <a href="tg://user?id=2">runner&lt;2&gt;</a>, up from 2.0km to 11.0km
+🔥 runner1 has run 4 days in a row
+
+<code> 1.  12km  1🏅</code> <b><a href="tg://user?id=1">runner1</a></b>
+"#
+        ));
+        assert!(render.ends_with(
+            r#"<code>10.   3km  1🏅</code> <a href="tg://user?id=10">runner10</a>
+…and 2 more
+"#
+        ));
+    }
+
+    #[test]
+    fn goal_progress() {
+        let day = |day| {
+            chrono::NaiveDate::from_ymd_opt(2025, 10, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        let goal = Goal {
+            distance: 500.,
+            period: Period::Month,
+            starts: day(1),
+            ends: day(31) + ::chrono::Duration::days(1),
+            reached: None,
+        };
+
+        // 185.5km in the first 10 days is on track to finish on the 27th.
+        assert_eq!(
+            display_goal(&goal, 185.5, day(11)),
+            "<b>Goal: 500.0km in October 2025</b>
+<code>████░░░░░░</code> 37%
+185.5km run, 314.5km to go by Fri 31 Oct.
+📈 At this pace, you'll get there on Mon 27 Oct."
+        );
+        assert!(display_goal(&goal, 100., day(11))
+            .ends_with("📉 At this pace, you'll only get there on Thu 20 Nov."));
+        assert!(display_goal(&goal, 0., day(11)).ends_with("Nobody has run towards it yet."));
+        assert!(
+            display_goal(&goal, 100., day(31) + ::chrono::Duration::days(2)).ends_with(
+                "100.0km run, 400.0km to go by Fri 31 Oct.\nThe goal ended on Fri 31 Oct."
+            )
+        );
+
+        let reached = Goal {
+            reached: Some(day(25)),
+            ..goal
+        };
+        assert_eq!(
+            display_goal(&reached, 512.25, day(26)),
+            "<b>Goal: 500.0km in October 2025</b>
+<code>██████████</code> 102%
+512.25km run.
+🎉 Reached on Sat 25 Oct!"
+        );
+    }
+
+    #[test]
+    fn journey_template() {
+        let route = crate::journey::find_route("singapore-kuala-lumpur").unwrap();
+        let journey = Journey {
+            route: route.id.into(),
+            starts: chrono::NaiveDate::from_ymd_opt(2025, 10, 18)
+                .unwrap()
+                .and_hms_opt(9, 0, 0)
+                .unwrap(),
+            reached: 0,
+        };
+        assert_eq!(
+            display_journey(&route, &journey, 120.5),
+            "<b>🗺️ Singapore to Kuala Lumpur</b>
+<i>Since Sat 18 Oct</i>
+<code>███░░░░░░░</code> 34%
+120.5km of 350.0km run, past Ayer Hitam.
+Next up: Yong Peng, 24.5km away."
+        );
+        assert!(display_journey(&route, &journey, 0.)
+            .contains("Setting off from Singapore.\nNext up: Johor Bahru, 27.0km away."));
+        assert!(display_journey(&route, &journey, 351.)
+            .ends_with("100%\n🏁 Arrived in Kuala Lumpur, 351.0km run!"));
+
+        assert_eq!(
+            display_landmark(&route, 1),
+            "📍 You've reached Johor Bahru, 27.0km from Singapore! Next up: Kulai, 33.0km on."
+        );
+        assert_eq!(
+            display_landmark(&route, 10),
+            "🏁 Journey complete! Together you've run all 350.0km from Singapore to Kuala Lumpur."
+        );
+    }
+
+    #[test]
+    fn long_messages_are_split_between_lines() {
+        let text: Vec<String> = (0..1000).map(|line| format!("Line {}", line)).collect();
+        let text = text.join("\n");
+
+        let parts = split_message(&text);
+        assert!(parts.len() > 1);
+        assert!(parts
+            .iter()
+            .all(|part| part.encode_utf16().count() <= MAX_MESSAGE_LENGTH));
+        assert_eq!(parts.join("\n"), text);
+        assert_eq!(split_message("Short\n\ntext"), ["Short\n\ntext"]);
+    }
 }
@@ -3,7 +3,13 @@
 //! Contains structs for an "ORM-like" approach to
 //! database interactions.
 
-use sqlx::types::chrono;
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use chrono_tz::Tz;
+use std::{error::Error, fmt, str::FromStr};
+use teloxide::{
+    types::{ChatId, UserId},
+    utils::command::ParseError,
+};
 
 /// Represents a user row in the `users` table.
 #[derive(sqlx::FromRow)]
@@ -16,6 +22,7 @@ pub struct User {
     /// as i64 first before storing in DB.
     pub telegram_userid: String,
     /// Id of telegram chat
+    #[allow(dead_code)]
     pub chat_id: String,
     /// Self-specified username
     pub user_name: String,
@@ -31,18 +38,1069 @@ pub struct Run {
     /// Datetime when the run was submitted to the database
     pub run_datetime: Option<chrono::NaiveDateTime>,
     /// User_id of the user who submitted the run
+    #[allow(dead_code)]
     pub user_id: i32,
+    /// Time taken for the run in seconds, if known
+    pub duration_secs: Option<i32>,
+    /// Kind of activity as stored by `Sport::as_str`, if known
+    pub sport: Option<String>,
+    /// Average heart rate in beats per minute, if recorded
+    pub avg_heart_rate: Option<i32>,
+    /// Maximum heart rate in beats per minute, if recorded
+    pub max_heart_rate: Option<i32>,
+    /// Total ascent in metres, if recorded
+    pub elevation_gain: Option<f32>,
+}
+
+/// Details of a run beyond its distance, when known.
+///
+/// Runs logged through `/add` only have a distance unless given a time,
+/// while activity files also tell when the run happened and how long it
+/// took.
+#[derive(Default)]
+pub struct RunDetails {
+    /// Datetime of the run, defaults to now if not given
+    pub run_datetime: Option<chrono::NaiveDateTime>,
+    /// Time taken for the run in seconds
+    pub duration_secs: Option<i32>,
+    /// Kind of activity
+    pub sport: Option<Sport>,
+    /// Average heart rate in beats per minute
+    pub avg_heart_rate: Option<i32>,
+    /// Maximum heart rate in beats per minute
+    pub max_heart_rate: Option<i32>,
+    /// Total ascent in metres
+    pub elevation_gain: Option<f32>,
+    /// Id of the activity with an external provider, used to log each
+    /// activity only once
+    pub external_id: Option<String>,
+    /// Whether the run was a race effort
+    pub race: bool,
+    /// Time taken to reach points along the run, in order
+    pub splits: Vec<Split>,
+}
+
+impl From<Timing> for RunDetails {
+    fn from(timing: Timing) -> Self {
+        RunDetails {
+            duration_secs: timing.duration_secs,
+            race: timing.race,
+            splits: timing.splits,
+            ..Default::default()
+        }
+    }
+}
+
+/// How long it took to reach a point along a run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Split {
+    /// Distance from the start, in km.
+    pub distance: f32,
+    /// Time from the start, in seconds.
+    pub elapsed_secs: i32,
+}
+
+/// How long a run took, as given to `/add` and `/edit` after its distance.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Timing {
+    /// Time taken for the run in seconds, or the sum of its splits.
+    pub duration_secs: Option<i32>,
+    /// Whether the run was a race effort.
+    pub race: bool,
+    /// Time taken to reach the end of every km, then the end of the run.
+    pub splits: Vec<Split>,
+}
+
+/// A run with a time, along with what personal records are worked out
+/// from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimedRun {
+    /// Run id
+    pub id: i32,
+    /// Datetime of the run
+    pub run_datetime: chrono::NaiveDateTime,
+    /// Distance ran in km
+    pub distance: f32,
+    /// Time taken for the run in seconds
+    pub duration_secs: i32,
+    /// Whether the run was a race effort
+    pub race: bool,
+    /// Time taken to reach points along the run, in order
+    pub splits: Vec<Split>,
+}
+
+/// Kinds of activities that can be logged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sport {
+    /// Running, the default for runs without a sport.
+    Run,
+    /// Cycling.
+    Ride,
+    /// Walking.
+    Walk,
+    /// Hiking.
+    Hike,
+    /// Swimming.
+    Swim,
+    /// Anything else.
+    Other,
+}
+
+impl Sport {
+    /// Name the sport is stored as in the `runs` table.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Sport::Run => "run",
+            Sport::Ride => "ride",
+            Sport::Walk => "walk",
+            Sport::Hike => "hike",
+            Sport::Swim => "swim",
+            Sport::Other => "other",
+        }
+    }
+
+    /// Verb used when reporting an activity of this sport.
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Sport::Run => "ran",
+            Sport::Ride => "rode",
+            Sport::Walk => "walked",
+            Sport::Hike => "hiked",
+            Sport::Swim => "swam",
+            Sport::Other => "covered",
+        }
+    }
+
+    /// Maps the sport names used by activity files, e.g. `Running` in TCX
+    /// or `cycling` in GPX, onto a `Sport`.
+    pub fn from_activity_name(name: &str) -> Sport {
+        let name = name.trim().to_lowercase();
+        match name.as_str() {
+            "run" | "running" | "trail_running" | "treadmill" => Sport::Run,
+            "ride" | "biking" | "cycling" | "road_biking" | "mountain_biking" => Sport::Ride,
+            "walk" | "walking" => Sport::Walk,
+            "hike" | "hiking" => Sport::Hike,
+            "swim" | "swimming" | "open_water_swimming" | "lap_swimming" => Sport::Swim,
+            _ => Sport::Other,
+        }
+    }
+}
+
+/// Error returned when a sport is not one of those in `Sport`.
+#[derive(Debug)]
+pub struct ParseSportError(String);
+
+impl fmt::Display for ParseSportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown sport \"{}\", expected run, ride, walk, hike, swim or other.",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseSportError {}
+
+/// Parses sports by the names they are stored as, e.g. `ride`.
+impl FromStr for Sport {
+    type Err = ParseSportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Sport::Run,
+            Sport::Ride,
+            Sport::Walk,
+            Sport::Hike,
+            Sport::Swim,
+            Sport::Other,
+        ]
+        .into_iter()
+        .find(|sport| sport.as_str() == s.trim().to_lowercase())
+        .ok_or_else(|| ParseSportError(s.trim().into()))
+    }
+}
+
+/// A run to be added to the `runs` table along with its user.
+///
+/// Used for bulk inserts, where users may not exist yet.
+pub struct NewRun {
+    /// Self-specified username
+    pub user_name: String,
+    /// User id as seen from Telegram
+    pub telegram_userid: UserId,
+    /// Distance ran
+    pub distance: f32,
+    /// Datetime of the run, defaults to now if not given
+    pub run_datetime: Option<chrono::NaiveDateTime>,
+}
+
+/// Total distance a user covered on a single day, as charted by `/chart`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DailyDistance {
+    /// Self-specified username
+    pub user_name: String,
+    /// Day the runs were on
+    pub day: chrono::NaiveDate,
+    /// Distance ran that day
+    pub distance: f32,
+}
+
+/// Window of time that runs are aggregated over.
+///
+/// Periods follow the calendar, e.g. `Week` covers runs since Monday.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Period {
+    /// Every run ever added.
+    #[default]
+    All,
+    /// Runs since the start of the current week.
+    Week,
+    /// Runs since the start of the current month.
+    Month,
+    /// Runs since the start of the current year.
+    Year,
+}
+
+impl Period {
+    /// Start of the period containing `now`, or `None` for all time.
+    pub fn start(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let today = now.date();
+        let start = match self {
+            Period::All => return None,
+            Period::Week => today - Duration::days(today.weekday().num_days_from_monday().into()),
+            Period::Month => today.with_day(1)?,
+            Period::Year => NaiveDate::from_ymd_opt(today.year(), 1, 1)?,
+        };
+
+        start.and_hms_opt(0, 0, 0)
+    }
+
+    /// End of the period containing `now`, which is the start of the next
+    /// one, or `None` for all time.
+    pub fn end(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = self.start(now)?;
+        let later = match self {
+            Period::All => return None,
+            Period::Week => return Some(start + Duration::days(7)),
+            Period::Month => start + Duration::days(31),
+            Period::Year => start + Duration::days(366),
+        };
+
+        self.start(later)
+    }
+}
+
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Period::All => "all",
+            Period::Week => "week",
+            Period::Month => "month",
+            Period::Year => "year",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Error returned when a period is not one of `week`, `month`, `year` or `all`.
+#[derive(Debug)]
+pub struct ParsePeriodError(String);
+
+impl fmt::Display for ParsePeriodError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown period \"{}\", expected week, month, year or all.",
+            self.0
+        )
+    }
+}
+
+impl Error for ParsePeriodError {}
+
+/// Parses periods from command arguments. No argument means all time.
+impl FromStr for Period {
+    type Err = ParsePeriodError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "all" => Ok(Period::All),
+            "week" => Ok(Period::Week),
+            "month" => Ok(Period::Month),
+            "year" => Ok(Period::Year),
+            _ => Err(ParsePeriodError(s.trim().into())),
+        }
+    }
+}
+
+/// Whose runs a `RunFilter` keeps.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RunOwner {
+    /// The user asking, to be resolved to `Id` once they are known.
+    Me,
+    /// The user with this Telegram id.
+    Id(UserId),
+    /// The user with this name, matched case insensitively.
+    Name(String),
+}
+
+/// Narrows down which runs are listed.
+///
+/// Filters are written as the arguments of `/list`, e.g. `week ride @alice`,
+/// and read back with `parse_list_args`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunFilter {
+    /// Only runs by this user, if given.
+    pub owner: Option<RunOwner>,
+    /// Only runs in this period.
+    pub period: Period,
+    /// Only activities of this sport, if given. Runs without a sport count
+    /// as `Sport::Run`.
+    pub sport: Option<Sport>,
+}
+
+impl RunFilter {
+    /// Replaces `RunOwner::Me` with the id of the user asking.
+    pub fn resolve(mut self, me: UserId) -> Self {
+        if self.owner == Some(RunOwner::Me) {
+            self.owner = Some(RunOwner::Id(me));
+        }
+        self
+    }
+}
+
+impl fmt::Display for RunFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut arguments = vec![];
+        if self.period != Period::All {
+            arguments.push(self.period.to_string());
+        }
+        if let Some(sport) = self.sport {
+            arguments.push(sport.as_str().to_string());
+        }
+        match &self.owner {
+            Some(RunOwner::Me) => arguments.push("me".into()),
+            Some(RunOwner::Id(id)) => arguments.push(format!("id:{}", id)),
+            Some(RunOwner::Name(name)) => arguments.push(format!("@{}", name)),
+            None => {}
+        }
+        write!(f, "{}", arguments.join(" "))
+    }
+}
+
+/// Number of runs `/list` shows when not given a number.
+pub const DEFAULT_LIST_LIMIT: u32 = 10;
+/// Most runs `/list` shows at once.
+pub const MAX_LIST_LIMIT: u32 = 50;
+
+/// Error returned when an argument of `/list` is not a filter.
+#[derive(Debug)]
+pub struct ParseFilterError(String);
+
+impl fmt::Display for ParseFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown filter \"{}\", expected me, @user, a number of runs, a period or a sport.",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseFilterError {}
+
+/// Parses the arguments of `/list [me|@user] [limit] [period] [sport]`.
+///
+/// Every argument is optional and they may come in any order. The limit
+/// defaults to `DEFAULT_LIST_LIMIT` and is capped at `MAX_LIST_LIMIT`.
+pub fn parse_list_args(input: String) -> Result<(RunFilter, u32), ParseError> {
+    let mut filter = RunFilter::default();
+    let mut limit = DEFAULT_LIST_LIMIT;
+    for argument in input.split_whitespace() {
+        let lowercase = argument.to_lowercase();
+        if let Ok(parsed) = argument.parse::<u32>() {
+            limit = parsed.clamp(1, MAX_LIST_LIMIT);
+        } else if lowercase == "me" {
+            filter.owner = Some(RunOwner::Me);
+        } else if let Some(name) = argument.strip_prefix('@').filter(|name| !name.is_empty()) {
+            filter.owner = Some(RunOwner::Name(name.into()));
+        } else if let Some(Ok(id)) = lowercase.strip_prefix("id:").map(str::parse) {
+            filter.owner = Some(RunOwner::Id(UserId(id)));
+        } else if let Ok(period) = argument.parse() {
+            filter.period = period;
+        } else if let Ok(sport) = argument.parse() {
+            filter.sport = Some(sport);
+        } else {
+            return Err(ParseError::IncorrectFormat(Box::new(ParseFilterError(
+                argument.into(),
+            ))));
+        }
+    }
+
+    Ok((filter, limit))
+}
+
+/// Error returned when the time, race mark or splits of a run cannot be
+/// read.
+#[derive(Debug, PartialEq)]
+pub struct ParseTimingError;
+
+impl fmt::Display for ParseTimingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Expected <distance> [time] [race] [splits <time per km>...], e.g. 5 24:30 race."
+        )
+    }
+}
+
+impl Error for ParseTimingError {}
+
+/// Parses a time as `m:ss` or `h:mm:ss` into seconds.
+pub fn parse_duration(input: &str) -> Option<i32> {
+    let parts: Vec<i32> = input
+        .split(':')
+        .map(|part| part.parse().ok().filter(|part| *part >= 0))
+        .collect::<Option<_>>()?;
+    let secs = match parts[..] {
+        [minutes, seconds] if seconds < 60 => minutes.checked_mul(60)?.checked_add(seconds)?,
+        [hours, minutes, seconds] if minutes < 60 && seconds < 60 => hours
+            .checked_mul(3600)?
+            .checked_add(minutes * 60 + seconds)?,
+        _ => return None,
+    };
+    (secs > 0).then_some(secs)
+}
+
+/// Reads what follows the distance of a run: a time, `race` and `splits`
+/// followed by the time each km took, the last one being the rest of the
+/// run.
+///
+/// Without a time, the run took as long as its splits add up to.
+fn parse_timing(distance: f32, arguments: &[&str]) -> Result<Timing, ParseTimingError> {
+    let mut timing = Timing::default();
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        match argument.to_lowercase().as_str() {
+            "race" => timing.race = true,
+            "splits" => {
+                let times: Vec<i32> = arguments
+                    .by_ref()
+                    .map(|time| parse_duration(time))
+                    .collect::<Option<_>>()
+                    .ok_or(ParseTimingError)?;
+                if distance <= 0. || times.len() != distance.ceil() as usize {
+                    return Err(ParseTimingError);
+                }
+                let mut elapsed_secs = 0;
+                for (km, time) in times.into_iter().enumerate() {
+                    elapsed_secs += time;
+                    timing.splits.push(Split {
+                        distance: ((km + 1) as f32).min(distance),
+                        elapsed_secs,
+                    });
+                }
+            }
+            _ if timing.duration_secs.is_none() => {
+                timing.duration_secs = Some(parse_duration(argument).ok_or(ParseTimingError)?);
+            }
+            _ => return Err(ParseTimingError),
+        }
+    }
+    if timing.duration_secs.is_none() {
+        timing.duration_secs = timing.splits.last().map(|split| split.elapsed_secs);
+    }
+    Ok(timing)
+}
+
+/// Parses the arguments of `/add <distance> [time] [race] [splits ...]`.
+pub fn parse_add_args(input: String) -> Result<(f32, Timing), ParseError> {
+    let arguments: Vec<&str> = input.split_whitespace().collect();
+    let Some((distance, rest)) = arguments.split_first() else {
+        return Err(ParseError::IncorrectFormat(Box::new(ParseTimingError)));
+    };
+    let distance: f32 = distance
+        .parse()
+        .map_err(|err| ParseError::IncorrectFormat(Box::new(err)))?;
+    let timing =
+        parse_timing(distance, rest).map_err(|err| ParseError::IncorrectFormat(Box::new(err)))?;
+    Ok((distance, timing))
+}
+
+/// Parses the arguments of `/edit <run_id> <distance> [time] [race]
+/// [splits ...]`.
+pub fn parse_edit_args(input: String) -> Result<(i32, f32, Timing), ParseError> {
+    let input = input.trim_start();
+    let (run_id, rest) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    let run_id: i32 = run_id
+        .parse()
+        .map_err(|err| ParseError::IncorrectFormat(Box::new(err)))?;
+    let (distance, timing) = parse_add_args(rest.to_string())?;
+    Ok((run_id, distance, timing))
 }
 
 /// Represents a score that appears in the tally.
 ///
 /// While this struct those not correspond direclty to a database
 /// table, it is built directly from results retrieved.
+#[derive(Clone)]
 pub struct Score {
+    /// Place in the tally, shared by users who ran the same distance
+    pub rank: u32,
     /// Self-specified username
     pub user_name: String,
+    /// User id as seen from Telegram, used to mention the user
+    pub telegram_userid: String,
     /// Number of runs for the user, or in this case, medals
     pub medals: u32,
     /// Total distance run by the user
     pub distance: f32,
 }
+
+/// How the tally groups runs into places.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum TallyGrouping {
+    /// A place per user.
+    #[default]
+    Users,
+    /// A place per team, adding up the runs of its members.
+    Teams {
+        /// Whether distances are divided by the number of members, so that
+        /// teams of different sizes can compete.
+        per_member: bool,
+    },
+}
+
+/// A team within a chat.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Team {
+    /// Name of the team, as it was created
+    pub name: String,
+    /// Names of its members, in alphabetical order
+    pub members: Vec<String>,
+}
+
+/// Longest emoji, or text, accepted for a place in the tally, in characters.
+const MAX_RANK_EMOJI: usize = 16;
+/// Most places that can be given their own emoji.
+const MAX_PODIUM: usize = 10;
+
+/// Emojis marking places in the tally, configured per chat.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RankEmojis {
+    /// Emojis for the first places, in order.
+    pub podium: Vec<String>,
+    /// Emoji for everyone off the podium, if any.
+    pub others: Option<String>,
+    /// Emoji for last place, if any. Last place only gets it when it is
+    /// off the podium.
+    pub last: Option<String>,
+}
+
+impl Default for RankEmojis {
+    fn default() -> Self {
+        RankEmojis {
+            podium: vec!["🥇".into(), "🥈".into(), "🥉".into()],
+            others: Some("🏃".into()),
+            last: Some("🤡".into()),
+        }
+    }
+}
+
+impl RankEmojis {
+    /// No emojis at all.
+    pub fn off() -> Self {
+        RankEmojis {
+            podium: vec![],
+            others: None,
+            last: None,
+        }
+    }
+
+    /// Emoji for `rank`, where `last_rank` is the rank of last place.
+    pub fn emoji(&self, rank: u32, last_rank: u32) -> Option<&str> {
+        let podium = self.podium.len() as u32;
+        if rank == last_rank && rank > podium {
+            self.last.as_deref()
+        } else if rank <= podium {
+            self.podium.get(rank as usize - 1).map(String::as_str)
+        } else {
+            self.others.as_deref()
+        }
+    }
+}
+
+/// Written as `/emojis` takes them: the podium, then the emoji for everyone
+/// else and the one for last place, with `-` for none.
+impl fmt::Display for RankEmojis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut emojis: Vec<&str> = self.podium.iter().map(String::as_str).collect();
+        emojis.push(self.others.as_deref().unwrap_or("-"));
+        emojis.push(self.last.as_deref().unwrap_or("-"));
+        write!(f, "{}", emojis.join(" "))
+    }
+}
+
+/// Error returned when rank emojis can't be parsed.
+#[derive(Debug)]
+pub struct ParseRankEmojisError;
+
+impl fmt::Display for ParseRankEmojisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Expected up to {} emojis for the first places, then one for everyone else and one for last place, using - for none. Example: /emojis 🥇 🥈 🥉 🏃 -",
+            MAX_PODIUM
+        )
+    }
+}
+
+impl Error for ParseRankEmojisError {}
+
+/// Parses rank emojis as written by `Display`, or `off` for none.
+impl FromStr for RankEmojis {
+    type Err = ParseRankEmojisError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let emojis: Vec<&str> = s.split_whitespace().collect();
+        if emojis == ["off"] {
+            return Ok(RankEmojis::off());
+        }
+        if emojis.len() < 2
+            || emojis.len() > MAX_PODIUM + 2
+            || emojis
+                .iter()
+                .any(|emoji| emoji.chars().count() > MAX_RANK_EMOJI)
+        {
+            return Err(ParseRankEmojisError);
+        }
+        let emoji = |emoji: &str| (emoji != "-").then(|| emoji.to_string());
+        let (podium, rest) = emojis.split_at(emojis.len() - 2);
+
+        Ok(RankEmojis {
+            podium: podium.iter().map(|emoji| emoji.to_string()).collect(),
+            others: emoji(rest[0]),
+            last: emoji(rest[1]),
+        })
+    }
+}
+
+/// Represents a row in the `recap_schedules` table, a weekly or monthly
+/// recap a chat asked for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecapSchedule {
+    /// Id of telegram chat
+    pub chat_id: ChatId,
+    /// Period recapped, either `Week` or `Month`
+    pub period: Period,
+    /// Time of day the recap is posted at, in `timezone`
+    pub local_time: NaiveTime,
+    /// Time zone of the chat
+    pub timezone: Tz,
+    /// When the recap was last posted, in UTC
+    pub last_sent: NaiveDateTime,
+}
+
+/// Represents a row in the `reminder_schedules` table, the daily reminder
+/// a chat asked for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReminderSchedule {
+    /// Id of telegram chat
+    pub chat_id: ChatId,
+    /// Days without runs after which users are reminded
+    pub inactive_days: u32,
+    /// Time of day reminders are sent at, in `timezone`
+    pub local_time: NaiveTime,
+    /// Time zone of the chat
+    pub timezone: Tz,
+    /// When reminders were last sent, in UTC
+    pub last_sent: NaiveDateTime,
+}
+
+/// Represents a row in the `chat_goals` table, a distance a chat sets out
+/// to run together over a period.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Goal {
+    /// Distance to run together, in km
+    pub distance: f32,
+    /// Period the goal was set for
+    pub period: Period,
+    /// Start of the goal's window
+    pub starts: NaiveDateTime,
+    /// End of the goal's window, exclusive
+    pub ends: NaiveDateTime,
+    /// When the goal was reached, if it has been
+    pub reached: Option<NaiveDateTime>,
+}
+
+impl Goal {
+    /// When the goal would be reached if the chat keeps up the pace it has
+    /// had from the start, having run `done` by `now`. `None` if nobody has
+    /// run towards it yet.
+    pub fn projected_finish(&self, done: f32, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let elapsed = (now.min(self.ends) - self.starts).num_seconds();
+        if done <= 0.0 || elapsed <= 0 {
+            return None;
+        }
+        let needed = elapsed as f64 * f64::from(self.distance) / f64::from(done);
+        self.starts
+            .checked_add_signed(Duration::seconds(needed.min(1e12) as i64))
+    }
+}
+
+/// Represents a row in the `chat_journeys` table, a bundled route a chat
+/// runs along together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Journey {
+    /// Id of the route, e.g. `singapore-kuala-lumpur`
+    pub route: String,
+    /// When the chat set off, from which runs count
+    pub starts: NaiveDateTime,
+    /// Index of the furthest waypoint announced, 0 being the start
+    pub reached: u32,
+}
+
+/// What a personal goal counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GoalUnit {
+    /// Distance ran, in km.
+    Km,
+    /// Number of runs.
+    Runs,
+}
+
+impl GoalUnit {
+    /// Formats `amount` of the unit, e.g. `5.0km` or `4 runs`.
+    pub fn format(&self, amount: f32) -> String {
+        match self {
+            GoalUnit::Km if amount.fract() == 0. => format!("{:.1}km", amount),
+            GoalUnit::Km => format!("{}km", amount),
+            GoalUnit::Runs if amount == 1. => "1 run".into(),
+            GoalUnit::Runs => format!("{} runs", amount),
+        }
+    }
+}
+
+impl fmt::Display for GoalUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GoalUnit::Km => write!(f, "km"),
+            GoalUnit::Runs => write!(f, "runs"),
+        }
+    }
+}
+
+/// Error returned when a goal unit is neither `km` nor `runs`.
+#[derive(Debug, PartialEq)]
+pub struct ParseGoalUnitError;
+
+impl fmt::Display for ParseGoalUnitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expected km or runs.")
+    }
+}
+
+impl Error for ParseGoalUnitError {}
+
+impl FromStr for GoalUnit {
+    type Err = ParseGoalUnitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "km" => Ok(GoalUnit::Km),
+            "run" | "runs" => Ok(GoalUnit::Runs),
+            _ => Err(ParseGoalUnitError),
+        }
+    }
+}
+
+/// Represents a row in the `personal_goals` table, what a user aims to run
+/// every week or month in a chat.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PersonalGoal {
+    /// Period the goal repeats over, either `Week` or `Month`
+    pub period: Period,
+    /// Distance or number of runs to reach every period
+    pub target: f32,
+    /// Whether `target` is a distance or a number of runs
+    pub unit: GoalUnit,
+    /// When the user started aiming for goals over this period
+    pub since: NaiveDateTime,
+}
+
+/// Distance a user ran, and how many runs it took, over a period.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Progress {
+    /// Total distance ran
+    pub distance: f32,
+    /// Number of runs
+    pub runs: u32,
+}
+
+impl Progress {
+    /// Amount of `unit` covered.
+    pub fn amount(&self, unit: GoalUnit) -> f32 {
+        match unit {
+            GoalUnit::Km => (self.distance * 100.).round() / 100.,
+            GoalUnit::Runs => self.runs as f32,
+        }
+    }
+}
+
+/// Represents a row in the `challenges` table, a race between two users
+/// to be the first to run a distance.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Challenge {
+    /// Challenge id
+    pub id: i32,
+    /// Id of telegram chat
+    pub chat_id: ChatId,
+    /// Telegram id of the user who made the challenge
+    pub challenger_id: UserId,
+    /// Username of the user who made the challenge
+    pub challenger_name: String,
+    /// Username of the user challenged, without the `@`
+    pub opponent_name: String,
+    /// Telegram id of the user challenged, once they accept
+    pub opponent_id: Option<UserId>,
+    /// Distance to run first, in km
+    pub distance: f32,
+    /// Number of days the challenge lasts once accepted
+    pub days: u32,
+    /// When the challenge was accepted
+    pub starts: Option<NaiveDateTime>,
+    /// When the challenge ends, exclusive
+    pub ends: Option<NaiveDateTime>,
+}
+
+/// Which side of a challenge won.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The user who made the challenge won.
+    Challenger,
+    /// The user challenged won.
+    Opponent,
+    /// Both ran the same distance.
+    Draw,
+}
+
+/// Distances both sides of a challenge ran, and how it ended, if it has.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Standings {
+    /// Distance ran by the user who made the challenge
+    pub challenger: f32,
+    /// Distance ran by the user challenged
+    pub opponent: f32,
+    /// How the challenge ended, if it has
+    pub outcome: Option<Outcome>,
+}
+
+/// Days in a row a user has been running, as highlighted in recaps.
+#[derive(Debug, PartialEq)]
+pub struct Streak {
+    /// Self-specified username
+    pub user_name: String,
+    /// Number of consecutive days with runs
+    pub days: u32,
+}
+
+/// The user who ran the most beyond what they ran the period before.
+#[derive(Debug, PartialEq)]
+pub struct Improvement {
+    /// Self-specified username
+    pub user_name: String,
+    /// User id as seen from Telegram, used to mention the user
+    pub telegram_userid: String,
+    /// Distance ran over the previous period
+    pub previous: f32,
+    /// Distance ran over the recapped period
+    pub current: f32,
+}
+
+/// Summary of a week or month, posted to chats on a schedule.
+pub struct Recap {
+    /// Period recapped, either `Week` or `Month`
+    pub period: Period,
+    /// Tally of the period
+    pub scores: Vec<Score>,
+    /// User who improved the most on the previous period, if anyone did
+    pub most_improved: Option<Improvement>,
+    /// Longest ongoing streaks, longest first
+    pub streaks: Vec<Streak>,
+}
+
+/// Tokens letting the bot read the activities of a linked account, as
+/// stored in the `provider_links` table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessTokens {
+    /// Token sent as bearer auth when fetching activities
+    pub access_token: String,
+    /// Token exchanged for new tokens once `access_token` expires
+    pub refresh_token: String,
+    /// When `access_token` expires, in UTC
+    pub expires_at: NaiveDateTime,
+}
+
+/// Represents a row in the `link_requests` table, a user who started
+/// linking an account with a provider and has yet to finish.
+#[derive(Debug, PartialEq)]
+pub struct LinkRequest {
+    /// Telegram id of the user
+    pub telegram_userid: UserId,
+    /// Id of telegram chat the account is being linked in
+    pub chat_id: ChatId,
+    /// Self-specified username
+    pub user_name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn period_starts() {
+        // A Wednesday.
+        let now = NaiveDate::from_ymd_opt(2023, 10, 11)
+            .unwrap()
+            .and_hms_opt(18, 30, 0)
+            .unwrap();
+        let midnight = |y, m, d| {
+            NaiveDate::from_ymd_opt(y, m, d)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+        };
+
+        assert_eq!(Period::All.start(now), None);
+        assert_eq!(Period::Week.start(now), midnight(2023, 10, 9));
+        assert_eq!(Period::Month.start(now), midnight(2023, 10, 1));
+        assert_eq!(Period::Year.start(now), midnight(2023, 1, 1));
+        assert_eq!(Period::All.end(now), None);
+        assert_eq!(Period::Week.end(now), midnight(2023, 10, 16));
+        assert_eq!(Period::Month.end(now), midnight(2023, 11, 1));
+        assert_eq!(Period::Year.end(now), midnight(2024, 1, 1));
+    }
+
+    #[test]
+    fn sports_from_activity_names() {
+        assert_eq!(Sport::from_activity_name("Running"), Sport::Run);
+        assert_eq!(Sport::from_activity_name("Biking"), Sport::Ride);
+        assert_eq!(Sport::from_activity_name(" hiking "), Sport::Hike);
+        assert_eq!(Sport::from_activity_name("Other"), Sport::Other);
+        assert_eq!(Sport::from_activity_name("yoga"), Sport::Other);
+    }
+
+    #[test]
+    fn parse_periods() {
+        assert_eq!("".parse::<Period>().unwrap(), Period::All);
+        assert_eq!(" Week ".parse::<Period>().unwrap(), Period::Week);
+        assert_eq!("month".parse::<Period>().unwrap(), Period::Month);
+        assert!("fortnight".parse::<Period>().is_err());
+    }
+
+    #[test]
+    fn parse_list_filters() {
+        assert_eq!(
+            parse_list_args("".into()).unwrap(),
+            (RunFilter::default(), DEFAULT_LIST_LIMIT)
+        );
+        assert_eq!(parse_list_args("5".into()).unwrap().1, 5);
+        assert_eq!(parse_list_args("1000".into()).unwrap().1, MAX_LIST_LIMIT);
+
+        let (filter, limit) = parse_list_args("@alice 10 week RIDE".into()).unwrap();
+        assert_eq!(limit, 10);
+        assert_eq!(
+            filter,
+            RunFilter {
+                owner: Some(RunOwner::Name("alice".into())),
+                period: Period::Week,
+                sport: Some(Sport::Ride),
+            }
+        );
+        assert!(parse_list_args("yesterday".into()).is_err());
+    }
+
+    #[test]
+    fn parse_run_timings() {
+        assert_eq!(parse_add_args("5".into()).unwrap(), (5., Timing::default()));
+        assert_eq!(
+            parse_add_args("21.3 RACE 1:45:02".into()).unwrap(),
+            (
+                21.3,
+                Timing {
+                    duration_secs: Some(6302),
+                    race: true,
+                    splits: vec![],
+                }
+            )
+        );
+        let split = |distance, elapsed_secs| Split {
+            distance,
+            elapsed_secs,
+        };
+        assert_eq!(
+            parse_edit_args("3 2.5 splits 4:50 5:00 2:40".into()).unwrap(),
+            (
+                3,
+                2.5,
+                Timing {
+                    duration_secs: Some(750),
+                    race: false,
+                    splits: vec![split(1., 290), split(2., 590), split(2.5, 750)],
+                }
+            )
+        );
+        for invalid in [
+            "",
+            "five",
+            "5 24:61",
+            "5 0:00",
+            "5 24:30 25:00",
+            "5 fast",
+            "2.5 splits 4:50 5:00",
+            "2 splits 4:50 5:00 1:00",
+        ] {
+            assert!(parse_add_args(invalid.into()).is_err(), "{}", invalid);
+        }
+        assert!(parse_edit_args("5".into()).is_err());
+        assert_eq!(parse_duration("75:00"), Some(4500));
+        assert_eq!(parse_duration("1:15:00"), Some(4500));
+        assert_eq!(parse_duration("1:60:00"), None);
+    }
+
+    #[test]
+    fn filters_round_trip() {
+        let filter = RunFilter {
+            owner: Some(RunOwner::Me),
+            period: Period::Month,
+            sport: Some(Sport::Run),
+        }
+        .resolve(UserId(11));
+        assert_eq!(filter.to_string(), "month run id:11");
+        assert_eq!(parse_list_args(filter.to_string()).unwrap().0, filter);
+    }
+
+    #[test]
+    fn rank_emojis() {
+        let emojis = RankEmojis::default();
+        assert_eq!(emojis.emoji(1, 5), Some("🥇"));
+        assert_eq!(emojis.emoji(4, 5), Some("🏃"));
+        assert_eq!(emojis.emoji(5, 5), Some("🤡"));
+        // Last place on the podium keeps their medal.
+        assert_eq!(emojis.emoji(2, 2), Some("🥈"));
+
+        let emojis: RankEmojis = "⭐ - -".parse().unwrap();
+        assert_eq!(emojis.emoji(1, 3), Some("⭐"));
+        assert_eq!(emojis.emoji(3, 3), None);
+        assert_eq!(emojis.to_string(), "⭐ - -");
+        assert_eq!("off".parse::<RankEmojis>().unwrap(), RankEmojis::off());
+        assert_eq!(
+            RankEmojis::default()
+                .to_string()
+                .parse::<RankEmojis>()
+                .unwrap(),
+            RankEmojis::default()
+        );
+        assert!("🥇".parse::<RankEmojis>().is_err());
+    }
+}
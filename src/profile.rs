@@ -0,0 +1,210 @@
+//! Users' profiles across chats, with `/profile`.
+//!
+//! Runs belong to the chat they were added in, but users are known across
+//! chats by their Telegram id. A user can share runs between chats, so
+//! that a run added in one is posted to the others too, and see everything
+//! they ran in a private chat with the bot, without counting shared runs
+//! more than once.
+use crate::{
+    cache::TallyCache,
+    challenge::settle_challenges,
+    database::{count_user_chats, get_profile_progress, get_shared_chats, set_chat_shared},
+    goal::celebrate_goal,
+    journey::announce_landmarks,
+    message::display_profile,
+    models::{Period, Progress},
+};
+use chrono::{NaiveDateTime, Utc};
+use sqlx::PgPool;
+use std::{error::Error, fmt, str::FromStr};
+use teloxide::{prelude::*, types::ParseMode};
+use tracing::error;
+
+/// What `/profile` was asked to do.
+#[derive(Debug, PartialEq)]
+pub enum ProfileAction {
+    /// Show the user's runs across chats, or whether they share runs from
+    /// a group chat.
+    Show,
+    /// Share runs added in the chat with the user's other chats, or stop.
+    Share(bool),
+}
+
+/// Error returned when the arguments of `/profile` cannot be read.
+#[derive(Debug, PartialEq)]
+pub struct ParseProfileError;
+
+impl fmt::Display for ParseProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expected /profile [share on|off].")
+    }
+}
+
+impl Error for ParseProfileError {}
+
+/// Parses the arguments of `/profile [share on|off]`.
+impl FromStr for ProfileAction {
+    type Err = ParseProfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let arguments: Vec<String> = s.split_whitespace().map(str::to_lowercase).collect();
+        match &arguments[..] {
+            [] => Ok(ProfileAction::Show),
+            [share, on] if share == "share" && on == "on" => Ok(ProfileAction::Share(true)),
+            [share, off] if share == "share" && off == "off" => Ok(ProfileAction::Share(false)),
+            _ => Err(ParseProfileError),
+        }
+    }
+}
+
+/// Handles `/profile`, showing the user's runs across chats or sharing runs
+/// between chats.
+pub async fn answer_profile(bot: &Bot, msg: &Message, args: &str, connection: &PgPool) {
+    let Some(user) = msg.from() else {
+        error!("Unable to retrieve user from message.");
+        return;
+    };
+    let action = match args.parse::<ProfileAction>() {
+        Ok(action) => action,
+        Err(err) => {
+            bot.send_message(msg.chat.id, err.to_string())
+                .await
+                .map_err(|err| error!("Unable to send Profile message: {:?}", err))
+                .ok();
+            return;
+        }
+    };
+    let reply = match action {
+        ProfileAction::Show if msg.chat.is_private() => {
+            let now = chrono::Utc::now().naive_utc();
+            let user_name = user.username.as_deref().unwrap_or(&user.first_name);
+            profile_stats(user.id, now, connection)
+                .await
+                .map(|stats| display_profile(user_name, &stats))
+        }
+        ProfileAction::Share(_) if msg.chat.is_private() => Ok(
+            "Send /profile share on in each of the group chats you want to share runs between."
+                .into(),
+        ),
+        ProfileAction::Show | ProfileAction::Share(_) => {
+            let set = match action {
+                ProfileAction::Share(shared) => {
+                    let user_name = user.username.as_deref().unwrap_or(&user.first_name);
+                    set_chat_shared(user.id, user_name, msg.chat.id, shared, connection).await
+                }
+                ProfileAction::Show => Ok(()),
+            };
+            match set {
+                Ok(()) => get_shared_chats(user.id, connection).await.map(|chat_ids| {
+                    let shared = chat_ids.contains(&msg.chat.id);
+                    describe_sharing(shared, chat_ids.len() - usize::from(shared))
+                }),
+                Err(err) => Err(err),
+            }
+        }
+    };
+    match reply {
+        Ok(reply) => {
+            bot.send_message(msg.chat.id, reply)
+                .parse_mode(ParseMode::Html)
+                .await
+                .map_err(|err| error!("Unable to send Profile message: {:?}", err))
+                .ok();
+        }
+        Err(err) => error!("Unable to update profile: {:?}", err),
+    }
+}
+
+/// Describes whether runs added in a chat are shared with the `others`
+/// chats the user shares runs between.
+pub fn describe_sharing(shared: bool, others: usize) -> String {
+    let sharing = match (shared, others) {
+        (false, _) => "Runs you /add here stay here. Send /profile share on to also post them to your other chats that share runs.".to_string(),
+        (true, 0) => "Runs you /add here will be posted to your other chats once you send /profile share on in them too.".to_string(),
+        (true, others) => format!(
+            "Runs you /add here are also posted to {} other chat{} that share{} runs. Send /profile share off to stop.",
+            others,
+            if others == 1 { "" } else { "s" },
+            if others == 1 { "s" } else { "" },
+        ),
+    };
+    format!(
+        "{}\nSend me /profile in a private chat to see all your runs.",
+        sharing
+    )
+}
+
+/// Posts a run shared from another chat to the chats it was copied to.
+///
+/// Their tallies are invalidated, and goals reached, landmarks passed and
+/// challenges won thanks to the run are announced, as in the chat it was
+/// added to.
+pub async fn post_shared_run(
+    bot: &Bot,
+    shared_to: &[ChatId],
+    user_name: &str,
+    distance: f32,
+    tally_cache: &TallyCache,
+    connection: &PgPool,
+) {
+    for &chat_id in shared_to {
+        tally_cache.invalidate(chat_id);
+        bot.send_message(
+            chat_id,
+            format!(
+                "{} ran {}km, shared from another chat.",
+                user_name, distance
+            ),
+        )
+        .await
+        .map_err(|error| error!("Unable to send shared run message: {:?}", error))
+        .ok();
+        celebrate_goal(bot, chat_id, connection).await;
+        announce_landmarks(bot, chat_id, connection).await;
+        settle_challenges(bot, Some(chat_id), connection, Utc::now().naive_utc()).await;
+    }
+}
+
+/// What a user ran across all of their chats.
+pub struct ProfileStats {
+    /// Number of chats the user has added runs to.
+    pub chats: u32,
+    /// Number of chats the user shares runs between.
+    pub shared_chats: u32,
+    /// What they ran all time, this year, this month and this week.
+    pub periods: Vec<(Period, Progress)>,
+}
+
+/// Looks up what a user ran across all of their chats, as of `now`.
+pub async fn profile_stats(
+    telegram_userid: UserId,
+    now: NaiveDateTime,
+    connection: &PgPool,
+) -> Result<ProfileStats, sqlx::Error> {
+    let mut periods = vec![];
+    for period in [Period::All, Period::Year, Period::Month, Period::Week] {
+        let progress = get_profile_progress(telegram_userid, period.start(now), connection).await?;
+        periods.push((period, progress));
+    }
+
+    Ok(ProfileStats {
+        chats: count_user_chats(telegram_userid, connection).await?,
+        shared_chats: get_shared_chats(telegram_userid, connection).await?.len() as u32,
+        periods,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_profile_actions() {
+        assert_eq!("".parse(), Ok(ProfileAction::Show));
+        assert_eq!("share on".parse(), Ok(ProfileAction::Share(true)));
+        assert_eq!("Share OFF".parse(), Ok(ProfileAction::Share(false)));
+        for invalid in ["share", "share maybe", "stats"] {
+            assert_eq!(invalid.parse::<ProfileAction>(), Err(ParseProfileError));
+        }
+    }
+}
@@ -0,0 +1,602 @@
+//! Activity providers that push new activities to the bot.
+//!
+//! Users link their account by logging in with the provider, which hands
+//! the bot tokens to read the account's activities (see `webhook`).
+//! Providers such as Strava then notify the bot of new activities through
+//! a webhook, after which the activity itself is fetched from the
+//! provider's API with the account's tokens. Each provider implements
+//! `ActivityProvider`, so more can be added without touching the webhook.
+use crate::{
+    activity::{round_km, Activity},
+    database::{request_link, unlink_account},
+    models::{AccessTokens, Split, Sport},
+};
+use chrono::DateTime;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use shuttle_runtime::async_trait;
+use sqlx::PgPool;
+use std::{collections::HashMap, error::Error, fmt, sync::Arc};
+use teloxide::prelude::*;
+use tracing::error;
+
+/// A push event announcing a new activity.
+#[derive(Debug, PartialEq)]
+pub struct ActivityEvent {
+    /// Id of the account the activity belongs to.
+    pub account_id: String,
+    /// Id of the activity with the provider.
+    pub activity_id: String,
+}
+
+/// An account a user showed to be theirs by logging in with the provider.
+#[derive(Debug, PartialEq)]
+pub struct LinkedAccount {
+    /// Id of the account with the provider.
+    pub account_id: String,
+    /// Tokens to read the account's activities with.
+    pub tokens: AccessTokens,
+}
+
+/// A push event that was not sent for the bot's own subscription.
+#[derive(Debug, PartialEq)]
+pub struct UnknownSubscription;
+
+impl fmt::Display for UnknownSubscription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Event is not for the bot's subscription.")
+    }
+}
+
+impl Error for UnknownSubscription {}
+
+/// A source of activities pushed through a webhook.
+#[async_trait]
+pub trait ActivityProvider: Send + Sync {
+    /// Name used in `/link` and in the webhook's path, e.g. `strava`.
+    fn name(&self) -> &'static str;
+
+    /// Answers a check made by the provider when subscribing to its
+    /// events, if it makes one. `None` rejects the check.
+    fn verify(&self, _query: &HashMap<String, String>) -> Option<Value> {
+        None
+    }
+
+    /// Url users open to let the bot read their activities. The provider
+    /// sends them back to `/oauth/<provider>` with `state` and a code.
+    fn authorize_url(&self, state: &str) -> String;
+
+    /// Exchanges the code a user was sent back with for the account they
+    /// logged in with and its tokens.
+    async fn exchange_code(
+        &self,
+        code: &str,
+    ) -> Result<LinkedAccount, Box<dyn Error + Send + Sync>>;
+
+    /// Exchanges the refresh token of an account for new tokens, once its
+    /// access token expires.
+    async fn refresh_tokens(
+        &self,
+        refresh_token: &str,
+    ) -> Result<AccessTokens, Box<dyn Error + Send + Sync>>;
+
+    /// Reads a push event. Events other than new activities are `None`,
+    /// and events sent for another subscription are rejected.
+    fn parse_event(&self, body: &[u8]) -> Result<Option<ActivityEvent>, UnknownSubscription>;
+
+    /// Fetches the activity announced by `event` with the access token of
+    /// the account it belongs to. Activities of other accounts are
+    /// rejected.
+    async fn fetch_activity(
+        &self,
+        event: &ActivityEvent,
+        access_token: &str,
+    ) -> Result<Activity, Box<dyn Error + Send + Sync>>;
+}
+
+/// Providers that accounts can be linked to, by name.
+#[derive(Clone, Default)]
+pub struct Providers(HashMap<&'static str, Arc<dyn ActivityProvider>>);
+
+impl Providers {
+    /// Adds a provider.
+    pub fn with(mut self, provider: impl ActivityProvider + 'static) -> Self {
+        self.0.insert(provider.name(), Arc::new(provider));
+        self
+    }
+
+    /// Looks up a provider by name.
+    pub fn get(&self, name: &str) -> Option<Arc<dyn ActivityProvider>> {
+        self.0.get(name).cloned()
+    }
+
+    /// Names of every provider, sorted.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.0.keys().copied().collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+/// Reply to `/link` or `/unlink` with a provider that is not set up.
+fn unknown_provider(provider: &str, providers: &Providers) -> String {
+    let names = providers.names();
+    if names.is_empty() {
+        "No activity providers are set up.".into()
+    } else {
+        format!(
+            "Unknown provider {}, expected one of: {}.",
+            provider,
+            names.join(", ")
+        )
+    }
+}
+
+/// Handles `/link`, replying with a link to log in with the provider. The
+/// account the user logs in with is then linked in the chat, so accounts
+/// can only be linked by their owners. Anything after the provider's name
+/// is ignored.
+pub async fn answer_link(
+    bot: &Bot,
+    msg: &Message,
+    args: &str,
+    providers: &Providers,
+    connection: &PgPool,
+) {
+    let Some(user) = msg.from() else {
+        error!("Unable to retrieve user from message.");
+        return;
+    };
+    let Some(user_name) = &user.username else {
+        error!("Unable to retrieve username information from Telegram.");
+        return;
+    };
+    let name = args.split_whitespace().next().unwrap_or_default();
+    let reply = match providers.get(name) {
+        None => unknown_provider(name, providers),
+        Some(provider) => {
+            match request_link(provider.name(), user_name, user.id, msg.chat.id, connection).await
+            {
+                Ok(state) => format!(
+                    "Open this link within an hour and log in with {} to link your account, new activities will then be added here:\n{}",
+                    provider.name(),
+                    provider.authorize_url(&state)
+                ),
+                Err(err) => {
+                    error!("Unable to request link: {:?}", err);
+                    return;
+                }
+            }
+        }
+    };
+    bot.send_message(msg.chat.id, reply)
+        .reply_to_message_id(msg.id)
+        .await
+        .map_err(|err| error!("Unable to send Link message: {:?}", err))
+        .ok();
+}
+
+/// Handles `/unlink`, no longer logging the provider's activities of the
+/// user in the chat.
+pub async fn answer_unlink(
+    bot: &Bot,
+    msg: &Message,
+    provider: &str,
+    providers: &Providers,
+    connection: &PgPool,
+) {
+    let Some(user) = msg.from() else {
+        error!("Unable to retrieve user from message.");
+        return;
+    };
+    let reply = if providers.get(provider).is_none() {
+        unknown_provider(provider, providers)
+    } else {
+        match unlink_account(provider, user.id, msg.chat.id, connection).await {
+            Ok(true) => format!("Unlinked your {} account.", provider),
+            Ok(false) => format!("No {} account is linked in this chat.", provider),
+            Err(_) => {
+                error!("Unable to Unlink account.");
+                return;
+            }
+        }
+    };
+    bot.send_message(msg.chat.id, reply)
+        .await
+        .map_err(|err| error!("Unable to send Unlink message: {:?}", err))
+        .ok();
+}
+
+/// Default base url of Strava's API.
+pub const STRAVA_API_URL: &str = "https://www.strava.com/api/v3";
+
+/// Default url users authorize the bot at, as documented at
+/// <https://developers.strava.com/docs/authentication/>.
+pub const STRAVA_AUTHORIZE_URL: &str = "https://www.strava.com/oauth/authorize";
+
+/// Settings of the Strava app the bot reads activities as.
+pub struct StravaConfig {
+    /// Base url of the API, e.g. `STRAVA_API_URL`.
+    pub api_url: String,
+    /// Url users authorize the bot at, e.g. `STRAVA_AUTHORIZE_URL`.
+    pub authorize_url: Url,
+    /// Id of the app.
+    pub client_id: String,
+    /// Secret of the app.
+    pub client_secret: String,
+    /// Url users are sent back to once they authorize the bot, which is
+    /// `/oauth/strava` on the webhook server.
+    pub redirect_url: String,
+    /// Token Strava echoes back when subscribing to events.
+    pub verify_token: String,
+    /// Id of the app's subscription to events.
+    pub subscription_id: u64,
+}
+
+/// Strava, or any service with the same webhook events, authorization and
+/// activity API.
+pub struct Strava {
+    /// Client used to fetch activities and tokens.
+    client: reqwest::Client,
+    /// Settings of the app.
+    config: StravaConfig,
+}
+
+impl Strava {
+    /// Creates a provider reading activities as the app in `config`.
+    pub fn new(config: StravaConfig) -> Self {
+        Strava {
+            client: reqwest::Client::new(),
+            config: StravaConfig {
+                api_url: config.api_url.trim_end_matches('/').into(),
+                ..config
+            },
+        }
+    }
+
+    /// Requests tokens from `/oauth/token`, for the `grant` given along
+    /// with the app's credentials.
+    async fn request_tokens(
+        &self,
+        grant: &[(&str, &str)],
+    ) -> Result<StravaTokens, Box<dyn Error + Send + Sync>> {
+        let mut form = vec![
+            ("client_id", self.config.client_id.as_str()),
+            ("client_secret", self.config.client_secret.as_str()),
+        ];
+        form.extend_from_slice(grant);
+        let body = self
+            .client
+            .post(format!("{}/oauth/token", self.config.api_url))
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+#[cfg(test)]
+impl Strava {
+    /// A provider reading activities from `api_url`, as app `client` with
+    /// secret `secret`, subscribed to events as subscription 1 with the
+    /// verify token `verify`.
+    pub fn for_tests(api_url: &str) -> Self {
+        Strava::new(StravaConfig {
+            api_url: api_url.into(),
+            authorize_url: STRAVA_AUTHORIZE_URL.parse().unwrap(),
+            client_id: "client".into(),
+            client_secret: "secret".into(),
+            redirect_url: "https://telerun.example/oauth/strava".into(),
+            verify_token: "verify".into(),
+            subscription_id: 1,
+        })
+    }
+}
+
+/// A webhook event, as documented at
+/// <https://developers.strava.com/docs/webhooks/>.
+#[derive(Deserialize)]
+struct StravaEvent {
+    /// `activity` or `athlete`.
+    object_type: String,
+    /// Id of the activity or athlete.
+    object_id: u64,
+    /// `create`, `update` or `delete`.
+    aspect_type: String,
+    /// Id of the athlete.
+    owner_id: u64,
+    /// Id of the subscription the event was sent for.
+    subscription_id: u64,
+}
+
+/// Tokens handed out by `/oauth/token`.
+#[derive(Deserialize)]
+struct StravaTokens {
+    /// Token sent as bearer auth when fetching activities.
+    access_token: String,
+    /// Token exchanged for new tokens once `access_token` expires.
+    refresh_token: String,
+    /// When `access_token` expires, in seconds since the epoch.
+    expires_at: i64,
+    /// Athlete who authorized the bot, when exchanging a code.
+    athlete: Option<StravaAthlete>,
+}
+
+impl StravaTokens {
+    /// The tokens, without the athlete.
+    fn access_tokens(&self) -> Result<AccessTokens, Box<dyn Error + Send + Sync>> {
+        let expires_at = DateTime::from_timestamp(self.expires_at, 0)
+            .ok_or("Tokens expire at an invalid time.")?;
+        Ok(AccessTokens {
+            access_token: self.access_token.clone(),
+            refresh_token: self.refresh_token.clone(),
+            expires_at: expires_at.naive_utc(),
+        })
+    }
+}
+
+/// An athlete, as summarised in tokens and activities.
+#[derive(Deserialize)]
+struct StravaAthlete {
+    /// Id of the athlete.
+    id: u64,
+}
+
+/// The fields of a detailed activity that runs are stored with.
+#[derive(Deserialize)]
+struct StravaActivity {
+    /// Athlete the activity belongs to.
+    athlete: StravaAthlete,
+    /// Distance in metres.
+    distance: f64,
+    /// Time spent moving in seconds.
+    moving_time: Option<i32>,
+    /// Time from start to finish in seconds.
+    elapsed_time: Option<i32>,
+    /// Start time in UTC.
+    start_date: Option<String>,
+    /// Sport, e.g. `Run` or `TrailRun`.
+    sport_type: Option<String>,
+    /// Average heart rate, if recorded.
+    average_heartrate: Option<f64>,
+    /// Maximum heart rate, if recorded.
+    max_heartrate: Option<f64>,
+    /// Total ascent in metres.
+    total_elevation_gain: Option<f32>,
+    /// Kind of workout, 1 being a race for runs.
+    workout_type: Option<i32>,
+    /// Splits of every km, the last being the rest of the activity.
+    splits_metric: Option<Vec<StravaSplit>>,
+}
+
+/// A split of a detailed activity.
+#[derive(Deserialize)]
+struct StravaSplit {
+    /// Distance covered in the split, in metres.
+    distance: f64,
+    /// Time taken for the split, in seconds.
+    elapsed_time: i32,
+}
+
+/// Strava's `workout_type` of runs marked as races.
+const STRAVA_RACE: i32 = 1;
+
+/// Maps Strava's sport types onto a `Sport`.
+fn strava_sport(sport_type: &str) -> Sport {
+    match sport_type {
+        "Run" | "TrailRun" | "VirtualRun" => Sport::Run,
+        "Ride" | "GravelRide" | "MountainBikeRide" | "EBikeRide" | "VirtualRide" => Sport::Ride,
+        "Walk" => Sport::Walk,
+        "Hike" => Sport::Hike,
+        "Swim" => Sport::Swim,
+        _ => Sport::Other,
+    }
+}
+
+#[async_trait]
+impl ActivityProvider for Strava {
+    fn name(&self) -> &'static str {
+        "strava"
+    }
+
+    fn verify(&self, query: &HashMap<String, String>) -> Option<Value> {
+        let challenge = query.get("hub.challenge")?;
+        (query.get("hub.mode")? == "subscribe"
+            && query.get("hub.verify_token")? == &self.config.verify_token)
+            .then(|| json!({ "hub.challenge": challenge }))
+    }
+
+    fn authorize_url(&self, state: &str) -> String {
+        let mut url = self.config.authorize_url.clone();
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("response_type", "code")
+            .append_pair("approval_prompt", "auto")
+            .append_pair("scope", "activity:read_all")
+            .append_pair("state", state);
+        url.into()
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+    ) -> Result<LinkedAccount, Box<dyn Error + Send + Sync>> {
+        let tokens = self
+            .request_tokens(&[("grant_type", "authorization_code"), ("code", code)])
+            .await?;
+        let athlete = tokens
+            .athlete
+            .as_ref()
+            .ok_or("Tokens were handed out without an athlete.")?;
+        Ok(LinkedAccount {
+            account_id: athlete.id.to_string(),
+            tokens: tokens.access_tokens()?,
+        })
+    }
+
+    async fn refresh_tokens(
+        &self,
+        refresh_token: &str,
+    ) -> Result<AccessTokens, Box<dyn Error + Send + Sync>> {
+        self.request_tokens(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ])
+        .await?
+        .access_tokens()
+    }
+
+    fn parse_event(&self, body: &[u8]) -> Result<Option<ActivityEvent>, UnknownSubscription> {
+        let Ok(event) = serde_json::from_slice::<StravaEvent>(body) else {
+            return Err(UnknownSubscription);
+        };
+        if event.subscription_id != self.config.subscription_id {
+            return Err(UnknownSubscription);
+        }
+        Ok(
+            (event.object_type == "activity" && event.aspect_type == "create").then(|| {
+                ActivityEvent {
+                    account_id: event.owner_id.to_string(),
+                    activity_id: event.object_id.to_string(),
+                }
+            }),
+        )
+    }
+
+    async fn fetch_activity(
+        &self,
+        event: &ActivityEvent,
+        access_token: &str,
+    ) -> Result<Activity, Box<dyn Error + Send + Sync>> {
+        let body = self
+            .client
+            .get(format!(
+                "{}/activities/{}",
+                self.config.api_url, event.activity_id
+            ))
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let activity: StravaActivity = serde_json::from_slice(&body)?;
+        if activity.athlete.id.to_string() != event.account_id {
+            return Err("Activity belongs to another athlete.".into());
+        }
+        let mut metres = 0.;
+        let mut elapsed_secs = 0;
+        let splits = activity
+            .splits_metric
+            .iter()
+            .flatten()
+            .map(|split| {
+                metres += split.distance;
+                elapsed_secs += split.elapsed_time;
+                Split {
+                    distance: round_km(metres),
+                    elapsed_secs,
+                }
+            })
+            .collect();
+
+        Ok(Activity {
+            sport: activity.sport_type.as_deref().map(strava_sport),
+            distance: round_km(activity.distance),
+            start_time: activity
+                .start_date
+                .as_deref()
+                .and_then(|start| DateTime::parse_from_rfc3339(start).ok())
+                .map(|start| start.naive_utc()),
+            elapsed_secs: activity.elapsed_time,
+            moving_secs: activity.moving_time,
+            avg_heart_rate: activity.average_heartrate.map(|hr| hr.round() as i32),
+            max_heart_rate: activity.max_heartrate.map(|hr| hr.round() as i32),
+            elevation_gain: activity.total_elevation_gain,
+            race: activity.workout_type == Some(STRAVA_RACE),
+            splits,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_strava_events() {
+        let strava = Strava::for_tests(STRAVA_API_URL);
+        let event = |object_type, aspect_type, subscription_id| {
+            json!({
+                "object_type": object_type,
+                "object_id": 1234,
+                "aspect_type": aspect_type,
+                "owner_id": 99,
+                "subscription_id": subscription_id,
+                "event_time": 1_697_267_520,
+            })
+            .to_string()
+        };
+
+        assert_eq!(
+            strava.parse_event(event("activity", "create", 1).as_bytes()),
+            Ok(Some(ActivityEvent {
+                account_id: "99".into(),
+                activity_id: "1234".into(),
+            }))
+        );
+        assert_eq!(
+            strava.parse_event(event("activity", "update", 1).as_bytes()),
+            Ok(None)
+        );
+        assert_eq!(
+            strava.parse_event(event("athlete", "create", 1).as_bytes()),
+            Ok(None)
+        );
+        assert_eq!(
+            strava.parse_event(event("activity", "create", 2).as_bytes()),
+            Err(UnknownSubscription)
+        );
+        assert_eq!(strava.parse_event(b"{}"), Err(UnknownSubscription));
+    }
+
+    #[test]
+    fn strava_authorize_url() {
+        let strava = Strava::for_tests(STRAVA_API_URL);
+
+        assert_eq!(
+            strava.authorize_url("abc-123"),
+            "https://www.strava.com/oauth/authorize?client_id=client&redirect_uri=https%3A%2F%2Ftelerun.example%2Foauth%2Fstrava&response_type=code&approval_prompt=auto&scope=activity%3Aread_all&state=abc-123"
+        );
+    }
+
+    #[test]
+    fn verify_strava_subscription() {
+        let strava = Strava::for_tests(STRAVA_API_URL);
+        let query = |token: &str| {
+            HashMap::from([
+                ("hub.mode".to_string(), "subscribe".to_string()),
+                ("hub.verify_token".to_string(), token.to_string()),
+                ("hub.challenge".to_string(), "abc".to_string()),
+            ])
+        };
+
+        assert_eq!(
+            strava.verify(&query("verify")),
+            Some(json!({ "hub.challenge": "abc" }))
+        );
+        assert_eq!(strava.verify(&query("wrong")), None);
+    }
+
+    #[test]
+    fn strava_sports() {
+        assert_eq!(strava_sport("TrailRun"), Sport::Run);
+        assert_eq!(strava_sport("GravelRide"), Sport::Ride);
+        assert_eq!(strava_sport("Yoga"), Sport::Other);
+    }
+}
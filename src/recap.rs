@@ -0,0 +1,682 @@
+//! Weekly and monthly recaps, posted to chats on a schedule.
+//!
+//! Chats opt in with `/recap week` or `/recap month`, at a time of day and
+//! in a time zone of their choosing. Weekly recaps are posted on Sundays and
+//! monthly ones on the last day of the month, so that they cover the whole
+//! period. The bot checks for due recaps every minute.
+use crate::{
+    database::{
+        get_daily_distances, get_rank_emojis, get_recap_schedules, get_tally, mark_recap_sent,
+        schedule_recap, unschedule_recap,
+    },
+    message::{display_recap, split_message},
+    models::{
+        DailyDistance, Improvement, Period, Recap, RecapSchedule, Score, Streak, TallyGrouping,
+    },
+};
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+use sqlx::PgPool;
+use std::{collections::HashMap, error::Error, fmt, str::FromStr};
+use teloxide::{prelude::*, types::ParseMode};
+use tracing::{error, info};
+
+/// How late a recap may still be posted, in hours, e.g. after the bot was
+/// down. Recaps that are later than this are skipped.
+const MAX_DELAY_HOURS: i64 = 12;
+
+/// Hour of the day recaps are posted at unless the chat picks a time.
+const DEFAULT_RECAP_HOUR: u32 = 20;
+
+/// Shortest streak, in days, worth highlighting.
+const MIN_STREAK: u32 = 3;
+
+/// Most streaks highlighted in a recap.
+const MAX_STREAKS: usize = 3;
+
+/// How many days of runs are looked back on when measuring streaks.
+const STREAK_LOOKBACK_DAYS: i64 = 90;
+
+/// What `/recap` was asked to do.
+#[derive(Debug, PartialEq)]
+pub enum RecapAction {
+    /// Show the chat's recaps.
+    Show,
+    /// Post a recap of every `period` at `local_time` in `timezone`.
+    Schedule {
+        /// Period recapped, either `Week` or `Month`.
+        period: Period,
+        /// Time of day the recap is posted at.
+        local_time: NaiveTime,
+        /// Time zone of the chat.
+        timezone: Tz,
+    },
+    /// Stop the recap for a period, or every recap if `None`.
+    Stop(Option<Period>),
+}
+
+/// Error returned when the arguments of `/recap` cannot be read.
+#[derive(Debug, PartialEq)]
+pub enum ParseRecapError {
+    /// The period was neither `week` nor `month`.
+    Period(String),
+    /// An argument was neither a time nor a time zone.
+    Argument(String),
+}
+
+impl fmt::Display for ParseRecapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseRecapError::Period(period) => write!(
+                f,
+                "Unknown recap \"{}\", expected week, month or off.",
+                period
+            ),
+            ParseRecapError::Argument(argument) => write!(
+                f,
+                "Unknown time or time zone \"{}\", expected e.g. 18:30 Asia/Singapore.",
+                argument
+            ),
+        }
+    }
+}
+
+impl Error for ParseRecapError {}
+
+/// Reads a period that can be recapped.
+fn recap_period(period: &str) -> Result<Period, ParseRecapError> {
+    match period.parse() {
+        Ok(period @ (Period::Week | Period::Month)) => Ok(period),
+        _ => Err(ParseRecapError::Period(period.into())),
+    }
+}
+
+/// Reads an optional time of day, e.g. `18:30`, and time zone, e.g.
+/// `Asia/Singapore`, in either order.
+///
+/// The time defaults to `hour` and the time zone to UTC. Returns the first
+/// argument that is neither.
+pub fn parse_local_time<'a>(
+    arguments: impl Iterator<Item = &'a str>,
+    hour: u32,
+) -> Result<(NaiveTime, Tz), &'a str> {
+    let mut local_time = NaiveTime::from_hms_opt(hour, 0, 0).unwrap_or_default();
+    let mut timezone = Tz::UTC;
+    for argument in arguments {
+        if let Ok(time) = NaiveTime::parse_from_str(argument, "%H:%M") {
+            local_time = time;
+        } else {
+            timezone = argument.parse().map_err(|_| argument)?;
+        }
+    }
+
+    Ok((local_time, timezone))
+}
+
+/// Parses the arguments of `/recap [week|month [HH:MM] [time zone]|off [week|month]]`.
+///
+/// The time and time zone may come in either order, and default to
+/// `DEFAULT_RECAP_HOUR` in UTC.
+impl FromStr for RecapAction {
+    type Err = ParseRecapError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut arguments = s.split_whitespace();
+        let Some(first) = arguments.next() else {
+            return Ok(RecapAction::Show);
+        };
+        if first.eq_ignore_ascii_case("off") {
+            return match arguments.next() {
+                Some(period) => Ok(RecapAction::Stop(Some(recap_period(period)?))),
+                None => Ok(RecapAction::Stop(None)),
+            };
+        }
+
+        let period = recap_period(first)?;
+        let (local_time, timezone) = parse_local_time(arguments, DEFAULT_RECAP_HOUR)
+            .map_err(|argument| ParseRecapError::Argument(argument.into()))?;
+
+        Ok(RecapAction::Schedule {
+            period,
+            local_time,
+            timezone,
+        })
+    }
+}
+
+/// Handles `/recap`, showing or changing when the chat's recaps are posted.
+pub async fn answer_recap(bot: &Bot, chat_id: ChatId, args: &str, connection: &PgPool) {
+    let reply =
+        match args.parse::<RecapAction>() {
+            Ok(RecapAction::Show) => {
+                get_recap_schedules(Some(chat_id), connection)
+                    .await
+                    .map(|schedules| {
+                        if schedules.is_empty() {
+                            "No recaps are posted in this chat.".into()
+                        } else {
+                            schedules
+                                .iter()
+                                .map(|schedule| {
+                                    describe_schedule(
+                                        schedule.period,
+                                        schedule.local_time,
+                                        schedule.timezone,
+                                    )
+                                })
+                                .collect::<Vec<_>>()
+                                .join("\n")
+                        }
+                    })
+            }
+            Ok(RecapAction::Schedule {
+                period,
+                local_time,
+                timezone,
+            }) => schedule_recap(chat_id, period, local_time, timezone, connection)
+                .await
+                .map(|_| describe_schedule(period, local_time, timezone)),
+            Ok(RecapAction::Stop(period)) => unschedule_recap(chat_id, period, connection)
+                .await
+                .map(|stopped| match (stopped, period) {
+                    (0, _) => "No recaps are posted in this chat.".into(),
+                    (_, Some(Period::Month)) => "Monthly recaps are no longer posted.".into(),
+                    (_, Some(_)) => "Weekly recaps are no longer posted.".into(),
+                    (_, None) => "Recaps are no longer posted.".to_string(),
+                }),
+            Err(err) => Ok(err.to_string()),
+        };
+    match reply {
+        Ok(reply) => {
+            bot.send_message(chat_id, reply)
+                .await
+                .map_err(|err| error!("Unable to send Recap message: {:?}", err))
+                .ok();
+        }
+        Err(err) => error!("Unable to update recaps: {:?}", err),
+    }
+}
+
+/// Describes when a chat's recap is posted, e.g. `Weekly recaps are posted
+/// on Sundays at 20:00 (UTC).`
+pub fn describe_schedule(period: Period, local_time: NaiveTime, timezone: Tz) -> String {
+    let day = match period {
+        Period::Month => "Monthly recaps are posted on the last day of the month",
+        _ => "Weekly recaps are posted on Sundays",
+    };
+    format!("{} at {} ({}).", day, local_time.format("%H:%M"), timezone)
+}
+
+/// Day the recap of the period containing `day` is posted on, its last.
+fn recap_day(period: Period, day: NaiveDate) -> Option<NaiveDate> {
+    Some(period.end(day.and_hms_opt(0, 0, 0)?)?.date() - Duration::days(1))
+}
+
+/// Converts a local time in `timezone` to UTC.
+///
+/// Times skipped when clocks go forward are moved an hour later, and times
+/// repeated when clocks go back are taken at their first occurrence.
+pub fn to_utc(timezone: Tz, local: NaiveDateTime) -> Option<NaiveDateTime> {
+    timezone
+        .from_local_datetime(&local)
+        .earliest()
+        .or_else(|| {
+            timezone
+                .from_local_datetime(&(local + Duration::hours(1)))
+                .earliest()
+        })
+        .map(|time| time.naive_utc())
+}
+
+/// Latest time at or before `now` that the recap was due, local to the chat.
+fn latest_occurrence(schedule: &RecapSchedule, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    let local_now = schedule.timezone.from_utc_datetime(&now).naive_local();
+    let occurrence = recap_day(schedule.period, local_now.date())?.and_time(schedule.local_time);
+    if occurrence <= local_now {
+        return Some(occurrence);
+    }
+
+    let start = schedule.period.start(local_now)?.date().pred_opt()?;
+    Some(recap_day(schedule.period, start)?.and_time(schedule.local_time))
+}
+
+/// Whether a message due at `due`, in UTC, should be posted at `now`.
+///
+/// Messages are due once their time has passed and they have not been
+/// posted since, unless they are more than `MAX_DELAY_HOURS` late.
+pub fn is_due(due: NaiveDateTime, last_sent: NaiveDateTime, now: NaiveDateTime) -> bool {
+    due > last_sent && due <= now && now - due <= Duration::hours(MAX_DELAY_HOURS)
+}
+
+/// The local time of the recap to post at `now`, if one is due.
+pub fn due_recap(schedule: &RecapSchedule, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    let occurrence = latest_occurrence(schedule, now)?;
+    is_due(
+        to_utc(schedule.timezone, occurrence)?,
+        schedule.last_sent,
+        now,
+    )
+    .then_some(occurrence)
+}
+
+/// The user who ran the most beyond what they ran the period before.
+///
+/// `current` is the tally of the recapped period and `both` the tally of it
+/// together with the period before. Only users who ran in both periods can
+/// improve.
+fn most_improved(current: &[Score], both: &[Score]) -> Option<Improvement> {
+    let totals: HashMap<(&str, &str), f32> = both
+        .iter()
+        .map(|score| {
+            (
+                (score.user_name.as_str(), score.telegram_userid.as_str()),
+                score.distance,
+            )
+        })
+        .collect();
+    current
+        .iter()
+        .filter_map(|score| {
+            let total = totals.get(&(score.user_name.as_str(), score.telegram_userid.as_str()))?;
+            let previous = ((total - score.distance) * 100.0).round() / 100.0;
+            (previous > 0.0 && score.distance > previous).then(|| Improvement {
+                user_name: score.user_name.clone(),
+                telegram_userid: score.telegram_userid.clone(),
+                previous,
+                current: score.distance,
+            })
+        })
+        .max_by(|a, b| (a.current - a.previous).total_cmp(&(b.current - b.previous)))
+}
+
+/// Ongoing streaks of at least `MIN_STREAK` days, longest first.
+///
+/// A streak is ongoing if its last run was `today` or the day before.
+/// `days` are ordered from oldest to newest.
+fn streaks(days: &[DailyDistance], today: NaiveDate) -> Vec<Streak> {
+    let mut latest: HashMap<&str, (NaiveDate, u32)> = HashMap::new();
+    for day in days {
+        latest
+            .entry(&day.user_name)
+            .and_modify(|(last, length)| {
+                *length = if day.day.pred_opt() == Some(*last) {
+                    *length + 1
+                } else {
+                    1
+                };
+                *last = day.day;
+            })
+            .or_insert((day.day, 1));
+    }
+
+    let mut streaks: Vec<Streak> = latest
+        .into_iter()
+        .filter(|(_, (last, days))| *last >= today - Duration::days(1) && *days >= MIN_STREAK)
+        .map(|(user_name, (_, days))| Streak {
+            user_name: user_name.into(),
+            days,
+        })
+        .collect();
+    streaks.sort_by(|a, b| {
+        b.days
+            .cmp(&a.days)
+            .then_with(|| a.user_name.cmp(&b.user_name))
+    });
+    streaks.truncate(MAX_STREAKS);
+    streaks
+}
+
+/// Gathers the recap a chat is due at `occurrence`, its local time.
+///
+/// Only runs up to the end of the period count, even if the recap is late,
+/// and streaks are counted in the chat's days up to `occurrence`. Returns
+/// `None` if nobody ran during the period.
+async fn build_recap(
+    schedule: &RecapSchedule,
+    occurrence: NaiveDateTime,
+    connection: &PgPool,
+) -> Result<Option<Recap>, sqlx::Error> {
+    let period = schedule.period;
+    let local_since = period.start(occurrence);
+    let local_until = period.end(occurrence);
+    let local_previous = local_since
+        .and_then(|since| since.date().pred_opt()?.and_hms_opt(0, 0, 0))
+        .and_then(|previous| period.start(previous));
+    let utc =
+        |local: Option<NaiveDateTime>| local.and_then(|local| to_utc(schedule.timezone, local));
+
+    let users = TallyGrouping::Users;
+    let until = utc(local_until);
+    let Some(scores) = get_tally(schedule.chat_id, utc(local_since), until, users, connection)
+        .await?
+        .filter(|scores| !scores.is_empty())
+    else {
+        return Ok(None);
+    };
+    let both = get_tally(
+        schedule.chat_id,
+        utc(local_previous),
+        until,
+        users,
+        connection,
+    )
+    .await?
+    .unwrap_or_default();
+    let today = occurrence.date();
+    let days = get_daily_distances(
+        schedule.chat_id,
+        utc((today - Duration::days(STREAK_LOOKBACK_DAYS)).and_hms_opt(0, 0, 0)),
+        until,
+        schedule.timezone,
+        connection,
+    )
+    .await?;
+
+    Ok(Some(Recap {
+        period,
+        most_improved: most_improved(&scores, &both),
+        streaks: streaks(&days, today),
+        scores,
+    }))
+}
+
+/// Posts every recap that is due at `now`, in UTC.
+///
+/// Recaps are marked as posted even if sending them fails, so that a chat
+/// that removed the bot is not retried every minute.
+pub async fn send_due_recaps(bot: &Bot, connection: &PgPool, now: NaiveDateTime) {
+    let schedules = match get_recap_schedules(None, connection).await {
+        Ok(schedules) => schedules,
+        Err(err) => {
+            error!("Unable to retrieve recap schedules: {:?}", err);
+            return;
+        }
+    };
+
+    for schedule in schedules {
+        let Some(occurrence) = due_recap(&schedule, now) else {
+            continue;
+        };
+        info!(
+            "[send_due_recaps]: chat_id: {}, period: {}",
+            schedule.chat_id, schedule.period
+        );
+        let recap = match build_recap(&schedule, occurrence, connection).await {
+            Ok(Some(recap)) => {
+                let emojis = get_rank_emojis(schedule.chat_id, connection)
+                    .await
+                    .unwrap_or_default();
+                display_recap(&recap, &emojis)
+            }
+            Ok(None) => format!("Nobody ran this {}.", schedule.period),
+            Err(err) => {
+                error!("Unable to build recap: {:?}", err);
+                continue;
+            }
+        };
+        for part in split_message(&recap) {
+            bot.send_message(schedule.chat_id, part)
+                .parse_mode(ParseMode::Html)
+                .await
+                .map_err(|err| error!("Unable to send Recap message: {:?}", err))
+                .ok();
+        }
+        mark_recap_sent(schedule.chat_id, schedule.period, now, connection)
+            .await
+            .map_err(|err| error!("Unable to mark recap as sent: {:?}", err))
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::add_runs,
+        harness::{sent_messages, Harness, TestUser},
+        models::NewRun,
+    };
+    use chrono::Utc;
+
+    /// A time on a day in October 2023, which starts on a Sunday.
+    fn october(day: u32, hour: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2023, 10, day)
+            .unwrap()
+            .and_hms_opt(hour, min, 0)
+            .unwrap()
+    }
+
+    /// A recap at 18:30 in Singapore, which is 8 hours ahead of UTC.
+    fn schedule(period: Period, last_sent: NaiveDateTime) -> RecapSchedule {
+        RecapSchedule {
+            chat_id: ChatId(-100),
+            period,
+            local_time: NaiveTime::from_hms_opt(18, 30, 0).unwrap(),
+            timezone: "Asia/Singapore".parse().unwrap(),
+            last_sent,
+        }
+    }
+
+    #[test]
+    fn parse_recap_actions() {
+        assert_eq!("".parse(), Ok(RecapAction::Show));
+        assert_eq!("off".parse(), Ok(RecapAction::Stop(None)));
+        assert_eq!(
+            "off month".parse(),
+            Ok(RecapAction::Stop(Some(Period::Month)))
+        );
+        assert_eq!(
+            "week".parse(),
+            Ok(RecapAction::Schedule {
+                period: Period::Week,
+                local_time: NaiveTime::from_hms_opt(20, 0, 0).unwrap(),
+                timezone: Tz::UTC,
+            })
+        );
+        assert_eq!(
+            "month Europe/London 07:45".parse(),
+            Ok(RecapAction::Schedule {
+                period: Period::Month,
+                local_time: NaiveTime::from_hms_opt(7, 45, 0).unwrap(),
+                timezone: Tz::Europe__London,
+            })
+        );
+        assert_eq!(
+            "year".parse::<RecapAction>(),
+            Err(ParseRecapError::Period("year".into()))
+        );
+        assert_eq!(
+            "week 25:00".parse::<RecapAction>(),
+            Err(ParseRecapError::Argument("25:00".into()))
+        );
+    }
+
+    #[test]
+    fn recaps_are_due_at_the_end_of_each_period() {
+        // Sunday the 15th at 18:30 in Singapore is 10:30 in UTC.
+        let weekly = schedule(Period::Week, october(9, 0, 0));
+        assert_eq!(due_recap(&weekly, october(15, 10, 29)), None);
+        assert_eq!(
+            due_recap(&weekly, october(15, 10, 30)),
+            Some(october(15, 18, 30))
+        );
+        assert_eq!(
+            due_recap(&weekly, october(15, 22, 30)),
+            Some(october(15, 18, 30))
+        );
+        // Too late, or already posted.
+        assert_eq!(due_recap(&weekly, october(15, 22, 31)), None);
+        let posted = schedule(Period::Week, october(15, 10, 31));
+        assert_eq!(due_recap(&posted, october(15, 11, 0)), None);
+
+        // The 31st, and the last day of September before it.
+        let monthly = schedule(Period::Month, october(1, 0, 0));
+        assert_eq!(due_recap(&monthly, october(30, 12, 0)), None);
+        assert_eq!(
+            due_recap(&monthly, october(31, 10, 30)),
+            Some(october(31, 18, 30))
+        );
+        let september = |hour| {
+            NaiveDate::from_ymd_opt(2023, 9, 30)
+                .unwrap()
+                .and_hms_opt(hour, 30, 0)
+                .unwrap()
+        };
+        let monthly = RecapSchedule {
+            last_sent: september(0),
+            ..monthly
+        };
+        // Posted the morning after in Singapore, which is still September in UTC.
+        assert_eq!(due_recap(&monthly, september(20)), Some(september(18)));
+    }
+
+    #[test]
+    fn recap_highlights() {
+        let score = |user_name: &str, distance| Score {
+            rank: 1,
+            user_name: user_name.into(),
+            telegram_userid: "1".into(),
+            medals: 1,
+            distance,
+        };
+        // Milton ran 10km this week after 2km last week, reuben didn't run
+        // last week and jerrell slowed down.
+        let current = [
+            score("milton", 10.0),
+            score("reuben", 20.0),
+            score("jerrell", 5.0),
+        ];
+        let both = [
+            score("milton", 12.0),
+            score("reuben", 20.0),
+            score("jerrell", 15.0),
+        ];
+        assert_eq!(
+            most_improved(&current, &both),
+            Some(Improvement {
+                user_name: "milton".into(),
+                telegram_userid: "1".into(),
+                previous: 2.0,
+                current: 10.0,
+            })
+        );
+        assert_eq!(most_improved(&current[2..], &both), None);
+
+        let ran = |user_name: &str, day| DailyDistance {
+            user_name: user_name.into(),
+            day: october(day, 0, 0).date(),
+            distance: 5.0,
+        };
+        let days = [
+            ran("reuben", 10),
+            ran("milton", 11),
+            ran("reuben", 11),
+            ran("taigy", 11),
+            ran("milton", 12),
+            ran("reuben", 12),
+            ran("taigy", 12),
+            ran("milton", 13),
+            ran("reuben", 13),
+            ran("taigy", 13),
+            ran("reuben", 14),
+            ran("taigy", 15),
+        ];
+        // Milton's streak ended the day before yesterday, taigy's was broken.
+        assert_eq!(
+            streaks(&days, october(15, 0, 0).date()),
+            vec![Streak {
+                user_name: "reuben".into(),
+                days: 5,
+            }]
+        );
+    }
+
+    #[sqlx::test]
+    async fn late_recaps_cover_their_period(connection: PgPool) {
+        // In UTC, reuben ran on the 12th, 13th and 15th, but in Singapore
+        // on the 13th, 14th and 15th. Milton ran on the Monday after the
+        // recapped week in Singapore, still Sunday in UTC.
+        let runs: Vec<NewRun> = [
+            ("reuben", 1, october(12, 23, 0), 5.),
+            ("reuben", 1, october(13, 16, 30), 5.),
+            ("reuben", 1, october(15, 1, 0), 5.),
+            ("milton", 2, october(15, 20, 0), 10.),
+        ]
+        .into_iter()
+        .map(|(user_name, id, run_datetime, distance)| NewRun {
+            user_name: user_name.into(),
+            telegram_userid: UserId(id),
+            distance,
+            run_datetime: Some(run_datetime),
+        })
+        .collect();
+        add_runs(&runs, ChatId(-100), &connection).await.unwrap();
+
+        let weekly = schedule(Period::Week, october(9, 0, 0));
+        let recap = build_recap(&weekly, october(15, 18, 30), &connection)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(recap.scores.len(), 1);
+        assert_eq!(recap.scores[0].user_name, "reuben");
+        assert_eq!(recap.scores[0].distance, 15.);
+        assert_eq!(
+            recap.streaks,
+            vec![Streak {
+                user_name: "reuben".into(),
+                days: 3,
+            }]
+        );
+    }
+
+    #[sqlx::test]
+    async fn due_recaps_are_posted_once(postgres: PgPool) {
+        let harness = Harness::new(postgres.clone()).await;
+        for (id, username, distance) in [(1, "reuben", 5), (2, "milton", 2), (1, "reuben", 3)] {
+            let user = TestUser { id, username };
+            harness.send(-100, &user, &format!("/add {}", distance));
+        }
+        harness.send(
+            -100,
+            &TestUser {
+                id: 1,
+                username: "reuben",
+            },
+            "/recap week 18:30 Asia/Singapore",
+        );
+        harness.run(4).await;
+
+        // This Sunday's recap, whether it is still to come or already due.
+        let mut weekly = get_recap_schedules(None, &postgres)
+            .await
+            .unwrap()
+            .remove(0);
+        let local_now = weekly
+            .timezone
+            .from_utc_datetime(&Utc::now().naive_utc())
+            .naive_local();
+        let occurrence = recap_day(Period::Week, local_now.date())
+            .unwrap()
+            .and_time(weekly.local_time);
+        let now = to_utc(weekly.timezone, occurrence).unwrap() + Duration::minutes(1);
+        weekly.last_sent = now - Duration::days(1);
+        mark_recap_sent(weekly.chat_id, Period::Week, weekly.last_sent, &postgres)
+            .await
+            .unwrap();
+
+        let bot = harness.bot();
+        send_due_recaps(&bot, &postgres, now).await;
+        send_due_recaps(&bot, &postgres, now + Duration::minutes(1)).await;
+
+        let messages = sent_messages(&harness.calls());
+        assert_eq!(messages.len(), 5);
+        assert_eq!(
+            messages[4],
+            "<b>Weekly recap</b>
+Together you ran <b>10.0km</b> over 3 runs.
+
+🥇 <code>1.  8km  2🏅</code> <b><a href=\"tg://user?id=1\">reuben</a></b>
+🥈 <code>2.  2km  1🏅</code> <a href=\"tg://user?id=2\">milton</a>"
+        );
+    }
+}
@@ -0,0 +1,344 @@
+//! Personal records over standard distances, with `/pbs`.
+//!
+//! Every timed run counts towards the records of the standard distances
+//! it was run at. A run longer than a standard distance only counts if it
+//! was a race effort, for the longest distance it covers, or if it has
+//! splits, which tell the fastest stretch of that distance within it.
+//! Records are worked out from a user's runs in a chat whenever they are
+//! shown, and compared before and after a run is added or edited to tell
+//! whether it set a new one.
+use crate::{
+    database::get_timed_runs,
+    message::{display_new_records, display_personal_bests},
+    models::{Split, TimedRun},
+};
+use chrono::NaiveDateTime;
+use sqlx::PgPool;
+use teloxide::{prelude::*, types::ParseMode};
+use tracing::error;
+
+/// Share of a standard distance a run can go over by and still count as
+/// run at it, since GPS tracks tend to measure long.
+const OVERSHOOT: f32 = 0.01;
+
+/// Distance in km a run can fall short of a standard distance by and still
+/// count as run at it, since distances are rounded to 10m.
+const SHORTFALL: f32 = 0.01;
+
+/// Distances personal records are kept for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StandardDistance {
+    /// 1km.
+    OneK,
+    /// 5km.
+    FiveK,
+    /// 10km.
+    TenK,
+    /// Half marathon, 21.0975km.
+    Half,
+    /// Marathon, 42.195km.
+    Full,
+}
+
+impl StandardDistance {
+    /// Every standard distance, from shortest to longest.
+    pub const ALL: [StandardDistance; 5] = [
+        StandardDistance::OneK,
+        StandardDistance::FiveK,
+        StandardDistance::TenK,
+        StandardDistance::Half,
+        StandardDistance::Full,
+    ];
+
+    /// Length of the distance, in km.
+    pub fn km(&self) -> f32 {
+        match self {
+            StandardDistance::OneK => 1.,
+            StandardDistance::FiveK => 5.,
+            StandardDistance::TenK => 10.,
+            StandardDistance::Half => 21.0975,
+            StandardDistance::Full => 42.195,
+        }
+    }
+
+    /// Name of the distance, e.g. `5k` or `half marathon`.
+    pub fn label(&self) -> &'static str {
+        match self {
+            StandardDistance::OneK => "1k",
+            StandardDistance::FiveK => "5k",
+            StandardDistance::TenK => "10k",
+            StandardDistance::Half => "half marathon",
+            StandardDistance::Full => "marathon",
+        }
+    }
+}
+
+/// A user's best time over a standard distance.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PersonalBest {
+    /// Distance the time was run over.
+    pub distance: StandardDistance,
+    /// Time taken, in seconds.
+    pub secs: i32,
+    /// Id of the run it was set in.
+    pub run_id: i32,
+    /// When it was set.
+    pub run_datetime: NaiveDateTime,
+}
+
+/// A personal best set by the latest change to a user's runs.
+#[derive(Debug, PartialEq)]
+pub struct NewRecord {
+    /// The new best.
+    pub best: PersonalBest,
+    /// Time of the best it beat, if there was one.
+    pub previous: Option<i32>,
+}
+
+/// Fastest time to cover `km` between any of a run's `splits`, found by
+/// working out when the run would have reached `km` past each of them.
+fn fastest_stretch(splits: &[Split], km: f32) -> Option<i32> {
+    let start = Split {
+        distance: 0.,
+        elapsed_secs: 0,
+    };
+    let points: Vec<Split> = std::iter::once(start)
+        .chain(splits.iter().copied())
+        .collect();
+
+    let mut fastest: Option<f32> = None;
+    for (index, from) in points.iter().enumerate() {
+        let target = from.distance + km;
+        let Some(end) = points[index..]
+            .iter()
+            .position(|point| point.distance + 0.001 >= target)
+            .map(|end| index + end)
+        else {
+            break;
+        };
+        let (before, after) = (&points[end - 1], &points[end]);
+        let share = ((target - before.distance) / (after.distance - before.distance)).min(1.);
+        let reached =
+            before.elapsed_secs as f32 + share * (after.elapsed_secs - before.elapsed_secs) as f32;
+        let secs = reached - from.elapsed_secs as f32;
+        fastest = Some(fastest.map_or(secs, |fastest| fastest.min(secs)));
+    }
+    fastest.map(|secs| secs.round() as i32)
+}
+
+/// Time a run counts for towards the record of a standard distance, if
+/// any.
+///
+/// Splits tell the fastest stretch of the distance. Without splits
+/// covering it, the run's whole time counts if it was run at the
+/// distance, or if it was a race and this is the longest standard
+/// distance it covers.
+pub fn effort(run: &TimedRun, distance: StandardDistance) -> Option<i32> {
+    let km = distance.km();
+    if run.distance < km - SHORTFALL {
+        return None;
+    }
+    if let Some(secs) = fastest_stretch(&run.splits, km).filter(|secs| *secs > 0) {
+        return Some(secs.min(run.duration_secs));
+    }
+
+    let at_distance = run.distance <= km * (1. + OVERSHOOT);
+    let longest_covered = StandardDistance::ALL
+        .iter()
+        .rev()
+        .find(|standard| standard.km() - SHORTFALL <= run.distance);
+    let raced = run.race && longest_covered == Some(&distance);
+    (at_distance || raced).then_some(run.duration_secs)
+}
+
+/// Best times over every standard distance across `runs`, given oldest
+/// first. Ties go to the run that set the time first.
+pub fn personal_bests(runs: &[TimedRun]) -> Vec<PersonalBest> {
+    StandardDistance::ALL
+        .iter()
+        .filter_map(|distance| {
+            runs.iter()
+                .filter_map(|run| {
+                    effort(run, *distance).map(|secs| PersonalBest {
+                        distance: *distance,
+                        secs,
+                        run_id: run.id,
+                        run_datetime: run.run_datetime,
+                    })
+                })
+                .min_by_key(|best| best.secs)
+        })
+        .collect()
+}
+
+/// Records in `after` that beat those in `before`, or that are the first
+/// over their distance.
+pub fn new_records(before: &[PersonalBest], after: &[PersonalBest]) -> Vec<NewRecord> {
+    after
+        .iter()
+        .filter_map(|best| {
+            let previous = before
+                .iter()
+                .find(|previous| previous.distance == best.distance)
+                .map(|previous| previous.secs);
+            previous
+                .is_none_or(|previous| best.secs < previous)
+                .then(|| NewRecord {
+                    best: best.clone(),
+                    previous,
+                })
+        })
+        .collect()
+}
+
+/// Works out a user's personal records in a chat.
+pub async fn get_personal_bests(
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    connection: &PgPool,
+) -> Result<Vec<PersonalBest>, sqlx::Error> {
+    let runs = get_timed_runs(chat_id, telegram_userid, connection).await?;
+    Ok(personal_bests(&runs))
+}
+
+/// A user's personal records in a chat, looked up before changing their
+/// runs so that `congratulate_on_records` can tell what changed. `None`
+/// if they cannot be looked up.
+pub async fn current_records(
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    connection: &PgPool,
+) -> Option<Vec<PersonalBest>> {
+    get_personal_bests(chat_id, telegram_userid, connection)
+        .await
+        .map_err(|err| error!("Unable to retrieve personal records: {:?}", err))
+        .ok()
+}
+
+/// Congratulates a user on the personal records they set since `before`
+/// was looked up, if any.
+pub async fn congratulate_on_records(
+    before: Option<Vec<PersonalBest>>,
+    chat_id: ChatId,
+    telegram_userid: UserId,
+    connection: &PgPool,
+) -> Option<String> {
+    let before = before?;
+    let after = current_records(chat_id, telegram_userid, connection).await?;
+    let records = new_records(&before, &after);
+    (!records.is_empty()).then(|| display_new_records(&records))
+}
+
+/// Handles `/pbs`, listing the user's personal records.
+pub async fn answer_pbs(bot: &Bot, msg: &Message, connection: &PgPool) {
+    let Some(user) = msg.from() else {
+        error!("Unable to retrieve user from message.");
+        return;
+    };
+    match get_personal_bests(msg.chat.id, user.id, connection).await {
+        Ok(bests) => {
+            let user_name = user.username.as_deref().unwrap_or(&user.first_name);
+            bot.send_message(msg.chat.id, display_personal_bests(user_name, &bests))
+                .parse_mode(ParseMode::Html)
+                .await
+                .map_err(|err| error!("Unable to send Pbs message: {:?}", err))
+                .ok();
+        }
+        Err(err) => error!("Unable to retrieve personal records: {:?}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    /// A run on a day of October 2023.
+    fn run(id: i32, distance: f32, duration_secs: i32, race: bool) -> TimedRun {
+        TimedRun {
+            id,
+            run_datetime: NaiveDate::from_ymd_opt(2023, 10, id as u32)
+                .unwrap()
+                .and_hms_opt(7, 0, 0)
+                .unwrap(),
+            distance,
+            duration_secs,
+            race,
+            splits: vec![],
+        }
+    }
+
+    #[test]
+    fn efforts_at_standard_distances() {
+        let five_k = run(1, 5.03, 1500, false);
+        assert_eq!(effort(&five_k, StandardDistance::FiveK), Some(1500));
+        assert_eq!(effort(&five_k, StandardDistance::OneK), None);
+        assert_eq!(
+            effort(&run(1, 4.99, 1500, false), StandardDistance::FiveK),
+            Some(1500)
+        );
+        assert_eq!(
+            effort(&run(1, 4.9, 1500, false), StandardDistance::FiveK),
+            None
+        );
+
+        // Longer runs only count when raced, towards the longest distance
+        // they cover.
+        assert_eq!(
+            effort(&run(1, 5.3, 1500, false), StandardDistance::FiveK),
+            None
+        );
+        let race = run(1, 21.4, 6300, true);
+        assert_eq!(effort(&race, StandardDistance::Half), Some(6300));
+        assert_eq!(effort(&race, StandardDistance::TenK), None);
+    }
+
+    #[test]
+    fn efforts_from_splits() {
+        let split = |distance, elapsed_secs| Split {
+            distance,
+            elapsed_secs,
+        };
+        let mut long_run = run(1, 6.5, 2000, false);
+        long_run.splits = vec![
+            split(1., 330),
+            split(2., 630),
+            split(3., 900),
+            split(4., 1170),
+            split(5., 1470),
+            split(6., 1800),
+            split(6.5, 2000),
+        ];
+        assert_eq!(effort(&long_run, StandardDistance::OneK), Some(270));
+        assert_eq!(effort(&long_run, StandardDistance::FiveK), Some(1470));
+        assert_eq!(effort(&long_run, StandardDistance::TenK), None);
+
+        // Stretches may end between splits.
+        let mut short_run = run(1, 1.5, 600, false);
+        short_run.splits = vec![split(0.5, 100), split(1.5, 600)];
+        assert_eq!(effort(&short_run, StandardDistance::OneK), Some(350));
+    }
+
+    #[test]
+    fn records_beat_earlier_bests() {
+        let runs = vec![
+            run(1, 5., 1600, false),
+            run(2, 10., 3300, false),
+            run(3, 5., 1500, false),
+            run(4, 5., 1500, false),
+        ];
+        let before = personal_bests(&runs[..2]);
+        let after = personal_bests(&runs);
+        assert_eq!(after.len(), 2);
+        assert_eq!((after[0].secs, after[0].run_id), (1500, 3));
+        assert_eq!(
+            new_records(&before, &after),
+            vec![NewRecord {
+                best: after[0].clone(),
+                previous: Some(1600),
+            }]
+        );
+        assert_eq!(new_records(&after, &after), vec![]);
+        assert_eq!(new_records(&[], &before).len(), 2);
+    }
+}
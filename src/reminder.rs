@@ -0,0 +1,356 @@
+//! Reminders for users who have stopped running.
+//!
+//! Chats opt in with `/remind <days>`, after which the bot mentions users
+//! whose latest run is `days` old, once a day at a time of the chat's
+//! choosing. Each stretch without runs is only reminded of once, on the day
+//! it reaches `days`. Users can opt out with `/remind off`, and users who
+//! have left the chat are never mentioned.
+use crate::{
+    database::{
+        get_inactive_users, get_reminder_schedules, mark_reminder_sent, schedule_reminder,
+        set_reminder_opt_out, unschedule_reminder,
+    },
+    message::display_reminder,
+    models::ReminderSchedule,
+    recap::{is_due, parse_local_time, to_utc},
+};
+use chrono::{Duration, NaiveDateTime, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+use sqlx::PgPool;
+use std::{error::Error, fmt, str::FromStr};
+use teloxide::{prelude::*, types::ParseMode};
+use tracing::{error, info};
+
+/// Hour of the day reminders are sent at unless the chat picks a time.
+const DEFAULT_REMINDER_HOUR: u32 = 19;
+
+/// Most days without runs that reminders can wait for.
+const MAX_INACTIVE_DAYS: u32 = 365;
+
+/// What `/remind` was asked to do.
+#[derive(Debug, PartialEq)]
+pub enum ReminderAction {
+    /// Show the chat's reminder.
+    Show,
+    /// Remind users who have not run for `inactive_days`, at `local_time`
+    /// in `timezone`.
+    Schedule {
+        /// Days without runs after which users are reminded.
+        inactive_days: u32,
+        /// Time of day reminders are sent at.
+        local_time: NaiveTime,
+        /// Time zone of the chat.
+        timezone: Tz,
+    },
+    /// Stop reminders in the chat.
+    Stop,
+    /// Opt the user asking out of reminders.
+    OptOut,
+    /// Opt the user asking back in to reminders.
+    OptIn,
+}
+
+/// Error returned when the arguments of `/remind` cannot be read.
+#[derive(Debug, PartialEq)]
+pub enum ParseReminderError {
+    /// The first argument was neither a number of days nor a keyword.
+    Days(String),
+    /// An argument was neither a time nor a time zone.
+    Argument(String),
+}
+
+impl fmt::Display for ParseReminderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseReminderError::Days(days) => write!(
+                f,
+                "Unknown reminder \"{}\", expected a number of days from 1 to {}, on, off or stop.",
+                days, MAX_INACTIVE_DAYS
+            ),
+            ParseReminderError::Argument(argument) => write!(
+                f,
+                "Unknown time or time zone \"{}\", expected e.g. 18:30 Asia/Singapore.",
+                argument
+            ),
+        }
+    }
+}
+
+impl Error for ParseReminderError {}
+
+/// Parses the arguments of `/remind [<days> [HH:MM] [time zone]|on|off|stop]`.
+///
+/// The time and time zone may come in either order, and default to
+/// `DEFAULT_REMINDER_HOUR` in UTC.
+impl FromStr for ReminderAction {
+    type Err = ParseReminderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut arguments = s.split_whitespace();
+        let Some(first) = arguments.next() else {
+            return Ok(ReminderAction::Show);
+        };
+        match first.to_lowercase().as_str() {
+            "on" => return Ok(ReminderAction::OptIn),
+            "off" => return Ok(ReminderAction::OptOut),
+            "stop" => return Ok(ReminderAction::Stop),
+            _ => {}
+        }
+
+        let inactive_days = first
+            .parse()
+            .ok()
+            .filter(|days| (1..=MAX_INACTIVE_DAYS).contains(days))
+            .ok_or_else(|| ParseReminderError::Days(first.into()))?;
+        let (local_time, timezone) = parse_local_time(arguments, DEFAULT_REMINDER_HOUR)
+            .map_err(|argument| ParseReminderError::Argument(argument.into()))?;
+
+        Ok(ReminderAction::Schedule {
+            inactive_days,
+            local_time,
+            timezone,
+        })
+    }
+}
+
+/// Handles `/remind`, showing or changing the chat's reminders, or opting
+/// the user out of them or back in.
+pub async fn answer_remind(bot: &Bot, msg: &Message, args: &str, connection: &PgPool) {
+    let reply = match (args.parse::<ReminderAction>(), msg.from()) {
+        (Ok(ReminderAction::Show), _) => get_reminder_schedules(Some(msg.chat.id), connection)
+            .await
+            .map(|schedules| match schedules.first() {
+                Some(schedule) => describe_reminder(
+                    schedule.inactive_days,
+                    schedule.local_time,
+                    schedule.timezone,
+                ),
+                None => "Reminders are off in this chat.".into(),
+            }),
+        (
+            Ok(ReminderAction::Schedule {
+                inactive_days,
+                local_time,
+                timezone,
+            }),
+            _,
+        ) => schedule_reminder(msg.chat.id, inactive_days, local_time, timezone, connection)
+            .await
+            .map(|_| describe_reminder(inactive_days, local_time, timezone)),
+        (Ok(ReminderAction::Stop), _) => unschedule_reminder(msg.chat.id, connection)
+            .await
+            .map(|_| "Reminders are off in this chat.".into()),
+        (Ok(ReminderAction::OptOut), Some(user)) => {
+            set_reminder_opt_out(msg.chat.id, user.id, true, connection)
+                .await
+                .map(|_| "You will no longer be reminded to run.".into())
+        }
+        (Ok(ReminderAction::OptIn), Some(user)) => {
+            set_reminder_opt_out(msg.chat.id, user.id, false, connection)
+                .await
+                .map(|_| "You will be reminded to run again.".into())
+        }
+        (Ok(ReminderAction::OptOut | ReminderAction::OptIn), None) => return,
+        (Err(err), _) => Ok(err.to_string()),
+    };
+    match reply {
+        Ok(reply) => {
+            bot.send_message(msg.chat.id, reply)
+                .await
+                .map_err(|err| error!("Unable to send Remind message: {:?}", err))
+                .ok();
+        }
+        Err(err) => error!("Unable to update reminders: {:?}", err),
+    }
+}
+
+/// Describes a chat's reminders, e.g. `Members who haven't run in 3 days
+/// are reminded at 19:00 (UTC).`
+pub fn describe_reminder(inactive_days: u32, local_time: NaiveTime, timezone: Tz) -> String {
+    format!(
+        "Members who haven't run in {} day{} are reminded at {} ({}).",
+        inactive_days,
+        if inactive_days == 1 { "" } else { "s" },
+        local_time.format("%H:%M"),
+        timezone
+    )
+}
+
+/// When the reminders to send at `now` were due, in UTC, if any are.
+pub fn due_reminder(schedule: &ReminderSchedule, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    let local_now = schedule.timezone.from_utc_datetime(&now).naive_local();
+    let mut occurrence = local_now.date().and_time(schedule.local_time);
+    if occurrence > local_now {
+        occurrence -= Duration::days(1);
+    }
+    let due = to_utc(schedule.timezone, occurrence)?;
+    is_due(due, schedule.last_sent, now).then_some(due)
+}
+
+/// Sends every reminder that is due at `now`, in UTC.
+///
+/// Users are reminded when their latest run is between `inactive_days` and
+/// a day more before the reminder was due, so that each user is only
+/// reminded once until they run again. Users who are no longer members of
+/// the chat, or whose membership cannot be checked, are left out.
+pub async fn send_due_reminders(bot: &Bot, connection: &PgPool, now: NaiveDateTime) {
+    let schedules = match get_reminder_schedules(None, connection).await {
+        Ok(schedules) => schedules,
+        Err(err) => {
+            error!("Unable to retrieve reminder schedules: {:?}", err);
+            return;
+        }
+    };
+
+    for schedule in schedules {
+        let Some(due) = due_reminder(&schedule, now) else {
+            continue;
+        };
+        let until = due - Duration::days(schedule.inactive_days.into());
+        let users = match get_inactive_users(
+            schedule.chat_id,
+            until - Duration::days(1),
+            until,
+            connection,
+        )
+        .await
+        {
+            Ok(users) => users,
+            Err(err) => {
+                error!("Unable to retrieve inactive users: {:?}", err);
+                continue;
+            }
+        };
+
+        let mut members = vec![];
+        for user in users {
+            let Ok(telegram_userid) = user.telegram_userid.parse() else {
+                error!("Unable to parse telegram id of user: {}", user.id);
+                continue;
+            };
+            match bot
+                .get_chat_member(schedule.chat_id, UserId(telegram_userid))
+                .await
+            {
+                Ok(member) if member.is_present() => members.push(user),
+                Ok(_) => {}
+                Err(err) => error!("Unable to check membership of user {}: {:?}", user.id, err),
+            }
+        }
+        info!(
+            "[send_due_reminders]: chat_id: {}, users: {}",
+            schedule.chat_id,
+            members.len()
+        );
+
+        if !members.is_empty() {
+            for part in display_reminder(&members, schedule.inactive_days) {
+                bot.send_message(schedule.chat_id, part)
+                    .parse_mode(ParseMode::Html)
+                    .await
+                    .map_err(|err| error!("Unable to send Reminder message: {:?}", err))
+                    .ok();
+            }
+        }
+        mark_reminder_sent(schedule.chat_id, now, connection)
+            .await
+            .map_err(|err| error!("Unable to mark reminders as sent: {:?}", err))
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::schedule_reminder,
+        harness::{sent_messages, Harness, TestUser},
+    };
+    use chrono::{NaiveDate, Utc};
+
+    #[test]
+    fn parse_reminder_actions() {
+        assert_eq!("".parse(), Ok(ReminderAction::Show));
+        assert_eq!("off".parse(), Ok(ReminderAction::OptOut));
+        assert_eq!("On".parse(), Ok(ReminderAction::OptIn));
+        assert_eq!("stop".parse(), Ok(ReminderAction::Stop));
+        assert_eq!(
+            "3 Asia/Singapore".parse(),
+            Ok(ReminderAction::Schedule {
+                inactive_days: 3,
+                local_time: NaiveTime::from_hms_opt(19, 0, 0).unwrap(),
+                timezone: Tz::Asia__Singapore,
+            })
+        );
+        assert_eq!(
+            "0".parse::<ReminderAction>(),
+            Err(ParseReminderError::Days("0".into()))
+        );
+        assert_eq!(
+            "3 7pm".parse::<ReminderAction>(),
+            Err(ParseReminderError::Argument("7pm".into()))
+        );
+    }
+
+    #[test]
+    fn reminders_are_due_daily() {
+        let at = |day, hour, min| {
+            NaiveDate::from_ymd_opt(2023, 10, day)
+                .unwrap()
+                .and_hms_opt(hour, min, 0)
+                .unwrap()
+        };
+        // 07:00 in New York is 11:00 in UTC during daylight saving time.
+        let schedule = ReminderSchedule {
+            chat_id: ChatId(-100),
+            inactive_days: 3,
+            local_time: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+            timezone: Tz::America__New_York,
+            last_sent: at(13, 11, 0),
+        };
+        assert_eq!(due_reminder(&schedule, at(14, 10, 59)), None);
+        assert_eq!(due_reminder(&schedule, at(14, 11, 0)), Some(at(14, 11, 0)));
+        assert_eq!(due_reminder(&schedule, at(14, 23, 0)), Some(at(14, 11, 0)));
+        assert_eq!(due_reminder(&schedule, at(15, 0, 0)), None);
+    }
+
+    #[sqlx::test]
+    async fn inactive_members_are_reminded_once(postgres: PgPool) {
+        let reuben = TestUser {
+            id: 1,
+            username: "reuben",
+        };
+        let harness = Harness::new(postgres.clone()).await;
+        for id in 1..=4 {
+            let user = TestUser {
+                id,
+                username: ["reuben", "milton", "jerrell", "taigy"][id as usize - 1],
+            };
+            harness.send(-100, &user, "/add 5");
+        }
+        harness.send(-100, &reuben, "/remind off");
+        harness.run(5).await;
+        harness.leave(-100, 3);
+
+        // Everyone ran now, so reminders three days from now are due for all
+        // but reuben, who opted out, and jerrell, who left.
+        let now = Utc::now().naive_utc() + Duration::days(3) + Duration::minutes(1);
+        let local_time = (now - Duration::minutes(1)).time();
+        schedule_reminder(ChatId(-100), 3, local_time, Tz::UTC, &postgres)
+            .await
+            .unwrap();
+        mark_reminder_sent(ChatId(-100), now - Duration::days(1), &postgres)
+            .await
+            .unwrap();
+        let bot = harness.bot();
+        send_due_reminders(&bot, &postgres, now).await;
+        send_due_reminders(&bot, &postgres, now + Duration::days(1)).await;
+
+        let messages = sent_messages(&harness.calls());
+        assert_eq!(messages.len(), 6);
+        assert_eq!(
+            messages[5],
+            "👟 <a href=\"tg://user?id=2\">milton</a>, <a href=\"tg://user?id=4\">taigy</a>: it's been 3 days since your last run. Time to lace up!\n<i>Send /remind off to stop these reminders.</i>"
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Teams within a chat, with `/team`.
+//!
+//! Members of a chat can create teams and join one at a time. The team
+//! tally adds up the runs of every team's members, optionally divided by
+//! the number of members so that small teams can keep up with large ones.
+use crate::{
+    bot::{chat_rank_emojis, page_numbers, Callback},
+    cache::TallyCache,
+    database::{
+        create_team, get_teams, get_teams_per_member, join_team, leave_team, set_teams_per_member,
+    },
+    message::{
+        display_tally, display_team_created, display_team_joined, display_team_left,
+        display_team_missing, display_team_taken, display_teams, split_message,
+    },
+    models::{Period, TallyGrouping},
+};
+use sqlx::PgPool;
+use std::{error::Error, fmt, str::FromStr};
+use teloxide::{prelude::*, types::ParseMode};
+use tracing::error;
+
+/// Longest team name accepted, in characters.
+const MAX_TEAM_NAME: usize = 32;
+
+/// What `/team` was asked to do.
+#[derive(Debug, PartialEq)]
+pub enum TeamAction {
+    /// List the chat's teams and their members.
+    List,
+    /// Create a team with the given name, and join it.
+    Create(String),
+    /// Join the team with the given name, leaving any other.
+    Join(String),
+    /// Leave the team the user is in.
+    Leave,
+    /// Show the team tally over a period.
+    Tally(Period),
+    /// Divide team distances by the number of members, or stop doing so.
+    PerMember(bool),
+}
+
+/// Error returned when the arguments of `/team` cannot be read.
+#[derive(Debug, PartialEq)]
+pub enum ParseTeamError {
+    /// The arguments matched none of the actions.
+    Action,
+    /// The team name was missing or too long.
+    Name,
+}
+
+impl fmt::Display for ParseTeamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseTeamError::Action => write!(
+                f,
+                "Expected /team [list|create <name>|join <name>|leave|tally [week|month|year]|normalise on|off], e.g. /team join Engineering."
+            ),
+            ParseTeamError::Name => write!(
+                f,
+                "Team names are 1 to {} characters long, e.g. /team create Engineering.",
+                MAX_TEAM_NAME
+            ),
+        }
+    }
+}
+
+impl Error for ParseTeamError {}
+
+/// Parses the arguments of `/team`.
+///
+/// Team names may contain spaces, which are collapsed. Keywords are not
+/// case sensitive, and `normalize` is accepted as well as `normalise`.
+impl FromStr for TeamAction {
+    type Err = ParseTeamError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut arguments = s.split_whitespace();
+        let Some(action) = arguments.next() else {
+            return Ok(TeamAction::List);
+        };
+        let rest: Vec<&str> = arguments.collect();
+        let name = || {
+            let name = rest.join(" ");
+            match name.chars().count() {
+                1..=MAX_TEAM_NAME => Ok(name),
+                _ => Err(ParseTeamError::Name),
+            }
+        };
+
+        match (action.to_lowercase().as_str(), &rest[..]) {
+            ("list", []) => Ok(TeamAction::List),
+            ("create", _) => name().map(TeamAction::Create),
+            ("join", _) => name().map(TeamAction::Join),
+            ("leave", []) => Ok(TeamAction::Leave),
+            ("tally", []) => Ok(TeamAction::Tally(Period::All)),
+            ("tally", [period]) => period
+                .parse()
+                .map(TeamAction::Tally)
+                .map_err(|_| ParseTeamError::Action),
+            ("normalise" | "normalize", [toggle]) => match toggle.to_lowercase().as_str() {
+                "on" => Ok(TeamAction::PerMember(true)),
+                "off" => Ok(TeamAction::PerMember(false)),
+                _ => Err(ParseTeamError::Action),
+            },
+            _ => Err(ParseTeamError::Action),
+        }
+    }
+}
+
+/// Handles `/team`, managing the chat's teams or showing the team tally.
+pub async fn answer_team(
+    bot: &Bot,
+    msg: &Message,
+    args: &str,
+    tally_cache: &TallyCache,
+    connection: &PgPool,
+) {
+    let Some(user) = msg.from() else {
+        error!("Unable to retrieve user from message.");
+        return;
+    };
+    let user_name = user.username.as_deref().unwrap_or(&user.first_name);
+    let action = match args.parse::<TeamAction>() {
+        Ok(action) => action,
+        Err(err) => {
+            bot.send_message(msg.chat.id, err.to_string())
+                .await
+                .map_err(|err| error!("Unable to send Team message: {:?}", err))
+                .ok();
+            return;
+        }
+    };
+    if let TeamAction::Tally(period) = action {
+        let per_member = get_teams_per_member(msg.chat.id, connection)
+            .await
+            .map_err(|err| error!("Unable to retrieve team settings: {:?}", err))
+            .unwrap_or_default();
+        let grouping = TallyGrouping::Teams { per_member };
+        match tally_cache
+            .get_tally(msg.chat.id, period, grouping, connection)
+            .await
+        {
+            Ok(tally) => {
+                let emojis = chat_rank_emojis(msg.chat.id, connection).await;
+                let tally_message = display_tally(tally, &emojis, 0, None, grouping);
+                let mut request = bot
+                    .send_message(msg.chat.id, &tally_message.text)
+                    .parse_mode(ParseMode::Html);
+                request.reply_markup = page_numbers(&tally_message, |page| {
+                    Callback::TeamTallyPage { period, page }
+                })
+                .map(Into::into);
+                request
+                    .await
+                    .map_err(|err| error!("Unable to send Team tally: {:?}", err))
+                    .ok();
+            }
+            Err(err) => error!("Unable to retrieve team tally: {:?}", err),
+        }
+        return;
+    }
+
+    // Whether the chat's teams changed, along with the reply.
+    let outcome = match action {
+        TeamAction::List => get_teams(msg.chat.id, connection)
+            .await
+            .map(|teams| (display_teams(&teams), false)),
+        TeamAction::Create(name) => match create_team(msg.chat.id, &name, connection).await {
+            Ok(true) => join_team(msg.chat.id, user.id, user_name, &name, connection)
+                .await
+                .map(|_| (display_team_created(&name), true)),
+            Ok(false) => Ok((display_team_taken(&name), false)),
+            Err(err) => Err(err),
+        },
+        TeamAction::Join(name) => join_team(msg.chat.id, user.id, user_name, &name, connection)
+            .await
+            .map(|team| match team {
+                Some(team) => (display_team_joined(user_name, &team), true),
+                None => (display_team_missing(&name), false),
+            }),
+        TeamAction::Leave => leave_team(msg.chat.id, user.id, connection)
+            .await
+            .map(|left| match left {
+                true => (display_team_left(user_name), true),
+                false => ("You're not in a team.".into(), false),
+            }),
+        TeamAction::PerMember(per_member) => {
+            set_teams_per_member(msg.chat.id, per_member, connection)
+                .await
+                .map(|_| match per_member {
+                    true => (
+                        "Team distances are now divided by the number of members.".into(),
+                        true,
+                    ),
+                    false => ("Team distances are now totals.".into(), true),
+                })
+        }
+        TeamAction::Tally(_) => return,
+    };
+    match outcome {
+        Ok((reply, changed)) => {
+            if changed {
+                tally_cache.invalidate(msg.chat.id);
+            }
+            for part in split_message(&reply) {
+                bot.send_message(msg.chat.id, part)
+                    .parse_mode(ParseMode::Html)
+                    .await
+                    .map_err(|err| error!("Unable to send Team message: {:?}", err))
+                    .ok();
+            }
+        }
+        Err(err) => error!("Unable to update teams: {:?}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_team_actions() {
+        for (input, action) in [
+            ("", TeamAction::List),
+            ("LIST", TeamAction::List),
+            ("create Sales  Ops", TeamAction::Create("Sales Ops".into())),
+            ("join engineering", TeamAction::Join("engineering".into())),
+            ("leave", TeamAction::Leave),
+            ("tally", TeamAction::Tally(Period::All)),
+            ("tally month", TeamAction::Tally(Period::Month)),
+            ("normalize On", TeamAction::PerMember(true)),
+            ("normalise off", TeamAction::PerMember(false)),
+        ] {
+            assert_eq!(input.parse(), Ok(action));
+        }
+        let long_name = format!("create {}", "a".repeat(33));
+        for (invalid, err) in [
+            ("create", ParseTeamError::Name),
+            ("join", ParseTeamError::Name),
+            (&long_name, ParseTeamError::Name),
+            ("tally fortnight", ParseTeamError::Action),
+            ("normalise", ParseTeamError::Action),
+            ("disband Sales", ParseTeamError::Action),
+        ] {
+            assert_eq!(invalid.parse::<TeamAction>(), Err(err));
+        }
+    }
+}
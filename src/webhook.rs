@@ -0,0 +1,659 @@
+//! HTTP endpoints that activity providers send users and events to.
+//!
+//! Each provider gets its own paths. Users who sent `/link` are sent back
+//! to `/oauth/<provider>` once they log in with the provider, which links
+//! the account they logged in with. New activities are pushed to
+//! `/webhooks/<provider>`, fetched from the provider with the account's
+//! tokens and logged as runs in every chat the account is linked in.
+//! Events are handled before they are answered, so that shutting down the
+//! server lets in-flight events finish.
+use crate::{
+    activity::summarise_activity,
+    cache::TallyCache,
+    database::{
+        add_shared_run, get_access_tokens, get_linked_users, link_account, take_link_request,
+        update_access_tokens,
+    },
+    followup::{after_run_added, AddedRun},
+    models::RunDetails,
+    provider::{ActivityProvider, Providers},
+    record::current_records,
+};
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use chrono::{Duration, Utc};
+use serde_json::Value;
+use sqlx::PgPool;
+use std::{collections::HashMap, error::Error, sync::Arc};
+use teloxide::prelude::*;
+use tracing::{error, info};
+
+/// Everything needed to log pushed activities.
+#[derive(Clone)]
+pub struct WebhookState {
+    /// Bot used to announce logged runs.
+    pub bot: Bot,
+    /// Database connection.
+    pub postgres: PgPool,
+    /// Tallies to invalidate when runs are logged.
+    pub tally_cache: Arc<TallyCache>,
+    /// Providers events can come from.
+    pub providers: Providers,
+}
+
+/// Routes of the webhook server.
+pub fn router(state: WebhookState) -> Router {
+    Router::new()
+        .route(
+            "/webhooks/:provider",
+            get(verify_subscription).post(receive_event),
+        )
+        .route("/oauth/:provider", get(finish_link))
+        .with_state(state)
+}
+
+/// Answers a provider checking the endpoint when subscribing to events.
+async fn verify_subscription(
+    State(state): State<WebhookState>,
+    Path(provider): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let provider = state
+        .providers
+        .get(&provider)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    provider
+        .verify(&query)
+        .map(Json)
+        .ok_or(StatusCode::FORBIDDEN)
+}
+
+/// Links the account a user logged in with to the chat they sent `/link`
+/// in, once the provider sends them back with the state `/link` handed
+/// out and a code.
+async fn finish_link(
+    State(state): State<WebhookState>,
+    Path(provider): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> (StatusCode, &'static str) {
+    let Some(provider) = state.providers.get(&provider) else {
+        return (StatusCode::NOT_FOUND, "Unknown provider.");
+    };
+    // Providers send users back without a code if they decline.
+    let (Some(link_state), Some(code)) = (query.get("state"), query.get("code")) else {
+        return (StatusCode::BAD_REQUEST, "Linking was cancelled.");
+    };
+    let request = match take_link_request(provider.name(), link_state, &state.postgres).await {
+        Ok(Some(request)) => request,
+        Ok(None) => {
+            return (
+                StatusCode::FORBIDDEN,
+                "This link has expired or was already used, send /link again.",
+            )
+        }
+        Err(err) => {
+            error!("Unable to retrieve link request: {:?}", err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to link your account.",
+            );
+        }
+    };
+    let account = match provider.exchange_code(code).await {
+        Ok(account) => account,
+        Err(err) => {
+            error!("Unable to exchange code: {:?}", err);
+            return (StatusCode::BAD_GATEWAY, "Unable to link your account.");
+        }
+    };
+    info!(
+        "[finish_link]: provider: {}, account_id: {}, telegram_userid: {}, chat_id: {}",
+        provider.name(),
+        account.account_id,
+        request.telegram_userid,
+        request.chat_id
+    );
+
+    let linked = link_account(
+        provider.name(),
+        &account.account_id,
+        &account.tokens,
+        &request.user_name,
+        request.telegram_userid,
+        request.chat_id,
+        &state.postgres,
+    )
+    .await;
+    if let Err(err) = linked {
+        error!("Unable to Link account: {:?}", err);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unable to link your account.",
+        );
+    }
+    state
+        .bot
+        .send_message(
+            request.chat_id,
+            format!(
+                "Linked {} to {} account {}, new activities will be added here.",
+                request.user_name,
+                provider.name(),
+                account.account_id
+            ),
+        )
+        .await
+        .map_err(|err| error!("Unable to send Link message: {:?}", err))
+        .ok();
+
+    (
+        StatusCode::OK,
+        "Your account is linked, you can go back to Telegram.",
+    )
+}
+
+/// How long before its access token expires an account's tokens are
+/// refreshed.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::minutes(5);
+
+/// The access token of an account linked with `provider`, refreshed first
+/// if it is about to expire. `None` if the account is not linked.
+async fn access_token(
+    provider: &dyn ActivityProvider,
+    account_id: &str,
+    connection: &PgPool,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    let Some(tokens) = get_access_tokens(provider.name(), account_id, connection).await? else {
+        return Ok(None);
+    };
+    if tokens.expires_at > Utc::now().naive_utc() + TOKEN_EXPIRY_MARGIN {
+        return Ok(Some(tokens.access_token));
+    }
+    let tokens = provider.refresh_tokens(&tokens.refresh_token).await?;
+    update_access_tokens(provider.name(), account_id, &tokens, connection).await?;
+
+    Ok(Some(tokens.access_token))
+}
+
+/// Logs the activity announced by an event in every linked chat, and
+/// shares it with the chats the user shares runs with.
+///
+/// Activities are logged once per chat, repeated events are ignored.
+/// Events sent for another subscription, or for accounts that are not
+/// linked, are rejected.
+async fn receive_event(
+    State(state): State<WebhookState>,
+    Path(provider): Path<String>,
+    body: Bytes,
+) -> StatusCode {
+    let Some(provider) = state.providers.get(&provider) else {
+        return StatusCode::NOT_FOUND;
+    };
+    // Providers expect every event to be acknowledged, including those
+    // that are of no interest to us.
+    let event = match provider.parse_event(&body) {
+        Ok(Some(event)) => event,
+        Ok(None) => return StatusCode::OK,
+        Err(err) => {
+            info!("Rejected event: {}", err);
+            return StatusCode::FORBIDDEN;
+        }
+    };
+    info!(
+        "[receive_event]: provider: {}, account_id: {}, activity_id: {}",
+        provider.name(),
+        event.account_id,
+        event.activity_id
+    );
+
+    let access_token =
+        match access_token(provider.as_ref(), &event.account_id, &state.postgres).await {
+            Ok(Some(access_token)) => access_token,
+            Ok(None) => {
+                info!("Rejected event of unlinked account: {}", event.account_id);
+                return StatusCode::FORBIDDEN;
+            }
+            Err(err) => {
+                error!("Unable to retrieve access token: {:?}", err);
+                return StatusCode::BAD_GATEWAY;
+            }
+        };
+    let users = match get_linked_users(provider.name(), &event.account_id, &state.postgres).await {
+        Ok(users) => users,
+        Err(err) => {
+            error!("Unable to retrieve linked users: {:?}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+    let activity = match provider.fetch_activity(&event, &access_token).await {
+        Ok(activity) => activity,
+        Err(err) => {
+            error!("Unable to fetch activity: {:?}", err);
+            return StatusCode::BAD_GATEWAY;
+        }
+    };
+
+    for user in users {
+        let (Ok(telegram_userid), Ok(chat_id)) =
+            (user.telegram_userid.parse(), user.chat_id.parse())
+        else {
+            error!("Unable to parse ids of user: {}", user.id);
+            continue;
+        };
+        let chat_id = ChatId(chat_id);
+        let details = RunDetails {
+            external_id: Some(format!("{}:{}", provider.name(), event.activity_id)),
+            ..activity.details()
+        };
+        let records = current_records(chat_id, UserId(telegram_userid), &state.postgres).await;
+        let added = add_shared_run(
+            activity.distance,
+            details,
+            &user.user_name,
+            UserId(telegram_userid),
+            chat_id,
+            &state.postgres,
+        )
+        .await;
+        match added {
+            Ok(Some(shared_to)) => {
+                let run = AddedRun {
+                    chat_id,
+                    telegram_userid: UserId(telegram_userid),
+                    user_name: &user.user_name,
+                    distance: activity.distance,
+                    shared_to,
+                    records_before: records,
+                };
+                let summary = summarise_activity(&user.user_name, &activity);
+                after_run_added(
+                    &state.bot,
+                    run,
+                    summary,
+                    None,
+                    &state.tally_cache,
+                    &state.postgres,
+                )
+                .await;
+            }
+            Ok(None) => info!("Activity already logged in chat_id: {}", chat_id.0),
+            Err(err) => error!("Unable to Add run information from provider: {:?}", err),
+        }
+    }
+
+    StatusCode::OK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::get_profile_progress,
+        harness::{sent_messages, Harness, TestUser},
+        provider::Strava,
+    };
+    use axum::{
+        http::{header::AUTHORIZATION, HeaderMap},
+        routing::post,
+        Form,
+    };
+    use serde_json::json;
+    use std::net::SocketAddr;
+
+    const CHAT: i64 = -100;
+    const OTHER_CHAT: i64 = -200;
+    const REUBEN: TestUser = TestUser {
+        id: 11,
+        username: "reuben",
+    };
+    const MILTON: TestUser = TestUser {
+        id: 22,
+        username: "milton",
+    };
+
+    /// When tokens handed out by the fake Strava expire, unless they
+    /// already have.
+    const EXPIRES_AT: i64 = 4_102_444_800;
+
+    /// Serves `router` on an ephemeral port.
+    fn serve(router: Router) -> SocketAddr {
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(router.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// A stand-in for Strava's API. Logging in with the code `reuben` or
+    /// `milton` links athlete 99 or 77, and `expired` links athlete 99 with
+    /// tokens that have expired. Activity 1234 is athlete 99's and 4321 is
+    /// athlete 55's, and both are only served with the access token `token`.
+    fn fake_strava() -> SocketAddr {
+        serve(
+            Router::new()
+                .route(
+                    "/oauth/token",
+                    post(|Form(form): Form<HashMap<String, String>>| async move {
+                        let field = |key: &str| form.get(key).map(String::as_str);
+                        if field("client_id") != Some("client")
+                            || field("client_secret") != Some("secret")
+                        {
+                            return Err(StatusCode::UNAUTHORIZED);
+                        }
+                        let (athlete, access_token, refresh_token, expires_at) =
+                            match (field("grant_type"), field("code"), field("refresh_token")) {
+                                (Some("authorization_code"), Some("reuben"), _) => {
+                                    (Some(99), "token", "refresh", EXPIRES_AT)
+                                }
+                                (Some("authorization_code"), Some("milton"), _) => {
+                                    (Some(77), "token", "refresh", EXPIRES_AT)
+                                }
+                                (Some("authorization_code"), Some("expired"), _) => {
+                                    (Some(99), "stale", "refresh", 0)
+                                }
+                                (Some("refresh_token"), _, Some("refresh")) => {
+                                    (None, "token", "refreshed", EXPIRES_AT)
+                                }
+                                _ => return Err(StatusCode::BAD_REQUEST),
+                            };
+                        Ok(Json(json!({
+                            "token_type": "Bearer",
+                            "access_token": access_token,
+                            "refresh_token": refresh_token,
+                            "expires_at": expires_at,
+                            "athlete": athlete.map(|id| json!({ "id": id })),
+                        })))
+                    }),
+                )
+                .route(
+                    "/activities/:id",
+                    get(|Path(id): Path<u64>, headers: HeaderMap| async move {
+                        if headers
+                            .get(AUTHORIZATION)
+                            .and_then(|value| value.to_str().ok())
+                            != Some("Bearer token")
+                        {
+                            return Err(StatusCode::UNAUTHORIZED);
+                        }
+                        let athlete = match id {
+                            1234 => 99,
+                            4321 => 55,
+                            _ => return Err(StatusCode::NOT_FOUND),
+                        };
+                        Ok(Json(json!({
+                            "id": id,
+                            "athlete": { "id": athlete },
+                            "name": "Morning Run",
+                            "distance": 10012.3,
+                            "moving_time": 3000,
+                            "elapsed_time": 3060,
+                            "start_date": "2023-10-14T07:12:00Z",
+                            "sport_type": "Run",
+                            "average_heartrate": 151.4,
+                            "max_heartrate": 170.0,
+                            "total_elevation_gain": 35.0,
+                            "workout_type": 1,
+                            "splits_metric": (0..11)
+                                .map(|km| match km {
+                                    10 => json!({ "distance": 12.3, "elapsed_time": 60 }),
+                                    _ => json!({ "distance": 1000.0, "elapsed_time": 300 }),
+                                })
+                                .collect::<Vec<_>>(),
+                        })))
+                    }),
+                ),
+        )
+    }
+
+    /// Starts a bot and webhook server with Strava served by `fake_strava`.
+    async fn start(postgres: PgPool) -> (Harness, SocketAddr) {
+        let strava = fake_strava();
+        let providers = Providers::default().with(Strava::for_tests(&format!("http://{}", strava)));
+        let harness = Harness::with_providers(postgres.clone(), providers.clone()).await;
+        let webhooks = serve(router(WebhookState {
+            bot: harness.bot(),
+            postgres,
+            tally_cache: Arc::new(TallyCache::default()),
+            providers,
+        }));
+        (harness, webhooks)
+    }
+
+    /// The state handed to Strava in the link sent in reply to `/link`.
+    fn state_in(reply: &str) -> String {
+        let url: reqwest::Url = reply[reply.find("https://").unwrap()..].parse().unwrap();
+        url.query_pairs()
+            .find(|(key, _)| key == "state")
+            .map(|(_, state)| state.into_owned())
+            .unwrap()
+    }
+
+    /// Comes back from logging in with Strava with `state` and `code`.
+    async fn log_in(webhooks: SocketAddr, state: &str, code: &str) -> StatusCode {
+        reqwest::get(format!(
+            "http://{}/oauth/strava?state={}&code={}&scope=read,activity:read_all",
+            webhooks, state, code
+        ))
+        .await
+        .unwrap()
+        .status()
+    }
+
+    /// Sends `/link strava` as `user` and logs in with `code`, once `sent`
+    /// messages have been sent before.
+    async fn link(
+        harness: &Harness,
+        webhooks: SocketAddr,
+        chat_id: i64,
+        user: &TestUser,
+        code: &str,
+        sent: usize,
+    ) -> StatusCode {
+        harness.send(chat_id, user, "/link strava");
+        let replies = harness.run(sent + 1).await;
+        log_in(webhooks, &state_in(&replies[sent]), code).await
+    }
+
+    /// An event announcing a new activity of `owner_id`'s.
+    fn event(activity_id: u64, owner_id: u64) -> Value {
+        json!({
+            "object_type": "activity",
+            "object_id": activity_id,
+            "aspect_type": "create",
+            "owner_id": owner_id,
+            "subscription_id": 1,
+            "event_time": 1_697_267_520,
+        })
+    }
+
+    /// Pushes an event to the webhook.
+    async fn push(webhooks: SocketAddr, body: String) -> StatusCode {
+        reqwest::Client::new()
+            .post(format!("http://{}/webhooks/strava", webhooks))
+            .body(body)
+            .send()
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[sqlx::test]
+    async fn pushed_activities_are_logged_in_linked_chats(postgres: PgPool) {
+        let (harness, webhooks) = start(postgres).await;
+        assert_eq!(
+            link(&harness, webhooks, CHAT, &REUBEN, "reuben", 0).await,
+            200
+        );
+        assert_eq!(
+            link(&harness, webhooks, OTHER_CHAT, &REUBEN, "reuben", 2).await,
+            200
+        );
+        harness.send(CHAT, &REUBEN, "/link garmin");
+        let replies = harness.run(5).await;
+        assert_eq!(
+            replies[1],
+            "Linked reuben to strava account 99, new activities will be added here."
+        );
+        assert_eq!(
+            replies[4],
+            "Unknown provider garmin, expected one of: strava."
+        );
+
+        // The same event twice, an update and an activity that can't be found.
+        for (activity_id, aspect_type, status) in [
+            (1234, "create", 200),
+            (1234, "create", 200),
+            (1234, "update", 200),
+            (5678, "create", 502),
+        ] {
+            let mut body = event(activity_id, 99);
+            body["aspect_type"] = json!(aspect_type);
+            assert_eq!(push(webhooks, body.to_string()).await, status);
+        }
+
+        let calls = harness.calls();
+        let logged: Vec<_> = calls[5..]
+            .iter()
+            .map(|call| (call.body["chat_id"].clone(), call.body["text"].clone()))
+            .collect();
+        let summary = json!("reuben ran 10.01km in 51:00 (50:00 moving) from 2023-10-14 07:12, avg HR 151 (max 170), 35m climbed, added to database.\n🏅 First 1k on record: 5:00!\n🏅 First 5k on record: 25:00!\n🏅 First 10k on record: 50:00!");
+        assert_eq!(
+            logged,
+            vec![(json!(CHAT), summary.clone()), (json!(OTHER_CHAT), summary)]
+        );
+        assert_eq!(sent_messages(&calls).len(), 7);
+    }
+
+    #[sqlx::test]
+    async fn pushed_activities_are_shared_once_per_chat(postgres: PgPool) {
+        const THIRD_CHAT: i64 = -300;
+        let (harness, webhooks) = start(postgres.clone()).await;
+        link(&harness, webhooks, CHAT, &REUBEN, "reuben", 0).await;
+        link(&harness, webhooks, OTHER_CHAT, &REUBEN, "reuben", 2).await;
+        for chat_id in [CHAT, OTHER_CHAT, THIRD_CHAT] {
+            harness.send(chat_id, &REUBEN, "/profile share on");
+        }
+        harness.run(7).await;
+
+        assert_eq!(push(webhooks, event(1234, 99).to_string()).await, 200);
+
+        // The linked chat logged first shares the activity with the others,
+        // including the other linked chat, which then does not log it again.
+        let calls = harness.calls();
+        let logged: Vec<_> = calls[7..]
+            .iter()
+            .map(|call| (call.body["chat_id"].clone(), call.body["text"].clone()))
+            .collect();
+        let shared = json!("reuben ran 10.01km, shared from another chat.");
+        assert_eq!(logged.len(), 3);
+        assert_eq!(logged[0].0, json!(CHAT));
+        assert_eq!(
+            logged[1..],
+            [
+                (json!(OTHER_CHAT), shared.clone()),
+                (json!(THIRD_CHAT), shared)
+            ]
+        );
+        let progress = get_profile_progress(UserId(11), None, &postgres)
+            .await
+            .unwrap();
+        assert_eq!(progress.runs, 1);
+    }
+
+    #[sqlx::test]
+    async fn accounts_are_linked_by_logging_in(postgres: PgPool) {
+        let (harness, webhooks) = start(postgres).await;
+        // milton claims reuben's account, but gets the one he logs in with.
+        harness.send(CHAT, &MILTON, "/link strava 99");
+        let replies = harness.run(1).await;
+        assert!(replies[0].starts_with(
+            "Open this link within an hour and log in with strava to link your account"
+        ));
+        let state = state_in(&replies[0]);
+        assert_eq!(log_in(webhooks, &state, "milton").await, 200);
+
+        // Links work once, and only those handed out by /link work.
+        assert_eq!(log_in(webhooks, &state, "reuben").await, 403);
+        assert_eq!(log_in(webhooks, "made-up", "reuben").await, 403);
+        let cancelled = reqwest::get(format!(
+            "http://{}/oauth/strava?state={}&error=access_denied",
+            webhooks, state
+        ))
+        .await
+        .unwrap();
+        assert_eq!(cancelled.status(), 400);
+
+        assert_eq!(push(webhooks, event(1234, 99).to_string()).await, 403);
+        let replies = sent_messages(&harness.calls());
+        assert_eq!(
+            replies[1..],
+            ["Linked milton to strava account 77, new activities will be added here."]
+        );
+    }
+
+    #[sqlx::test]
+    async fn forged_events_are_rejected(postgres: PgPool) {
+        let (harness, webhooks) = start(postgres).await;
+        link(&harness, webhooks, CHAT, &REUBEN, "reuben", 0).await;
+
+        let mut other_subscription = event(1234, 99);
+        other_subscription["subscription_id"] = json!(2);
+        assert_eq!(push(webhooks, other_subscription.to_string()).await, 403);
+        assert_eq!(push(webhooks, "not an event".into()).await, 403);
+        // Athlete 55 never linked their account.
+        assert_eq!(push(webhooks, event(4321, 55).to_string()).await, 403);
+        // An activity of athlete 55's passed off as reuben's.
+        assert_eq!(push(webhooks, event(4321, 99).to_string()).await, 502);
+        assert_eq!(sent_messages(&harness.calls()).len(), 2);
+
+        assert_eq!(push(webhooks, event(1234, 99).to_string()).await, 200);
+        assert_eq!(sent_messages(&harness.calls()).len(), 3);
+    }
+
+    #[sqlx::test]
+    async fn expired_tokens_are_refreshed(postgres: PgPool) {
+        let (harness, webhooks) = start(postgres.clone()).await;
+        link(&harness, webhooks, CHAT, &REUBEN, "expired", 0).await;
+
+        assert_eq!(push(webhooks, event(1234, 99).to_string()).await, 200);
+        let replies = sent_messages(&harness.calls());
+        assert!(replies[2].starts_with("reuben ran 10.01km"));
+        let tokens = get_access_tokens("strava", "99", &postgres)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (tokens.access_token.as_str(), tokens.refresh_token.as_str()),
+            ("token", "refreshed")
+        );
+    }
+
+    #[sqlx::test]
+    async fn subscriptions_are_verified(postgres: PgPool) {
+        let providers = Providers::default().with(Strava::for_tests("http://localhost"));
+        let harness = Harness::new(postgres.clone()).await;
+        let webhooks = serve(router(WebhookState {
+            bot: harness.bot(),
+            postgres,
+            tally_cache: Arc::new(TallyCache::default()),
+            providers,
+        }));
+        let verify = |provider: &str, token: &str| {
+            reqwest::get(format!(
+                "http://{}/webhooks/{}?hub.mode=subscribe&hub.verify_token={}&hub.challenge=abc",
+                webhooks, provider, token
+            ))
+        };
+
+        let response = verify("strava", "verify").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), r#"{"hub.challenge":"abc"}"#);
+        assert_eq!(verify("strava", "wrong").await.unwrap().status(), 403);
+        assert_eq!(verify("garmin", "verify").await.unwrap().status(), 404);
+    }
+}